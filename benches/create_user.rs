@@ -0,0 +1,32 @@
+// Creating users against a running server, `RSAPP_URL` or http://localhost:9009:
+//
+//     cargo run -- up &
+//     cargo bench
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn create_user(c: &mut Criterion) {
+    let url = std::env::var("RSAPP_URL").unwrap_or_else(|_| "http://localhost:9009".to_owned());
+    let client = reqwest::Client::new();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    // usernames are unique, each run and iteration takes another
+    let run = std::process::id();
+    let created = AtomicUsize::new(0);
+
+    c.bench_function("create_user", |b| {
+        b.iter(|| {
+            let n = created.fetch_add(1, Ordering::Relaxed);
+            let response = rt.block_on(
+                client
+                    .post(format!("{}/users", url))
+                    .json(&serde_json::json!({"username": format!("bench-{}-{}", run, n)}))
+                    .send(),
+            );
+            assert!(response.unwrap().status().is_success());
+        })
+    });
+}
+
+criterion_group!(benches, create_user);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+// generates the gRPC server of proto/rsapp.proto, see src/grpc.rs
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/rsapp.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,3 @@
+DROP TABLE playlist_items;
+DROP TABLE playlist_collaborators;
+DROP TABLE playlists;
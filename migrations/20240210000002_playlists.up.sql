@@ -0,0 +1,29 @@
+CREATE TABLE playlists (
+    id BIGSERIAL PRIMARY KEY,
+    owner_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    -- whether collaborators may edit the playlist
+    collaborative BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX playlists_owner_id_idx ON playlists (owner_id);
+
+CREATE TABLE playlist_collaborators (
+    playlist_id BIGINT NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL,
+    PRIMARY KEY (playlist_id, user_id)
+);
+
+CREATE INDEX playlist_collaborators_user_id_idx ON playlist_collaborators (user_id);
+
+CREATE TABLE playlist_items (
+    id BIGSERIAL PRIMARY KEY,
+    playlist_id BIGINT NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+    asset_id BIGINT NOT NULL REFERENCES assets (id) ON DELETE CASCADE,
+    -- fractional index, compared bytewise
+    position TEXT COLLATE "C" NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (playlist_id, position)
+);
@@ -0,0 +1,15 @@
+CREATE TABLE bookmarks (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    asset_id BIGINT NOT NULL REFERENCES assets (id) ON DELETE CASCADE,
+    -- offset into the asset in seconds, NULL bookmarks the whole asset (a favorite)
+    time_secs DOUBLE PRECISION,
+    note TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX bookmarks_user_id_asset_id_idx ON bookmarks (user_id, asset_id);
+CREATE INDEX bookmarks_user_id_updated_at_idx ON bookmarks (user_id, updated_at);
+-- an asset is favorited at most once per user
+CREATE UNIQUE INDEX bookmarks_favorite_idx ON bookmarks (user_id, asset_id) WHERE time_secs IS NULL;
@@ -0,0 +1,2 @@
+DROP TABLE rate_plan_assignments;
+DROP TABLE rate_plans;
@@ -0,0 +1,14 @@
+-- limits a client may use; NULL means unlimited
+CREATE TABLE rate_plans (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    requests_per_minute INTEGER CHECK (requests_per_minute >= 0),
+    transcode_minutes_per_day INTEGER CHECK (transcode_minutes_per_day >= 0),
+    bandwidth_bytes_per_day BIGINT CHECK (bandwidth_bytes_per_day >= 0)
+);
+
+-- clients without an assignment get the plan named 'default', if there is one
+CREATE TABLE rate_plan_assignments (
+    user_id BIGINT PRIMARY KEY,
+    plan_id BIGINT NOT NULL REFERENCES rate_plans (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- probed when the asset is registered, NULL until a probe succeeded
+ALTER TABLE assets ADD COLUMN metadata JSONB;
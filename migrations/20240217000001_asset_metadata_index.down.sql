@@ -0,0 +1 @@
+DROP INDEX assets_metadata_idx;
@@ -0,0 +1,2 @@
+-- serves the `metadata @? path` filters of `GET /assets?meta.…`
+CREATE INDEX assets_metadata_idx ON assets USING GIN (metadata jsonb_path_ops);
@@ -0,0 +1,13 @@
+-- notes shown on the public status page, written by admins
+CREATE TABLE incidents (
+    id BIGSERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    message TEXT NOT NULL DEFAULT '',
+    -- the status page component it is about, if any
+    component TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    resolved_at TIMESTAMPTZ
+);
+
+CREATE INDEX incidents_created_at ON incidents (created_at);
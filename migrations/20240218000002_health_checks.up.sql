@@ -0,0 +1,9 @@
+-- one row per component per status page sample, for availability reports
+CREATE TABLE health_checks (
+    component TEXT NOT NULL,
+    checked_at TIMESTAMPTZ NOT NULL,
+    up BOOLEAN NOT NULL,
+    PRIMARY KEY (component, checked_at)
+);
+
+CREATE INDEX health_checks_checked_at ON health_checks (checked_at);
@@ -0,0 +1,11 @@
+-- running server processes, each refreshing its row while it's up
+CREATE TABLE instances (
+    id TEXT PRIMARY KEY,
+    version TEXT NOT NULL,
+    config_hash TEXT NOT NULL,
+    -- the newest migration the build knows
+    migration BIGINT NOT NULL,
+    ffmpeg TEXT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,9 @@
+-- when a user was first shown a variant of an experiment, for analysis
+CREATE TABLE experiment_exposures (
+    experiment TEXT NOT NULL,
+    variant TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    exposed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- a new salt can move users to another variant, that's another exposure
+    PRIMARY KEY (experiment, user_id, variant)
+);
@@ -0,0 +1,5 @@
+DROP INDEX users_username;
+
+ALTER TABLE users
+    DROP COLUMN role,
+    DROP COLUMN password_hash;
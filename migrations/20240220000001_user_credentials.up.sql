@@ -0,0 +1,6 @@
+-- accounts that can log in have a password; the rest were created before authentication
+ALTER TABLE users
+    ADD COLUMN password_hash TEXT,
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin'));
+
+CREATE UNIQUE INDEX users_username ON users (username);
@@ -0,0 +1,35 @@
+-- the media job queue, shared by every process with workers
+CREATE TABLE jobs (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    kind JSONB NOT NULL,
+    -- the media file to work on
+    input TEXT NOT NULL,
+    -- the transcoder implementation picked at submission, see the canaries
+    variant TEXT NOT NULL DEFAULT 'stable' CHECK (variant IN ('stable', 'canary')),
+    state TEXT NOT NULL DEFAULT 'queued'
+        CHECK (state IN ('queued', 'running', 'finished', 'failed')),
+    progress REAL NOT NULL DEFAULT 0,
+    output TEXT,
+    error TEXT,
+    -- length of the input, for transcodes, which count against rate plans by it
+    media_seconds DOUBLE PRECISION,
+    -- the instance running it
+    worker TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX jobs_queued ON jobs (id) WHERE state = 'queued';
+CREATE INDEX jobs_user_created_at ON jobs (user_id, created_at);
+
+-- tells listeners on the `jobs` channel the id of every job that changed
+CREATE FUNCTION notify_job_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('jobs', NEW.id::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER jobs_notify AFTER INSERT OR UPDATE ON jobs
+    FOR EACH ROW EXECUTE FUNCTION notify_job_change();
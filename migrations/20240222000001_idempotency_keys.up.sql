@@ -0,0 +1,15 @@
+-- answers to requests sent with an Idempotency-Key, replayed when they're retried
+CREATE TABLE idempotency_keys (
+    -- the endpoint, like `POST /users`
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    -- of the request the key first came with, it's refused with another one
+    request_hash TEXT NOT NULL,
+    -- both null while the first request is being handled
+    status SMALLINT,
+    body JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (scope, key)
+);
+
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
@@ -0,0 +1 @@
+DROP TABLE aggregate_snapshots;
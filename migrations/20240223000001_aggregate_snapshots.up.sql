@@ -0,0 +1,8 @@
+-- the last computed value of expensive admin aggregates, answered after a restart until they're
+-- computed again
+CREATE TABLE aggregate_snapshots (
+    -- the aggregate and what it was asked for, like `uptime:2592000`
+    name TEXT PRIMARY KEY,
+    value JSONB NOT NULL,
+    computed_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,9 @@
+-- files the library scanner found under its root, each registered as an asset
+CREATE TABLE library_files (
+    asset_id BIGINT PRIMARY KEY REFERENCES assets (id) ON DELETE CASCADE,
+    size BIGINT NOT NULL,
+    modified_at TIMESTAMPTZ NOT NULL,
+    scanned_at TIMESTAMPTZ NOT NULL,
+    -- since when scans don't find it anymore, NULL while it's there
+    missing_since TIMESTAMPTZ
+);
@@ -0,0 +1,2 @@
+DROP INDEX assets_search_idx;
+ALTER TABLE assets DROP COLUMN search;
@@ -0,0 +1,17 @@
+-- words to find assets by with `GET /library/search`: the title weighs most, then the tags, then
+-- the container format and stream codecs. The simple configuration doesn't stem, titles and
+-- tags come in any language.
+ALTER TABLE assets ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', title), 'A')
+    || setweight(jsonb_to_tsvector('simple', coalesce(metadata -> 'tags', '{}'), '["string"]'), 'B')
+    || setweight(
+        to_tsvector('simple', coalesce(metadata ->> 'format', ''))
+        || jsonb_to_tsvector(
+            'simple',
+            coalesce(jsonb_path_query_array(metadata, '$.streams[*].codec'), '[]'),
+            '["string"]'
+        ),
+        'C'
+    )
+) STORED;
+CREATE INDEX assets_search_idx ON assets USING GIN (search);
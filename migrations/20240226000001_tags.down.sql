@@ -0,0 +1,2 @@
+DROP TABLE asset_tags;
+DROP TABLE tags;
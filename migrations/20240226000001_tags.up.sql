@@ -0,0 +1,15 @@
+-- shared labels to organize the library with
+CREATE TABLE tags (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- names differing only in case are the same tag
+CREATE UNIQUE INDEX tags_name_idx ON tags (lower(name));
+
+CREATE TABLE asset_tags (
+    asset_id BIGINT NOT NULL REFERENCES assets (id) ON DELETE CASCADE,
+    tag_id BIGINT NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+    PRIMARY KEY (asset_id, tag_id)
+);
+CREATE INDEX asset_tags_tag_id_idx ON asset_tags (tag_id);
@@ -0,0 +1,3 @@
+DROP INDEX library_files_sha256_idx;
+ALTER TABLE library_files DROP COLUMN frame_hash;
+ALTER TABLE library_files DROP COLUMN sha256;
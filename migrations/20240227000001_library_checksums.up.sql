@@ -0,0 +1,5 @@
+-- of the contents when last scanned, NULL for files scanned before
+ALTER TABLE library_files ADD COLUMN sha256 TEXT;
+-- perceptual hash of a frame of videos, with `library.frame_hashes`
+ALTER TABLE library_files ADD COLUMN frame_hash BIGINT;
+CREATE INDEX library_files_sha256_idx ON library_files (sha256);
@@ -0,0 +1,12 @@
+-- resumable uploads, the bytes received so far are in a file of their own until complete
+CREATE TABLE uploads (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    file_name TEXT NOT NULL,
+    title TEXT,
+    -- bytes the upload has once complete
+    length BIGINT NOT NULL,
+    -- the asset it became once complete
+    asset_id BIGINT REFERENCES assets (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+DROP TRIGGER jobs_history_update ON jobs;
+DROP TRIGGER jobs_history_insert ON jobs;
+DROP TABLE job_history;
+DROP FUNCTION record_job_state();
@@ -0,0 +1,28 @@
+-- every state each job got into, for as long as the job is kept
+CREATE TABLE job_history (
+    id BIGSERIAL PRIMARY KEY,
+    job_id BIGINT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    state TEXT NOT NULL,
+    error TEXT,
+    at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX job_history_job_id ON job_history (job_id, id);
+
+-- what there is of the jobs from before
+INSERT INTO job_history (job_id, state, error, at)
+    SELECT id, state, error, updated_at FROM jobs;
+
+CREATE FUNCTION record_job_state() RETURNS trigger AS $$
+BEGIN
+    INSERT INTO job_history (job_id, state, error) VALUES (NEW.id, NEW.state, NEW.error);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- whoever changes the state, workers or the requeueing of orphans
+CREATE TRIGGER jobs_history_insert AFTER INSERT ON jobs
+    FOR EACH ROW EXECUTE FUNCTION record_job_state();
+CREATE TRIGGER jobs_history_update AFTER UPDATE OF state ON jobs
+    FOR EACH ROW WHEN (OLD.state IS DISTINCT FROM NEW.state)
+    EXECUTE FUNCTION record_job_state();
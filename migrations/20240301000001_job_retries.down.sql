@@ -0,0 +1,7 @@
+DROP INDEX jobs_dead;
+UPDATE jobs SET state = 'failed' WHERE state = 'dead';
+ALTER TABLE jobs DROP COLUMN run_after;
+ALTER TABLE jobs DROP COLUMN attempts;
+ALTER TABLE jobs DROP CONSTRAINT jobs_state_check;
+ALTER TABLE jobs ADD CONSTRAINT jobs_state_check
+    CHECK (state IN ('queued', 'running', 'finished', 'failed'));
@@ -0,0 +1,11 @@
+-- failed jobs are retried, backing off; jobs that failed every retry are `dead`, kept until an
+-- admin queues them again
+ALTER TABLE jobs DROP CONSTRAINT jobs_state_check;
+ALTER TABLE jobs ADD CONSTRAINT jobs_state_check
+    CHECK (state IN ('queued', 'running', 'finished', 'failed', 'dead'));
+-- runs so far, counting those of instances that stopped
+ALTER TABLE jobs ADD COLUMN attempts INT NOT NULL DEFAULT 0;
+-- not claimed before, while a retry backs off
+ALTER TABLE jobs ADD COLUMN run_after TIMESTAMPTZ;
+
+CREATE INDEX jobs_dead ON jobs (id) WHERE state = 'dead';
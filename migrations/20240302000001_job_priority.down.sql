@@ -0,0 +1,4 @@
+DROP INDEX jobs_queued;
+CREATE INDEX jobs_queued ON jobs (id) WHERE state = 'queued';
+
+ALTER TABLE jobs DROP COLUMN priority;
@@ -0,0 +1,5 @@
+-- claimed highest first: 0 low, 1 normal, 2 high
+ALTER TABLE jobs ADD COLUMN priority SMALLINT NOT NULL DEFAULT 1 CHECK (priority BETWEEN 0 AND 2);
+
+DROP INDEX jobs_queued;
+CREATE INDEX jobs_queued ON jobs (priority DESC, id) WHERE state = 'queued';
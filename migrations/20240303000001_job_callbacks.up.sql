@@ -0,0 +1,2 @@
+-- where jobs are POSTed once done, see the webhooks
+ALTER TABLE jobs ADD COLUMN callback_url TEXT;
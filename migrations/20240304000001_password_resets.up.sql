@@ -0,0 +1,11 @@
+-- where password reset links are mailed to, for accounts that have one
+ALTER TABLE users ADD COLUMN email TEXT;
+
+CREATE UNIQUE INDEX users_email ON users (lower(email));
+
+-- links mailed for resetting a password, by the SHA-256 of their token; used ones are deleted
+CREATE TABLE password_resets (
+    token_hash TEXT PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,8 @@
+-- cookie sessions of browsers, as tower-sessions' Postgres store keeps them
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    data BYTEA NOT NULL,
+    expiry_date TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX sessions_expiry_date ON sessions (expiry_date);
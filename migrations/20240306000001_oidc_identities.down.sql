@@ -0,0 +1,5 @@
+DROP INDEX users_oidc_identity;
+
+ALTER TABLE users
+    DROP COLUMN oidc_issuer,
+    DROP COLUMN oidc_subject;
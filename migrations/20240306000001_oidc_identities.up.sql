@@ -0,0 +1,6 @@
+-- who accounts signed in as at an OpenID Connect provider, see `oidc`
+ALTER TABLE users
+    ADD COLUMN oidc_issuer TEXT,
+    ADD COLUMN oidc_subject TEXT;
+
+CREATE UNIQUE INDEX users_oidc_identity ON users (oidc_issuer, oidc_subject);
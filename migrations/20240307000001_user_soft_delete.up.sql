@@ -0,0 +1,2 @@
+-- users are soft deleted, so admins can restore them
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
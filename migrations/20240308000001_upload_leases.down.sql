@@ -0,0 +1 @@
+ALTER TABLE uploads DROP COLUMN lease, DROP COLUMN leased_until;
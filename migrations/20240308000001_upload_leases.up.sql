@@ -0,0 +1,3 @@
+-- who appends to an upload, whichever server they're on: a lease renewed while they do, so one
+-- held by a server that went away runs out
+ALTER TABLE uploads ADD COLUMN lease BIGINT, ADD COLUMN leased_until TIMESTAMPTZ;
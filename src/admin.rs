@@ -0,0 +1,265 @@
+// Runtime introspection for operators: the configuration in effect with its secrets masked, how
+// busy the database pool, the job queue and this instance's request handling are, and the log
+// level, which `PUT /admin/log-level` changes until SIGHUP resets it to the configured one.
+//
+// The admin endpoints are those under /admin. With `server.admin_listen` they are served there
+// only, apart from the rest, so a firewall can keep them internal.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{
+    app::AppState,
+    auth::Admin,
+    cli, db_error,
+    job::{Jobs, QueueDepth},
+    listen::{ConnectionStats, Connections},
+    logging,
+};
+
+pub struct Runtime {
+    // as merged from all sources, masked
+    config: Value,
+    // requests being handled, until their response started
+    in_flight: AtomicUsize,
+    connections: Arc<Connections>,
+}
+
+impl Runtime {
+    // `settings` as merged from all sources, which are masked here
+    pub fn new(settings: &Value) -> Self {
+        let mut config = settings.clone();
+        cli::redact(&mut config);
+        Runtime {
+            config,
+            in_flight: AtomicUsize::new(0),
+            connections: Arc::default(),
+        }
+    }
+
+    // those of the listener serving the app
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+// counts the request as in flight until its response started, or it was dropped
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// middleware counting the requests being handled
+pub async fn track(State(runtime): State<Arc<Runtime>>, request: Request, next: Next) -> Response {
+    runtime.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _in_flight = InFlight(&runtime.in_flight);
+    next.run(request).await
+}
+
+pub fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+// `router` as served on the public listener, and on the admin listener
+pub fn split(router: Router) -> (Router, Router) {
+    let public = router.clone().layer(middleware::from_fn(public_only));
+    (public, router.layer(middleware::from_fn(admin_only)))
+}
+
+async fn public_only(request: Request, next: Next) -> Response {
+    if is_admin_path(request.uri().path()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(request).await
+}
+
+async fn admin_only(request: Request, next: Next) -> Response {
+    if !is_admin_path(request.uri().path()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(request).await
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PoolStats {
+    // connections open, in use or idle
+    pub size: u32,
+    pub idle: usize,
+    pub in_use: usize,
+    pub max: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RuntimeReport {
+    pub pool: PoolStats,
+    pub jobs: QueueDepth,
+    // by this instance
+    pub in_flight_requests: usize,
+    pub connections: ConnectionStats,
+    pub log_level: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LogLevel {
+    // off, error, warn, info, debug or trace
+    pub level: String,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/runtime", get(runtime))
+        .route("/admin/config", get(config))
+        .route("/admin/log-level", get(log_level).put(set_log_level))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/runtime",
+    responses(
+        (status = 200, description = "Database pool, job queue, requests in flight, connections and log level", body = RuntimeReport),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn runtime(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(runtime): State<Arc<Runtime>>,
+    State(jobs): State<Arc<Jobs>>,
+) -> Result<Json<RuntimeReport>, (StatusCode, String)> {
+    let jobs = jobs.queue_depth().await.map_err(db_error)?;
+    let idle = pool.num_idle();
+    Ok(Json(RuntimeReport {
+        pool: PoolStats {
+            size: pool.size(),
+            idle,
+            in_use: (pool.size() as usize).saturating_sub(idle),
+            max: pool.options().get_max_connections(),
+        },
+        jobs,
+        in_flight_requests: runtime.in_flight(),
+        connections: runtime.connections.stats(),
+        log_level: logging::level().to_string(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/config",
+    responses(
+        (status = 200, description = "The configuration in effect, as merged from all sources, with secrets masked", content_type = "application/json"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn config(_: Admin, State(runtime): State<Arc<Runtime>>) -> Json<Value> {
+    Json(runtime.config.clone())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-level",
+    responses((status = 200, description = "The level messages are logged from", body = LogLevel)),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn log_level(_: Admin) -> Json<LogLevel> {
+    Json(LogLevel {
+        level: logging::level().to_string(),
+    })
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/log-level",
+    request_body = LogLevel,
+    responses(
+        (status = 200, description = "The level messages are logged from now on, until SIGHUP resets it to the configured one", body = LogLevel),
+        (status = 422, description = "Unknown level"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn set_log_level(
+    _: Admin,
+    Json(level): Json<LogLevel>,
+) -> Result<Json<LogLevel>, (StatusCode, String)> {
+    let filter =
+        logging::parse(&level.level).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    logging::set_level(filter).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    info!("log level set to {}", filter);
+    Ok(Json(LogLevel {
+        level: filter.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn masks_config() {
+        let runtime = Runtime::new(&json!({"admin": {"token": "change-me"}, "name": "rsapp"}));
+        assert_eq!(runtime.config["admin"]["token"], "***");
+        assert_eq!(runtime.config["name"], "rsapp");
+    }
+
+    #[tokio::test]
+    async fn splits_admin_endpoints() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let router = Router::new()
+            .route("/admin/runtime", get(|| async { "runtime" }))
+            .route("/administrators", get(|| async { "not admin" }))
+            .route("/users", get(|| async { "users" }));
+        let (public, admin) = split(router);
+        let status = |router: &Router, path: &str| {
+            let request = axum::http::Request::get(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request);
+            async move { response.await.unwrap().status() }
+        };
+        assert_eq!(status(&public, "/users").await, StatusCode::OK);
+        assert_eq!(status(&public, "/administrators").await, StatusCode::OK);
+        assert_eq!(
+            status(&public, "/admin/runtime").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(&admin, "/admin/runtime").await, StatusCode::OK);
+        assert_eq!(status(&admin, "/users").await, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn counts_in_flight() {
+        let runtime = Runtime::new(&json!({}));
+        runtime.in_flight.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight(&runtime.in_flight);
+        assert_eq!(runtime.in_flight(), 1);
+        drop(in_flight);
+        assert_eq!(runtime.in_flight(), 0);
+    }
+}
@@ -0,0 +1,153 @@
+use axum::{
+    extract::Request,
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+
+use crate::{
+    app::AppState, asset, audio, bookmark, conditional, delivery, experiment, federation, job,
+    keyframes, library, metering, oidc, password, playlist, remux, session, tag, upload,
+};
+
+pub mod v1;
+pub mod v2;
+
+// Maps an internal model to its wire shape in one API version, e.g. `ToVersion<v1::Asset>`.
+//
+// Requests stay compatible within a version: fields a version doesn't know are ignored, and
+// renamed fields keep their old name as a `#[serde(alias)]`. A new version starts strict, with
+// `#[serde(deny_unknown_fields)]` on its request types, so typos fail loudly instead of being
+// dropped.
+pub trait ToVersion<T> {
+    fn to_version(self) -> T;
+}
+
+impl<M: ToVersion<T>, T> ToVersion<Vec<T>> for Vec<M> {
+    fn to_version(self) -> Vec<T> {
+        self.into_iter().map(ToVersion::to_version).collect()
+    }
+}
+
+// Each API version is mounted under its own prefix, so a breaking change goes into a new
+// version while clients of the old one keep working:
+//
+//     .nest("/api/v1", v1())
+//     .nest("/api/v2", v2())
+//
+// where `v2()` typically starts from the v1 routes it doesn't change.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        // files, not JSON, and clashing with storage's `/files/*key` without the prefix
+        .nest("/api/v1", v1().merge(asset::content_routes()))
+        .nest("/api/v2", v2().merge(asset::content_routes()))
+        // the paths from before versioning, kept for existing clients
+        .merge(unprefixed().route_layer(middleware::from_fn(unversioned)))
+}
+
+fn v1() -> Router<AppState> {
+    unprefixed().merge(versioned_only())
+}
+
+// the routes from before versioning, which existing clients may still call without the prefix
+fn unprefixed() -> Router<AppState> {
+    Router::new()
+        .route("/users", post(crate::create_user))
+        .route("/video/metadata", get(crate::video_metadata))
+        .merge(asset::routes())
+        .merge(bookmark::routes())
+        .merge(metering::routes())
+        .merge(playlist::routes())
+        .layer(middleware::from_fn(conditional::conditional))
+}
+
+// routes added since versioning, which never had a path without the prefix to keep
+fn versioned_only() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(crate::list_users))
+        .route(
+            "/users/:id",
+            get(crate::get_user).delete(crate::delete_user),
+        )
+        .route("/users/:id/restore", post(crate::restore_user))
+        .route("/media/capabilities", get(crate::media_capabilities))
+        .merge(asset::metadata_routes())
+        .merge(audio::routes())
+        .merge(delivery::routes())
+        .merge(experiment::routes())
+        .merge(keyframes::routes())
+        .merge(remux::routes())
+        .merge(library::routes())
+        .merge(tag::routes())
+        .merge(federation::routes())
+        .merge(job::routes())
+        .merge(upload::routes())
+        .merge(password::routes())
+        .merge(session::routes())
+        .merge(oidc::routes())
+        .layer(middleware::from_fn(conditional::conditional))
+}
+
+// v1 with every JSON answer in an envelope, see `v2`
+fn v2() -> Router<AppState> {
+    v1().layer(middleware::from_fn(v2::envelope))
+}
+
+// marks responses served from an unprefixed path, see `deprecation::Surface::Unversioned`
+#[derive(Clone, Copy)]
+pub struct Unversioned;
+
+async fn unversioned(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(Unversioned);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // axum panics on clashing paths when the router is built, which only serving would find out
+    #[test]
+    fn paths_dont_clash() {
+        let _: Router<AppState> = routes().merge(crate::storage::routes());
+    }
+
+    #[tokio::test]
+    async fn later_routes_need_the_prefix() {
+        let app = crate::testing::TestApp::without_db().await;
+        let later = [
+            "/users/1",
+            "/users/1/restore",
+            "/media/capabilities",
+            "/assets/1/metadata",
+            "/assets/1/metadata/diff?against=2",
+            "/assets/1/video",
+            "/audio/waveform",
+            "/me/experiments",
+            "/video/keyframes",
+            "/video/remux",
+            "/video/clip",
+            "/library",
+            "/tags",
+            "/jobs",
+            "/uploads",
+        ];
+        // what no route matches is answered 404 without a body, unlike handlers not finding things
+        let app = &app;
+        let routed = |path: String| async move {
+            let response = app.client.get(app.url(&path)).send().await.unwrap();
+            let status = response.status();
+            status != reqwest::StatusCode::NOT_FOUND || !response.bytes().await.unwrap().is_empty()
+        };
+        for path in later {
+            assert!(!routed(path.to_owned()).await, "{}", path);
+            assert!(routed(format!("/api/v1{}", path)).await, "{}", path);
+            assert!(routed(format!("/api/v2{}", path)).await, "{}", path);
+        }
+        // creating users goes back to before versioning, listing them doesn't
+        let listed = app.client.get(app.url("/users")).send().await.unwrap();
+        assert_eq!(listed.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
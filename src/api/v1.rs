@@ -0,0 +1,533 @@
+// The wire format of `/api/v1`. Internal models convert into these types, so the models can
+// be renamed and reshaped without v1 clients noticing; a change here is a breaking change and
+// belongs in a new API version instead.
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    asset, bookmark, experiment,
+    job::{self, JobKind, JobState, Priority, StateChange},
+    media, playlist,
+};
+
+use super::{v1, ToVersion};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::Asset)]
+pub struct Asset {
+    pub id: i64,
+    pub path: String,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ToVersion<Asset> for asset::Asset {
+    fn to_version(self) -> Asset {
+        Asset {
+            id: self.id,
+            path: self.path,
+            title: self.title,
+            created_at: self.created_at,
+        }
+    }
+}
+
+// a favorited asset, or a point in it when `time` is set
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::Bookmark)]
+pub struct Bookmark {
+    pub id: i64,
+    pub asset_id: i64,
+    pub asset_title: String,
+    // seconds from the start of the asset
+    pub time: Option<f64>,
+    pub note: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ToVersion<Bookmark> for bookmark::Bookmark {
+    fn to_version(self) -> Bookmark {
+        Bookmark {
+            id: self.id,
+            asset_id: self.asset_id,
+            asset_title: self.asset_title,
+            time: self.time,
+            note: self.note,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::Playlist)]
+pub struct Playlist {
+    pub id: i64,
+    pub owner_id: i64,
+    pub name: String,
+    pub collaborative: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ToVersion<Playlist> for playlist::Playlist {
+    fn to_version(self) -> Playlist {
+        Playlist {
+            id: self.id,
+            owner_id: self.owner_id,
+            name: self.name,
+            collaborative: self.collaborative,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::PlaylistItem)]
+pub struct PlaylistItem {
+    pub id: i64,
+    pub asset_id: i64,
+    // fractional index, items are ordered by it
+    pub position: String,
+    pub title: String,
+    pub path: String,
+}
+
+impl ToVersion<PlaylistItem> for playlist::PlaylistItem {
+    fn to_version(self) -> PlaylistItem {
+        PlaylistItem {
+            id: self.id,
+            asset_id: self.asset_id,
+            position: self.position,
+            title: self.title,
+            path: self.path,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::PlaylistDetail)]
+pub struct PlaylistDetail {
+    // named by path so the references match the `v1.*` schema names
+    #[serde(flatten)]
+    pub playlist: v1::Playlist,
+    pub collaborators: Vec<i64>,
+    pub items: Vec<v1::PlaylistItem>,
+}
+
+impl ToVersion<PlaylistDetail> for playlist::PlaylistDetail {
+    fn to_version(self) -> PlaylistDetail {
+        PlaylistDetail {
+            playlist: self.playlist.to_version(),
+            collaborators: self.collaborators,
+            items: self.items.to_version(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::Job)]
+pub struct Job {
+    pub id: i64,
+    #[serde(flatten)]
+    pub kind: JobKind,
+    pub priority: Priority,
+    pub state: JobState,
+    // share of the work done, from 0 to 1
+    pub progress: f32,
+    // where the result was written, once finished
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ToVersion<Job> for job::Job {
+    fn to_version(self) -> Job {
+        Job {
+            id: self.id,
+            kind: self.kind,
+            priority: self.priority,
+            state: self.state,
+            progress: self.progress,
+            output: self.output,
+            error: self.error,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::JobWithHistory)]
+pub struct JobWithHistory {
+    #[serde(flatten)]
+    pub job: v1::Job,
+    // every state it got into, the first first
+    pub history: Vec<StateChange>,
+}
+
+impl ToVersion<JobWithHistory> for (job::Job, Vec<StateChange>) {
+    fn to_version(self) -> JobWithHistory {
+        let (job, history) = self;
+        JobWithHistory {
+            job: job.to_version(),
+            history,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::ExperimentAssignment)]
+pub struct ExperimentAssignment {
+    pub experiment: String,
+    pub variant: String,
+}
+
+impl ToVersion<ExperimentAssignment> for experiment::Assignment {
+    fn to_version(self) -> ExperimentAssignment {
+        ExperimentAssignment {
+            experiment: self.experiment,
+            variant: self.variant,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::MediaMetadata)]
+pub struct MediaMetadata {
+    // container format, as ffmpeg names it
+    pub format: String,
+    // seconds, 0 when unknown
+    pub duration: f64,
+    pub bit_rate: i64,
+    pub tags: BTreeMap<String, String>,
+    pub streams: Vec<v1::MediaStream>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::MediaStream)]
+pub struct MediaStream {
+    pub index: usize,
+    // video, audio, subtitle, data, attachment or unknown
+    pub medium: String,
+    pub codec: String,
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u16>,
+}
+
+impl ToVersion<MediaMetadata> for &media::Metadata {
+    fn to_version(self) -> MediaMetadata {
+        MediaMetadata {
+            format: self.format.clone(),
+            // v1 predates unknown values, it reports them as 0
+            duration: self.duration.unwrap_or(0.0),
+            bit_rate: self.bit_rate.unwrap_or(0),
+            tags: self.tags.clone(),
+            streams: self
+                .streams
+                .iter()
+                .map(|stream| MediaStream {
+                    index: stream.index,
+                    medium: stream.medium.clone(),
+                    codec: stream.codec.clone(),
+                    duration: stream.duration.unwrap_or(0.0),
+                    width: stream.width,
+                    height: stream.height,
+                    frame_rate: stream.frame_rate,
+                    sample_rate: stream.sample_rate,
+                    channels: stream.channels,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::MetadataDiff)]
+pub struct MetadataDiff {
+    pub asset_id: i64,
+    pub against: i64,
+    // ordered by path
+    pub changes: Vec<v1::MetadataChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, ToSchema)]
+#[schema(as = v1::MetadataChange)]
+pub struct MetadataChange {
+    // where in `MediaMetadata` the values differ, e.g. `streams[0].codec`
+    pub path: String,
+    // null when only the other asset has the field
+    #[schema(value_type = Object)]
+    pub before: serde_json::Value,
+    #[schema(value_type = Object)]
+    pub after: serde_json::Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{canary::Variant, media::ImageFormat};
+
+    // the model maps to exactly `expected`, which reads back into the same value
+    fn round_trip<M, D>(model: M, expected: serde_json::Value)
+    where
+        M: ToVersion<D>,
+        D: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let dto = model.to_version();
+        let json = serde_json::to_value(&dto).unwrap();
+        assert_eq!(json, expected);
+        assert_eq!(serde_json::from_value::<D>(json).unwrap(), dto);
+    }
+
+    fn at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 2, 10, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn asset() {
+        round_trip::<_, Asset>(
+            asset::Asset {
+                id: 1,
+                path: "/media/movie.mp4".to_owned(),
+                title: "movie".to_owned(),
+                created_at: at(),
+            },
+            json!({
+                "id": 1,
+                "path": "/media/movie.mp4",
+                "title": "movie",
+                "created_at": "2024-02-10T12:00:00Z",
+            }),
+        );
+    }
+
+    #[test]
+    fn bookmark() {
+        round_trip::<_, Bookmark>(
+            bookmark::Bookmark {
+                id: 2,
+                asset_id: 1,
+                asset_title: "movie".to_owned(),
+                time: Some(12.5),
+                note: "chase scene".to_owned(),
+                created_at: at(),
+                updated_at: at(),
+            },
+            json!({
+                "id": 2,
+                "asset_id": 1,
+                "asset_title": "movie",
+                "time": 12.5,
+                "note": "chase scene",
+                "created_at": "2024-02-10T12:00:00Z",
+                "updated_at": "2024-02-10T12:00:00Z",
+            }),
+        );
+    }
+
+    #[test]
+    fn playlist_detail() {
+        round_trip::<_, PlaylistDetail>(
+            playlist::PlaylistDetail {
+                playlist: playlist::Playlist {
+                    id: 3,
+                    owner_id: 7,
+                    name: "evening".to_owned(),
+                    collaborative: true,
+                    created_at: at(),
+                    updated_at: at(),
+                },
+                collaborators: vec![8],
+                items: vec![playlist::PlaylistItem {
+                    id: 4,
+                    asset_id: 1,
+                    position: "a0".to_owned(),
+                    title: "movie".to_owned(),
+                    path: "/media/movie.mp4".to_owned(),
+                }],
+            },
+            json!({
+                "id": 3,
+                "owner_id": 7,
+                "name": "evening",
+                "collaborative": true,
+                "created_at": "2024-02-10T12:00:00Z",
+                "updated_at": "2024-02-10T12:00:00Z",
+                "collaborators": [8],
+                "items": [{
+                    "id": 4,
+                    "asset_id": 1,
+                    "position": "a0",
+                    "title": "movie",
+                    "path": "/media/movie.mp4",
+                }],
+            }),
+        );
+    }
+
+    #[test]
+    fn job() {
+        round_trip::<_, Job>(
+            job::Job {
+                id: 5,
+                user_id: 7,
+                kind: JobKind::Thumbnail {
+                    asset_id: 1,
+                    time: 12.5,
+                    width: Some(320),
+                    format: ImageFormat::Jpeg,
+                },
+                input: "/media/movie.mp4".into(),
+                variant: Variant::Stable,
+                priority: Priority::High,
+                state: JobState::Finished,
+                progress: 1.0,
+                output: Some("data/jobs/5.jpg".to_owned()),
+                error: None,
+                attempts: 1,
+                callback_url: None,
+                created_at: at(),
+                updated_at: at(),
+            },
+            json!({
+                "id": 5,
+                "type": "thumbnail",
+                "asset_id": 1,
+                "time": 12.5,
+                "width": 320,
+                "format": "jpeg",
+                "priority": "high",
+                "state": "finished",
+                "progress": 1.0,
+                "output": "data/jobs/5.jpg",
+                "error": null,
+                "created_at": "2024-02-10T12:00:00Z",
+                "updated_at": "2024-02-10T12:00:00Z",
+            }),
+        );
+    }
+
+    #[test]
+    fn job_with_history() {
+        let job = job::Job {
+            id: 6,
+            user_id: 7,
+            kind: JobKind::Transcode {
+                asset_id: 1,
+                codec: media::VideoCodec::H264,
+                overlay: None,
+                hwaccel: None,
+            },
+            input: "/media/movie.mp4".into(),
+            variant: Variant::Canary,
+            priority: Priority::Low,
+            state: JobState::Failed,
+            progress: 0.5,
+            output: None,
+            error: Some("can't decode".to_owned()),
+            attempts: 1,
+            callback_url: None,
+            created_at: at(),
+            updated_at: at(),
+        };
+        let history = [JobState::Queued, JobState::Running, JobState::Failed]
+            .into_iter()
+            .map(|state| StateChange {
+                state,
+                error: (state == JobState::Failed).then(|| "can't decode".to_owned()),
+                at: at(),
+            })
+            .collect();
+        let change = |state: &str, error: Option<&str>| json!({ "state": state, "error": error, "at": "2024-02-10T12:00:00Z" });
+        round_trip::<_, JobWithHistory>(
+            (job, history),
+            json!({
+                "id": 6,
+                "type": "transcode",
+                "asset_id": 1,
+                "codec": "h264",
+                "priority": "low",
+                "state": "failed",
+                "progress": 0.5,
+                "output": null,
+                "error": "can't decode",
+                "created_at": "2024-02-10T12:00:00Z",
+                "updated_at": "2024-02-10T12:00:00Z",
+                "history": [
+                    change("queued", None),
+                    change("running", None),
+                    change("failed", Some("can't decode")),
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn experiment_assignment() {
+        round_trip::<_, ExperimentAssignment>(
+            experiment::Assignment {
+                experiment: "player".to_owned(),
+                variant: "new".to_owned(),
+            },
+            json!({"experiment": "player", "variant": "new"}),
+        );
+    }
+
+    #[test]
+    fn media_metadata() {
+        round_trip::<_, MediaMetadata>(
+            &media::Metadata {
+                schema: media::schema::VERSION,
+                format: "mov,mp4,m4a,3gp,3g2,mj2".to_owned(),
+                duration: Some(60.0),
+                bit_rate: None,
+                tags: BTreeMap::from([("title".to_owned(), "movie".to_owned())]),
+                streams: vec![media::StreamInfo {
+                    index: 0,
+                    medium: "audio".to_owned(),
+                    codec: "aac".to_owned(),
+                    duration: None,
+                    width: None,
+                    height: None,
+                    frame_rate: None,
+                    sample_rate: Some(48000),
+                    channels: Some(2),
+                }],
+            },
+            json!({
+                "format": "mov,mp4,m4a,3gp,3g2,mj2",
+                "duration": 60.0,
+                "bit_rate": 0,
+                "tags": {"title": "movie"},
+                "streams": [{
+                    "index": 0,
+                    "medium": "audio",
+                    "codec": "aac",
+                    "duration": 0.0,
+                    "sample_rate": 48000,
+                    "channels": 2,
+                }],
+            }),
+        );
+    }
+}
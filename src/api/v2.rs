@@ -0,0 +1,283 @@
+// The wire format of `/api/v2`: the v1 routes and shapes, with every JSON answer wrapped in an
+// `ApiResponse`, successes and failures alike, so clients read one contract. Handlers stay as
+// they are; `envelope` wraps what they answer. Bodies that aren't JSON, like downloads and event
+// streams, go out as they are, with just the `X-Request-Id` header.
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::clock::IdGenerator;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+// larger answers aren't wrapped, rather than buffered
+const MAX_BODY: u64 = 16 << 20;
+// request ids clients send are used when they're no longer than this
+const MAX_REQUEST_ID: usize = 128;
+
+// an answer of `/api/v2`, with `data` on success and `error` otherwise
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+#[aliases(Envelope = ApiResponse<Value>)]
+pub struct ApiResponse<T> {
+    #[schema(value_type = Option<Object>)]
+    pub data: Option<T>,
+    pub error: Option<ApiError>,
+    // the client's `X-Request-Id`, or one made up, which the header of the answer has too
+    pub request_id: String,
+    // about `data` rather than part of it, like the page of a list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub meta: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    // the status as a word, like `not_found`
+    pub code: String,
+    pub message: String,
+    // more about it when there is, like what's wrong with each field of the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<Value>,
+}
+
+// put in a response by handlers, becomes `meta` of the envelope
+#[derive(Clone, Debug)]
+pub struct Meta(pub Value);
+
+pub async fn envelope(
+    Extension(ids): Extension<Arc<dyn IdGenerator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request_id(&mut request, &*ids);
+
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    let status = response.status();
+    let json = is_json(&response);
+    let small = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_BODY);
+    let wrapped = if status.is_success() {
+        json && status != StatusCode::NO_CONTENT
+    } else {
+        status.is_client_error() || status.is_server_error()
+    };
+    if !wrapped || !small {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_BODY as usize).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let body: ApiResponse<Value> = if status.is_success() {
+        ApiResponse {
+            data: serde_json::from_slice(&bytes).ok(),
+            error: None,
+            request_id,
+            meta: parts.extensions.get::<Meta>().map(|meta| meta.0.clone()),
+        }
+    } else {
+        ApiResponse {
+            data: None,
+            error: Some(error(status, &bytes, json)),
+            request_id,
+            meta: None,
+        }
+    };
+    let Ok(body) = serde_json::to_vec(&body) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+// the error of a failed response with `body`. Handlers answer with text mostly; JSON bodies,
+// like those of failed validation, keep their `message` and the rest goes into `details`.
+fn error(status: StatusCode, body: &[u8], json: bool) -> ApiError {
+    let code = code(status);
+    let parsed = json
+        .then(|| serde_json::from_slice::<Value>(body).ok())
+        .flatten();
+    let (message, details) = match parsed {
+        Some(Value::Object(mut fields)) => {
+            let message = match fields.remove("message") {
+                Some(Value::String(message)) => message,
+                _ => code.replace('_', " "),
+            };
+            let details = (!fields.is_empty()).then_some(Value::Object(fields));
+            (message, details)
+        }
+        Some(other) => (code.replace('_', " "), Some(other)),
+        None => {
+            let text = String::from_utf8_lossy(body).trim().to_owned();
+            if text.is_empty() {
+                (code.replace('_', " "), None)
+            } else {
+                (text, None)
+            }
+        }
+    };
+    ApiError {
+        status: status.as_u16(),
+        code,
+        message,
+        details,
+    }
+}
+
+// the status as a word, like `not_found`
+fn code(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_lowercase()
+        .replace([' ', '-'], "_")
+}
+
+// an error answer of middleware rather than handlers, with `request_id` in the header too
+pub fn error_response(status: StatusCode, message: &str, request_id: &str) -> Response {
+    let body: ApiResponse<()> = ApiResponse {
+        data: None,
+        error: Some(ApiError {
+            status: status.as_u16(),
+            code: code(status),
+            message: message.to_owned(),
+            details: None,
+        }),
+        request_id: request_id.to_owned(),
+        meta: None,
+    };
+    let mut response = (status, Json(body)).into_response();
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+// the client's `X-Request-Id` of `request`, or one made up, which it has from then on
+pub fn request_id(request: &mut Request, ids: &dyn IdGenerator) -> String {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID)
+        .map_or_else(|| ids.next_id(), str::to_owned);
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        request.headers_mut().insert(REQUEST_ID, value);
+    }
+    request_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SequentialIds;
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn send(app: &Router, path: &str, request_id: Option<&str>) -> (Response, Value) {
+        let mut request = Request::get(path).body(Body::empty()).unwrap();
+        if let Some(id) = request_id {
+            request
+                .headers_mut()
+                .insert(REQUEST_ID, HeaderValue::from_str(id).unwrap());
+        }
+        let response = app.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (Response::from_parts(parts, Body::empty()), json)
+    }
+
+    #[tokio::test]
+    async fn wraps_answers() {
+        let app = Router::new()
+            .route(
+                "/user",
+                get(|| async {
+                    (
+                        Extension(Meta(serde_json::json!({"limit": 1}))),
+                        Json(serde_json::json!({"id": 1})),
+                    )
+                }),
+            )
+            .route(
+                "/missing",
+                get(|| async { (StatusCode::NOT_FOUND, "not found") }),
+            )
+            .route(
+                "/invalid",
+                get(|| async {
+                    (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        Json(serde_json::json!({"message": "bad", "fields": {"name": ["empty"]}})),
+                    )
+                }),
+            )
+            .route("/text", get(|| async { "plain" }))
+            .layer(middleware::from_fn(envelope))
+            .layer(Extension(
+                Arc::new(SequentialIds::default()) as Arc<dyn IdGenerator>
+            ));
+
+        let (response, body) = send(&app, "/user", Some("abc")).await;
+        assert_eq!(response.headers()[&REQUEST_ID], "abc");
+        assert_eq!(
+            body,
+            serde_json::json!({"data": {"id": 1}, "error": null, "request_id": "abc", "meta": {"limit": 1}})
+        );
+
+        let (response, body) = send(&app, "/missing", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let answer: ApiResponse<Value> = serde_json::from_value(body).unwrap();
+        assert_eq!(answer.data, None);
+        assert_eq!(answer.request_id, "id-1");
+        assert_eq!(response.headers()[&REQUEST_ID], "id-1");
+        let error = answer.error.unwrap();
+        assert_eq!(
+            (error.code.as_str(), error.message.as_str()),
+            ("not_found", "not found")
+        );
+
+        let (_, body) = send(&app, "/invalid", None).await;
+        assert_eq!(body["error"]["code"], "unprocessable_entity");
+        assert_eq!(body["error"]["message"], "bad");
+        assert_eq!(
+            body["error"]["details"],
+            serde_json::json!({"fields": {"name": ["empty"]}})
+        );
+
+        let (response, body) = send(&app, "/text", None).await;
+        assert!(response.headers().contains_key(&REQUEST_ID));
+        assert_eq!(body, Value::Null);
+    }
+}
@@ -0,0 +1,721 @@
+// The services of the server and the router over them, built from the config and a database.
+// `serve` builds them and runs the rest, such as workers, gRPC and the scheduler, around them;
+// tests build them to call the API in process, see `testing`. Embedders build them the same way,
+// with routes and middleware of their own:
+//
+//     let router = App::builder()
+//         .config(conf)
+//         .pool(pool)
+//         .routes(Router::new().route("/hello", get(hello)))
+//         .layer(TimeoutLayer::new(Duration::from_secs(30)))
+//         .build_router()
+//         .await?;
+use std::{
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{FromRef, Request},
+    middleware,
+    response::IntoResponse,
+    routing::{get, Route},
+    Extension, Router,
+};
+use sqlx::PgPool;
+use tower::{Layer, Service};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+    services::{ServeDir, ServeFile},
+};
+use tracing::warn;
+
+use crate::{
+    admin, api,
+    asset::MediaRoots,
+    auth::{AdminToken, TrustedProxy},
+    canary::{self, Canaries},
+    clock::{Clock, HashedIds, IdGenerator, SystemClock},
+    db::DbExecutor,
+    deprecation::{self, Deprecations},
+    event::{self, Events},
+    experiment::Experiments,
+    federation::{self, Federation},
+    graphql, grpc, instance,
+    job::{self, Jobs},
+    library::{self, Library},
+    listen::Connections,
+    long_time_request, mail, media,
+    metering::{self, Metering},
+    oidc::Oidc,
+    open_bus, openapi, panics,
+    password::Passwords,
+    probe::{self, Probes},
+    rate_plan::{self, RatePlans},
+    read_only::{self, ReadOnly},
+    redis,
+    repository::{
+        idempotency::PgIdempotency, identity::PgIdentities, library::PgLibrary, media::PgMedia,
+        password::PgPasswords, snapshot::PgSnapshots, tag::PgTags, upload::PgUploads,
+        user::PgUsers, IdempotencyRepository, LibraryRepository, MediaRepository, TagRepository,
+        UserRepository,
+    },
+    root,
+    session::Sessions,
+    shed::{self, Shedder},
+    snapshot::Snapshots,
+    status,
+    storage::{self, Storage},
+    task, timeout,
+    upload::Uploads,
+    webhook::Webhooks,
+    Compression, Conf,
+};
+
+// why the app couldn't be built
+#[derive(Debug)]
+pub enum Error {
+    // `config` wasn't called
+    NoConfig,
+    // a setting that doesn't hold up, naming it
+    Config(String),
+    // connecting to the database or reading from it, while building
+    Database(&'static str, sqlx::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoConfig => write!(f, "the app is built from a config"),
+            Error::Config(err) => write!(f, "invalid configuration: {}", err),
+            Error::Database(what, err) => write!(f, "{}: {}", what, err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// The state of the router, what handlers share. Each takes the parts it needs, such as
+// `State<PgPool>` or `State<Arc<Jobs>>`, so adding one doesn't change the others.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub db: Arc<DbExecutor>,
+    pub conf: Arc<Conf>,
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub storage: Arc<dyn Storage>,
+    pub jobs: Arc<Jobs>,
+    // repositories
+    pub users: Arc<dyn UserRepository>,
+    pub media: Arc<dyn MediaRepository>,
+    pub tags: Arc<dyn TagRepository>,
+    pub library_files: Arc<dyn LibraryRepository>,
+    pub idempotency: Arc<dyn IdempotencyRepository>,
+    // services
+    pub library: Arc<Library>,
+    pub uploads: Arc<Uploads>,
+    pub passwords: Arc<Passwords>,
+    // None without `auth.oidc`
+    pub oidc: Option<Arc<Oidc>>,
+    pub federation: Arc<Federation>,
+    pub canaries: Arc<Canaries>,
+    pub experiments: Arc<Experiments>,
+    pub events: Arc<Events>,
+    pub rate_plans: Arc<RatePlans>,
+    pub metering: Arc<Metering>,
+    pub deprecations: Arc<Deprecations>,
+    pub read_only: Arc<ReadOnly>,
+    pub runtime: Arc<admin::Runtime>,
+    pub status: Arc<status::Status>,
+    pub instances: Arc<instance::Instances>,
+    pub capabilities: Arc<media::Capabilities>,
+    pub schema: graphql::AppSchema,
+    // caches
+    pub snapshots: Arc<Snapshots>,
+    pub probes: Arc<Probes>,
+    // where the API may read media files from
+    pub media_roots: Arc<MediaRoots>,
+}
+
+// `State<T>` of the field of type T
+macro_rules! from_ref {
+    ($($field:ident: $type:ty),* $(,)?) => {
+        $(
+            impl FromRef<AppState> for $type {
+                fn from_ref(state: &AppState) -> Self {
+                    state.$field.clone()
+                }
+            }
+        )*
+    };
+}
+
+from_ref! {
+    pool: PgPool,
+    db: Arc<DbExecutor>,
+    conf: Arc<Conf>,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdGenerator>,
+    storage: Arc<dyn Storage>,
+    jobs: Arc<Jobs>,
+    users: Arc<dyn UserRepository>,
+    media: Arc<dyn MediaRepository>,
+    tags: Arc<dyn TagRepository>,
+    library_files: Arc<dyn LibraryRepository>,
+    idempotency: Arc<dyn IdempotencyRepository>,
+    library: Arc<Library>,
+    uploads: Arc<Uploads>,
+    passwords: Arc<Passwords>,
+    oidc: Option<Arc<Oidc>>,
+    federation: Arc<Federation>,
+    canaries: Arc<Canaries>,
+    experiments: Arc<Experiments>,
+    events: Arc<Events>,
+    rate_plans: Arc<RatePlans>,
+    metering: Arc<Metering>,
+    deprecations: Arc<Deprecations>,
+    read_only: Arc<ReadOnly>,
+    runtime: Arc<admin::Runtime>,
+    status: Arc<status::Status>,
+    instances: Arc<instance::Instances>,
+    capabilities: Arc<media::Capabilities>,
+    schema: graphql::AppSchema,
+    snapshots: Arc<Snapshots>,
+    probes: Arc<Probes>,
+    media_roots: Arc<MediaRoots>,
+}
+
+pub struct App {
+    pub router: Router,
+    pub db: Arc<DbExecutor>,
+    pub status: Arc<status::Status>,
+    pub instances: Arc<instance::Instances>,
+    pub jobs: Arc<Jobs>,
+    pub events: Arc<Events>,
+    pub rate_plans: Arc<RatePlans>,
+    pub metering: Arc<Metering>,
+    pub idempotency: Arc<dyn IdempotencyRepository>,
+    pub library: Arc<Library>,
+    pub uploads: Arc<Uploads>,
+    pub sessions: Arc<Sessions>,
+    // of the gRPC server
+    pub services: grpc::Services,
+    // as of startup, since probing the ffmpeg command and hardware takes a while
+    pub capabilities: Arc<media::Capabilities>,
+    // taken by the listener serving the router, when it's `listen`'s
+    pub connections: Arc<Connections>,
+}
+
+impl App {
+    pub fn builder() -> AppBuilder {
+        AppBuilder::default()
+    }
+}
+
+type Wrap = Box<dyn FnOnce(Router) -> Router + Send>;
+
+pub struct AppBuilder {
+    conf: Option<Conf>,
+    pool: Option<PgPool>,
+    degraded: bool,
+    clock: Arc<dyn Clock>,
+    ids: Arc<dyn IdGenerator>,
+    routes: Router<AppState>,
+    layers: Vec<Wrap>,
+}
+
+impl Default for AppBuilder {
+    fn default() -> Self {
+        AppBuilder {
+            conf: None,
+            pool: None,
+            degraded: false,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(HashedIds::default()),
+            routes: Router::new(),
+            layers: Vec::new(),
+        }
+    }
+}
+
+impl AppBuilder {
+    // the only thing required
+    pub fn config(mut self, conf: Conf) -> Self {
+        self.conf = Some(conf);
+        self
+    }
+
+    // connected lazily to the database of the config without it
+    pub fn pool(mut self, pool: PgPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    // when the database couldn't be reached, leaving out the rate plans kept in it
+    pub fn degraded(mut self, degraded: bool) -> Self {
+        self.degraded = degraded;
+        self
+    }
+
+    // what handlers and services tell the time by
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // what handlers and services make up ids with
+    pub fn ids(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    // more routes, served with the state and middleware of the others
+    pub fn routes(mut self, routes: Router<AppState>) -> Self {
+        self.routes = self.routes.merge(routes);
+        self
+    }
+
+    // wraps the whole router, outside of the app's own middleware, in the order added
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers
+            .push(Box::new(move |router| router.layer(layer)));
+        self
+    }
+
+    pub async fn build_router(self) -> Result<Router, Error> {
+        Ok(self.build().await?.router)
+    }
+
+    pub async fn build(self) -> Result<App, Error> {
+        let AppBuilder {
+            conf,
+            pool,
+            degraded,
+            clock,
+            ids,
+            routes,
+            layers,
+        } = self;
+        let conf = conf.ok_or(Error::NoConfig)?;
+        let pool = match pool {
+            Some(pool) => pool,
+            None => conf
+                .postgres
+                .connect_lazy()
+                .map_err(|err| Error::Database("postgres.dsn", err))?,
+        };
+        let replica = conf
+            .postgres
+            .connect_replica()
+            .map_err(|err| Error::Database("postgres.replica_dsn", err))?;
+        let db = Arc::new(DbExecutor::new(pool.clone(), replica));
+        let idempotency: Arc<dyn IdempotencyRepository> = Arc::new(PgIdempotency::new(db.clone()));
+        let status = Arc::new(status::Status::new(db.clone()));
+        let instances = Arc::new(instance::Instances::new(conf.fingerprint.clone()));
+
+        // build our application with a route
+        let router = Router::new()
+            // `GET /` goes to `root`
+            .route("/", get(root))
+            .route("/longtime", get(long_time_request))
+            // `POST /api/v1/users` goes to `create_user`
+            .merge(api::routes())
+            .merge(job::ws_routes())
+            .merge(event::routes())
+            .merge(deprecation::routes())
+            .merge(rate_plan::routes())
+            .merge(probe::routes())
+            .merge(status::routes())
+            .merge(instance::routes())
+            .merge(canary::routes())
+            .merge(storage::routes())
+            .merge(read_only::routes())
+            .merge(graphql::routes())
+            .merge(federation::peer_routes())
+            .merge(task::routes())
+            .merge(library::admin_routes())
+            .merge(job::admin_routes())
+            .merge(admin::routes())
+            .merge(openapi::routes())
+            .merge(routes);
+        let metering = Arc::new(Metering::default());
+        let redis = conf
+            .redis
+            .as_ref()
+            .and_then(|settings| match redis::Redis::open(settings) {
+                Ok(redis) => Some(Arc::new(redis)),
+                Err(err) => {
+                    warn!("not using redis: {}", err);
+                    None
+                }
+            });
+        let bus = open_bus(&conf).await;
+        let deprecations = Arc::new(Deprecations::new(&conf.deprecations).map_err(Error::Config)?);
+        let read_only = Arc::new(ReadOnly::new(conf.read_only.clone()));
+        let canaries = Arc::new(Canaries::new(conf.canaries.clone()));
+        let federation = Arc::new(Federation::new(&conf.federation));
+        let snapshots = Arc::new(
+            Snapshots::new(&conf.snapshots, Arc::new(PgSnapshots::new(db.clone())))
+                .with_clock(clock.clone()),
+        );
+        let experiments = Arc::new(
+            Experiments::new(conf.experiments.clone(), Some(pool.clone()))
+                .map_err(|err| Error::Config(format!("experiments: {}", err)))?,
+        );
+        let storage = storage::open(
+            &conf.storage,
+            Path::new(&conf.jobs.output_dir),
+            clock.clone(),
+        );
+        let mut capabilities = media::capabilities();
+        let mut jobs = Jobs::new(
+            pool.clone(),
+            conf.jobs.workers,
+            &conf.jobs.output_dir,
+            storage.clone(),
+            canaries.clone(),
+        )
+        .with_reserved_workers(conf.jobs.high_workers, conf.jobs.low_workers)
+        .with_webhooks(Arc::new(Webhooks::new(conf.webhooks.clone())))
+        .with_retries(
+            conf.jobs.max_retries,
+            Duration::from_secs(conf.jobs.retry_backoff),
+        );
+        if conf.jobs.in_server {
+            let hardware = media::Hardware::detect(conf.jobs.hwaccel, &conf.jobs.vaapi_device);
+            capabilities.hwaccel = hardware.available.clone();
+            jobs = jobs.with_hardware(hardware);
+        }
+        if let Some(bus) = &bus {
+            jobs = jobs.with_bus(bus.clone());
+        }
+        let jobs = Arc::new(jobs);
+        let capabilities = Arc::new(capabilities);
+        let rate_plans = Arc::new(if degraded {
+            RatePlans::default()
+        } else {
+            RatePlans::load(&pool)
+                .await
+                .map_err(|err| Error::Database("loading the rate plans", err))?
+        });
+        let events = Arc::new(Events::default());
+        if let Some(bus) = bus {
+            events.forward_to(bus);
+        }
+        let users: Arc<dyn UserRepository> = Arc::new(PgUsers::new(db.clone()));
+        let media: Arc<dyn MediaRepository> = Arc::new(PgMedia::new(db.clone()));
+        let probes = Arc::new(Probes::new(&conf.probes, redis.clone()));
+        let library_files: Arc<dyn LibraryRepository> = Arc::new(PgLibrary::new(db.clone()));
+        let tags: Arc<dyn TagRepository> = Arc::new(PgTags::new(db.clone()));
+        let library = Arc::new(Library::new(
+            conf.library.clone(),
+            library_files.clone(),
+            probes.clone(),
+        ));
+        let uploads = Arc::new(Uploads::new(
+            conf.uploads.clone(),
+            Arc::new(PgUploads::new(db.clone())),
+        ));
+        let passwords = Arc::new(Passwords::new(
+            conf.passwords.clone(),
+            Arc::new(PgPasswords::new(db.clone())),
+            mail::open(&conf.mail),
+            clock.clone(),
+        ));
+        let sessions = Arc::new(Sessions::new(conf.sessions.clone(), pool.clone()));
+        let oidc = conf.auth.oidc.clone().map(|settings| {
+            Arc::new(Oidc::new(
+                settings,
+                Arc::new(PgIdentities::new(db.clone())),
+                clock.clone(),
+            ))
+        });
+        let services = grpc::Services {
+            users: users.clone(),
+            events: events.clone(),
+            read_only: read_only.clone(),
+            media: media.clone(),
+            probes: probes.clone(),
+            rate_plans: rate_plans.clone(),
+            metering: metering.clone(),
+        };
+        let schema = graphql::schema(services.clone());
+        let connections = Arc::new(Connections::default());
+        let runtime =
+            Arc::new(admin::Runtime::new(&conf.settings).with_connections(connections.clone()));
+        let state = AppState {
+            pool,
+            db: db.clone(),
+            conf: Arc::new(conf.clone()),
+            clock,
+            ids: ids.clone(),
+            storage,
+            jobs: jobs.clone(),
+            users: users.clone(),
+            media,
+            tags,
+            library_files,
+            idempotency: idempotency.clone(),
+            library: library.clone(),
+            uploads: uploads.clone(),
+            passwords,
+            oidc,
+            federation,
+            canaries,
+            experiments,
+            events: events.clone(),
+            rate_plans: rate_plans.clone(),
+            metering: metering.clone(),
+            deprecations: deprecations.clone(),
+            read_only: read_only.clone(),
+            runtime: runtime.clone(),
+            status: status.clone(),
+            instances: instances.clone(),
+            capabilities: capabilities.clone(),
+            schema,
+            snapshots,
+            probes,
+            media_roots: Arc::new(media_roots(&conf)),
+        };
+        let router = with_static_dir(router, conf.server.static_dir.as_deref())
+            .layer(middleware::from_fn_with_state(
+                (rate_plans.clone(), metering.clone(), redis.clone()),
+                rate_plan::enforce,
+            ))
+            .layer(middleware::from_fn_with_state(
+                metering.clone(),
+                metering::track,
+            ))
+            .layer(middleware::from_fn_with_state(
+                deprecations,
+                deprecation::annotate,
+            ));
+        // Outside of the rate plans, metering and deprecation counts, which take sessions' users.
+        // Extractors and middleware without the state find the rest in the request: the users for
+        // signed in sessions, the ids of v2's request ids, the admin token and whether to trust
+        // the proxy.
+        let router = with_sessions(router, &sessions)
+            .layer(Extension(users))
+            .layer(Extension(ids.clone()))
+            .layer(middleware::from_fn_with_state(read_only, read_only::guard))
+            .layer(middleware::from_fn_with_state(runtime, admin::track))
+            .layer(Extension(AdminToken(
+                conf.admin.token.as_deref().map(Arc::from),
+            )))
+            .layer(Extension(TrustedProxy(conf.auth.trusted_proxy)))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(conf.server.timeouts.clone()),
+                timeout::limit,
+            ))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(Shedder::new(&conf.server.limits)),
+                shed::shed,
+            ))
+            .layer(middleware::from_fn_with_state(ids, panics::catch))
+            .with_state(state);
+        let mut router = with_compression(router, &conf.server.compression);
+        for layer in layers {
+            router = layer(router);
+        }
+
+        Ok(App {
+            router,
+            db,
+            status,
+            instances,
+            jobs,
+            events,
+            rate_plans,
+            metering,
+            idempotency,
+            library,
+            uploads,
+            sessions,
+            services,
+            capabilities,
+            connections,
+        })
+    }
+}
+
+// the library, uploads, job results when stored locally and replicas of peers' assets
+fn media_roots(conf: &Conf) -> MediaRoots {
+    let mut roots = vec![
+        PathBuf::from(&conf.uploads.dir),
+        conf.federation.replica_dir.clone(),
+    ];
+    roots.extend(conf.library.root.as_ref().map(PathBuf::from));
+    if let storage::Settings::Local { .. } = conf.storage {
+        roots.push(PathBuf::from(&conf.jobs.output_dir));
+    }
+    MediaRoots::new(roots)
+}
+
+fn with_sessions(app: Router<AppState>, sessions: &Sessions) -> Router<AppState> {
+    match sessions.layer() {
+        Some(layer) => app.layer(layer),
+        None => app,
+    }
+}
+
+fn with_static_dir(app: Router<AppState>, dir: Option<&str>) -> Router<AppState> {
+    match dir {
+        Some(dir) => {
+            // let the frontend's router handle paths that aren't files (SPA fallback)
+            let index = ServeFile::new(Path::new(dir).join("index.html"));
+            app.fallback_service(ServeDir::new(dir).fallback(index))
+        }
+        None => app,
+    }
+}
+
+fn with_compression(app: Router, conf: &Compression) -> Router {
+    let mut app = app;
+    if conf.enabled {
+        // same as the default predicate, but with a configurable size threshold
+        let predicate = SizeAbove::new(conf.min_size)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE);
+        app = app.layer(CompressionLayer::new().compress_when(predicate));
+    }
+    if conf.decompress_requests {
+        app = app.layer(RequestDecompressionLayer::new());
+    }
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http, middleware::Next, response::Response};
+    use tower::ServiceExt;
+
+    use crate::testing;
+
+    async fn stamp(request: Request, next: Next) -> Response {
+        let mut response = next.run(request).await;
+        let stamp = http::HeaderValue::from_static("yes");
+        response.headers_mut().insert("x-embedded", stamp);
+        response
+    }
+
+    #[tokio::test]
+    async fn builds_with_routes_and_layers_of_embedders() {
+        let router = App::builder()
+            .config(testing::conf("postgres://rsapp@127.0.0.1:1/rsapp"))
+            .degraded(true)
+            .routes(Router::new().route("/hello", get(|| async { "hello" })))
+            .layer(middleware::from_fn(stamp))
+            .build_router()
+            .await
+            .unwrap();
+        for path in ["/hello", "/"] {
+            let request = http::Request::get(path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK, "{}", path);
+            assert_eq!(response.headers()["x-embedded"], "yes");
+        }
+    }
+
+    #[tokio::test]
+    async fn session_users_are_held_to_their_plans() {
+        let Some(app) = testing::TestApp::with_db().await else {
+            return;
+        };
+        let admin = |request: reqwest::RequestBuilder| request.bearer_auth(testing::ADMIN_TOKEN);
+        let username = format!("planned{}", chrono::Utc::now().timestamp_micros());
+        let user: serde_json::Value = app
+            .client
+            .post(app.url("/api/v1/users"))
+            .json(&serde_json::json!({ "username": username }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let id = user["id"].as_i64().unwrap();
+        let password = admin(
+            app.client
+                .post(app.url(&format!("/api/v1/users/{}/password", id))),
+        )
+        .json(&serde_json::json!({ "new_password": "correct horse" }))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(password.status(), reqwest::StatusCode::NO_CONTENT);
+        let plan: serde_json::Value = admin(app.client.post(app.url("/admin/rate-plans")))
+            .json(&serde_json::json!({ "name": username, "requests_per_minute": 1 }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let assigned = admin(
+            app.client
+                .put(app.url(&format!("/admin/users/{}/rate-plan", id))),
+        )
+        .json(&serde_json::json!({ "plan_id": plan["id"] }))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(assigned.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let login = app
+            .client
+            .post(app.url("/api/v1/auth/login"))
+            .json(&serde_json::json!({ "login": username, "password": "correct horse" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(login.status(), reqwest::StatusCode::OK);
+        let cookie = login.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        // two of three land in the same minute, whenever the first one is sent
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            let response = app
+                .client
+                .get(app.url("/api/v1/me/usage"))
+                .header("cookie", &cookie)
+                .send()
+                .await
+                .unwrap();
+            statuses.push(response.status());
+        }
+        assert_eq!(statuses[0], reqwest::StatusCode::OK);
+        assert!(
+            statuses.contains(&reqwest::StatusCode::TOO_MANY_REQUESTS),
+            "{:?}",
+            statuses
+        );
+    }
+}
@@ -0,0 +1,609 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path as UrlPath, Query, Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::{instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
+
+use crate::{
+    api::{v1, v2, ToVersion},
+    app::AppState,
+    auth::{Admin, CurrentUser},
+    db::DbExecutor,
+    db_error, internal_error, media, meta_query,
+    negotiate::{Format, Negotiated},
+    probe::Probes,
+    repository::{media::MediaFile, MediaRepository},
+    validation::{self, Valid},
+};
+
+// a media file known to the app, referenced by playlists and friends
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Asset {
+    pub id: i64,
+    pub path: String,
+    pub title: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct CreateAsset {
+    #[validate(length(min = 1, max = 4096), custom = "validation::media_path")]
+    path: String,
+    // defaults to the file name without extension
+    #[validate(length(min = 1, max = 200))]
+    title: Option<String>,
+}
+
+// The directories media files are registered and served from: the library, uploads, job results
+// stored locally and assets replicated from peers. Other files of the server, such as its config,
+// stay out of reach of the API.
+pub struct MediaRoots(Vec<PathBuf>);
+
+impl MediaRoots {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        MediaRoots(roots)
+    }
+
+    // `path` with links and `..` resolved, when that's under one of the roots
+    pub async fn resolve(&self, path: &str) -> io::Result<Option<PathBuf>> {
+        let path = tokio::fs::canonicalize(path).await?;
+        for root in &self.0 {
+            // resolved each time, roots may only be created after startup
+            if let Ok(root) = tokio::fs::canonicalize(root).await {
+                if path.starts_with(&root) {
+                    return Ok(Some(path));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListAssets {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct Refresh {
+    // probe the file again instead of answering from the database or an earlier probe
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/assets", get(list_assets).post(create_asset))
+        .route("/assets/:id", get(get_asset).delete(delete_asset))
+}
+
+// added since versioning, only under the versioned prefixes
+pub fn metadata_routes() -> Router<AppState> {
+    Router::new()
+        .route("/assets/:id/metadata", get(asset_metadata))
+        .route("/assets/:id/metadata/diff", get(asset_metadata_diff))
+}
+
+// only under the versioned prefixes, `/files` is the storage's without them
+pub fn content_routes() -> Router<AppState> {
+    Router::new().route("/files/:id/content", get(file_content))
+}
+
+// The asset's media file as it is, for players: `Range` requests get just the bytes asked for,
+// so browsers can seek in videos without downloading them first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{id}/content",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses(
+        (status = 200, description = "The media file, with `Accept-Ranges: bytes`"),
+        (status = 206, description = "The range of it asked for with `Range`"),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "The file isn't in a media directory"),
+        (status = 404, description = "No such asset, or its file is gone"),
+        (status = 416, description = "The range isn't in the file"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn file_content(
+    _: CurrentUser,
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(roots): State<Arc<MediaRoots>>,
+    UrlPath(id): UrlPath<i64>,
+    request: Request,
+) -> Result<Response, (StatusCode, String)> {
+    let file = media.find(id).await.map_err(db_error)?;
+    // checked again, a link in a media directory may have been changed since registering
+    let path = match roots.resolve(&file.path).await {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            warn!(
+                "not serving {} of asset {}, outside the media directories",
+                file.path, id
+            );
+            return Err((
+                StatusCode::FORBIDDEN,
+                "the file isn't in a media directory".to_owned(),
+            ));
+        }
+        Err(_) => return Err((StatusCode::NOT_FOUND, "the file is gone".to_owned())),
+    };
+    // conditional requests, and the content type from the extension, come with it too
+    match ServeFile::new(path).oneshot(request).await {
+        Ok(response) => Ok(response.into_response()),
+        Err(never) => match never {},
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/assets",
+    request_body = CreateAsset,
+    responses(
+        (status = 201, description = "Asset registered", body = v1::Asset),
+        (status = 401, description = "Not an admin"),
+        (status = 409, description = "Path is already registered"),
+        (status = 422, description = "Invalid path or title, by field, or a file that isn't in a media directory", body = Invalid),
+    ),
+    security(("admin" = [])),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(path = %payload.path))]
+async fn create_asset(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    State(roots): State<Arc<MediaRoots>>,
+    Valid(payload): Valid<CreateAsset>,
+) -> Result<(StatusCode, Json<v1::Asset>), (StatusCode, String)> {
+    let unusable = |reason: String| (StatusCode::UNPROCESSABLE_ENTITY, reason);
+    // registered resolved, so a path is registered once however it's spelled
+    let path = match roots.resolve(&payload.path).await {
+        Ok(Some(path)) => path,
+        Ok(None) => return Err(unusable("the file isn't in a media directory".to_owned())),
+        Err(err) => return Err(unusable(format!("can't open the file: {}", err))),
+    };
+    let path = path
+        .to_str()
+        .ok_or_else(|| unusable("the path isn't UTF-8".to_owned()))?;
+    let title = payload.title.unwrap_or_else(|| title_from_path(path));
+    let asset = sqlx::query_as::<_, Asset>(
+        "INSERT INTO assets (path, title) VALUES ($1, $2) RETURNING id, path, title, created_at",
+    )
+    .bind(path)
+    .bind(&title)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+
+    // the asset is registered either way, its metadata gets probed when first asked for
+    let file = MediaFile {
+        asset_id: asset.id,
+        path: asset.path.clone(),
+        metadata: None,
+        upgraded: false,
+    };
+    if let Err((_, err)) = metadata(&*media, &probes, file, false).await {
+        warn!("probing asset {} failed: {}", asset.id, err);
+    }
+
+    Ok((StatusCode::CREATED, Json(asset.to_version())))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets",
+    params(
+        ListAssets,
+        ("meta.{field}" = Option<String>, Query, description = "Filters on the stored metadata, e.g. `meta.video.codec=h264&meta.video.height>=1080`; also `!=`, `>`, `<` and `<=`. Fields: format, duration and bit_rate of the file, or index, codec, duration, width, height, frame_rate, sample_rate and channels of a stream after its medium (video, audio, subtitle, data, attachment)"),
+    ),
+    responses(
+        (status = 200, description = "Assets ordered by id", body = [v1::Asset]),
+        (status = 400, description = "Unknown metadata field or bad value"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all)]
+async fn list_assets(
+    State(db): State<Arc<DbExecutor>>,
+    Query(query): Query<ListAssets>,
+    Query(pairs): Query<Vec<(String, String)>>,
+) -> Result<(Extension<v2::Meta>, Json<Vec<v1::Asset>>), (StatusCode, String)> {
+    let paths = meta_query::json_paths(&pairs).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let mut select =
+        QueryBuilder::<Postgres>::new("SELECT id, path, title, created_at FROM assets WHERE TRUE");
+    for path in paths {
+        select
+            .push(" AND metadata @? ")
+            .push_bind(path)
+            .push("::jsonpath");
+    }
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    select
+        .push(" ORDER BY id LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(offset);
+    let assets = select
+        .build_query_as::<Asset>()
+        .fetch_all(db.read())
+        .await
+        .map_err(db_error)?;
+
+    // the page, for clients of v2
+    let meta = v2::Meta(json!({ "limit": limit, "offset": offset, "count": assets.len() }));
+    Ok((Extension(meta), Json(assets.to_version())))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses(
+        (status = 200, description = "The asset", body = v1::Asset),
+        (status = 404, description = "No such asset"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn get_asset(
+    State(db): State<Arc<DbExecutor>>,
+    UrlPath(id): UrlPath<i64>,
+) -> Result<Json<v1::Asset>, (StatusCode, String)> {
+    let asset = find(db.read(), id).await.map_err(db_error)?;
+    Ok(Json(asset.to_version()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/metadata",
+    params(("id" = i64, Path, description = "Asset id"), Refresh),
+    responses(
+        (status = 200, description = "Format and streams of the asset's media file", body = v1::MediaMetadata,
+            content_type = ["application/json", "application/msgpack"]),
+        (status = 304, description = "Unchanged since If-None-Match"),
+        (status = 404, description = "No such asset"),
+        (status = 422, description = "The media file can't be read"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn asset_metadata(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    UrlPath(id): UrlPath<i64>,
+    Query(query): Query<Refresh>,
+    format: Format,
+) -> Result<Negotiated<v1::MediaMetadata>, (StatusCode, String)> {
+    let file = media.find(id).await.map_err(db_error)?;
+    let metadata = metadata(&*media, &probes, file, query.refresh).await?;
+    Ok(Negotiated(format, metadata.to_version()))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DiffAgainst {
+    // the asset to compare with
+    against: i64,
+}
+
+// what differs between the metadata of two assets, e.g. an original and its re-encode
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/metadata/diff",
+    params(("id" = i64, Path, description = "Asset id"), DiffAgainst),
+    responses(
+        (status = 200, description = "Differing metadata fields, by their path in the metadata document", body = v1::MetadataDiff,
+            content_type = ["application/json", "application/msgpack"]),
+        (status = 404, description = "No such asset"),
+        (status = 422, description = "A media file can't be read"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn asset_metadata_diff(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    UrlPath(id): UrlPath<i64>,
+    Query(query): Query<DiffAgainst>,
+    format: Format,
+) -> Result<Negotiated<v1::MetadataDiff>, (StatusCode, String)> {
+    let mut documents = Vec::new();
+    for id in [id, query.against] {
+        let file = media.find(id).await.map_err(db_error)?;
+        let metadata: v1::MediaMetadata =
+            metadata(&*media, &probes, file, false).await?.to_version();
+        documents.push(serde_json::to_value(metadata).map_err(internal_error)?);
+    }
+    let mut changes = Vec::new();
+    diff(String::new(), &documents[0], &documents[1], &mut changes);
+    let diff = v1::MetadataDiff {
+        asset_id: id,
+        against: query.against,
+        changes,
+    };
+    Ok(Negotiated(format, diff))
+}
+
+// Collects the leaves that differ between two JSON documents. Objects are compared key by key
+// and arrays position by position, so streams pair up by their index; a side missing a key or
+// position shows as null.
+fn diff(path: String, before: &Value, after: &Value, changes: &mut Vec<v1::MetadataChange>) {
+    let child = |key: &dyn std::fmt::Display| match path.as_str() {
+        "" => key.to_string(),
+        path => format!("{}.{}", path, key),
+    };
+    match (before, after) {
+        (Value::Object(before_fields), Value::Object(after_fields)) => {
+            let keys: BTreeSet<_> = before_fields.keys().chain(after_fields.keys()).collect();
+            for key in keys {
+                diff(
+                    child(key),
+                    before_fields.get(key).unwrap_or(&Value::Null),
+                    after_fields.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(before_items), Value::Array(after_items)) => {
+            for at in 0..before_items.len().max(after_items.len()) {
+                diff(
+                    format!("{}[{}]", path, at),
+                    before_items.get(at).unwrap_or(&Value::Null),
+                    after_items.get(at).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (before, after) if before != after => changes.push(v1::MetadataChange {
+            path,
+            before: before.clone(),
+            after: after.clone(),
+        }),
+        _ => {}
+    }
+}
+
+// the stored metadata of `file`, probed and stored first when there is none or `refresh` is set
+pub async fn metadata(
+    media: &dyn MediaRepository,
+    probes: &Probes,
+    file: MediaFile,
+    refresh: bool,
+) -> Result<media::Metadata, (StatusCode, String)> {
+    if let (Some(metadata), false) = (file.metadata, refresh) {
+        // documents of an older schema are stored again as they're read
+        if file.upgraded {
+            if let Err(err) = media.store_metadata(file.asset_id, &metadata).await {
+                warn!(
+                    "storing upgraded metadata of asset {} failed: {}",
+                    file.asset_id, err
+                );
+            }
+        }
+        return Ok(metadata);
+    }
+    let metadata = probes
+        .probe(file.path.into(), refresh)
+        .await
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    media
+        .store_metadata(file.asset_id, &metadata)
+        .await
+        .map_err(db_error)?;
+    Ok(metadata.as_ref().clone())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/assets/{id}",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses(
+        (status = 204, description = "Asset removed along with its playlist entries and bookmarks"),
+        (status = 404, description = "No such asset"),
+    ),
+    tag = "assets"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn delete_asset(
+    State(pool): State<PgPool>,
+    UrlPath(id): UrlPath<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("DELETE FROM assets WHERE id = $1")
+        .bind(id)
+        .execute(&pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(db_error(sqlx::Error::RowNotFound));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn find(pool: &PgPool, id: i64) -> Result<Asset, sqlx::Error> {
+    sqlx::query_as::<_, Asset>("SELECT id, path, title, created_at FROM assets WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
+
+pub(crate) fn title_from_path(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::media::MemoryMedia;
+
+    #[tokio::test]
+    async fn stored_metadata_is_served_without_probing() {
+        let stored = media::Metadata {
+            schema: media::schema::VERSION,
+            format: "matroska,webm".to_owned(),
+            duration: Some(60.0),
+            bit_rate: None,
+            tags: Default::default(),
+            streams: Vec::new(),
+        };
+        let media = MemoryMedia::default();
+        media.insert(MediaFile {
+            asset_id: 1,
+            path: "/media/movie.mkv".to_owned(),
+            metadata: Some(stored.clone()),
+            upgraded: false,
+        });
+        let probes = Probes::default();
+
+        let file = media.find_by_path("/media/movie.mkv").await.unwrap();
+        assert_eq!(metadata(&media, &probes, file, false).await, Ok(stored));
+        assert_eq!(probes.stats().probed, 0);
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let dir = std::env::temp_dir().join(format!("rsapp-content-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("movie.mp4");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let outside = std::env::temp_dir().join(format!("rsapp-outside-{}", std::process::id()));
+        std::fs::write(&outside, b"secret").unwrap();
+        let media = MemoryMedia::default();
+        for (asset_id, path) in [(1, &path), (3, &outside)] {
+            media.insert(MediaFile {
+                asset_id,
+                path: path.to_string_lossy().into_owned(),
+                metadata: None,
+                upgraded: false,
+            });
+        }
+        let media: Arc<dyn MediaRepository> = Arc::new(media);
+        let roots = Arc::new(MediaRoots::new(vec![dir.clone()]));
+        let content = |id: i64, request: Request| {
+            file_content(
+                CurrentUser(1),
+                State(media.clone()),
+                State(roots.clone()),
+                UrlPath(id),
+                request,
+            )
+        };
+        let get = |range: &str| {
+            let request = Request::builder()
+                .header(axum::http::header::RANGE, range)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            content(1, request)
+        };
+
+        let response = get("bytes=2-5").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers["content-range"], "bytes 2-5/10");
+        assert_eq!(headers["accept-ranges"], "bytes");
+        assert_eq!(headers["content-type"], "video/mp4");
+        let body = axum::body::to_bytes(response.into_body(), 10)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"2345");
+        let response = get("bytes=20-").await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let outside_roots = content(3, Request::new(axum::body::Body::empty())).await;
+        assert_eq!(outside_roots.unwrap_err().0, StatusCode::FORBIDDEN);
+        std::fs::remove_file(&outside).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let missing = content(2, Request::new(axum::body::Body::empty())).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+        let gone = content(1, Request::new(axum::body::Body::empty())).await;
+        assert_eq!(gone.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn confines_to_media_directories() {
+        let dir = std::env::temp_dir().join(format!("rsapp-roots-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("media")).unwrap();
+        std::fs::write(dir.join("media/movie.mkv"), b"").unwrap();
+        std::fs::write(dir.join("config.toml"), b"").unwrap();
+        let roots = MediaRoots::new(vec![dir.join("media"), dir.join("not-created-yet")]);
+        let within = |path: &str| roots.resolve(&format!("{}/{}", dir.display(), path));
+
+        let resolved = within("media/./movie.mkv").await.unwrap();
+        assert_eq!(
+            resolved,
+            Some(dir.join("media/movie.mkv").canonicalize().unwrap())
+        );
+        assert_eq!(within("media/../config.toml").await.unwrap(), None);
+        assert_eq!(within("config.toml").await.unwrap(), None);
+        assert!(within("media/missing.mkv").await.is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("config.toml"), dir.join("media/link.mkv"))
+                .unwrap();
+            assert_eq!(within("media/link.mkv").await.unwrap(), None);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diffs() {
+        let before = serde_json::json!({
+            "format": "matroska,webm",
+            "tags": {"title": "movie"},
+            "streams": [
+                {"index": 0, "codec": "hevc", "width": 3840},
+                {"index": 1, "codec": "aac"},
+            ],
+        });
+        let after = serde_json::json!({
+            "format": "matroska,webm",
+            "tags": {"title": "movie", "encoder": "lavf"},
+            "streams": [{"index": 0, "codec": "h264", "width": 3840}],
+        });
+        let mut changes = Vec::new();
+        diff(String::new(), &before, &after, &mut changes);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|change| (change.path.as_str(), &change.before, &change.after))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("streams[0].codec", &"hevc".into(), &"h264".into()),
+                // a missing stream is one change
+                ("streams[1]", &before["streams"][1], &Value::Null),
+                ("tags.encoder", &Value::Null, &"lavf".into()),
+            ]
+        );
+    }
+}
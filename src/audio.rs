@@ -0,0 +1,121 @@
+// Waveforms of audio for drawing in a UI: the peaks of the file's audio, decoded with ffmpeg, as
+// JSON or binary in the formats of audiowaveform, which players like peaks.js read as they are.
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{app::AppState, db_error, media, repository::MediaRepository, validation};
+
+const MAX_SAMPLES_PER_SECOND: u32 = 1000;
+
+#[derive(Deserialize, IntoParams)]
+pub struct WaveformQuery {
+    // path of a registered asset
+    file: String,
+    // peaks per second of audio
+    #[serde(default = "default_samples_per_second")]
+    samples_per_second: u32,
+    #[serde(default)]
+    format: PeaksFormat,
+}
+
+fn default_samples_per_second() -> u32 {
+    20
+}
+
+#[derive(Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PeaksFormat {
+    #[default]
+    Json,
+    // audiowaveform's .dat, 16 bit little endian
+    Binary,
+}
+
+// audiowaveform's JSON, a single channel of 16 bit peaks
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct Waveform {
+    version: u32,
+    channels: u32,
+    sample_rate: u32,
+    samples_per_pixel: u32,
+    bits: u32,
+    // slices, each a min and max in `data`
+    length: usize,
+    data: Vec<i16>,
+}
+
+impl From<media::Peaks> for Waveform {
+    fn from(peaks: media::Peaks) -> Self {
+        Waveform {
+            version: 2,
+            channels: 1,
+            sample_rate: peaks.sample_rate,
+            samples_per_pixel: peaks.samples_per_pixel,
+            bits: 16,
+            length: peaks.data.len() / 2,
+            data: peaks.data,
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/audio/waveform", get(waveform))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audio/waveform",
+    params(WaveformQuery),
+    responses(
+        (status = 200, description = "Min and max of each slice of the audio, in audiowaveform's binary format with `format=binary`", body = Waveform),
+        (status = 404, description = "The file isn't a registered asset"),
+        (status = 422, description = "Invalid path or samples per second, or no audio to decode"),
+    ),
+    tag = "media"
+)]
+async fn waveform(
+    State(media): State<Arc<dyn MediaRepository>>,
+    Query(query): Query<WaveformQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    validation::media_path(&query.file)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    if !(1..=MAX_SAMPLES_PER_SECOND).contains(&query.samples_per_second) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "samples_per_second must be from 1 to {}",
+                MAX_SAMPLES_PER_SECOND
+            ),
+        ));
+    }
+    let file = media.find_by_path(&query.file).await.map_err(db_error)?;
+    let path = PathBuf::from(file.path);
+    let samples_per_second = query.samples_per_second;
+    // decoding stops when the client goes away
+    let cancel = media::Cancel::default();
+    let _abandoned = cancel.guard();
+    let peaks =
+        tokio::task::spawn_blocking(move || media::peaks(&path, samples_per_second, &cancel))
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+            .map_err(|err| {
+                let message = format!("can't decode the audio: {}", err);
+                (StatusCode::UNPROCESSABLE_ENTITY, message)
+            })?;
+    Ok(match query.format {
+        PeaksFormat::Json => Json(Waveform::from(peaks)).into_response(),
+        PeaksFormat::Binary => {
+            let content_type = [(header::CONTENT_TYPE, "application/octet-stream")];
+            (content_type, peaks.to_dat()).into_response()
+        }
+    })
+}
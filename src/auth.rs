@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+use clap::ValueEnum;
+use serde_derive::Deserialize;
+use tower_sessions::Session;
+
+use crate::{listen::RemoteAddress, oidc, session};
+
+// `[auth]` in the config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+    // taking the user from the `X-User-Id` header, for a proxy in front that authenticates them and
+    // sets it, and their address from `X-Forwarded-For`. Anyone reaching the server directly could
+    // send those too, so it's off by default.
+    #[serde(default)]
+    pub trusted_proxy: bool,
+    // signing in through an OpenID Connect provider, see `oidc`
+    pub oidc: Option<oidc::Settings>,
+}
+
+// shorter passwords are refused when accounts are created
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    // as kept in `users.role`, anything unknown is a user
+    pub fn from_name(name: &str) -> Role {
+        match name {
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+}
+
+// an Argon2id hash of `password` with a random salt, in PHC string format
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| err.to_string())
+}
+
+// whether `password` is the one `hash` was made from; false for malformed hashes
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// 32 random bytes in hex, for reset links and sessions
+pub fn random_token() -> String {
+    let mut token = [0; 32];
+    OsRng.fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+// whether `X-User-Id` and `X-Forwarded-For` come from a trusted proxy, `auth.trusted_proxy`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrustedProxy(pub bool);
+
+// The address of who is asking, for limits per client: the one the connection is from, or with
+// `auth.trusted_proxy` the one the proxy was connected from, the last of `X-Forwarded-For`.
+// "unknown" for requests that didn't come through a listener, such as those of tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAddress(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientAddress
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let trusted = parts
+            .extensions
+            .get::<TrustedProxy>()
+            .is_some_and(|proxy| proxy.0);
+        // the proxy appends the address it was connected from to those it was told
+        let forwarded = trusted
+            .then(|| parts.headers.get_all("x-forwarded-for").iter().last())
+            .flatten()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .map(str::trim)
+            .filter(|address| !address.is_empty());
+        let address = match forwarded {
+            Some(address) => address.to_owned(),
+            None => parts
+                .extensions
+                .get::<RemoteAddress>()
+                .map_or_else(|| "unknown".to_owned(), |remote| remote.0.to_string()),
+        };
+        Ok(ClientAddress(address))
+    }
+}
+
+// the user issuing the request: the one its session cookie is signed in as, see `session`, or
+// with `auth.trusted_proxy` the one of the `X-User-Id` header the proxy sets.
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentUser(pub i64);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user) = session::user(parts).await? {
+            return Ok(CurrentUser(user.id));
+        }
+        if !parts
+            .extensions
+            .get::<TrustedProxy>()
+            .is_some_and(|proxy| proxy.0)
+        {
+            return Err((StatusCode::UNAUTHORIZED, "not signed in".to_owned()));
+        }
+        parts
+            .headers
+            .get("x-user-id")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map(CurrentUser)
+            .ok_or((
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid X-User-Id header".to_owned(),
+            ))
+    }
+}
+
+// the configured admin token; without one only admins signed in reach admin routes, and with
+// sessions off too they're disabled
+#[derive(Clone, Default)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+// proof that the request carries the admin token as `Authorization: Bearer <token>`, or comes
+// through the session of an account with the admin role, such as `oidc` gives with `admin_roles`
+#[derive(Debug, Clone, Copy)]
+pub struct Admin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .extensions
+            .get::<AdminToken>()
+            .and_then(|token| token.0.clone());
+        let given = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let (Some(token), Some(given)) = (&token, given) {
+            if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+                return Ok(Admin);
+            }
+            return Err((StatusCode::UNAUTHORIZED, "invalid admin token".to_owned()));
+        }
+        match session::user(parts).await? {
+            Some(user) if user.admin => Ok(Admin),
+            Some(_) => Err((StatusCode::FORBIDDEN, "only admins may do this".to_owned())),
+            None if token.is_none() && parts.extensions.get::<Session>().is_none() => {
+                Err((StatusCode::NOT_FOUND, "admin API is disabled".to_owned()))
+            }
+            None => Err((StatusCode::UNAUTHORIZED, "invalid admin token".to_owned())),
+        }
+    }
+}
+
+// compares without bailing out at the first difference, so timing doesn't leak the token
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn takes_x_user_id_only_from_a_trusted_proxy() {
+        let request = Request::get("/api/v1/playlists").header("x-user-id", "7");
+        let mut parts = request.body(()).unwrap().into_parts().0;
+        let forged = CurrentUser::from_request_parts(&mut parts, &()).await;
+        assert_eq!(forged.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        parts.extensions.insert(TrustedProxy(true));
+        let user = CurrentUser::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(user.0, 7);
+    }
+
+    #[test]
+    fn hashes_passwords() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        // salted, so never the same twice
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+    }
+}
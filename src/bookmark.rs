@@ -0,0 +1,351 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, patch},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    api::{v1, ToVersion},
+    app::AppState,
+    asset,
+    auth::CurrentUser,
+    db::DbExecutor,
+    db_error,
+};
+
+// a favorited asset, or a point in it when `time` is set
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Bookmark {
+    pub id: i64,
+    pub asset_id: i64,
+    pub asset_title: String,
+    // seconds from the start of the asset
+    #[sqlx(rename = "time_secs")]
+    pub time: Option<f64>,
+    pub note: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// what players such as videojs-markers consume
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+pub struct Marker {
+    time: f64,
+    text: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateBookmark {
+    time: Option<f64>,
+    #[serde(default)]
+    note: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateBookmark {
+    time: Option<f64>,
+    note: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListBookmarks {
+    asset_id: Option<i64>,
+    // only bookmarks changed after this, for clients keeping a local copy in sync
+    since: Option<DateTime<Utc>>,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+const COLUMNS: &str =
+    "b.id, b.asset_id, a.title AS asset_title, b.time_secs, b.note, b.created_at, b.updated_at";
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/assets/:id/bookmarks",
+            get(asset_bookmarks).post(create_bookmark),
+        )
+        .route("/assets/:id/markers", get(markers))
+        .route("/bookmarks", get(list_bookmarks))
+        .route(
+            "/bookmarks/:id",
+            patch(update_bookmark).delete(delete_bookmark),
+        )
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/assets/{id}/bookmarks",
+    params(("id" = i64, Path, description = "Asset id")),
+    request_body = CreateBookmark,
+    responses(
+        (status = 201, description = "Bookmark created", body = v1::Bookmark),
+        (status = 404, description = "No such asset"),
+        (status = 409, description = "The asset is already a favorite"),
+        (status = 422, description = "Invalid time"),
+    ),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn create_bookmark(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(asset_id): Path<i64>,
+    Json(payload): Json<CreateBookmark>,
+) -> Result<(StatusCode, Json<v1::Bookmark>), (StatusCode, String)> {
+    validate_time(payload.time)?;
+    asset::find(&pool, asset_id).await.map_err(db_error)?;
+
+    let id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO bookmarks (user_id, asset_id, time_secs, note) VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(user)
+    .bind(asset_id)
+    .bind(payload.time)
+    .bind(&payload.note)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(find(&pool, user, id).await?.to_version()),
+    ))
+}
+
+// the user's bookmarks in one asset, in playback order with favorites first
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/bookmarks",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses((status = 200, description = "Bookmarks in playback order, favorites first", body = [v1::Bookmark])),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn asset_bookmarks(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+    Path(asset_id): Path<i64>,
+) -> Result<Json<Vec<v1::Bookmark>>, (StatusCode, String)> {
+    Ok(Json(
+        in_asset(db.read(), user, asset_id).await?.to_version(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/markers",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses((status = 200, description = "Timeline markers for players", body = [Marker])),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn markers(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+    Path(asset_id): Path<i64>,
+) -> Result<Json<Vec<Marker>>, (StatusCode, String)> {
+    let bookmarks = in_asset(db.read(), user, asset_id).await?;
+    Ok(Json(to_markers(bookmarks)))
+}
+
+// the user's bookmarks across the whole library, most recently changed first
+#[utoipa::path(
+    get,
+    path = "/api/v1/bookmarks",
+    params(ListBookmarks),
+    responses((status = 200, description = "Bookmarks, most recently changed first", body = [v1::Bookmark])),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn list_bookmarks(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<ListBookmarks>,
+) -> Result<Json<Vec<v1::Bookmark>>, (StatusCode, String)> {
+    let bookmarks = sqlx::query_as::<_, Bookmark>(&format!(
+        "SELECT {} FROM bookmarks b JOIN assets a ON a.id = b.asset_id
+            WHERE b.user_id = $1 AND ($2::BIGINT IS NULL OR b.asset_id = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR b.updated_at > $3)
+            ORDER BY b.updated_at DESC, b.id DESC LIMIT $4 OFFSET $5",
+        COLUMNS
+    ))
+    .bind(user)
+    .bind(query.asset_id)
+    .bind(query.since)
+    .bind(query.limit.clamp(1, 1000))
+    .bind(query.offset.max(0))
+    .fetch_all(db.read())
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(bookmarks.to_version()))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/bookmarks/{id}",
+    params(("id" = i64, Path, description = "Bookmark id")),
+    request_body = UpdateBookmark,
+    responses(
+        (status = 200, description = "Bookmark updated", body = v1::Bookmark),
+        (status = 404, description = "No such bookmark"),
+        (status = 422, description = "Invalid time"),
+    ),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn update_bookmark(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateBookmark>,
+) -> Result<Json<v1::Bookmark>, (StatusCode, String)> {
+    validate_time(payload.time)?;
+    let result = sqlx::query(
+        "UPDATE bookmarks SET time_secs = COALESCE($3, time_secs), note = COALESCE($4, note),
+            updated_at = now() WHERE id = $1 AND user_id = $2",
+    )
+    .bind(id)
+    .bind(user)
+    .bind(payload.time)
+    .bind(payload.note)
+    .execute(&pool)
+    .await
+    .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(db_error(sqlx::Error::RowNotFound));
+    }
+
+    Ok(Json(find(&pool, user, id).await?.to_version()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/bookmarks/{id}",
+    params(("id" = i64, Path, description = "Bookmark id")),
+    responses(
+        (status = 204, description = "Bookmark removed"),
+        (status = 404, description = "No such bookmark"),
+    ),
+    security(("user_id" = [])),
+    tag = "bookmarks"
+)]
+async fn delete_bookmark(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("DELETE FROM bookmarks WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user)
+        .execute(&pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(db_error(sqlx::Error::RowNotFound));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn in_asset(
+    pool: &PgPool,
+    user: i64,
+    asset_id: i64,
+) -> Result<Vec<Bookmark>, (StatusCode, String)> {
+    sqlx::query_as::<_, Bookmark>(&format!(
+        "SELECT {} FROM bookmarks b JOIN assets a ON a.id = b.asset_id
+            WHERE b.user_id = $1 AND b.asset_id = $2 ORDER BY b.time_secs NULLS FIRST, b.id",
+        COLUMNS
+    ))
+    .bind(user)
+    .bind(asset_id)
+    .fetch_all(pool)
+    .await
+    .map_err(db_error)
+}
+
+async fn find(pool: &PgPool, user: i64, id: i64) -> Result<Bookmark, (StatusCode, String)> {
+    sqlx::query_as::<_, Bookmark>(&format!(
+        "SELECT {} FROM bookmarks b JOIN assets a ON a.id = b.asset_id WHERE b.id = $1 AND b.user_id = $2",
+        COLUMNS
+    ))
+    .bind(id)
+    .bind(user)
+    .fetch_one(pool)
+    .await
+    .map_err(db_error)
+}
+
+fn validate_time(time: Option<f64>) -> Result<(), (StatusCode, String)> {
+    match time {
+        Some(time) if !time.is_finite() || time < 0.0 => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "time must be a non-negative number of seconds".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// favorites have no place on the timeline and are left out
+fn to_markers(bookmarks: Vec<Bookmark>) -> Vec<Marker> {
+    bookmarks
+        .into_iter()
+        .filter_map(|bookmark| {
+            bookmark.time.map(|time| Marker {
+                time,
+                text: bookmark.note,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_skip_favorites() {
+        let bookmark = |time: Option<f64>, note: &str| Bookmark {
+            id: 1,
+            asset_id: 1,
+            asset_title: "movie".to_owned(),
+            time,
+            note: note.to_owned(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+
+        assert_eq!(
+            to_markers(vec![
+                bookmark(None, "favorite"),
+                bookmark(Some(12.5), "chase scene"),
+                bookmark(Some(80.0), ""),
+            ]),
+            vec![
+                Marker {
+                    time: 12.5,
+                    text: "chase scene".to_owned()
+                },
+                Marker {
+                    time: 80.0,
+                    text: "".to_owned()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+// A message bus the events of the app are published to, for consumers elsewhere: NATS when built
+// with the `nats` feature, Kafka with the `kafka` one, configured in `[bus]`. Each event goes to
+// the subject or topic `[bus.topics]` names for it, those without one aren't published:
+//
+//     {"event": "job_finished", "at": "2024-02-21T16:00:00Z", "data": {"id": 5, ...}}
+//
+// Events are queued in memory and sent in order by one task; while the bus can't take them they
+// are dropped, with a warning, rather than holding up the requests publishing them. Every instance
+// publishes the events that happen in it, finished jobs by the instance that ran them.
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{event::Topic, task};
+
+// events waiting to be sent before further ones are dropped
+const BUFFER: usize = 1024;
+// how long Kafka gets to take an event
+#[cfg(feature = "kafka")]
+const SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Nats,
+    Kafka,
+}
+
+impl Backend {
+    fn feature(self) -> &'static str {
+        match self {
+            Backend::Nats => "nats",
+            Backend::Kafka => "kafka",
+        }
+    }
+}
+
+// `[bus]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    pub backend: Backend,
+    // nats://localhost:4222, or the comma separated brokers of Kafka such as localhost:9092
+    pub url: String,
+    // subject or topic by event name, like `job_finished = "rsapp.jobs.finished"`
+    #[serde(default)]
+    pub topics: HashMap<String, String>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        for (event, subject) in &self.topics {
+            if Topic::parse(event).is_none() {
+                return Err(format!("bus.topics: there is no event {}", event));
+            }
+            if subject.is_empty() {
+                return Err(format!("bus.topics.{} is empty", event));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    event: &'static str,
+    at: chrono::DateTime<Utc>,
+    data: &'a serde_json::Value,
+}
+
+enum Client {
+    #[cfg(feature = "nats")]
+    Nats(async_nats::Client),
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::producer::FutureProducer),
+}
+
+impl Client {
+    #[cfg_attr(not(any(feature = "nats", feature = "kafka")), allow(unreachable_code))]
+    async fn connect(settings: &Settings) -> Result<Self, String> {
+        let client = match settings.backend {
+            #[cfg(feature = "nats")]
+            Backend::Nats => Client::Nats(
+                async_nats::connect(settings.url.as_str())
+                    .await
+                    .map_err(|err| err.to_string())?,
+            ),
+            #[cfg(feature = "kafka")]
+            Backend::Kafka => Client::Kafka(
+                rdkafka::ClientConfig::new()
+                    .set("bootstrap.servers", &settings.url)
+                    .create()
+                    .map_err(|err: rdkafka::error::KafkaError| err.to_string())?,
+            ),
+            #[allow(unreachable_patterns)]
+            backend => return Err(format!("built without the {} feature", backend.feature())),
+        };
+        Ok(client)
+    }
+
+    #[cfg_attr(not(any(feature = "nats", feature = "kafka")), allow(unused_variables))]
+    async fn send(&self, subject: &str, payload: Vec<u8>) -> Result<(), String> {
+        match *self {
+            #[cfg(feature = "nats")]
+            Client::Nats(ref client) => client
+                .publish(subject.to_owned(), payload.into())
+                .await
+                .map_err(|err| err.to_string()),
+            #[cfg(feature = "kafka")]
+            Client::Kafka(ref producer) => {
+                let record =
+                    rdkafka::producer::FutureRecord::<(), _>::to(subject).payload(&payload);
+                producer
+                    .send(record, SEND_TIMEOUT)
+                    .await
+                    .map(|_| ())
+                    .map_err(|(err, _)| err.to_string())
+            }
+        }
+    }
+}
+
+pub struct Bus {
+    topics: HashMap<String, String>,
+    queue: mpsc::Sender<(String, Vec<u8>)>,
+}
+
+impl Bus {
+    // connects to the bus, and sends what's published to it from then on
+    pub async fn open(settings: &Settings) -> Result<Arc<Self>, String> {
+        let client = Client::connect(settings).await?;
+        info!(
+            "publishing events to {:?} at {}",
+            settings.backend, settings.url
+        );
+        let (queue, mut queued) = mpsc::channel::<(String, Vec<u8>)>(BUFFER);
+        task::spawn("bus publishing", task::Kind::Background, async move {
+            while let Some((subject, payload)) = queued.recv().await {
+                if let Err(err) = client.send(&subject, payload).await {
+                    warn!("publishing to {} failed: {}", subject, err);
+                }
+            }
+        });
+        Ok(Arc::new(Bus {
+            topics: settings.topics.clone(),
+            queue,
+        }))
+    }
+
+    // queues `data` for the subject of `topic`, if it has one
+    pub fn publish(&self, topic: Topic, data: &serde_json::Value) {
+        let Some(subject) = self.topics.get(topic.name()) else {
+            return;
+        };
+        let envelope = Envelope {
+            event: topic.name(),
+            at: Utc::now(),
+            data,
+        };
+        let Ok(payload) = serde_json::to_vec(&envelope) else {
+            return;
+        };
+        if self.queue.try_send((subject.clone(), payload)).is_err() {
+            warn!("the bus is behind, dropped a {} event", topic.name());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_name_events() {
+        let settings: Settings = serde_json::from_value(serde_json::json!({
+            "backend": "nats",
+            "url": "nats://localhost:4222",
+            "topics": {"job_finished": "rsapp.jobs.finished"},
+        }))
+        .unwrap();
+        assert_eq!(settings.validate(), Ok(()));
+        let settings = Settings {
+            topics: HashMap::from([("job_started".to_owned(), "rsapp.jobs".to_owned())]),
+            ..settings
+        };
+        assert!(settings.validate().is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{app::AppState, auth::Admin, instance::fnv1a};
+
+// requests naming a canary in this header, comma separated, get its alternate implementation
+// whatever the percentage, while it's enabled
+pub const HEADER: &str = "x-canary";
+
+pub const TRANSCODER: &str = "transcoder";
+
+// the canaries there are an alternate implementation for, and what it does differently
+const KNOWN: &[(&str, &str)] = &[(
+    TRANSCODER,
+    "transcodes by running the ffmpeg command line tool instead of the linked libraries",
+)];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Variant::Stable => "stable",
+            Variant::Canary => "canary",
+        }
+    }
+
+    // anything but "canary" is the stable implementation
+    pub fn from_name(name: &str) -> Self {
+        if name == "canary" {
+            Variant::Canary
+        } else {
+            Variant::Stable
+        }
+    }
+}
+
+// `[canaries.<name>]` in the config, or set through the admin API
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct Settings {
+    // false sends everything to the stable implementation, the header included
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    // share of users, from 0 to 100, whose requests get the alternate implementation. A user
+    // always gets the same one, so their results can be compared.
+    #[serde(default)]
+    pub percent: u8,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: true,
+            percent: 0,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Stats {
+    runs: u64,
+    failures: u64,
+    total: Duration,
+    max: Duration,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct VariantStats {
+    pub runs: u64,
+    pub failures: u64,
+    // share of runs that failed, from 0 to 1
+    pub failure_rate: Option<f64>,
+    pub mean_ms: Option<f64>,
+    pub max_ms: u64,
+}
+
+impl From<Stats> for VariantStats {
+    fn from(stats: Stats) -> Self {
+        let runs = (stats.runs > 0).then_some(stats.runs as f64);
+        VariantStats {
+            runs: stats.runs,
+            failures: stats.failures,
+            failure_rate: runs.map(|runs| stats.failures as f64 / runs),
+            mean_ms: runs.map(|runs| stats.total.as_secs_f64() * 1000.0 / runs),
+            max_ms: stats.max.as_millis() as u64,
+        }
+    }
+}
+
+// the two implementations side by side, since the process started
+#[derive(Serialize, ToSchema, Debug)]
+pub struct CanaryReport {
+    pub name: String,
+    pub description: String,
+    pub settings: Settings,
+    pub stable: VariantStats,
+    pub canary: VariantStats,
+}
+
+struct Canary {
+    description: &'static str,
+    settings: Settings,
+    stable: Stats,
+    canary: Stats,
+}
+
+impl Canary {
+    fn report(&self, name: &str) -> CanaryReport {
+        CanaryReport {
+            name: name.to_owned(),
+            description: self.description.to_owned(),
+            settings: self.settings.clone(),
+            stable: self.stable.into(),
+            canary: self.canary.into(),
+        }
+    }
+}
+
+// Sends part of the traffic to alternate implementations of something, to try them on real
+// requests before switching over. Code with an alternate asks `pick` which one to run and tells
+// `record` how it went; stats are kept in memory only.
+pub struct Canaries {
+    canaries: RwLock<BTreeMap<&'static str, Canary>>,
+}
+
+impl Default for Canaries {
+    fn default() -> Self {
+        Canaries::new(HashMap::new())
+    }
+}
+
+impl Canaries {
+    pub fn new(mut configured: HashMap<String, Settings>) -> Self {
+        let canaries = KNOWN
+            .iter()
+            .map(|(name, description)| {
+                let canary = Canary {
+                    description,
+                    settings: configured.remove(*name).unwrap_or_default(),
+                    stable: Stats::default(),
+                    canary: Stats::default(),
+                };
+                (*name, canary)
+            })
+            .collect();
+        for name in configured.keys() {
+            warn!("there is no canary {}, its settings are ignored", name);
+        }
+        Canaries {
+            canaries: RwLock::new(canaries),
+        }
+    }
+
+    // which implementation of `name` to use for a request with `headers` by user `user`
+    pub fn pick(&self, name: &str, headers: &HeaderMap, user: i64) -> Variant {
+        let canaries = self.canaries.read().unwrap();
+        let Some(canary) = canaries.get(name) else {
+            return Variant::Stable;
+        };
+        let asked = headers
+            .get_all(HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|asked| asked.trim().eq_ignore_ascii_case(name));
+        let settings = &canary.settings;
+        if settings.enabled && (asked || bucket(name, user) < settings.percent) {
+            Variant::Canary
+        } else {
+            Variant::Stable
+        }
+    }
+
+    pub fn record(&self, name: &str, variant: Variant, took: Duration, ok: bool) {
+        let mut canaries = self.canaries.write().unwrap();
+        let Some(canary) = canaries.get_mut(name) else {
+            return;
+        };
+        let stats = match variant {
+            Variant::Stable => &mut canary.stable,
+            Variant::Canary => &mut canary.canary,
+        };
+        stats.runs += 1;
+        stats.failures += u64::from(!ok);
+        stats.total += took;
+        stats.max = stats.max.max(took);
+    }
+
+    fn reports(&self) -> Vec<CanaryReport> {
+        let canaries = self.canaries.read().unwrap();
+        canaries
+            .iter()
+            .map(|(name, canary)| canary.report(name))
+            .collect()
+    }
+
+    fn change(
+        &self,
+        name: &str,
+        change: impl FnOnce(&mut Settings),
+    ) -> Result<CanaryReport, (StatusCode, String)> {
+        let mut canaries = self.canaries.write().unwrap();
+        let (name, canary) = canaries
+            .iter_mut()
+            .find(|(known, _)| **known == name)
+            .ok_or((StatusCode::NOT_FOUND, "no such canary".to_owned()))?;
+        change(&mut canary.settings);
+        Ok(canary.report(name))
+    }
+}
+
+// 0 to 99, the same for a user as long as the canary keeps its name
+fn bucket(name: &str, user: i64) -> u8 {
+    let mut key = name.as_bytes().to_vec();
+    key.extend_from_slice(&user.to_le_bytes());
+    (fnv1a(&key) % 100) as u8
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/canaries", get(list_canaries))
+        .route("/admin/canaries/:name", put(update_canary))
+        .route("/admin/canaries/:name/rollback", post(rollback))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/canaries",
+    responses((status = 200, description = "Canaries with stats of both implementations", body = [CanaryReport])),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn list_canaries(_: Admin, State(canaries): State<Arc<Canaries>>) -> Json<Vec<CanaryReport>> {
+    Json(canaries.reports())
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/canaries/{name}",
+    params(("name" = String, Path, description = "Canary name")),
+    request_body = Settings,
+    responses(
+        (status = 200, description = "Settings replaced, effective immediately", body = CanaryReport),
+        (status = 404, description = "No such canary"),
+        (status = 422, description = "Percentage above 100"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn update_canary(
+    _: Admin,
+    State(canaries): State<Arc<Canaries>>,
+    Path(name): Path<String>,
+    Json(settings): Json<Settings>,
+) -> Result<Json<CanaryReport>, (StatusCode, String)> {
+    if settings.percent > 100 {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "percent goes up to 100".to_owned(),
+        ));
+    }
+    canaries
+        .change(&name, |current| *current = settings)
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/canaries/{name}/rollback",
+    params(("name" = String, Path, description = "Canary name")),
+    responses(
+        (status = 200, description = "Canary disabled, everything uses the stable implementation again", body = CanaryReport),
+        (status = 404, description = "No such canary"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn rollback(
+    _: Admin,
+    State(canaries): State<Arc<Canaries>>,
+    Path(name): Path<String>,
+) -> Result<Json<CanaryReport>, (StatusCode, String)> {
+    canaries
+        .change(&name, |settings| {
+            warn!("canary {} rolled back", name);
+            settings.enabled = false;
+            settings.percent = 0;
+        })
+        .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks() {
+        let canaries = Canaries::new(HashMap::from([(
+            TRANSCODER.to_owned(),
+            Settings {
+                enabled: true,
+                percent: 30,
+            },
+        )]));
+        let none = HeaderMap::new();
+        let canary_users = (0..1000)
+            .filter(|user| canaries.pick(TRANSCODER, &none, *user) == Variant::Canary)
+            .count();
+        assert!((200..400).contains(&canary_users), "{}", canary_users);
+        // the same answer every time
+        for user in 0..100 {
+            assert_eq!(
+                canaries.pick(TRANSCODER, &none, user),
+                canaries.pick(TRANSCODER, &none, user)
+            );
+        }
+
+        let mut asked = HeaderMap::new();
+        asked.insert(HEADER, "other, Transcoder".parse().unwrap());
+        assert!((0..100).all(|user| canaries.pick(TRANSCODER, &asked, user) == Variant::Canary));
+        assert_eq!(canaries.pick("unknown", &asked, 1), Variant::Stable);
+
+        canaries
+            .change(TRANSCODER, |settings| settings.enabled = false)
+            .unwrap();
+        assert!((0..100).all(|user| canaries.pick(TRANSCODER, &asked, user) == Variant::Stable));
+    }
+
+    #[test]
+    fn stats() {
+        let canaries = Canaries::default();
+        canaries.record(
+            TRANSCODER,
+            Variant::Canary,
+            Duration::from_millis(100),
+            true,
+        );
+        canaries.record(
+            TRANSCODER,
+            Variant::Canary,
+            Duration::from_millis(300),
+            false,
+        );
+        let report = canaries.change(TRANSCODER, |_| {}).unwrap();
+        assert_eq!(report.settings, Settings::default());
+        assert_eq!(
+            report.canary,
+            VariantStats {
+                runs: 2,
+                failures: 1,
+                failure_rate: Some(0.5),
+                mean_ms: Some(200.0),
+                max_ms: 300,
+            }
+        );
+        assert_eq!(report.stable.runs, 0);
+        assert_eq!(report.stable.mean_ms, None);
+    }
+}
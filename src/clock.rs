@@ -0,0 +1,93 @@
+// The time and the made up ids as the app sees them, behind traits so tests can stop the one and
+// predict the other. Services hold an `Arc<dyn Clock>` or `Arc<dyn IdGenerator>`, handlers take
+// them as state; outside of tests they are `SystemClock` and `HashedIds`.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::instance::fnv1a;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// ids of things that aren't rows, like requests and stored clips
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> String;
+}
+
+// unique enough to find a request in the logs: the time it came in and a counter, hashed
+#[derive(Default)]
+pub struct HashedIds {
+    next: AtomicU64,
+}
+
+impl IdGenerator for HashedIds {
+    fn next_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        let mut seed = nanos.to_le_bytes().to_vec();
+        seed.extend_from_slice(&self.next.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        format!("{:016x}", fnv1a(&seed))
+    }
+}
+
+// stands still at the time it's set to, for tests
+#[cfg(test)]
+pub struct FixedClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn at(rfc3339: &str) -> Self {
+        let at = DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        FixedClock(std::sync::Mutex::new(at.with_timezone(&Utc)))
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+// `id-1`, `id-2` and so on, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialIds(AtomicU64);
+
+#[cfg(test)]
+impl IdGenerator for SequentialIds {
+    fn next_id(&self) -> String {
+        format!("id-{}", self.0.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_ids_differ() {
+        let ids = HashedIds::default();
+        let (first, second) = (ids.next_id(), ids.next_id());
+        assert_eq!(first.len(), 16);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,157 @@
+// Conditional GETs. JSON and MessagePack answers to GET requests get an ETag, a hash of the body,
+// and a request whose If-None-Match names it gets `304 Not Modified` without the body. Handlers
+// that know when their data last changed set Last-Modified, which If-Modified-Since is compared
+// with instead when there's no If-None-Match. Either way clients have to ask again before reusing
+// an answer.
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+
+use crate::{instance::fnv1a, negotiate};
+
+// larger answers are sent as they are, rather than buffered for the hash
+const MAX_BODY: u64 = 1 << 20;
+
+pub async fn conditional(request: Request, next: Next) -> Response {
+    if request.method() != Method::GET {
+        return next.run(request).await;
+    }
+    let asked = request.headers().clone();
+    let response = next.run(request).await;
+    let json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json") || value == negotiate::MSGPACK);
+    let small = response
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size <= MAX_BODY);
+    if response.status() != StatusCode::OK || !json || !small {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_BODY as usize).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let etag = format!("\"{:016x}\"", fnv1a(&bytes));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        parts.headers.insert(header::ETAG, value);
+    }
+    parts
+        .headers
+        .entry(header::CACHE_CONTROL)
+        .or_insert(HeaderValue::from_static("no-cache"));
+    if not_modified(&asked, &parts.headers) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+// whether the client's copy, as described by the request headers `asked`, is still the answer
+// with `headers`
+fn not_modified(asked: &HeaderMap, headers: &HeaderMap) -> bool {
+    let header = |headers: &HeaderMap, name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    if let Some(candidates) = header(asked, header::IF_NONE_MATCH) {
+        let Some(etag) = header(headers, header::ETAG) else {
+            return false;
+        };
+        // compared weakly, as If-None-Match is
+        return candidates
+            .split(',')
+            .map(|candidate| candidate.trim())
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+    }
+    let since = header(asked, header::IF_MODIFIED_SINCE).and_then(|date| parse_http_date(&date));
+    let modified = header(headers, header::LAST_MODIFIED).and_then(|date| parse_http_date(&date));
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+// as Last-Modified has it, e.g. `Tue, 20 Feb 2024 10:00:00 GMT`
+pub fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Json, Router};
+    use tower::ServiceExt;
+
+    async fn send(app: &Router, headers: &[(header::HeaderName, &str)]) -> Response {
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_not_modified() {
+        let modified = "Tue, 20 Feb 2024 10:00:00 GMT";
+        let app = Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    (
+                        [(header::LAST_MODIFIED, modified)],
+                        Json(serde_json::json!({"id": 1})),
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(conditional));
+
+        let first = send(&app, &[]).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[header::CACHE_CONTROL], "no-cache");
+        let etag = first.headers()[header::ETAG].to_str().unwrap().to_owned();
+
+        let same = send(
+            &app,
+            &[(header::IF_NONE_MATCH, &format!("\"x\", W/{}", etag))],
+        )
+        .await;
+        assert_eq!(same.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(same.headers()[header::ETAG], etag.as_str());
+        let other = send(&app, &[(header::IF_NONE_MATCH, "\"x\"")]).await;
+        assert_eq!(other.status(), StatusCode::OK);
+
+        let later = send(&app, &[(header::IF_MODIFIED_SINCE, modified)]).await;
+        assert_eq!(later.status(), StatusCode::NOT_MODIFIED);
+        let earlier = "Mon, 19 Feb 2024 10:00:00 GMT";
+        let earlier = send(&app, &[(header::IF_MODIFIED_SINCE, earlier)]).await;
+        assert_eq!(earlier.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn http_dates() {
+        let at = DateTime::parse_from_rfc3339("2024-02-20T10:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(http_date(at), "Tue, 20 Feb 2024 10:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(at)), Some(at));
+    }
+}
@@ -0,0 +1,117 @@
+// What requests to other services carry of the request they're made for, so that their logs line
+// up with ours: the `X-Request-Id` it's handled under, the client's or one made up, and the W3C
+// trace context (`traceparent`, `tracestate`) of a tracing proxy in front, with this server as
+// the parent of the outgoing call. Peers of the federation take the request id over as theirs.
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+
+use crate::api::v2::REQUEST_ID;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Correlation {
+    request_id: Option<String>,
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+}
+
+impl Correlation {
+    // for calls made on the server's own account, like the webhook of a job
+    pub fn new(request_id: String) -> Self {
+        Correlation {
+            request_id: Some(request_id),
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(request_id) = &self.request_id {
+            request = request.header(REQUEST_ID.as_str(), request_id);
+        }
+        if let Some(traceparent) = &self.traceparent {
+            request = request.header(TRACEPARENT, traceparent);
+            if let Some(tracestate) = &self.tracestate {
+                request = request.header(TRACESTATE, tracestate);
+            }
+        }
+        request
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Correlation
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        // always there, set by `panics::catch` if the client didn't
+        let request_id = header(REQUEST_ID.as_str());
+        let traceparent = header(TRACEPARENT).and_then(|parent| child(&parent));
+        let tracestate = traceparent.as_ref().and(header(TRACESTATE));
+        Ok(Correlation {
+            request_id,
+            traceparent,
+            tracestate,
+        })
+    }
+}
+
+// `traceparent` of a call made while handling a request with `parent`: the same trace and flags,
+// with a span id of its own. None for versions and shapes it doesn't know, which aren't passed on.
+fn child(parent: &str) -> Option<String> {
+    let fields: Vec<&str> = parent.trim().split('-').collect();
+    let [version, trace, _span, flags] = fields[..] else {
+        return None;
+    };
+    let lower_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let valid = version == "00"
+        && lower_hex(trace, 32)
+        && trace.bytes().any(|b| b != b'0')
+        && lower_hex(flags, 2);
+    if !valid {
+        return None;
+    }
+    let mut span = [0u8; 8];
+    OsRng.fill_bytes(&mut span);
+    Some(format!("00-{}-{}-{}", trace, hex::encode(span), flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_traces() {
+        let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let child = child(parent).unwrap();
+        assert!(child.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(child.ends_with("-01"));
+        assert_ne!(child, parent);
+        assert_eq!(child.len(), parent.len());
+        for unknown in [
+            "",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-01",
+        ] {
+            assert_eq!(super::child(unknown), None, "{}", unknown);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+use crate::task;
+
+pub mod tls;
+
+// how often the replica is checked, and how long a check may take
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Picks the pool for a query. Reads go to the replica when one is configured and answered its
+// last health check, to the primary otherwise; writes always go to the primary, which is also
+// the router state. A replica lags a little, so a read that has to see the request's own
+// writes should use `write()`.
+pub struct DbExecutor {
+    primary: PgPool,
+    replica: Option<PgPool>,
+    replica_up: AtomicBool,
+}
+
+impl DbExecutor {
+    pub fn new(primary: PgPool, replica: Option<PgPool>) -> Self {
+        DbExecutor {
+            primary,
+            replica,
+            // until the first check says otherwise
+            replica_up: AtomicBool::new(false),
+        }
+    }
+
+    pub fn read(&self) -> &PgPool {
+        match &self.replica {
+            Some(replica) if self.replica_up.load(Ordering::Relaxed) => replica,
+            _ => &self.primary,
+        }
+    }
+
+    pub fn write(&self) -> &PgPool {
+        &self.primary
+    }
+
+    // whether the replica answered its last check, None without a replica
+    pub fn replica_up(&self) -> Option<bool> {
+        self.replica
+            .as_ref()
+            .map(|_| self.replica_up.load(Ordering::Relaxed))
+    }
+
+    // checks the replica in the background for as long as the executor is in use
+    pub fn watch_replica(self: &Arc<Self>) {
+        let Some(replica) = self.replica.clone() else {
+            return;
+        };
+        let db = Arc::downgrade(self);
+        task::spawn("replica check", task::Kind::Background, async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let check = sqlx::query("SELECT 1").execute(&replica);
+                let up = matches!(tokio::time::timeout(CHECK_TIMEOUT, check).await, Ok(Ok(_)));
+                let Some(db) = db.upgrade() else {
+                    return;
+                };
+                if db.replica_up.swap(up, Ordering::Relaxed) != up {
+                    if up {
+                        info!("read replica is up, reads go to it");
+                    } else {
+                        warn!("read replica is down, reads go to the primary");
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_fall_back_to_primary() {
+        let primary = PgPool::connect_lazy("postgres://localhost/primary").unwrap();
+        let replica = PgPool::connect_lazy("postgres://localhost/replica").unwrap();
+
+        let db = DbExecutor::new(primary.clone(), None);
+        assert!(std::ptr::eq(db.read(), db.write()));
+
+        let db = DbExecutor::new(primary, Some(replica));
+        assert!(std::ptr::eq(db.read(), db.write()));
+        db.replica_up.store(true, Ordering::Relaxed);
+        assert!(!std::ptr::eq(db.read(), db.write()));
+    }
+}
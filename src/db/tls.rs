@@ -0,0 +1,151 @@
+// `[postgres.tls]`: how connections to the primary and the replica are encrypted, instead of
+// `sslmode` and friends in the DSNs. Settings left out keep what the DSN says.
+//
+// To pin the server certificate, give the self-signed certificate of the server as `root_cert`
+// with `sslmode = "verify-ca"`: connections to a server presenting any other fail, whoever signed
+// it. sqlx doesn't let the handshake it makes check a fingerprint, so there's no setting for one.
+use std::{path::PathBuf, str::FromStr};
+
+use serde_derive::Deserialize;
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+    // disable, allow, prefer, require, verify-ca or verify-full, as with libpq
+    pub sslmode: Option<String>,
+    // PEM file of the CA that signed the server certificate, for verify-ca and verify-full, or
+    // the self-signed server certificate itself to pin it
+    pub root_cert: Option<PathBuf>,
+    // PEM files to authenticate with, both or neither
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        let mode = self.mode()?;
+        for (name, path) in [
+            ("root_cert", &self.root_cert),
+            ("client_cert", &self.client_cert),
+            ("client_key", &self.client_key),
+        ] {
+            let Some(path) = path else {
+                continue;
+            };
+            let pem = std::fs::read_to_string(path).map_err(|err| {
+                format!(
+                    "postgres.tls.{}: can't read {}: {}",
+                    name,
+                    path.display(),
+                    err
+                )
+            })?;
+            if !pem.contains("-----BEGIN ") {
+                return Err(format!(
+                    "postgres.tls.{}: {} isn't a PEM file",
+                    name,
+                    path.display()
+                ));
+            }
+        }
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err("postgres.tls.client_cert and client_key go together".to_owned());
+        }
+        let tls_settings = self.root_cert.is_some() || self.client_cert.is_some();
+        if matches!(mode, Some(PgSslMode::Disable)) && tls_settings {
+            return Err(
+                "postgres.tls.sslmode is disable, which leaves the certificates unused".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    fn mode(&self) -> Result<Option<PgSslMode>, String> {
+        self.sslmode
+            .as_deref()
+            .map(|mode| {
+                PgSslMode::from_str(mode).map_err(|_| {
+                    format!(
+                        "postgres.tls.sslmode is one of disable, allow, prefer, require, \
+                         verify-ca and verify-full, not {}",
+                        mode
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    // `options` with these settings on top, which are valid
+    pub fn apply(&self, mut options: PgConnectOptions) -> PgConnectOptions {
+        if let Ok(Some(mode)) = self.mode() {
+            options = options.ssl_mode(mode);
+        }
+        if let Some(path) = &self.root_cert {
+            options = options.ssl_root_cert(path);
+        }
+        if let Some(path) = &self.client_cert {
+            options = options.ssl_client_cert(path);
+        }
+        if let Some(path) = &self.client_key {
+            options = options.ssl_client_key(path);
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates() {
+        let pem = |name: &str| {
+            let path =
+                std::env::temp_dir().join(format!("rsapp-tls-{}-{}", std::process::id(), name));
+            std::fs::write(
+                &path,
+                "-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----\n",
+            )
+            .unwrap();
+            path
+        };
+        let settings = Settings {
+            sslmode: Some("verify-full".to_owned()),
+            root_cert: Some(pem("ca.pem")),
+            ..Settings::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+
+        let disabled = Settings {
+            sslmode: Some("disable".to_owned()),
+            ..settings.clone()
+        };
+        assert!(disabled.validate().unwrap_err().contains("disable"));
+        let unknown = Settings {
+            sslmode: Some("strict".to_owned()),
+            ..Settings::default()
+        };
+        assert!(unknown.validate().unwrap_err().contains("not strict"));
+        let missing = Settings {
+            client_key: Some("missing.key".into()),
+            ..Settings::default()
+        };
+        assert!(missing
+            .validate()
+            .unwrap_err()
+            .contains("can't read missing.key"));
+        let half = Settings {
+            client_cert: Some(pem("client.pem")),
+            ..Settings::default()
+        };
+        assert_eq!(
+            half.validate(),
+            Err("postgres.tls.client_cert and client_key go together".to_owned())
+        );
+        let both = Settings {
+            client_key: Some(pem("client.key")),
+            ..half
+        };
+        assert_eq!(both.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,549 @@
+// Media delivery: the best derivative of an asset for the client asking, among the finished
+// transcodes and thumbnails of it. Newer codecs and formats are smaller, so AV1 beats H.264 and
+// WebP beats JPEG, when the client handles them. What it handles is told by, first to last:
+// `codecs`/`formats` query hints listing what it decodes, codecs in `Accept` (like
+// `video/mp4; codecs="av01.0.05M.08"`, or `image/webp`), and what its `User-Agent` is known to
+// support. H.264 and JPEG go to everyone else. `codec`/`format` pick one regardless.
+//
+// Answers redirect to the chosen result like `/api/v1/jobs/{id}/download` does, saying what was
+// picked and why in `X-Media-Selection`, like `codec=av1; reason=accept; job=12`.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::Redirect,
+    routing::get,
+    Router,
+};
+use serde_derive::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    app::AppState,
+    auth::CurrentUser,
+    db_error,
+    job::{Job, JobKind, Jobs},
+    media::{ImageFormat, VideoCodec},
+};
+
+pub const SELECTION: HeaderName = HeaderName::from_static("x-media-selection");
+// the same URL answers differently by these
+const VARY: &str = "accept, user-agent";
+
+// the redirect to the chosen derivative, with what was chosen
+type Delivered = ([(HeaderName, String); 2], Redirect);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    // asked for by `codec` or `format`
+    Override,
+    // listed in `codecs` or `formats`
+    Hint,
+    Accept,
+    UserAgent,
+    // what every client handles, or all there is
+    Fallback,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Override => "override",
+            Reason::Hint => "hint",
+            Reason::Accept => "accept",
+            Reason::UserAgent => "user-agent",
+            Reason::Fallback => "fallback",
+        }
+    }
+}
+
+// what a client may be given of one kind of derivative
+trait Choice: Copy + PartialEq + Sized + 'static {
+    // best first, the last is what every client handles
+    const PREFERENCE: &'static [Self];
+
+    fn name(self) -> &'static str;
+    // also names it, like a codec string of `Accept`
+    fn named(self, name: &str) -> bool;
+    // whether a client sending `accept` takes it, None when `accept` doesn't tell
+    fn accepted(self, accept: &str) -> Option<bool>;
+    // whether a browser sending `user_agent` handles it, None when unknown
+    fn supported_by(self, user_agent: &str) -> Option<bool>;
+
+    fn parse(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        Self::PREFERENCE
+            .iter()
+            .copied()
+            .find(|choice| choice.named(&name))
+    }
+}
+
+impl Choice for VideoCodec {
+    const PREFERENCE: &'static [Self] = &[VideoCodec::Av1, VideoCodec::H264];
+
+    fn name(self) -> &'static str {
+        VideoCodec::name(self)
+    }
+
+    fn named(self, name: &str) -> bool {
+        match self {
+            VideoCodec::Av1 => name == "av1" || name.starts_with("av01"),
+            VideoCodec::H264 => {
+                name == "h264" || name.starts_with("avc1") || name.starts_with("avc3")
+            }
+        }
+    }
+
+    fn accepted(self, accept: &str) -> Option<bool> {
+        let codecs = accepted_codecs(accept)?;
+        Some(codecs.iter().any(|codec| self.named(codec)))
+    }
+
+    fn supported_by(self, user_agent: &str) -> Option<bool> {
+        match self {
+            VideoCodec::H264 => Some(true),
+            // Safari decodes it only on hardware that has a decoder
+            VideoCodec::Av1 => match browser(user_agent)? {
+                (Browser::Chrome, version) => Some(version >= 70),
+                (Browser::Firefox, version) => Some(version >= 67),
+                (Browser::Safari, _) => Some(false),
+            },
+        }
+    }
+}
+
+impl Choice for ImageFormat {
+    const PREFERENCE: &'static [Self] = &[ImageFormat::Webp, ImageFormat::Jpeg];
+
+    fn name(self) -> &'static str {
+        ImageFormat::name(self)
+    }
+
+    fn named(self, name: &str) -> bool {
+        match self {
+            ImageFormat::Webp => name == "webp" || name == "image/webp",
+            ImageFormat::Jpeg => matches!(name, "jpeg" | "jpg" | "image/jpeg"),
+        }
+    }
+
+    // browsers that decode WebP list it, those that don't still send `image/*`
+    fn accepted(self, accept: &str) -> Option<bool> {
+        let listed = media_ranges(accept).any(|(range, _)| self.named(&range));
+        listed.then_some(true)
+    }
+
+    fn supported_by(self, user_agent: &str) -> Option<bool> {
+        match self {
+            ImageFormat::Jpeg => Some(true),
+            ImageFormat::Webp => match browser(user_agent)? {
+                (Browser::Chrome, version) => Some(version >= 32),
+                (Browser::Firefox, version) => Some(version >= 65),
+                (Browser::Safari, version) => Some(version >= 14),
+            },
+        }
+    }
+}
+
+// the media ranges of `accept` the client takes, in lower case, with their parameters
+fn media_ranges(accept: &str) -> impl Iterator<Item = (String, Vec<(String, String)>)> + '_ {
+    accept.split(',').filter_map(|range| {
+        let mut parts = split_params(range);
+        let media = parts.next()?.to_ascii_lowercase();
+        let params: Vec<(String, String)> = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                let value = value.trim().trim_matches('"');
+                Some((name.trim().to_ascii_lowercase(), value.to_owned()))
+            })
+            .collect();
+        let refused = params
+            .iter()
+            .any(|(name, value)| name == "q" && value.parse::<f32>().is_ok_and(|q| q <= 0.0));
+        (!media.is_empty() && !refused).then_some((media, params))
+    })
+}
+
+// `;` separated, but not within quotes, which codec lists are in
+fn split_params(range: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    range
+        .split(move |c: char| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ';' && !quoted
+        })
+        .map(str::trim)
+}
+
+// the codecs `accept` lists in `codecs` parameters, None when it has none
+fn accepted_codecs(accept: &str) -> Option<Vec<String>> {
+    let mut codecs = None;
+    for (_, params) in media_ranges(accept) {
+        for (name, value) in params {
+            if name == "codecs" {
+                let listed = value
+                    .split(',')
+                    .map(|codec| codec.trim().to_ascii_lowercase());
+                codecs.get_or_insert_with(Vec::new).extend(listed);
+            }
+        }
+    }
+    codecs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Browser {
+    // and the others built on Chromium, like Edge and Opera
+    Chrome,
+    Firefox,
+    Safari,
+}
+
+// the browser and its major version by `user_agent`, for the ones that matter here
+fn browser(user_agent: &str) -> Option<(Browser, u32)> {
+    let version = |marker: &str| -> Option<u32> {
+        let rest = &user_agent[user_agent.find(marker)? + marker.len()..];
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse().ok()
+    };
+    if let Some(version) = version("Firefox/") {
+        return Some((Browser::Firefox, version));
+    }
+    if let Some(version) = version("Chrome/").or_else(|| version("Chromium/")) {
+        return Some((Browser::Chrome, version));
+    }
+    // Safari names its own version apart from the WebKit one
+    if user_agent.contains("Safari/") {
+        return Some((Browser::Safari, version("Version/")?));
+    }
+    None
+}
+
+// what the client asked for and sent
+struct Request<'a> {
+    chosen: Option<&'a str>,
+    hints: Option<&'a str>,
+    accept: &'a str,
+    user_agent: &'a str,
+}
+
+// the best of `available` for the client, and why
+fn choose<T: Choice>(
+    available: &[T],
+    request: &Request,
+) -> Result<(T, Reason), (StatusCode, String)> {
+    if let Some(name) = request.chosen {
+        let choice = T::parse(name).ok_or_else(|| {
+            let known: Vec<&str> = T::PREFERENCE.iter().map(|choice| choice.name()).collect();
+            let known = known.join(", ");
+            (
+                StatusCode::BAD_REQUEST,
+                format!("unknown {}, one of {}", name, known),
+            )
+        })?;
+        if !available.contains(&choice) {
+            let message = format!("there's no {} derivative of the asset", choice.name());
+            return Err((StatusCode::NOT_FOUND, message));
+        }
+        return Ok((choice, Reason::Override));
+    }
+    let hints: Option<Vec<T>> = request
+        .hints
+        .map(|hints| hints.split(',').filter_map(T::parse).collect());
+    let baseline = T::PREFERENCE[T::PREFERENCE.len() - 1];
+    for &candidate in T::PREFERENCE {
+        if !available.contains(&candidate) {
+            continue;
+        }
+        let verdict = match &hints {
+            Some(hints) => Some((hints.contains(&candidate), Reason::Hint)),
+            None => candidate
+                .accepted(request.accept)
+                .map(|accepted| (accepted, Reason::Accept))
+                .or_else(|| {
+                    let supported = candidate.supported_by(request.user_agent)?;
+                    Some((supported, Reason::UserAgent))
+                }),
+        };
+        match verdict {
+            Some((true, reason)) if candidate != baseline => return Ok((candidate, reason)),
+            _ if candidate == baseline => return Ok((candidate, Reason::Fallback)),
+            _ => {}
+        }
+    }
+    available
+        .first()
+        .map(|&choice| (choice, Reason::Fallback))
+        .ok_or((StatusCode::NOT_FOUND, "not found".to_owned()))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct VideoQuery {
+    // `av1` or `h264`, whatever the client seems to handle
+    codec: Option<String>,
+    // what the client decodes, like `av1,h264`
+    codecs: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ThumbnailQuery {
+    // `webp` or `jpeg`, whatever the client seems to handle
+    format: Option<String>,
+    // what the client decodes, like `webp,jpeg`
+    formats: Option<String>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/assets/:id/video", get(video))
+        .route("/assets/:id/thumbnail", get(thumbnail))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/video",
+    params(("id" = i64, Path, description = "Asset id"), VideoQuery),
+    responses(
+        (status = 307, description = "Redirect to the best finished transcode for the client, `X-Media-Selection` says which and why"),
+        (status = 400, description = "Unknown codec"),
+        (status = 404, description = "No finished transcode of the asset, or none in the codec asked for"),
+    ),
+    security(("user_id" = [])),
+    tag = "assets"
+)]
+async fn video(
+    State(jobs): State<Arc<Jobs>>,
+    _: CurrentUser,
+    Path(id): Path<i64>,
+    Query(query): Query<VideoQuery>,
+    headers: HeaderMap,
+) -> Result<Delivered, (StatusCode, String)> {
+    let derivatives = jobs.derivatives(id).await.map_err(db_error)?;
+    let derivatives: Vec<(VideoCodec, Job)> = derivatives
+        .into_iter()
+        .filter_map(|job| match job.kind {
+            // branded exports aren't renditions of the asset
+            JobKind::Transcode {
+                codec,
+                overlay: None,
+                ..
+            } => Some((codec, job)),
+            _ => None,
+        })
+        .collect();
+    let request = request(&headers, query.codec.as_deref(), query.codecs.as_deref());
+    deliver(&jobs, id, "transcode", derivatives, &request)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/assets/{id}/thumbnail",
+    params(("id" = i64, Path, description = "Asset id"), ThumbnailQuery),
+    responses(
+        (status = 307, description = "Redirect to the best finished thumbnail for the client, `X-Media-Selection` says which and why"),
+        (status = 400, description = "Unknown format"),
+        (status = 404, description = "No finished thumbnail of the asset, or none in the format asked for"),
+    ),
+    security(("user_id" = [])),
+    tag = "assets"
+)]
+async fn thumbnail(
+    State(jobs): State<Arc<Jobs>>,
+    _: CurrentUser,
+    Path(id): Path<i64>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+) -> Result<Delivered, (StatusCode, String)> {
+    let derivatives = jobs.derivatives(id).await.map_err(db_error)?;
+    let derivatives: Vec<(ImageFormat, Job)> = derivatives
+        .into_iter()
+        .filter_map(|job| match job.kind {
+            JobKind::Thumbnail { format, .. } => Some((format, job)),
+            _ => None,
+        })
+        .collect();
+    let request = request(&headers, query.format.as_deref(), query.formats.as_deref());
+    deliver(&jobs, id, "thumbnail", derivatives, &request)
+}
+
+fn request<'a>(
+    headers: &'a HeaderMap,
+    chosen: Option<&'a str>,
+    hints: Option<&'a str>,
+) -> Request<'a> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    Request {
+        chosen,
+        hints,
+        accept: header(header::ACCEPT),
+        user_agent: header(header::USER_AGENT),
+    }
+}
+
+// redirects to the best of `derivatives`, the latest of each first
+fn deliver<T: Choice>(
+    jobs: &Jobs,
+    asset_id: i64,
+    kind: &str,
+    derivatives: Vec<(T, Job)>,
+    request: &Request,
+) -> Result<Delivered, (StatusCode, String)> {
+    if derivatives.is_empty() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!(
+                "no {} of asset {} has finished, submit one to /api/v1/jobs",
+                kind, asset_id
+            ),
+        ));
+    }
+    let available: Vec<T> = derivatives.iter().map(|(choice, _)| *choice).collect();
+    let (choice, reason) = choose(&available, request)?;
+    let (_, job) = derivatives
+        .iter()
+        .find(|(candidate, _)| *candidate == choice)
+        .ok_or((StatusCode::NOT_FOUND, "not found".to_owned()))?;
+    let url = jobs
+        .download_url(job)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let selection = format!(
+        "{}={}; reason={}; job={}",
+        if kind == "thumbnail" {
+            "format"
+        } else {
+            "codec"
+        },
+        choice.name(),
+        reason.name(),
+        job.id
+    );
+    Ok((
+        [(SELECTION, selection), (header::VARY, VARY.to_owned())],
+        Redirect::temporary(&url),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+        Chrome/121.0.0.0 Safari/537.36";
+    const SAFARI_13: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+        (KHTML, like Gecko) Version/13.1.2 Safari/605.1.15";
+
+    fn request<'a>(
+        chosen: Option<&'a str>,
+        hints: Option<&'a str>,
+        accept: &'a str,
+        user_agent: &'a str,
+    ) -> Request<'a> {
+        Request {
+            chosen,
+            hints,
+            accept,
+            user_agent,
+        }
+    }
+
+    #[test]
+    fn chooses_video() {
+        let both = [VideoCodec::H264, VideoCodec::Av1];
+        let chose = |request: Request| choose(&both, &request).unwrap();
+        assert_eq!(
+            chose(request(None, None, "", CHROME)),
+            (VideoCodec::Av1, Reason::UserAgent)
+        );
+        assert_eq!(
+            chose(request(None, None, "", SAFARI_13)),
+            (VideoCodec::H264, Reason::Fallback)
+        );
+        assert_eq!(
+            chose(request(
+                None,
+                None,
+                r#"video/mp4; codecs="av01.0.05M.08, mp4a.40.2""#,
+                SAFARI_13
+            )),
+            (VideoCodec::Av1, Reason::Accept)
+        );
+        assert_eq!(
+            chose(request(
+                None,
+                None,
+                r#"video/mp4; codecs="avc1.42E01E""#,
+                CHROME
+            )),
+            (VideoCodec::H264, Reason::Fallback)
+        );
+        assert_eq!(
+            chose(request(None, Some("h264"), "", CHROME)),
+            (VideoCodec::H264, Reason::Fallback)
+        );
+        assert_eq!(
+            chose(request(None, Some("vp9, av1"), "", "curl/8.5.0")),
+            (VideoCodec::Av1, Reason::Hint)
+        );
+        assert_eq!(
+            chose(request(Some("h264"), None, "", CHROME)),
+            (VideoCodec::H264, Reason::Override)
+        );
+
+        // only what there is
+        let h264 = [VideoCodec::H264];
+        assert_eq!(
+            choose(&h264, &request(None, None, "", CHROME)).unwrap(),
+            (VideoCodec::H264, Reason::Fallback)
+        );
+        let missing = choose(&h264, &request(Some("av1"), None, "", CHROME));
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+        let unknown = choose(&h264, &request(Some("mpeg2"), None, "", CHROME));
+        assert_eq!(unknown.unwrap_err().0, StatusCode::BAD_REQUEST);
+        let av1 = [VideoCodec::Av1];
+        assert_eq!(
+            choose(&av1, &request(None, None, "", SAFARI_13)).unwrap(),
+            (VideoCodec::Av1, Reason::Fallback)
+        );
+    }
+
+    #[test]
+    fn chooses_thumbnails() {
+        let both = [ImageFormat::Jpeg, ImageFormat::Webp];
+        let chose = |request: Request| choose(&both, &request).unwrap();
+        assert_eq!(
+            chose(request(None, None, "image/avif,image/webp,*/*", SAFARI_13)),
+            (ImageFormat::Webp, Reason::Accept)
+        );
+        assert_eq!(
+            chose(request(None, None, "image/png,image/*;q=0.8", SAFARI_13)),
+            (ImageFormat::Jpeg, Reason::Fallback)
+        );
+        assert_eq!(
+            chose(request(None, None, "image/webp;q=0,*/*", CHROME)),
+            (ImageFormat::Webp, Reason::UserAgent)
+        );
+        assert_eq!(
+            chose(request(Some("jpg"), None, "image/webp", CHROME)),
+            (ImageFormat::Jpeg, Reason::Override)
+        );
+    }
+
+    #[test]
+    fn browsers() {
+        assert_eq!(browser(CHROME), Some((Browser::Chrome, 121)));
+        assert_eq!(browser(SAFARI_13), Some((Browser::Safari, 13)));
+        assert_eq!(
+            browser("Mozilla/5.0 (X11; Linux x86_64; rv:122.0) Gecko/20100101 Firefox/122.0"),
+            Some((Browser::Firefox, 122))
+        );
+        assert_eq!(browser("curl/8.5.0"), None);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::DateTime;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    api::Unversioned,
+    app::AppState,
+    auth::{Admin, ClientAddress, CurrentUser},
+};
+
+// deprecated API surface. Besides unprefixed paths, entries come from `[[deprecations]]` in the
+// config. Matching responses carry `Deprecation`, `Sunset` and `Link` headers, and who still uses
+// them is counted.
+fn builtin() -> Vec<Deprecation> {
+    vec![Deprecation {
+        surface: Surface::Unversioned,
+        since: 1_792_108_800,
+        sunset: None,
+        link: Some("/api-docs/openapi.json".to_owned()),
+    }]
+}
+
+// `[[deprecations]]` in the config: a route, or one of its fields when `field` is given
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    method: String,
+    // as the router matches it, e.g. "/api/v1/video/metadata"
+    path: String,
+    // a query parameter or top level JSON field
+    field: Option<String>,
+    // unix timestamps
+    since: i64,
+    sunset: Option<i64>,
+    // where to read about the replacement
+    link: Option<String>,
+}
+
+impl Settings {
+    fn deprecation(&self) -> Result<Deprecation, String> {
+        let method = Method::from_bytes(self.method.to_uppercase().as_bytes())
+            .map_err(|_| format!("deprecations: invalid method {:?}", self.method))?;
+        let path = self.path.clone();
+        let surface = match &self.field {
+            Some(field) => Surface::Field {
+                method,
+                path,
+                field: field.clone(),
+            },
+            None => Surface::Route { method, path },
+        };
+        Ok(Deprecation {
+            surface,
+            since: self.since,
+            sunset: self.sunset,
+            link: self.link.clone(),
+        })
+    }
+}
+
+pub struct Deprecation {
+    pub surface: Surface,
+    // unix timestamps
+    pub since: i64,
+    pub sunset: Option<i64>,
+    // where to read about the replacement
+    pub link: Option<String>,
+}
+
+pub enum Surface {
+    // a whole route, as registered with the router
+    Route {
+        method: Method,
+        path: String,
+    },
+    // a query parameter or top level JSON field of a route
+    Field {
+        method: Method,
+        path: String,
+        field: String,
+    },
+    // any route reached without its `/api/v<n>` prefix
+    Unversioned,
+}
+
+impl Surface {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        match self {
+            Surface::Route {
+                method: m, path: p, ..
+            }
+            | Surface::Field {
+                method: m, path: p, ..
+            } => m == method && p == path,
+            Surface::Unversioned => false,
+        }
+    }
+
+    fn field(&self) -> Option<&str> {
+        match self {
+            Surface::Field { field, .. } => Some(field),
+            _ => None,
+        }
+    }
+}
+
+// same as axum's default for JSON bodies
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+pub struct Deprecations {
+    entries: Vec<Deprecation>,
+    // (entry index, method, matched path, client) -> requests
+    usage: Mutex<HashMap<(usize, Method, String, String), u64>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeprecationUsage {
+    method: String,
+    path: String,
+    field: Option<String>,
+    sunset: Option<i64>,
+    client: String,
+    requests: u64,
+}
+
+impl Deprecations {
+    pub fn new(settings: &[Settings]) -> Result<Self, String> {
+        let mut entries = builtin();
+        for entry in settings {
+            entries.push(entry.deprecation()?);
+        }
+        Ok(Deprecations {
+            entries,
+            usage: Mutex::default(),
+        })
+    }
+
+    fn for_route<'a>(
+        &'a self,
+        method: &'a Method,
+        path: &'a str,
+    ) -> impl Iterator<Item = (usize, &'a Deprecation)> + 'a {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(move |(_, entry)| entry.surface.matches(method, path))
+    }
+
+    fn unversioned(&self) -> impl Iterator<Item = (usize, &Deprecation)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| matches!(entry.surface, Surface::Unversioned))
+    }
+
+    fn count(&self, hits: &[usize], method: &Method, path: &str, client: &str) {
+        let mut usage = self.usage.lock().unwrap();
+        for hit in hits {
+            let key = (*hit, method.clone(), path.to_owned(), client.to_owned());
+            *usage.entry(key).or_default() += 1;
+        }
+    }
+
+    pub fn usage(&self) -> Vec<DeprecationUsage> {
+        let usage = self.usage.lock().unwrap();
+        let mut report: Vec<_> = usage
+            .iter()
+            .map(|((index, method, path, client), requests)| {
+                let entry = &self.entries[*index];
+                DeprecationUsage {
+                    method: method.to_string(),
+                    path: path.clone(),
+                    field: entry.surface.field().map(str::to_owned),
+                    sunset: entry.sunset,
+                    client: client.clone(),
+                    requests: *requests,
+                }
+            })
+            .collect();
+        report.sort_by_key(|entry| std::cmp::Reverse(entry.requests));
+        report
+    }
+}
+
+// annotates responses of deprecated routes, or of requests using deprecated fields or paths
+pub async fn annotate(
+    State(deprecations): State<Arc<Deprecations>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = request.extensions().get::<MatchedPath>().cloned() else {
+        return next.run(request).await;
+    };
+    let method = request.method().clone();
+    let candidates: Vec<_> = deprecations.for_route(&method, path.as_str()).collect();
+
+    let (mut parts, body) = request.into_parts();
+    let mut used_fields: Vec<String> = parts
+        .uri
+        .query()
+        .map(|query| {
+            query
+                .split('&')
+                .map(|pair| pair.split('=').next().unwrap_or_default().to_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    let body = if candidates
+        .iter()
+        .any(|(_, entry)| entry.surface.field().is_some())
+        && is_json(&parts.headers)
+    {
+        // the body has to be buffered to look at it, and put back for the handler
+        let bytes = match to_bytes(body, BODY_LIMIT).await {
+            Ok(bytes) => bytes,
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        };
+        used_fields.extend(json_fields(&bytes));
+        Body::from(bytes)
+    } else {
+        body
+    };
+
+    let mut hits: Vec<_> = candidates
+        .into_iter()
+        .filter(|(_, entry)| match entry.surface.field() {
+            Some(field) => used_fields.iter().any(|used| used == field),
+            None => true,
+        })
+        .collect();
+    let client = client(&mut parts).await;
+    let successor = format!("</api/v1{}>; rel=\"successor-version\"", parts.uri.path());
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    let unversioned = response.extensions().get::<Unversioned>().is_some();
+    if unversioned {
+        hits.extend(deprecations.unversioned());
+    }
+    if hits.is_empty() {
+        return response;
+    }
+
+    let entries: Vec<_> = hits.iter().map(|(_, entry)| *entry).collect();
+    response.headers_mut().extend(headers(&entries));
+    if unversioned {
+        if let Ok(value) = HeaderValue::from_str(&successor) {
+            response.headers_mut().append(header::LINK, value);
+        }
+    }
+    let indexes: Vec<_> = hits.iter().map(|(index, _)| *index).collect();
+    deprecations.count(&indexes, &method, path.as_str(), &client);
+    response
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("application/json"))
+        .unwrap_or(false)
+}
+
+// top level keys of a JSON object
+fn json_fields(body: &[u8]) -> Vec<String> {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(object)) => object.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+// usage is tracked per user when known, per client address otherwise
+async fn client(parts: &mut Parts) -> String {
+    if let Ok(CurrentUser(user)) = CurrentUser::from_request_parts(parts, &()).await {
+        return format!("user:{}", user);
+    }
+    match ClientAddress::from_request_parts(parts, &()).await {
+        Ok(ClientAddress(address)) => format!("address:{}", address),
+        Err(never) => match never {},
+    }
+}
+
+// `Deprecation` as in RFC 9745 and `Sunset` as in RFC 8594, taking the earliest dates when
+// several entries apply
+fn headers(entries: &[&Deprecation]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(since) = entries.iter().map(|entry| entry.since).min() {
+        headers.insert(
+            "deprecation",
+            HeaderValue::from_str(&format!("@{}", since)).unwrap(),
+        );
+    }
+    if let Some(sunset) = entries.iter().filter_map(|entry| entry.sunset).min() {
+        if let Some(date) = DateTime::from_timestamp(sunset, 0) {
+            let date = date.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            headers.insert("sunset", HeaderValue::from_str(&date).unwrap());
+        }
+    }
+    for link in entries.iter().filter_map(|entry| entry.link.as_deref()) {
+        if let Ok(value) = HeaderValue::from_str(&format!("<{}>; rel=\"deprecation\"", link)) {
+            headers.append(header::LINK, value);
+        }
+    }
+    headers
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/deprecations", get(usage))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/deprecations",
+    responses((status = 200, description = "Who still uses deprecated API surface, heaviest users first", body = [DeprecationUsage])),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn usage(
+    _: Admin,
+    State(deprecations): State<Arc<Deprecations>>,
+) -> Json<Vec<DeprecationUsage>> {
+    Json(deprecations.usage())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TrustedProxy;
+
+    fn deprecations() -> Deprecations {
+        let settings: Vec<Settings> = serde_json::from_value(serde_json::json!([
+            {
+                "method": "GET",
+                "path": "/video/metadata",
+                "since": 1_706_745_600,
+                "sunset": 1_719_792_000,
+                "link": "https://example.com/changelog",
+            },
+            {"method": "get", "path": "/video/metadata", "field": "file", "since": 1_704_067_200},
+        ]))
+        .unwrap();
+        Deprecations::new(&settings).unwrap()
+    }
+
+    #[test]
+    fn earliest_dates_win() {
+        let deprecations = deprecations();
+        let headers = headers(&deprecations.entries[1..].iter().collect::<Vec<_>>());
+        assert_eq!(headers["deprecation"], "@1704067200");
+        assert_eq!(headers["sunset"], "Mon, 01 Jul 2024 00:00:00 GMT");
+        assert_eq!(
+            headers[header::LINK],
+            "<https://example.com/changelog>; rel=\"deprecation\""
+        );
+    }
+
+    #[test]
+    fn counts_per_client() {
+        let deprecations = deprecations();
+        assert_eq!(
+            deprecations
+                .for_route(&Method::GET, "/video/metadata")
+                .count(),
+            2
+        );
+        assert_eq!(
+            deprecations
+                .for_route(&Method::POST, "/video/metadata")
+                .count(),
+            0
+        );
+
+        assert_eq!(deprecations.unversioned().count(), 1);
+
+        let get = Method::GET;
+        deprecations.count(&[1, 2], &get, "/video/metadata", "user:1");
+        deprecations.count(&[1], &get, "/video/metadata", "user:1");
+        deprecations.count(&[1], &get, "/video/metadata", "address:192.0.2.1");
+        deprecations.count(&[0], &get, "/assets", "address:192.0.2.1");
+        let usage = deprecations.usage();
+        assert_eq!(usage.len(), 4);
+        assert_eq!((usage[0].client.as_str(), usage[0].requests), ("user:1", 2));
+        assert!(usage
+            .iter()
+            .any(|entry| entry.path == "/assets" && entry.field.is_none()));
+    }
+
+    #[tokio::test]
+    async fn clients_are_users_or_addresses() {
+        let parts = |proxied: bool| {
+            let (parts, _) = axum::http::Request::builder()
+                .header("x-user-id", "7")
+                .header("x-forwarded-for", "192.0.2.1")
+                .extension(TrustedProxy(proxied))
+                .body(())
+                .unwrap()
+                .into_parts();
+            parts
+        };
+        assert_eq!(client(&mut parts(true)).await, "user:7");
+        // the headers are only taken from a trusted proxy
+        assert_eq!(client(&mut parts(false)).await, "address:unknown");
+    }
+
+    #[test]
+    fn invalid_methods() {
+        let settings: Vec<Settings> = serde_json::from_value(serde_json::json!([
+            {"method": "GET PUT", "path": "/video/metadata", "since": 0},
+        ]))
+        .unwrap();
+        assert!(Deprecations::new(&settings).is_err());
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(json_fields(br#"{"file": "a.mp4"}"#), vec!["file"]);
+        assert!(json_fields(b"[1, 2]").is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::sse::{self, KeepAlive, Sse},
+    routing::get,
+    Router,
+};
+use serde_derive::Deserialize;
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tracing::warn;
+use utoipa::IntoParams;
+
+use crate::{
+    api::{v1, ToVersion},
+    app::AppState,
+    auth::CurrentUser,
+    bus::Bus,
+    job::Jobs,
+    task,
+};
+
+// how many events a slow subscriber may fall behind before it misses some
+const BUFFER: usize = 256;
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Topic {
+    UserCreated,
+    JobFinished,
+    ConfigReloaded,
+}
+
+impl Topic {
+    pub fn name(self) -> &'static str {
+        match self {
+            Topic::UserCreated => "user_created",
+            Topic::JobFinished => "job_finished",
+            Topic::ConfigReloaded => "config_reloaded",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Topic> {
+        [
+            Topic::UserCreated,
+            Topic::JobFinished,
+            Topic::ConfigReloaded,
+        ]
+        .into_iter()
+        .find(|topic| topic.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub topic: Topic,
+    // only delivered to this user when set
+    pub user_id: Option<i64>,
+    pub data: serde_json::Value,
+}
+
+// application events, pushed to `/events` subscribers
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(BUFFER).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, topic: Topic, user_id: Option<i64>, data: impl serde::Serialize) {
+        let data = serde_json::to_value(data).unwrap_or_default();
+        // nobody listening is fine
+        let _ = self.sender.send(Event {
+            topic,
+            user_id,
+            data,
+        });
+    }
+
+    // publishes finished and failed jobs to their owners
+    pub fn forward_jobs(self: &Arc<Self>, jobs: &Jobs) {
+        let events = self.clone();
+        let mut updates = jobs.subscribe();
+        task::spawn("job event forwarding", task::Kind::Background, async move {
+            loop {
+                match updates.recv().await {
+                    Ok(job) if job.state.is_done() => {
+                        let user = job.user_id;
+                        let job: v1::Job = job.to_version();
+                        events.publish(Topic::JobFinished, Some(user), job);
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    // publishes the events of this instance to `bus` too; finished jobs are left to the instance
+    // that ran them, see `Jobs::with_bus`, since every instance hears of them
+    pub fn forward_to(&self, bus: Arc<Bus>) {
+        let mut events = self.sender.subscribe();
+        task::spawn("bus forwarding", task::Kind::Background, async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.topic != Topic::JobFinished => {
+                        bus.publish(event.topic, &event.data);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("missed {} events for the bus", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct Subscribe {
+    // comma separated topics to receive, all of them by default
+    topics: Option<String>,
+}
+
+// parses `topics`, all topics when missing
+fn topics(query: Option<&str>) -> Result<Vec<Topic>, String> {
+    match query {
+        None => Ok(vec![
+            Topic::UserCreated,
+            Topic::JobFinished,
+            Topic::ConfigReloaded,
+        ]),
+        Some(query) => query
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Topic::parse(name).ok_or_else(|| format!("unknown topic {}", name)))
+            .collect(),
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/events", get(subscribe))
+}
+
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(Subscribe),
+    responses(
+        (status = 200, description = "Server-sent events named after their topic, with JSON data", content_type = "text/event-stream"),
+        (status = 400, description = "Unknown topic"),
+    ),
+    security(("user_id" = [])),
+    tag = "events"
+)]
+async fn subscribe(
+    State(events): State<Arc<Events>>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<Subscribe>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, (StatusCode, String)> {
+    let topics = topics(query.topics.as_deref()).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let stream = BroadcastStream::new(events.sender.subscribe()).filter_map(move |event| {
+        // events missed by falling behind are dropped
+        let event = event.ok()?;
+        if !topics.contains(&event.topic) || event.user_id.is_some_and(|id| id != user) {
+            return None;
+        }
+        Some(Ok(sse::Event::default()
+            .event(event.topic.name())
+            .data(event.data.to_string())))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_filter() {
+        assert_eq!(topics(None).unwrap().len(), 3);
+        assert_eq!(
+            topics(Some("job_finished, config_reloaded")).unwrap(),
+            vec![Topic::JobFinished, Topic::ConfigReloaded]
+        );
+        assert!(topics(Some("job_started")).is_err());
+    }
+}
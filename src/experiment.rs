@@ -0,0 +1,285 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use serde_derive::Deserialize;
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::{
+    api::{v1, ToVersion},
+    app::AppState,
+    auth::CurrentUser,
+    instance::fnv1a,
+    task,
+};
+
+// exposures remembered as logged, to skip writing them again; forgotten all at once past that
+const LOGGED_CACHE: usize = 100_000;
+
+// `[experiments.<name>]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    // changing it reshuffles users, the experiment's name by default
+    salt: Option<String>,
+    variants: Vec<String>,
+    // relative share of users per variant, an even split by default
+    #[serde(default)]
+    weights: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub experiment: String,
+    pub variant: String,
+}
+
+struct Experiment {
+    salt: String,
+    // variant and the cumulative weight up to and including it
+    variants: Vec<(String, u64)>,
+}
+
+impl Experiment {
+    fn variant(&self, user: i64) -> &str {
+        let total = self.variants.last().map_or(1, |(_, upto)| *upto);
+        let mut key = self.salt.as_bytes().to_vec();
+        key.extend_from_slice(&user.to_le_bytes());
+        let point = fnv1a(&key) % total;
+        self.variants
+            .iter()
+            .find(|(_, upto)| point < *upto)
+            .map_or("", |(variant, _)| variant)
+    }
+}
+
+// Puts every user in one variant of each experiment, the same one every time: the variant
+// follows from a hash of the experiment's salt and the user id, nothing is stored. The first
+// time a user is shown a variant is logged to `experiment_exposures` for analysis.
+pub struct Experiments {
+    experiments: BTreeMap<String, Experiment>,
+    // where exposures are logged, nowhere without
+    pool: Option<PgPool>,
+    logged: Mutex<HashSet<(String, String, i64)>>,
+}
+
+impl Experiments {
+    pub fn new(
+        configured: HashMap<String, Settings>,
+        pool: Option<PgPool>,
+    ) -> Result<Self, String> {
+        let mut experiments = BTreeMap::new();
+        for (name, settings) in configured {
+            if settings.variants.is_empty() {
+                return Err(format!("experiment {} has no variants", name));
+            }
+            let weights = if settings.weights.is_empty() {
+                vec![1; settings.variants.len()]
+            } else if settings.weights.len() == settings.variants.len() {
+                settings.weights
+            } else {
+                return Err(format!(
+                    "experiment {} has {} variants but {} weights",
+                    name,
+                    settings.variants.len(),
+                    settings.weights.len()
+                ));
+            };
+            if weights.iter().all(|weight| *weight == 0) {
+                return Err(format!("experiment {} has no weight", name));
+            }
+            let mut upto = 0;
+            let variants = settings
+                .variants
+                .into_iter()
+                .zip(weights)
+                .map(|(variant, weight)| {
+                    upto += u64::from(weight);
+                    (variant, upto)
+                })
+                .collect();
+            let experiment = Experiment {
+                salt: settings.salt.unwrap_or_else(|| name.clone()),
+                variants,
+            };
+            experiments.insert(name, experiment);
+        }
+        Ok(Experiments {
+            experiments,
+            pool,
+            logged: Mutex::default(),
+        })
+    }
+
+    fn assign(&self, experiment: &str, user: i64) -> Option<Assignment> {
+        let variant = self.experiments.get(experiment)?.variant(user);
+        Some(Assignment {
+            experiment: experiment.to_owned(),
+            variant: variant.to_owned(),
+        })
+    }
+
+    // logs in the background that `user` saw `assignment`, once
+    fn expose(&self, assignment: &Assignment, user: i64) {
+        let Some(pool) = self.pool.clone() else {
+            return;
+        };
+        let key = (
+            assignment.experiment.clone(),
+            assignment.variant.clone(),
+            user,
+        );
+        {
+            let mut logged = self.logged.lock().unwrap();
+            if logged.len() >= LOGGED_CACHE {
+                logged.clear();
+            }
+            if !logged.insert(key.clone()) {
+                return;
+            }
+        }
+        task::spawn("experiment exposure log", task::Kind::Work, async move {
+            let (experiment, variant, user) = key;
+            let result = sqlx::query(
+                "INSERT INTO experiment_exposures (experiment, variant, user_id)
+                    VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            )
+            .bind(&experiment)
+            .bind(&variant)
+            .bind(user)
+            .execute(&pool)
+            .await;
+            if let Err(err) = result {
+                warn!("logging exposure to {} failed: {}", experiment, err);
+            }
+        });
+    }
+}
+
+// The calling user's experiment variants, for handlers that behave differently per variant.
+// Asking for a variant counts as showing it to the user.
+pub struct Assignments {
+    user: i64,
+    experiments: Arc<Experiments>,
+}
+
+impl Assignments {
+    // the user's variant of `experiment`, None for experiments that aren't configured
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn variant(&self, experiment: &str) -> Option<String> {
+        let assignment = self.experiments.assign(experiment, self.user)?;
+        self.experiments.expose(&assignment, self.user);
+        Some(assignment.variant)
+    }
+
+    pub fn all(&self) -> Vec<Assignment> {
+        self.experiments
+            .experiments
+            .keys()
+            .filter_map(|experiment| self.experiments.assign(experiment, self.user))
+            .inspect(|assignment| self.experiments.expose(assignment, self.user))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Assignments
+where
+    S: Send + Sync,
+    Arc<Experiments>: FromRef<S>,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let CurrentUser(user) = CurrentUser::from_request_parts(parts, state).await?;
+        let experiments = Arc::<Experiments>::from_ref(state);
+        Ok(Assignments { user, experiments })
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/me/experiments", get(my_experiments))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/experiments",
+    responses((status = 200, description = "The caller's variant of every experiment, each counting as shown", body = [v1::ExperimentAssignment])),
+    security(("user_id" = [])),
+    tag = "experiments"
+)]
+async fn my_experiments(assignments: Assignments) -> Json<Vec<v1::ExperimentAssignment>> {
+    Json(assignments.all().to_version())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(variants: &[&str], weights: &[u32]) -> Settings {
+        Settings {
+            salt: None,
+            variants: variants.iter().map(|variant| variant.to_string()).collect(),
+            weights: weights.to_vec(),
+        }
+    }
+
+    #[test]
+    fn assigns_by_weight() {
+        let experiments = Arc::new(
+            Experiments::new(
+                HashMap::from([
+                    ("player".to_owned(), settings(&["control", "new"], &[3, 1])),
+                    ("search".to_owned(), settings(&["a", "b"], &[])),
+                ]),
+                None,
+            )
+            .unwrap(),
+        );
+        let new = (0..4000)
+            .filter(|user| {
+                let assignments = Assignments {
+                    user: *user,
+                    experiments: experiments.clone(),
+                };
+                assignments.variant("player").as_deref() == Some("new")
+            })
+            .count();
+        assert!((800..1200).contains(&new), "{}", new);
+
+        let assignments = Assignments {
+            user: 42,
+            experiments: experiments.clone(),
+        };
+        let all = assignments.all();
+        assert_eq!(
+            all.iter()
+                .map(|assignment| assignment.experiment.as_str())
+                .collect::<Vec<_>>(),
+            ["player", "search"]
+        );
+        // the same every time
+        assert_eq!(all, assignments.all());
+        assert_eq!(assignments.variant("unknown"), None);
+    }
+
+    #[test]
+    fn bad_settings() {
+        for settings in [
+            settings(&[], &[]),
+            settings(&["a", "b"], &[1]),
+            settings(&["a"], &[0]),
+        ] {
+            let configured = HashMap::from([("x".to_owned(), settings)]);
+            assert!(Experiments::new(configured, None).is_err());
+        }
+    }
+}
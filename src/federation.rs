@@ -0,0 +1,818 @@
+// Federation: rsapp deployments configured as each other's peers share playlists, so one site's
+// users browse and play another's library. Each side lists in `[[federation.peers]]` the other's
+// URL, a secret both know, and which of its own playlists the peer may see.
+//
+// Peers talk through `/federation/v1`, where every request is signed with the shared secret:
+// `X-Federation-Peer` names the caller, `X-Federation-Timestamp` is the unix time and
+// `X-Federation-Signature` the hex HMAC-SHA256 of "{method}\n{path and query}\n{timestamp}".
+// Only assets in the playlists shared with the caller can be seen there. Users reach peers
+// through `/api/v1/federation`, which proxies media as it's played or replicates it into a local
+// asset.
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRef, FromRequestParts, Path as UrlPath, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    api::{v1, ToVersion},
+    app::AppState,
+    asset::{self, Asset},
+    auth::CurrentUser,
+    correlation::Correlation,
+    db_error, internal_error, leak,
+    probe::Probes,
+    repository::MediaRepository,
+};
+
+const PEER: &str = "x-federation-peer";
+const TIMESTAMP: &str = "x-federation-timestamp";
+const SIGNATURE: &str = "x-federation-signature";
+// requests signed longer ago than this, or this far ahead, are refused as replays
+const MAX_SKEW: i64 = 300;
+// shorter secrets are refused by the config check
+const MIN_SECRET: usize = 16;
+// for answers of peers but media, which takes as long as it takes
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+// `[federation]` in the config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+    // how this deployment names itself to its peers, which know it by that name; federation is
+    // off without one
+    pub name: Option<String>,
+    // where replicated media files go, a directory per peer
+    #[serde(default = "default_replica_dir")]
+    pub replica_dir: PathBuf,
+    #[serde(default)]
+    pub peers: Vec<PeerSettings>,
+}
+
+fn default_replica_dir() -> PathBuf {
+    PathBuf::from("data/federated")
+}
+
+// `[[federation.peers]]`
+#[derive(Deserialize, Debug, Clone)]
+pub struct PeerSettings {
+    pub name: String,
+    // e.g. `https://berlin.example.com`, where the peer serves `/federation/v1`
+    pub url: String,
+    // the same on both sides
+    pub secret: String,
+    // playlists of this deployment the peer may see
+    #[serde(default)]
+    pub shared_playlists: Vec<i64>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_none() && !self.peers.is_empty() {
+            return Err("federation.peers need federation.name to sign requests".to_owned());
+        }
+        let mut names = HashSet::new();
+        for peer in &self.peers {
+            if !names.insert(peer.name.as_str()) {
+                return Err(format!("federation peer {} is there twice", peer.name));
+            }
+            reqwest::Url::parse(&peer.url)
+                .map_err(|err| format!("federation peer {}: invalid url: {}", peer.name, err))?;
+            if peer.secret.len() < MIN_SECRET {
+                return Err(format!(
+                    "federation peer {}: the secret needs at least {} characters",
+                    peer.name, MIN_SECRET
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Federation {
+    name: Option<String>,
+    replica_dir: PathBuf,
+    peers: Vec<PeerSettings>,
+    client: reqwest::Client,
+}
+
+impl Federation {
+    pub fn new(settings: &Settings) -> Self {
+        Federation {
+            name: settings.name.clone(),
+            replica_dir: settings.replica_dir.clone(),
+            peers: settings.peers.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn peer(&self, name: &str) -> Result<&PeerSettings, (StatusCode, String)> {
+        self.peers
+            .iter()
+            .find(|peer| peer.name == name)
+            .ok_or((StatusCode::NOT_FOUND, format!("no peer {}", name)))
+    }
+
+    // GETs `path` from `peer`, signed, asking for `range` of it when set, on behalf of the request
+    // `correlation` is of
+    async fn get(
+        &self,
+        peer: &str,
+        path: &str,
+        range: Option<&str>,
+        correlation: &Correlation,
+    ) -> Result<reqwest::Response, (StatusCode, String)> {
+        let name = self
+            .name
+            .as_deref()
+            .ok_or((StatusCode::NOT_FOUND, "federation is disabled".to_owned()))?;
+        let peer = self.peer(peer)?;
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(&peer.secret, "GET", path, timestamp);
+        let request = self
+            .client
+            .get(format!("{}{}", peer.url.trim_end_matches('/'), path));
+        let mut request = correlation
+            .apply(request)
+            .header(PEER, name)
+            .header(TIMESTAMP, timestamp.to_string())
+            .header(SIGNATURE, hex::encode(signature));
+        if let Some(range) = range {
+            request = request.header("range", range);
+        }
+        let response = request.send().await.map_err(|err| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("peer {} is unreachable: {}", peer.name, err),
+            )
+        })?;
+        let status = response.status().as_u16();
+        if (200..300).contains(&status) {
+            return Ok(response);
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(match status {
+            404 => (StatusCode::NOT_FOUND, text),
+            _ => (
+                StatusCode::BAD_GATEWAY,
+                format!("peer {} answered {}: {}", peer.name, status, text),
+            ),
+        })
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        peer: &str,
+        path: &str,
+        correlation: &Correlation,
+    ) -> Result<T, (StatusCode, String)> {
+        let response = tokio::time::timeout(PEER_TIMEOUT, async {
+            self.get(peer, path, None, correlation)
+                .await?
+                .json()
+                .await
+                .map_err(|err| {
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        format!("peer {} answered nonsense: {}", peer, err),
+                    )
+                })
+        });
+        response.await.map_err(|_| {
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("peer {} didn't answer within {:?}", peer, PEER_TIMEOUT),
+            )
+        })?
+    }
+}
+
+fn sign(secret: &str, method: &str, path: &str, timestamp: i64) -> Vec<u8> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}\n{}", method, path, timestamp).as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// whether `signature` is that of the request by `secret`, and recent
+fn verify(secret: &str, method: &str, path: &str, timestamp: i64, signature: &[u8]) -> bool {
+    // the timestamp is the sender's, any i64 at all
+    if Utc::now().timestamp().abs_diff(timestamp) > MAX_SKEW as u64 {
+        return false;
+    }
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}\n{}", method, path, timestamp).as_bytes());
+    mac.verify_slice(signature).is_ok()
+}
+
+// the peer a `/federation/v1` request is signed by
+pub struct Peer(PeerSettings);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Peer
+where
+    S: Send + Sync,
+    Arc<Federation>: FromRef<S>,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let federation = Arc::<Federation>::from_ref(state);
+        if federation.name.is_none() {
+            return Err((StatusCode::NOT_FOUND, "federation is disabled".to_owned()));
+        }
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let unsigned = || (StatusCode::UNAUTHORIZED, "invalid signature".to_owned());
+        let peer = header(PEER).ok_or_else(unsigned)?;
+        let peer = federation.peer(peer).map_err(|_| unsigned())?;
+        let timestamp = header(TIMESTAMP)
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or_else(unsigned)?;
+        let signature = header(SIGNATURE)
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or_else(unsigned)?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or_else(|| parts.uri.path(), |path| path.as_str());
+        let method = parts.method.as_str();
+        if !verify(&peer.secret, method, path, timestamp, &signature) {
+            warn!("refused a federation request signed as {}", peer.name);
+            return Err(unsigned());
+        }
+        Ok(Peer(peer.clone()))
+    }
+}
+
+// a playlist shared with a peer
+#[derive(Serialize, Deserialize, ToSchema, sqlx::FromRow, Debug, PartialEq)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub items: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub struct CollectionDetail {
+    pub id: i64,
+    pub name: String,
+    pub items: Vec<CollectionItem>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, sqlx::FromRow, Debug, PartialEq)]
+pub struct CollectionItem {
+    pub asset_id: i64,
+    pub title: String,
+    // fractional index, items are in this order
+    pub position: String,
+}
+
+// an asset in a shared playlist, as peers see it: the file name but not where it is
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub struct SharedAsset {
+    pub id: i64,
+    pub title: String,
+    pub file_name: String,
+    pub metadata: v1::MediaMetadata,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct PeerInfo {
+    pub name: String,
+    pub url: String,
+}
+
+// `/federation/v1`, for peers
+pub fn peer_routes() -> Router<AppState> {
+    Router::new()
+        .route("/federation/v1/collections", get(shared_collections))
+        .route("/federation/v1/collections/:id", get(shared_collection))
+        .route("/federation/v1/assets/:id", get(shared_asset))
+        .route("/federation/v1/assets/:id/media", get(shared_media))
+}
+
+// for users, under `/api/v1`
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/federation/peers", get(list_peers))
+        .route("/federation/peers/:peer/collections", get(peer_collections))
+        .route(
+            "/federation/peers/:peer/collections/:id",
+            get(peer_collection),
+        )
+        .route("/federation/peers/:peer/assets/:id", get(peer_asset))
+        .route("/federation/peers/:peer/assets/:id/media", get(peer_media))
+        .route(
+            "/federation/peers/:peer/assets/:id/replicate",
+            post(replicate),
+        )
+}
+
+// assets the peer may see are those in the playlists shared with it
+async fn shared(
+    pool: &PgPool,
+    peer: &PeerSettings,
+    asset_id: i64,
+) -> Result<(), (StatusCode, String)> {
+    let shared: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM playlist_items WHERE asset_id = $1 AND playlist_id = ANY($2))",
+    )
+    .bind(asset_id)
+    .bind(&peer.shared_playlists)
+    .fetch_one(pool)
+    .await
+    .map_err(db_error)?;
+    if !shared {
+        return Err((StatusCode::NOT_FOUND, "not found".to_owned()));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/federation/v1/collections",
+    responses(
+        (status = 200, description = "Playlists shared with the calling peer", body = [Collection]),
+        (status = 401, description = "Unknown peer, or an invalid or stale signature"),
+    ),
+    tag = "federation"
+)]
+async fn shared_collections(
+    Peer(peer): Peer,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<Collection>>, (StatusCode, String)> {
+    let collections = sqlx::query_as::<_, Collection>(
+        "SELECT p.id, p.name, count(i.id) AS items, p.updated_at FROM playlists p
+            LEFT JOIN playlist_items i ON i.playlist_id = p.id
+            WHERE p.id = ANY($1) GROUP BY p.id ORDER BY p.id",
+    )
+    .bind(&peer.shared_playlists)
+    .fetch_all(&pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(collections))
+}
+
+#[utoipa::path(
+    get,
+    path = "/federation/v1/collections/{id}",
+    params(("id" = i64, Path, description = "Playlist id")),
+    responses(
+        (status = 200, description = "The playlist and its items, in order", body = CollectionDetail),
+        (status = 401, description = "Unknown peer, or an invalid or stale signature"),
+        (status = 404, description = "No such playlist shared with the peer"),
+    ),
+    tag = "federation"
+)]
+async fn shared_collection(
+    Peer(peer): Peer,
+    State(pool): State<PgPool>,
+    UrlPath(id): UrlPath<i64>,
+) -> Result<Json<CollectionDetail>, (StatusCode, String)> {
+    if !peer.shared_playlists.contains(&id) {
+        return Err((StatusCode::NOT_FOUND, "not found".to_owned()));
+    }
+    let name: String = sqlx::query_scalar("SELECT name FROM playlists WHERE id = $1")
+        .bind(id)
+        .fetch_one(&pool)
+        .await
+        .map_err(db_error)?;
+    let items = sqlx::query_as::<_, CollectionItem>(
+        "SELECT i.asset_id, a.title, i.position FROM playlist_items i
+            JOIN assets a ON a.id = i.asset_id
+            WHERE i.playlist_id = $1 ORDER BY i.position",
+    )
+    .bind(id)
+    .fetch_all(&pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(CollectionDetail { id, name, items }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/federation/v1/assets/{id}",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses(
+        (status = 200, description = "The asset and its metadata", body = SharedAsset),
+        (status = 401, description = "Unknown peer, or an invalid or stale signature"),
+        (status = 404, description = "No such asset shared with the peer"),
+        (status = 422, description = "The media file can't be read"),
+    ),
+    tag = "federation"
+)]
+async fn shared_asset(
+    Peer(peer): Peer,
+    State(pool): State<PgPool>,
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    UrlPath(id): UrlPath<i64>,
+) -> Result<Json<SharedAsset>, (StatusCode, String)> {
+    shared(&pool, &peer, id).await?;
+    let asset = asset::find(&pool, id).await.map_err(db_error)?;
+    let file = media.find(id).await.map_err(db_error)?;
+    let metadata = asset::metadata(&*media, &probes, file, false).await?;
+    Ok(Json(SharedAsset {
+        id,
+        file_name: file_name(&asset.path),
+        title: asset.title,
+        metadata: (&metadata).to_version(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/federation/v1/assets/{id}/media",
+    params(("id" = i64, Path, description = "Asset id")),
+    responses(
+        (status = 200, description = "The media file, supporting range requests"),
+        (status = 401, description = "Unknown peer, or an invalid or stale signature"),
+        (status = 404, description = "No such asset shared with the peer"),
+    ),
+    tag = "federation"
+)]
+async fn shared_media(
+    Peer(peer): Peer,
+    State(pool): State<PgPool>,
+    UrlPath(id): UrlPath<i64>,
+    request: Request,
+) -> Result<Response, (StatusCode, String)> {
+    shared(&pool, &peer, id).await?;
+    let asset = asset::find(&pool, id).await.map_err(db_error)?;
+    match ServeFile::new(asset.path).oneshot(request).await {
+        Ok(response) => Ok(response.into_response()),
+        Err(never) => match never {},
+    }
+}
+
+// the last component of `path`, which is all of it that peers see
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/federation/peers",
+    responses((status = 200, description = "Deployments sharing playlists with this one", body = [PeerInfo])),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn list_peers(
+    _: CurrentUser,
+    State(federation): State<Arc<Federation>>,
+) -> Json<Vec<PeerInfo>> {
+    let peers = federation.peers.iter().map(|peer| PeerInfo {
+        name: peer.name.clone(),
+        url: peer.url.clone(),
+    });
+    Json(peers.collect())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/federation/peers/{peer}/collections",
+    params(("peer" = String, Path, description = "Peer name")),
+    responses(
+        (status = 200, description = "Playlists the peer shares with this deployment", body = [Collection]),
+        (status = 404, description = "No such peer"),
+        (status = 502, description = "The peer failed or can't be reached"),
+    ),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn peer_collections(
+    _: CurrentUser,
+    correlation: Correlation,
+    State(federation): State<Arc<Federation>>,
+    UrlPath(peer): UrlPath<String>,
+) -> Result<Json<Vec<Collection>>, (StatusCode, String)> {
+    let path = "/federation/v1/collections";
+    federation
+        .get_json(&peer, path, &correlation)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/federation/peers/{peer}/collections/{id}",
+    params(
+        ("peer" = String, Path, description = "Peer name"),
+        ("id" = i64, Path, description = "Playlist id at the peer"),
+    ),
+    responses(
+        (status = 200, description = "The playlist and its items, in order", body = CollectionDetail),
+        (status = 404, description = "No such peer, or playlist shared by it"),
+        (status = 502, description = "The peer failed or can't be reached"),
+    ),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn peer_collection(
+    _: CurrentUser,
+    correlation: Correlation,
+    State(federation): State<Arc<Federation>>,
+    UrlPath((peer, id)): UrlPath<(String, i64)>,
+) -> Result<Json<CollectionDetail>, (StatusCode, String)> {
+    let path = format!("/federation/v1/collections/{}", id);
+    federation
+        .get_json(&peer, &path, &correlation)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/federation/peers/{peer}/assets/{id}",
+    params(
+        ("peer" = String, Path, description = "Peer name"),
+        ("id" = i64, Path, description = "Asset id at the peer"),
+    ),
+    responses(
+        (status = 200, description = "The asset and its metadata", body = SharedAsset),
+        (status = 404, description = "No such peer, or asset shared by it"),
+        (status = 502, description = "The peer failed or can't be reached"),
+    ),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn peer_asset(
+    _: CurrentUser,
+    correlation: Correlation,
+    State(federation): State<Arc<Federation>>,
+    UrlPath((peer, id)): UrlPath<(String, i64)>,
+) -> Result<Json<SharedAsset>, (StatusCode, String)> {
+    let path = format!("/federation/v1/assets/{}", id);
+    federation
+        .get_json(&peer, &path, &correlation)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/federation/peers/{peer}/assets/{id}/media",
+    params(
+        ("peer" = String, Path, description = "Peer name"),
+        ("id" = i64, Path, description = "Asset id at the peer"),
+    ),
+    responses(
+        (status = 200, description = "The media file streamed from the peer, supporting range requests"),
+        (status = 404, description = "No such peer, or asset shared by it"),
+        (status = 502, description = "The peer failed or can't be reached"),
+    ),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn peer_media(
+    _: CurrentUser,
+    correlation: Correlation,
+    State(federation): State<Arc<Federation>>,
+    UrlPath((peer, id)): UrlPath<(String, i64)>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok());
+    let path = format!("/federation/v1/assets/{}/media", id);
+    let remote = federation.get(&peer, &path, range, &correlation).await?;
+    let status = StatusCode::from_u16(remote.status().as_u16()).map_err(internal_error)?;
+    let mut response = Response::builder().status(status);
+    for name in [
+        "content-type",
+        "content-length",
+        "content-range",
+        "accept-ranges",
+    ] {
+        if let Some(value) = remote.headers().get(name) {
+            if let Ok(value) = HeaderValue::from_bytes(value.as_bytes()) {
+                response = response.header(name, value);
+            }
+        }
+    }
+    response
+        .body(Body::from_stream(remote.bytes_stream()))
+        .map_err(internal_error)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/federation/peers/{peer}/assets/{id}/replicate",
+    params(
+        ("peer" = String, Path, description = "Peer name"),
+        ("id" = i64, Path, description = "Asset id at the peer"),
+    ),
+    responses(
+        (status = 201, description = "The media file was copied and registered as a local asset", body = v1::Asset),
+        (status = 200, description = "It was replicated before, the local asset", body = v1::Asset),
+        (status = 404, description = "No such peer, or asset shared by it"),
+        (status = 502, description = "The peer failed or can't be reached"),
+    ),
+    security(("user_id" = [])),
+    tag = "federation"
+)]
+async fn replicate(
+    _: CurrentUser,
+    correlation: Correlation,
+    State(pool): State<PgPool>,
+    State(federation): State<Arc<Federation>>,
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    UrlPath((peer, id)): UrlPath<(String, i64)>,
+) -> Result<(StatusCode, Json<v1::Asset>), (StatusCode, String)> {
+    let remote: SharedAsset = federation
+        .get_json(
+            &peer,
+            &format!("/federation/v1/assets/{}", id),
+            &correlation,
+        )
+        .await?;
+    let target = federation.replica_dir.join(file_name(&peer)).join(format!(
+        "{}-{}",
+        id,
+        file_name(&remote.file_name)
+    ));
+    let path = target.to_string_lossy().into_owned();
+    let existing = sqlx::query_as::<_, Asset>(
+        "SELECT id, path, title, created_at FROM assets WHERE path = $1",
+    )
+    .bind(&path)
+    .fetch_optional(&pool)
+    .await
+    .map_err(db_error)?;
+    if let Some(asset) = existing {
+        return Ok((StatusCode::OK, Json(asset.to_version())));
+    }
+
+    let media_path = format!("/federation/v1/assets/{}/media", id);
+    let response = federation
+        .get(&peer, &media_path, None, &correlation)
+        .await?;
+    download(response, &target).await.map_err(|err| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("replicating failed: {}", err),
+        )
+    })?;
+    let asset = sqlx::query_as::<_, Asset>(
+        "INSERT INTO assets (path, title) VALUES ($1, $2) RETURNING id, path, title, created_at",
+    )
+    .bind(&path)
+    .bind(&remote.title)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+    info!("asset {} of peer {} replicated as {}", id, peer, asset.id);
+
+    let file = media.find(asset.id).await.map_err(db_error)?;
+    if let Err((_, err)) = asset::metadata(&*media, &probes, file, false).await {
+        warn!("probing asset {} failed: {}", asset.id, err);
+    }
+    Ok((StatusCode::CREATED, Json(asset.to_version())))
+}
+
+// writes the body of `response` to `target`, which only appears once it's complete
+async fn download(response: reqwest::Response, target: &Path) -> Result<(), String> {
+    if let Some(dir) = target.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    let partial = target.with_extension("part");
+    let _file = leak::hold(
+        leak::Kind::TempFile,
+        format!("the replica {}", partial.display()),
+    );
+    let written = async {
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            file.write_all(&chunk.map_err(std::io::Error::other)?)
+                .await?;
+        }
+        file.flush().await?;
+        tokio::fs::rename(&partial, target).await
+    };
+    if let Err(err) = written.await {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(err.to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs() {
+        let secret = "a shared secret, long enough";
+        let now = Utc::now().timestamp();
+        let signature = sign(secret, "GET", "/federation/v1/collections", now);
+        assert!(verify(
+            secret,
+            "GET",
+            "/federation/v1/collections",
+            now,
+            &signature
+        ));
+        assert!(!verify(
+            secret,
+            "GET",
+            "/federation/v1/collections/2",
+            now,
+            &signature
+        ));
+        assert!(!verify(
+            "another secret",
+            "GET",
+            "/federation/v1/collections",
+            now,
+            &signature
+        ));
+        let stale = now - MAX_SKEW - 1;
+        let signature = sign(secret, "GET", "/federation/v1/collections", stale);
+        assert!(!verify(
+            secret,
+            "GET",
+            "/federation/v1/collections",
+            stale,
+            &signature
+        ));
+        for extreme in [i64::MIN, i64::MAX] {
+            let signature = sign(secret, "GET", "/federation/v1/collections", extreme);
+            assert!(!verify(
+                secret,
+                "GET",
+                "/federation/v1/collections",
+                extreme,
+                &signature
+            ));
+        }
+    }
+
+    #[test]
+    fn validates() {
+        let peer = PeerSettings {
+            name: "berlin".to_owned(),
+            url: "https://berlin.example.com".to_owned(),
+            secret: "a shared secret, long enough".to_owned(),
+            shared_playlists: vec![1],
+        };
+        let mut settings = Settings {
+            name: Some("paris".to_owned()),
+            peers: vec![peer.clone()],
+            ..Settings::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        settings.peers.push(peer.clone());
+        assert!(settings.validate().unwrap_err().contains("twice"));
+        settings.peers = vec![PeerSettings {
+            secret: "short".to_owned(),
+            ..peer
+        }];
+        assert!(settings.validate().unwrap_err().contains("at least 16"));
+        settings.name = None;
+        assert!(settings.validate().unwrap_err().contains("federation.name"));
+    }
+
+    #[test]
+    fn hides_paths() {
+        assert_eq!(file_name("/srv/media/talk.mp4"), "talk.mp4");
+        assert_eq!(file_name("../../etc"), "etc");
+    }
+}
@@ -0,0 +1,283 @@
+// Order keys that always have room for another key in between, so moving an item only
+// rewrites that item's key. Port of https://observablehq.com/@dgreenspan/implementing-fractional-indexing
+//
+// A key is an integer part followed by an optional fraction. The head character of the integer
+// part encodes its length: `a`..`z` for positive integers of 2..27 chars, `Z`..`A` for negative
+// ones, so appending at either end keeps keys short.
+
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const INTEGER_ZERO: &str = "a0";
+const SMALLEST_INTEGER: &str = "A00000000000000000000000000";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    InvalidKey(String),
+    // `a` is not strictly before `b`
+    OutOfOrder,
+    // ran out of integer space, practically unreachable
+    Exhausted,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidKey(key) => write!(f, "invalid order key: {}", key),
+            Error::OutOfOrder => write!(f, "order keys out of order"),
+            Error::Exhausted => write!(f, "order keys exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns a key that sorts strictly between `a` and `b`; `None` means the start or end of the list.
+pub fn key_between(a: Option<&str>, b: Option<&str>) -> std::result::Result<String, Error> {
+    if let Some(a) = a {
+        validate_key(a)?;
+    }
+    if let Some(b) = b {
+        validate_key(b)?;
+    }
+    match (a, b) {
+        (None, None) => Ok(INTEGER_ZERO.to_owned()),
+        (None, Some(b)) => {
+            let ib = integer_part(b)?;
+            let fb = &b[ib.len()..];
+            if ib == SMALLEST_INTEGER {
+                return Ok(format!("{}{}", ib, midpoint("", Some(fb))));
+            }
+            if ib.len() < b.len() {
+                return Ok(ib.to_owned());
+            }
+            decrement_integer(ib)?.ok_or(Error::Exhausted)
+        }
+        (Some(a), None) => {
+            let ia = integer_part(a)?;
+            let fa = &a[ia.len()..];
+            match increment_integer(ia)? {
+                Some(i) => Ok(i),
+                None => Ok(format!("{}{}", ia, midpoint(fa, None))),
+            }
+        }
+        (Some(a), Some(b)) => {
+            if a >= b {
+                return Err(Error::OutOfOrder);
+            }
+            let ia = integer_part(a)?;
+            let fa = &a[ia.len()..];
+            let ib = integer_part(b)?;
+            let fb = &b[ib.len()..];
+            if ia == ib {
+                return Ok(format!("{}{}", ia, midpoint(fa, Some(fb))));
+            }
+            let i = increment_integer(ia)?.ok_or(Error::Exhausted)?;
+            if i.as_str() < b {
+                return Ok(i);
+            }
+            Ok(format!("{}{}", ia, midpoint(fa, None)))
+        }
+    }
+}
+
+fn digit(c: u8) -> usize {
+    DIGITS.iter().position(|d| *d == c).unwrap_or(0)
+}
+
+// `a` < `b` and neither has trailing zeros; an empty `a` is 0, a missing `b` is 1.
+fn midpoint(a: &str, b: Option<&str>) -> String {
+    let (a, b) = (a.as_bytes(), b.map(str::as_bytes));
+    if let Some(b) = b {
+        // keep the common prefix, padding `a` with zeros
+        let n = (0..b.len())
+            .take_while(|i| a.get(*i).copied().unwrap_or(b'0') == b[*i])
+            .count();
+        if n > 0 {
+            let rest = std::str::from_utf8(&b[n..]).unwrap();
+            let a_rest = std::str::from_utf8(a.get(n..).unwrap_or_default()).unwrap();
+            return format!(
+                "{}{}",
+                std::str::from_utf8(&b[..n]).unwrap(),
+                midpoint(a_rest, Some(rest))
+            );
+        }
+    }
+    let digit_a = a.first().map(|c| digit(*c)).unwrap_or(0);
+    let digit_b = b.map(|b| digit(b[0])).unwrap_or(DIGITS.len());
+    if digit_b - digit_a > 1 {
+        return (DIGITS[(digit_a + digit_b).div_ceil(2)] as char).to_string();
+    }
+    match b {
+        Some(b) if b.len() > 1 => (b[0] as char).to_string(),
+        _ => {
+            let a_rest = std::str::from_utf8(a.get(1..).unwrap_or_default()).unwrap();
+            format!("{}{}", DIGITS[digit_a] as char, midpoint(a_rest, None))
+        }
+    }
+}
+
+fn integer_length(head: u8) -> std::result::Result<usize, Error> {
+    match head {
+        b'a'..=b'z' => Ok((head - b'a') as usize + 2),
+        b'A'..=b'Z' => Ok((b'Z' - head) as usize + 2),
+        _ => Err(Error::InvalidKey((head as char).to_string())),
+    }
+}
+
+fn integer_part(key: &str) -> std::result::Result<&str, Error> {
+    let len = integer_length(key.as_bytes()[0])?;
+    key.get(..len)
+        .ok_or_else(|| Error::InvalidKey(key.to_owned()))
+}
+
+fn validate_key(key: &str) -> std::result::Result<(), Error> {
+    let invalid = || Error::InvalidKey(key.to_owned());
+    if key.is_empty() || key == SMALLEST_INTEGER || !key.bytes().all(|c| DIGITS.contains(&c)) {
+        return Err(invalid());
+    }
+    let i = integer_part(key)?;
+    if key[i.len()..].ends_with('0') {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+fn increment_integer(x: &str) -> std::result::Result<Option<String>, Error> {
+    let head = x.as_bytes()[0];
+    let mut digs: Vec<u8> = x.as_bytes()[1..].to_vec();
+    let mut carry = true;
+    for d in digs.iter_mut().rev() {
+        let next = digit(*d) + 1;
+        if next == DIGITS.len() {
+            *d = b'0';
+        } else {
+            *d = DIGITS[next];
+            carry = false;
+            break;
+        }
+    }
+    if carry {
+        if head == b'Z' {
+            return Ok(Some(INTEGER_ZERO.to_owned()));
+        }
+        if head == b'z' {
+            return Ok(None);
+        }
+        let h = head + 1;
+        if h > b'a' {
+            digs.push(b'0');
+        } else {
+            digs.pop();
+        }
+        return Ok(Some(to_key(h, &digs)));
+    }
+    Ok(Some(to_key(head, &digs)))
+}
+
+fn decrement_integer(x: &str) -> std::result::Result<Option<String>, Error> {
+    let head = x.as_bytes()[0];
+    let last = DIGITS[DIGITS.len() - 1];
+    let mut digs: Vec<u8> = x.as_bytes()[1..].to_vec();
+    let mut borrow = true;
+    for d in digs.iter_mut().rev() {
+        let index = digit(*d);
+        if index == 0 {
+            *d = last;
+        } else {
+            *d = DIGITS[index - 1];
+            borrow = false;
+            break;
+        }
+    }
+    if borrow {
+        if head == b'a' {
+            return Ok(Some(to_key(b'Z', &[last])));
+        }
+        if head == b'A' {
+            return Ok(None);
+        }
+        let h = head - 1;
+        if h < b'Z' {
+            digs.push(last);
+        } else {
+            digs.pop();
+        }
+        return Ok(Some(to_key(h, &digs)));
+    }
+    Ok(Some(to_key(head, &digs)))
+}
+
+fn to_key(head: u8, digs: &[u8]) -> String {
+    let mut key = String::with_capacity(digs.len() + 1);
+    key.push(head as char);
+    key.extend(digs.iter().map(|d| *d as char));
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn between() {
+        let cases = [
+            (None, None, "a0"),
+            (None, Some("a0"), "Zz"),
+            (None, Some("Zz"), "Zy"),
+            (Some("a0"), None, "a1"),
+            (Some("a1"), None, "a2"),
+            (Some("a0"), Some("a1"), "a0V"),
+            (Some("a1"), Some("a2"), "a1V"),
+            (Some("a0V"), Some("a1"), "a0l"),
+            (Some("Zz"), Some("a0"), "ZzV"),
+            (Some("Zz"), Some("a1"), "a0"),
+            (None, Some("Y00"), "Xzzz"),
+            (Some("bzz"), None, "c000"),
+            (Some("a0"), Some("a0V"), "a0G"),
+            (Some("a0"), Some("a0G"), "a08"),
+            (Some("b125"), Some("b129"), "b127"),
+            (Some("a0"), Some("a1V"), "a1"),
+            (Some("Zz"), Some("a01"), "a0"),
+            (None, Some("a0V"), "a0"),
+            (None, Some("b999"), "b99"),
+        ];
+        for (a, b, want) in cases {
+            assert_eq!(
+                key_between(a, b).unwrap(),
+                want,
+                "between {:?} and {:?}",
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(key_between(None, Some("A00000000000000000000000000")).is_err());
+        assert!(key_between(Some("a00"), None).is_err());
+        assert!(key_between(Some("a1"), Some("a0")).is_err());
+        assert!(key_between(Some("a0"), Some("a0")).is_err());
+        assert!(key_between(Some("0"), None).is_err());
+    }
+
+    #[test]
+    fn keeps_order() {
+        let mut keys = vec![key_between(None, None).unwrap()];
+        for i in 0..200 {
+            // alternate between appending, prepending and inserting in the middle
+            let key = match i % 3 {
+                0 => key_between(keys.last().map(String::as_str), None),
+                1 => key_between(None, keys.first().map(String::as_str)),
+                _ => {
+                    let mid = keys.len() / 2;
+                    key_between(Some(&keys[mid - 1]), Some(&keys[mid]))
+                }
+            }
+            .unwrap();
+            keys.push(key);
+            keys.sort();
+        }
+        keys.dedup();
+        assert_eq!(keys.len(), 201);
+    }
+}
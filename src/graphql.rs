@@ -0,0 +1,257 @@
+// GraphQL at /graphql: users and media metadata, for clients that would rather ask for the
+// fields they need in one request. Resolvers go through the same repositories, probes and checks
+// as the REST handlers. Debug builds serve the GraphiQL playground on GET.
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject,
+};
+use axum::{extract::State, http::StatusCode, response::Html, routing::post, Json, Router};
+use tracing::info;
+use validator::Validate;
+
+use crate::{app::AppState, asset, event::Topic, grpc::Services, media, validation, CreateUser};
+
+pub const PATH: &str = "/graphql";
+// how deeply queries may nest, and how many fields they may ask for
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 200;
+
+pub type AppSchema = Schema<Query, Mutation, EmptySubscription>;
+
+// resolvers work with what the gRPC handlers do
+pub fn schema(services: Services) -> AppSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(services)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+pub fn routes() -> Router<AppState> {
+    let route = post(execute);
+    let route = if cfg!(debug_assertions) {
+        route.get(playground)
+    } else {
+        route
+    };
+    Router::new().route(PATH, route)
+}
+
+async fn execute(
+    State(schema): State<AppSchema>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request).await)
+}
+
+async fn playground() -> Html<String> {
+    Html(GraphiQLSource::build().endpoint(PATH).finish())
+}
+
+// the error of what a REST handler would answer, its status as `code` in the extensions
+fn error((status, message): (StatusCode, String)) -> async_graphql::Error {
+    let code = status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_uppercase()
+        .replace([' ', '-'], "_");
+    async_graphql::Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+#[derive(SimpleObject, Debug, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+}
+
+impl From<crate::repository::user::User> for User {
+    fn from(user: crate::repository::user::User) -> Self {
+        User {
+            id: user.id,
+            username: user.username,
+        }
+    }
+}
+
+#[derive(SimpleObject, Debug)]
+pub struct MediaMetadata {
+    // container format, as ffmpeg names it
+    pub format: String,
+    // seconds, null when unknown
+    pub duration: Option<f64>,
+    pub bit_rate: Option<i64>,
+    pub tags: Vec<Tag>,
+    pub streams: Vec<MediaStream>,
+}
+
+#[derive(SimpleObject, Debug)]
+pub struct Tag {
+    // in lower case
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(SimpleObject, Debug)]
+pub struct MediaStream {
+    pub index: i32,
+    // video, audio, subtitle, data, attachment or unknown
+    pub medium: String,
+    pub codec: String,
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+impl From<media::Metadata> for MediaMetadata {
+    fn from(metadata: media::Metadata) -> Self {
+        MediaMetadata {
+            format: metadata.format,
+            duration: metadata.duration,
+            bit_rate: metadata.bit_rate,
+            tags: metadata
+                .tags
+                .into_iter()
+                .map(|(key, value)| Tag { key, value })
+                .collect(),
+            streams: metadata
+                .streams
+                .into_iter()
+                .map(|stream| MediaStream {
+                    index: stream.index as i32,
+                    medium: stream.medium,
+                    codec: stream.codec,
+                    duration: stream.duration,
+                    width: stream.width,
+                    height: stream.height,
+                    frame_rate: stream.frame_rate,
+                    sample_rate: stream.sample_rate,
+                    channels: stream.channels,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    // null when there's no such user, or it's deleted
+    async fn user(&self, context: &Context<'_>, id: i64) -> async_graphql::Result<Option<User>> {
+        let services = context.data::<Services>()?;
+        match services.users.find(id, false).await {
+            Ok(user) => Ok(Some(User::from(user))),
+            Err(sqlx::Error::RowNotFound) => Ok(None),
+            Err(err) => Err(error(crate::db_error(err))),
+        }
+    }
+
+    // of a media file by its path on the server; `refresh` probes it again
+    async fn video_metadata(
+        &self,
+        context: &Context<'_>,
+        file: String,
+        #[graphql(default)] refresh: bool,
+    ) -> async_graphql::Result<MediaMetadata> {
+        let services = context.data::<Services>()?;
+        validation::media_path(&file)
+            .map_err(|err| error((StatusCode::UNPROCESSABLE_ENTITY, err.to_string())))?;
+        let media = &*services.media;
+        let found = media.find_by_path(&file).await.map_err(crate::db_error);
+        let file = found.map_err(error)?;
+        let metadata = asset::metadata(media, &services.probes, file, refresh)
+            .await
+            .map_err(error)?;
+        Ok(MediaMetadata::from(metadata))
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn create_user(
+        &self,
+        context: &Context<'_>,
+        username: String,
+    ) -> async_graphql::Result<User> {
+        let services = context.data::<Services>()?;
+        if let Some(message) = services.read_only.refusal_of_changes() {
+            return Err(error((StatusCode::SERVICE_UNAVAILABLE, message)));
+        }
+        let payload = CreateUser { username };
+        payload
+            .validate()
+            .map_err(|errors| error((StatusCode::UNPROCESSABLE_ENTITY, errors.to_string())))?;
+        let user = services.users.create(&payload.username).await;
+        let user = crate::User::from(user.map_err(|err| error(crate::db_error(err)))?);
+        info!("user {} created", user.id);
+        services.events.publish(Topic::UserCreated, None, &user);
+        Ok(User {
+            id: user.id,
+            username: user.username,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::Events,
+        metering::Metering,
+        probe::Probes,
+        rate_plan::RatePlans,
+        read_only::{self, ReadOnly},
+        repository::{media::MemoryMedia, user::MemoryUsers},
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn test_schema() -> AppSchema {
+        schema(Services {
+            users: Arc::new(MemoryUsers::default()),
+            events: Arc::new(Events::default()),
+            read_only: Arc::new(ReadOnly::new(read_only::Settings::default())),
+            media: Arc::new(MemoryMedia::default()),
+            probes: Arc::new(Probes::default()),
+            rate_plans: Arc::new(RatePlans::default()),
+            metering: Arc::new(Metering::default()),
+        })
+    }
+
+    #[tokio::test]
+    async fn creates_and_finds_users() {
+        let schema = test_schema();
+        let created = schema
+            .execute(r#"mutation { createUser(username: "jd") { id username } }"#)
+            .await;
+        assert!(created.errors.is_empty(), "{:?}", created.errors);
+        let created = created.data.into_json().unwrap();
+        assert_eq!(created["createUser"]["username"], "jd");
+
+        let id = created["createUser"]["id"].as_i64().unwrap();
+        let found = schema
+            .execute(format!("{{ user(id: {}) {{ username }} }}", id))
+            .await;
+        assert_eq!(
+            found.data.into_json().unwrap(),
+            json!({"user": {"username": "jd"}})
+        );
+        let missing = schema
+            .execute(format!("{{ user(id: {}) {{ username }} }}", id + 1))
+            .await;
+        assert_eq!(missing.data.into_json().unwrap(), json!({"user": null}));
+
+        let invalid = schema
+            .execute(r#"mutation { createUser(username: "-") { id } }"#)
+            .await;
+        let extensions = invalid.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(
+            extensions.get("code"),
+            Some(&async_graphql::Value::from("UNPROCESSABLE_ENTITY"))
+        );
+    }
+}
@@ -0,0 +1,471 @@
+// The gRPC API of proto/rsapp.proto, for services inside the network: users and media probes,
+// served on a port of their own next to the REST API. Handlers go through the same repositories,
+// probes and checks as their REST counterparts. Callers send the `[grpc]` token as
+// `authorization: Bearer <token>` metadata, and the user they call for as `x-user-id`, whose rate
+// plan limits their calls as it does requests. Calls count per instance, also with Redis.
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::http::StatusCode;
+use serde_derive::Deserialize;
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::{info, warn};
+use validator::Validate;
+
+use crate::{
+    asset,
+    auth::constant_time_eq,
+    db_error,
+    event::{Events, Topic},
+    media,
+    metering::Metering,
+    probe::Probes,
+    rate_plan::RatePlans,
+    read_only::ReadOnly,
+    repository::{MediaRepository, UserRepository},
+    task, validation, CreateUser, User,
+};
+
+pub mod pb {
+    tonic::include_proto!("rsapp.v1");
+}
+
+use pb::{
+    media_probe_server::{MediaProbe, MediaProbeServer},
+    users_server::{Users, UsersServer},
+};
+
+// shorter tokens are refused by the config check
+const MIN_TOKEN: usize = 16;
+
+// `[grpc]` in the config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Settings {
+    // like `0.0.0.0:9010`, gRPC is off without it
+    pub listen: Option<String>,
+    // what callers authorize with
+    pub token: Option<String>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(listen) = &self.listen else {
+            return Ok(());
+        };
+        listen
+            .parse::<SocketAddr>()
+            .map_err(|err| format!("grpc.listen: {}", err))?;
+        match &self.token {
+            Some(token) if token.len() >= MIN_TOKEN => Ok(()),
+            _ => Err(format!(
+                "grpc.listen needs a grpc.token of at least {} characters",
+                MIN_TOKEN
+            )),
+        }
+    }
+}
+
+// what the handlers work with, shared with the REST API
+#[derive(Clone)]
+pub struct Services {
+    pub users: Arc<dyn UserRepository>,
+    pub events: Arc<Events>,
+    pub read_only: Arc<ReadOnly>,
+    pub media: Arc<dyn MediaRepository>,
+    pub probes: Arc<Probes>,
+    pub rate_plans: Arc<RatePlans>,
+    pub metering: Arc<Metering>,
+}
+
+// the gRPC server, serving until stopped
+pub struct Serving {
+    pub address: SocketAddr,
+    // of the listening socket, to hand over
+    #[cfg(unix)]
+    pub fd: i32,
+    pub task: JoinHandle<()>,
+}
+
+// serves gRPC when `settings` say where, on `inherited` if the server took that over, until
+// `stopped`
+pub async fn start(
+    settings: &Settings,
+    services: Services,
+    inherited: Option<TcpListener>,
+    mut stopped: watch::Receiver<bool>,
+) -> std::io::Result<Option<Serving>> {
+    let (Some(listen), Some(token)) = (&settings.listen, &settings.token) else {
+        return Ok(None);
+    };
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => TcpListener::bind(listen).await?,
+    };
+    let address = listener.local_addr()?;
+    #[cfg(unix)]
+    let fd = std::os::fd::AsRawFd::as_raw_fd(&listener);
+    info!("gRPC on {}", address);
+
+    let intercept = intercept(
+        Arc::from(token.as_str()),
+        services.rate_plans,
+        services.metering,
+    );
+    let users = UserService {
+        users: services.users,
+        events: services.events,
+        read_only: services.read_only,
+    };
+    let probes = ProbeService {
+        media: services.media,
+        probes: services.probes,
+    };
+    let server = Server::builder()
+        .add_service(UsersServer::with_interceptor(users, intercept.clone()))
+        .add_service(MediaProbeServer::with_interceptor(probes, intercept))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        });
+    let task = task::spawn("gRPC server", task::Kind::Work, async move {
+        if let Err(err) = server.await {
+            warn!("gRPC stopped: {}", err);
+        }
+    });
+    Ok(Some(Serving {
+        address,
+        #[cfg(unix)]
+        fd,
+        task,
+    }))
+}
+
+// refuses calls without `token`; tonic interceptors return a bare `Status`
+#[allow(clippy::result_large_err)]
+fn authorize(
+    token: Arc<str>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone + Send + Sync + 'static {
+    move |request: Request<()>| {
+        let given = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(request),
+            _ => Err(Status::unauthenticated("invalid token")),
+        }
+    }
+}
+
+// Refuses calls for users over their rate plan's request or bandwidth limits, and counts the others
+// as requests of theirs. Calls without `x-user-id` are the caller's own, and not limited.
+#[allow(clippy::result_large_err)]
+fn limit(
+    plans: Arc<RatePlans>,
+    metering: Arc<Metering>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone + Send + Sync + 'static {
+    move |request: Request<()>| {
+        let Some(user) = request.metadata().get("x-user-id") else {
+            return Ok(request);
+        };
+        let user = user
+            .to_str()
+            .ok()
+            .and_then(|user| user.parse().ok())
+            .ok_or_else(|| Status::invalid_argument("invalid x-user-id"))?;
+        if let Some(plan) = plans.for_user(user) {
+            let (requests, bytes) = metering.totals(user);
+            plan.check_request(requests.last_minute, bytes.last_day)
+                .map_err(|exceeded| Status::resource_exhausted(exceeded.to_string()))?;
+        }
+        metering.record(user, 0);
+        Ok(request)
+    }
+}
+
+// `authorize`, then `limit`
+#[allow(clippy::result_large_err)]
+fn intercept(
+    token: Arc<str>,
+    plans: Arc<RatePlans>,
+    metering: Arc<Metering>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone + Send + Sync + 'static {
+    let mut authorize = authorize(token);
+    let mut limit = limit(plans, metering);
+    move |request: Request<()>| limit(authorize(request)?)
+}
+
+// the gRPC status of what a REST handler would answer
+fn status((code, message): (StatusCode, String)) -> Status {
+    match code {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            Status::invalid_argument(message)
+        }
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+struct UserService {
+    users: Arc<dyn UserRepository>,
+    events: Arc<Events>,
+    read_only: Arc<ReadOnly>,
+}
+
+#[tonic::async_trait]
+impl Users for UserService {
+    async fn create_user(
+        &self,
+        request: Request<pb::CreateUserRequest>,
+    ) -> Result<Response<pb::User>, Status> {
+        if let Some(message) = self.read_only.refusal_of_changes() {
+            return Err(Status::unavailable(message));
+        }
+        let payload = CreateUser {
+            username: request.into_inner().username,
+        };
+        payload
+            .validate()
+            .map_err(|errors| Status::invalid_argument(errors.to_string()))?;
+        let user = self
+            .users
+            .create(&payload.username)
+            .await
+            .map_err(|err| status(db_error(err)))?;
+        let user = User::from(user);
+        info!("user {} created", user.id);
+        self.events.publish(Topic::UserCreated, None, &user);
+        Ok(Response::new(pb::User {
+            id: user.id,
+            username: user.username,
+        }))
+    }
+
+    async fn get_user(
+        &self,
+        request: Request<pb::GetUserRequest>,
+    ) -> Result<Response<pb::User>, Status> {
+        let user = self
+            .users
+            .find(request.into_inner().id, false)
+            .await
+            .map_err(|err| status(db_error(err)))?;
+        Ok(Response::new(pb::User {
+            id: user.id,
+            username: user.username,
+        }))
+    }
+}
+
+struct ProbeService {
+    media: Arc<dyn MediaRepository>,
+    probes: Arc<Probes>,
+}
+
+#[tonic::async_trait]
+impl MediaProbe for ProbeService {
+    async fn probe_asset(
+        &self,
+        request: Request<pb::ProbeAssetRequest>,
+    ) -> Result<Response<pb::MediaMetadata>, Status> {
+        let request = request.into_inner();
+        let file = self
+            .media
+            .find(request.asset_id)
+            .await
+            .map_err(|err| status(db_error(err)))?;
+        let metadata = asset::metadata(&*self.media, &self.probes, file, request.refresh)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(pb::MediaMetadata::from(&metadata)))
+    }
+
+    async fn probe_file(
+        &self,
+        request: Request<pb::ProbeFileRequest>,
+    ) -> Result<Response<pb::MediaMetadata>, Status> {
+        let request = request.into_inner();
+        validation::media_path(&request.path)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let file = self
+            .media
+            .find_by_path(&request.path)
+            .await
+            .map_err(|err| status(db_error(err)))?;
+        let metadata = asset::metadata(&*self.media, &self.probes, file, request.refresh)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(pb::MediaMetadata::from(&metadata)))
+    }
+}
+
+impl From<&media::Metadata> for pb::MediaMetadata {
+    fn from(metadata: &media::Metadata) -> Self {
+        pb::MediaMetadata {
+            format: metadata.format.clone(),
+            duration: metadata.duration,
+            bit_rate: metadata.bit_rate,
+            tags: metadata.tags.clone().into_iter().collect(),
+            streams: metadata
+                .streams
+                .iter()
+                .map(|stream| pb::MediaStream {
+                    index: stream.index as u32,
+                    medium: stream.medium.clone(),
+                    codec: stream.codec.clone(),
+                    duration: stream.duration,
+                    width: stream.width,
+                    height: stream.height,
+                    frame_rate: stream.frame_rate,
+                    sample_rate: stream.sample_rate,
+                    channels: stream.channels.map(u32::from),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rate_plan::RatePlan, read_only, repository::user::MemoryUsers};
+
+    fn users(read_only: bool) -> UserService {
+        UserService {
+            users: Arc::new(MemoryUsers::default()),
+            events: Arc::new(Events::default()),
+            read_only: Arc::new(ReadOnly::new(read_only::Settings {
+                enabled: read_only,
+                reason: None,
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_users() {
+        let service = users(false);
+        let created = service
+            .create_user(Request::new(pb::CreateUserRequest {
+                username: "jd".to_owned(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.username, "jd");
+        let found = service
+            .get_user(Request::new(pb::GetUserRequest { id: created.id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(found, created);
+
+        let missing = service
+            .get_user(Request::new(pb::GetUserRequest { id: created.id + 1 }))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+        let invalid = service
+            .create_user(Request::new(pb::CreateUserRequest {
+                username: "-".to_owned(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.code(), tonic::Code::InvalidArgument);
+        let refused = users(true)
+            .create_user(Request::new(pb::CreateUserRequest {
+                username: "jd".to_owned(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::Unavailable);
+    }
+
+    #[test]
+    fn authorizes() {
+        let mut authorize = authorize(Arc::from("a token long enough"));
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            "Bearer a token long enough".parse().unwrap(),
+        );
+        assert!(authorize(request).is_ok());
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer guessed".parse().unwrap());
+        assert_eq!(
+            authorize(request).unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+        assert!(authorize(Request::new(())).is_err());
+    }
+
+    #[test]
+    fn limits_users_to_their_plans() {
+        let plan = RatePlan {
+            id: 1,
+            name: "trial".to_owned(),
+            requests_per_minute: Some(2),
+            transcode_minutes_per_day: None,
+            bandwidth_bytes_per_day: None,
+        };
+        let plans = Arc::new(RatePlans::default().with_plan(7, plan));
+        let metering = Arc::new(Metering::default());
+        let token = "a token long enough";
+        let mut intercept = intercept(Arc::from(token), plans, metering.clone());
+        let call = |user: &str| {
+            let mut request = Request::new(());
+            let metadata = request.metadata_mut();
+            metadata.insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+            metadata.insert("x-user-id", user.parse().unwrap());
+            request
+        };
+        assert!(intercept(call("7")).is_ok());
+        assert!(intercept(call("7")).is_ok());
+        let exceeded = intercept(call("7")).unwrap_err();
+        assert_eq!(exceeded.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(exceeded.message(), "rate plan allows 2 requests per minute");
+        assert_eq!(metering.totals(7).0.last_minute, 2);
+        // others and the caller's own calls go on
+        assert!(intercept(call("8")).is_ok());
+        let mut own = call("7");
+        own.metadata_mut().remove("x-user-id");
+        assert!(intercept(own).is_ok());
+        assert_eq!(
+            intercept(call("seven")).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+        // unauthorized calls aren't counted
+        let mut guessed = call("8");
+        guessed
+            .metadata_mut()
+            .insert("authorization", "Bearer guessed".parse().unwrap());
+        assert_eq!(
+            intercept(guessed).unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+        assert_eq!(metering.totals(8).0.last_minute, 1);
+    }
+
+    #[test]
+    fn validates() {
+        let mut settings = Settings {
+            listen: Some("0.0.0.0:9010".to_owned()),
+            token: None,
+        };
+        assert!(settings.validate().is_err());
+        settings.token = Some("a token long enough".to_owned());
+        assert_eq!(settings.validate(), Ok(()));
+        settings.listen = Some("9010".to_owned());
+        assert!(settings.validate().is_err());
+    }
+}
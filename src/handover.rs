@@ -0,0 +1,204 @@
+// `kill -USR2` replaces the running `rsapp server` with a new one of the binary as it is on disk
+// now, without refusing a connection or cutting one off: the new server is started with the
+// listening sockets as `--fd`, `--admin-fd` and `--grpc-fd`, and once it listens on them the old
+// one stops accepting and exits when the requests it's serving, like media downloads, are done.
+// When the new one doesn't get that far the old one keeps on serving.
+//
+// The new server gets another pid, which supervisors following the first one, as systemd does
+// by default, don't expect; restart those with socket activation instead, see `listen`.
+use std::{
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    process::Command,
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::{error, info, warn};
+
+use crate::listen::Fds;
+
+// how long the new server has to start, which includes connecting to postgres and migrating
+const READY_WAIT: Duration = Duration::from_secs(60);
+
+// the server to start in place of this one
+pub struct Handover {
+    pub port: String,
+    pub no_db: bool,
+    // of the listening sockets
+    pub fds: Fds,
+}
+
+impl Handover {
+    // completes once a new server took over after a SIGUSR2, there's no completing without one
+    pub async fn on_signal(self) {
+        let Ok(mut signals) = signal(SignalKind::user_defined2()) else {
+            warn!("can't hand over on SIGUSR2, its handler can't be installed");
+            return std::future::pending().await;
+        };
+        while signals.recv().await.is_some() {
+            info!("starting a new server to hand over to");
+            match self.start().await {
+                Ok(pid) => {
+                    info!(
+                        pid,
+                        "handed over, stopping once the requests in flight are done"
+                    );
+                    crate::listen::hand_over();
+                    return;
+                }
+                Err(err) => error!("keeping on serving, the new server didn't start: {}", err),
+            }
+        }
+        std::future::pending().await
+    }
+
+    // the pid of the new server once it listens
+    async fn start(&self) -> io::Result<u32> {
+        let (ready, theirs) = UnixStream::pair()?;
+        // the copies the new server gets, closed here once it runs
+        let mut passed = Vec::new();
+        let mut pass = |fd: Option<i32>| -> io::Result<Option<i32>> {
+            let Some(fd) = fd else {
+                return Ok(None);
+            };
+            let copy = inheritable(fd)?;
+            let fd = copy.as_raw_fd();
+            passed.push(copy);
+            Ok(Some(fd))
+        };
+        let fds = Fds {
+            public: pass(self.fds.public)?,
+            admin: pass(self.fds.admin)?,
+            grpc: pass(self.fds.grpc)?,
+            ready: pass(Some(theirs.as_raw_fd()))?,
+        };
+        let mut child = Command::new(program()?)
+            .args(arguments(&self.port, self.no_db, fds))
+            .spawn()?;
+        drop(passed);
+        drop(theirs);
+
+        ready.set_nonblocking(true)?;
+        let mut ready = tokio::net::UnixStream::from_std(ready)?;
+        match tokio::time::timeout(READY_WAIT, ready.read_u8()).await {
+            Ok(Ok(_)) => Ok(child.id()),
+            // closed without a word, when it exited
+            Ok(Err(_)) => {
+                let status = child.wait()?;
+                Err(io::Error::other(format!("it exited with {}", status)))
+            }
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                let message = format!("it didn't listen within {:?} and was killed", READY_WAIT);
+                Err(io::Error::other(message))
+            }
+        }
+    }
+}
+
+// tells the server that handed over through descriptor `fd` that this one listens
+pub fn ready(fd: i32) {
+    use std::io::Write;
+
+    // SAFETY: the descriptor was passed on for this, nothing else owns it
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    if let Err(err) = stream.write_all(b"1") {
+        warn!(
+            "can't tell the server handing over that this one listens: {}",
+            err
+        );
+    }
+}
+
+// the binary as it is on disk now: the path it was started by rather than `current_exe`, which
+// is the replaced file on Linux once a deployment moved a new one there
+fn program() -> io::Result<std::ffi::OsString> {
+    match std::env::args_os().next() {
+        Some(program) => Ok(program),
+        None => Ok(std::env::current_exe()?.into_os_string()),
+    }
+}
+
+// those of `rsapp server` taking over `fds`
+fn arguments(port: &str, no_db: bool, fds: Fds) -> Vec<String> {
+    let mut arguments = vec!["server".to_owned(), "--port".to_owned(), port.to_owned()];
+    if no_db {
+        arguments.push("--no-db".to_owned());
+    }
+    for (flag, fd) in [
+        ("--fd", fds.public),
+        ("--admin-fd", fds.admin),
+        ("--grpc-fd", fds.grpc),
+        ("--ready-fd", fds.ready),
+    ] {
+        if let Some(fd) = fd {
+            arguments.extend([flag.to_owned(), fd.to_string()]);
+        }
+    }
+    arguments
+}
+
+// a copy of `fd` that programs the server starts get, unlike the descriptors it opens itself
+fn inheritable(fd: i32) -> io::Result<OwnedFd> {
+    // SAFETY: `fd` is open, F_DUPFD makes a new descriptor without FD_CLOEXEC
+    let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD, 3) };
+    if copy < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: just made, nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(copy) })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{Cli, Commands};
+
+    #[test]
+    fn passes_descriptors_as_arguments() {
+        let fds = Fds {
+            public: Some(7),
+            admin: None,
+            grpc: Some(8),
+            ready: Some(9),
+        };
+        let mut command = vec!["rsapp".to_owned()];
+        command.extend(arguments("9009", true, fds));
+        match Cli::try_parse_from(command).unwrap().cmd {
+            Commands::Server {
+                port,
+                no_db,
+                fd,
+                admin_fd,
+                grpc_fd,
+                ready_fd,
+            } => {
+                assert_eq!((port.as_deref(), no_db), (Some("9009"), true));
+                assert_eq!(
+                    (fd, admin_fd, grpc_fd, ready_fd),
+                    (Some(7), None, Some(8), Some(9))
+                );
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn copies_are_inherited() {
+        let (stream, _) = UnixStream::pair().unwrap();
+        let copy = inheritable(stream.as_raw_fd()).unwrap();
+        // SAFETY: `copy` is open
+        let flags = unsafe { libc::fcntl(copy.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, 0);
+    }
+}
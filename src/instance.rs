@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use sqlx::PgPool;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{app::AppState, auth::Admin, db::DbExecutor, db_error, media, migrate, task};
+
+// how often an instance refreshes its row, and how long after the last refresh it counts as gone
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+pub const ACTIVE_SECS: i32 = 120;
+// rows of instances gone for longer than that are removed
+const FORGET_HOURS: i32 = 24;
+
+// What this process runs with, next to the other instances sharing the database. Each one
+// stores its own description every HEARTBEAT_INTERVAL and compares it with those of the other
+// active ones, so config, schema or ffmpeg differences between them show up as warnings.
+#[derive(Serialize, sqlx::FromRow, ToSchema, Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub id: String,
+    pub version: String,
+    // fingerprint of the merged configuration, secrets included
+    pub config_hash: String,
+    pub migration: i64,
+    pub ffmpeg: String,
+    pub started_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct InstancesReport {
+    // the instance answering
+    pub this: String,
+    // active ones
+    pub instances: Vec<Instance>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub instance: String,
+    // differences to other instances, from the last heartbeat
+    pub warnings: Vec<String>,
+}
+
+pub struct Instances {
+    this: Instance,
+    warnings: RwLock<Vec<String>>,
+}
+
+impl Instances {
+    pub fn new(config_hash: String) -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_owned());
+        let now = Utc::now();
+        Instances {
+            this: Instance {
+                id: format!("{}:{}", host, std::process::id()),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                config_hash,
+                migration: migrate::MIGRATOR
+                    .iter()
+                    .map(|migration| migration.version)
+                    .max()
+                    .unwrap_or_default(),
+                ffmpeg: media::ffmpeg_version(),
+                started_at: now,
+                last_seen_at: now,
+            },
+            warnings: RwLock::default(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.this.id
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.read().unwrap().clone()
+    }
+
+    // refreshes this instance's row and looks for drift for as long as the instances are in use
+    pub fn heartbeat(self: &Arc<Self>, pool: PgPool) {
+        let instances = Arc::downgrade(self);
+        task::spawn("instance heartbeat", task::Kind::Background, async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(instances) = instances.upgrade() else {
+                    return;
+                };
+                match instances.beat(&pool).await {
+                    Ok(warnings) => instances.set_warnings(warnings),
+                    Err(err) => warn!("instance heartbeat failed: {}", err),
+                }
+            }
+        });
+    }
+
+    async fn beat(&self, pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+        let this = &self.this;
+        sqlx::query(
+            "INSERT INTO instances (id, version, config_hash, migration, ffmpeg, started_at)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (id) DO UPDATE SET version = $2, config_hash = $3, migration = $4,
+                    ffmpeg = $5, started_at = $6, last_seen_at = now()",
+        )
+        .bind(&this.id)
+        .bind(&this.version)
+        .bind(&this.config_hash)
+        .bind(this.migration)
+        .bind(&this.ffmpeg)
+        .bind(this.started_at)
+        .execute(pool)
+        .await?;
+        sqlx::query(
+            "DELETE FROM instances WHERE last_seen_at < now() - make_interval(hours => $1)",
+        )
+        .bind(FORGET_HOURS)
+        .execute(pool)
+        .await?;
+        Ok(drift(&active(pool).await?))
+    }
+
+    fn set_warnings(&self, warnings: Vec<String>) {
+        let mut current = self.warnings.write().unwrap();
+        if *current != warnings {
+            if warnings.is_empty() {
+                info!("instances agree again");
+            }
+            for warning in &warnings {
+                warn!("{}", warning);
+            }
+            *current = warnings;
+        }
+    }
+}
+
+async fn active(pool: &PgPool) -> Result<Vec<Instance>, sqlx::Error> {
+    sqlx::query_as::<_, Instance>(
+        "SELECT * FROM instances WHERE last_seen_at > now() - make_interval(secs => $1)
+            ORDER BY id",
+    )
+    .bind(ACTIVE_SECS)
+    .fetch_all(pool)
+    .await
+}
+
+// what instances should agree on, by name
+fn properties(instance: &Instance) -> [(&'static str, String); 4] {
+    [
+        ("version", instance.version.clone()),
+        ("configuration", instance.config_hash.clone()),
+        ("newest migration", instance.migration.to_string()),
+        ("ffmpeg version", instance.ffmpeg.clone()),
+    ]
+}
+
+// one warning per property the instances don't agree on, naming who has which value
+fn drift(instances: &[Instance]) -> Vec<String> {
+    // property -> value -> instance ids, in the order of `properties`
+    let mut seen: Vec<(&str, BTreeMap<String, Vec<&str>>)> = Vec::new();
+    for instance in instances {
+        for (at, (name, value)) in properties(instance).into_iter().enumerate() {
+            if seen.len() == at {
+                seen.push((name, BTreeMap::new()));
+            }
+            seen[at].1.entry(value).or_default().push(&instance.id);
+        }
+    }
+    seen.into_iter()
+        .filter(|(_, values)| values.len() > 1)
+        .map(|(name, values)| {
+            let values: Vec<String> = values
+                .iter()
+                .map(|(value, ids)| format!("{} on {}", value, ids.join(", ")))
+                .collect();
+            format!("instances differ in {}: {}", name, values.join("; "))
+        })
+        .collect()
+}
+
+// FNV-1a, stable across builds unlike std's hasher, for comparing configurations between
+// instances
+pub fn fingerprint(bytes: &[u8]) -> String {
+    format!("{:016x}", fnv1a(bytes))
+}
+
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/readyz", get(ready))
+        .route("/admin/instances", get(list_instances))
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "Ready for traffic, with any differences to other instances", body = Readiness),
+        (status = 503, description = "The database can't be reached", body = Readiness),
+    ),
+    tag = "status"
+)]
+async fn ready(
+    State(instances): State<Arc<Instances>>,
+    State(db): State<Arc<DbExecutor>>,
+) -> (StatusCode, Json<Readiness>) {
+    let ready = sqlx::query("SELECT 1").execute(db.write()).await.is_ok();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            instance: instances.this.id.clone(),
+            warnings: instances.warnings(),
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/instances",
+    responses((status = 200, description = "Active instances and what they differ in", body = InstancesReport)),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn list_instances(
+    _: Admin,
+    State(instances): State<Arc<Instances>>,
+    State(db): State<Arc<DbExecutor>>,
+) -> Result<Json<InstancesReport>, (StatusCode, String)> {
+    let active = active(db.read()).await.map_err(db_error)?;
+    Ok(Json(InstancesReport {
+        this: instances.this.id.clone(),
+        warnings: drift(&active),
+        instances: active,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_drift() {
+        let a = Instances::new(fingerprint(b"name = \"a\"")).this;
+        let b = Instance {
+            id: "b:1".to_owned(),
+            ..a.clone()
+        };
+        assert_eq!(drift(&[a.clone(), b.clone()]), Vec::<String>::new());
+
+        let c = Instance {
+            id: "c:1".to_owned(),
+            config_hash: fingerprint(b"name = \"c\""),
+            ffmpeg: "6.1".to_owned(),
+            ..a.clone()
+        };
+        let warnings = drift(&[a.clone(), b, c]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("instances differ in configuration:"));
+        assert!(warnings[0].contains(&format!("{} on {}, b:1", a.config_hash, a.id)));
+        assert!(warnings[1].contains("6.1 on c:1"));
+    }
+
+    #[test]
+    fn stable_fingerprints() {
+        assert_eq!(fingerprint(b""), "cbf29ce484222325");
+        assert_eq!(fingerprint(b"a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,1598 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{postgres::PgListener, types::Json as Jsonb, PgPool};
+use tokio::{
+    runtime::Handle,
+    sync::{broadcast, watch, Notify},
+    task::{JoinHandle, JoinSet},
+    time::sleep,
+};
+use tracing::{info, instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    api::{v1, v2, ToVersion},
+    app::AppState,
+    asset,
+    auth::{Admin, CurrentUser},
+    bus::Bus,
+    canary::{self, Canaries, Variant},
+    clock::Clock,
+    db_error,
+    event::Topic,
+    instance, leak,
+    media::{self, Hardware, Hwaccel, ImageFormat, Overlay, SpriteLayout, VideoCodec},
+    probe::Probes,
+    rate_plan::RatePlans,
+    repository::MediaRepository,
+    storage::Storage,
+    task,
+    webhook::Webhooks,
+};
+
+// how many state changes a slow subscriber may fall behind before it skips ahead
+const EVENT_BUFFER: usize = 256;
+// how often idle workers look for queued jobs when no notification woke them, and how long to
+// wait before listening again after the connection broke
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// how often jobs left running by instances that are gone are queued again
+const ORPHAN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// the channel the `jobs` table announces changed jobs on
+const CHANNEL: &str = "jobs";
+// the longest a retry waits, however many came before
+const MAX_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(3600);
+// how long links to download results work
+pub(crate) const DOWNLOAD_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobKind {
+    // re-encodes the video into an MP4, to H.264 unless asked for another `codec`, with an
+    // `overlay` image laid over it when given, on the `hwaccel` hardware instead of
+    // `jobs.hwaccel` when given
+    Transcode {
+        asset_id: i64,
+        #[serde(default)]
+        codec: VideoCodec,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        overlay: Option<Overlay>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hwaccel: Option<Hwaccel>,
+    },
+    // an image of the frame at `time` seconds, `width` pixels wide (the source width by
+    // default), a JPEG unless asked for another `format`
+    Thumbnail {
+        asset_id: i64,
+        #[serde(default)]
+        time: f64,
+        width: Option<u32>,
+        #[serde(default)]
+        format: ImageFormat,
+    },
+    // a sprite sheet of `count` frames evenly spaced across the video, `columns` per row and
+    // `width` pixels wide each, for seek previews; its WebVTT track is at
+    // `/api/v1/jobs/{id}/thumbnails.vtt`
+    Sprites {
+        asset_id: i64,
+        #[serde(default = "default_sprites")]
+        count: u32,
+        #[serde(default = "default_sprite_columns")]
+        columns: u32,
+        #[serde(default = "default_sprite_width")]
+        width: u32,
+        #[serde(default)]
+        format: ImageFormat,
+    },
+}
+
+// bounds of sprite sheets, which are decoded and kept in memory whole
+const MAX_SPRITES: u32 = 400;
+const MAX_SPRITE_WIDTH: u32 = 640;
+
+fn default_sprites() -> u32 {
+    100
+}
+
+fn default_sprite_columns() -> u32 {
+    10
+}
+
+fn default_sprite_width() -> u32 {
+    160
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    // waiting for a worker, or for its next try after failing
+    Queued,
+    Running,
+    Finished,
+    // failed with retries off, see `jobs.max_retries`
+    Failed,
+    // failed every retry, kept until queued again at `/admin/jobs/{id}/requeue`
+    Dead,
+}
+
+impl JobState {
+    pub fn is_done(self) -> bool {
+        matches!(self, JobState::Finished | JobState::Failed | JobState::Dead)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Finished => "finished",
+            JobState::Failed => "failed",
+            JobState::Dead => "dead",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            JobState::Queued,
+            JobState::Running,
+            JobState::Finished,
+            JobState::Failed,
+            JobState::Dead,
+        ]
+        .into_iter()
+        .find(|state| state.as_str() == name)
+    }
+}
+
+// which queued jobs are claimed first, interactive ones above bulk work; besides the shared
+// workers, `jobs.high_workers` and `jobs.low_workers` run only jobs of their priority
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    // as stored in `jobs.priority`
+    fn rank(self) -> i16 {
+        self as i16
+    }
+
+    fn from_rank(rank: i16) -> Self {
+        match rank {
+            0 => Priority::Low,
+            2 => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+// a job as submitted, its kind along with how urgent it is and where to tell once it's done
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct SubmitJob {
+    #[serde(flatten)]
+    pub kind: JobKind,
+    #[serde(default)]
+    pub priority: Priority,
+    // POSTed the job once it finished or failed, see `webhook`; `webhooks.url` when missing
+    pub callback_url: Option<String>,
+}
+
+// the queued jobs a worker claims: the shared ones take any, highest priority first, reserved
+// ones only those of their priority
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pool {
+    Shared,
+    Reserved(Priority),
+}
+
+impl Pool {
+    // the lowest and highest rank of jobs it claims
+    fn ranks(self) -> (i16, i16) {
+        match self {
+            Pool::Shared => (Priority::Low.rank(), Priority::High.rank()),
+            Pool::Reserved(priority) => (priority.rank(), priority.rank()),
+        }
+    }
+}
+
+// how many jobs wait and run, across all instances
+#[derive(Serialize, Debug, Default, PartialEq, sqlx::FromRow, ToSchema)]
+pub struct QueueDepth {
+    // queued ones ready to run, by priority
+    pub high: i64,
+    pub normal: i64,
+    pub low: i64,
+    // queued ones waiting to be retried
+    pub backing_off: i64,
+    pub running: i64,
+}
+
+// a state a job got into, see the `job_history` table
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct StateChange {
+    pub state: JobState,
+    // why it failed
+    pub error: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListDeadJobs {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ListJobs {
+    // only jobs in this state
+    state: Option<JobState>,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub user_id: i64,
+    pub kind: JobKind,
+    // the media file to work on
+    pub input: PathBuf,
+    // the transcoder implementation, picked at submission
+    pub variant: Variant,
+    pub priority: Priority,
+    pub state: JobState,
+    // share of the work done, from 0 to 1
+    pub progress: f32,
+    // where the result is stored, once finished
+    pub output: Option<String>,
+    pub error: Option<String>,
+    // runs so far, the current one included
+    pub attempts: i32,
+    // where it's delivered once done, see `webhook`
+    pub callback_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+    id: i64,
+    user_id: i64,
+    kind: Jsonb<JobKind>,
+    input: String,
+    variant: String,
+    priority: i16,
+    state: String,
+    progress: f32,
+    output: Option<String>,
+    error: Option<String>,
+    attempts: i32,
+    callback_url: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<Row> for Job {
+    fn from(row: Row) -> Self {
+        Job {
+            id: row.id,
+            user_id: row.user_id,
+            kind: row.kind.0,
+            input: PathBuf::from(row.input),
+            variant: Variant::from_name(&row.variant),
+            priority: Priority::from_rank(row.priority),
+            // the table allows no other states
+            state: JobState::from_name(&row.state).unwrap_or(JobState::Failed),
+            progress: row.progress,
+            output: row.output,
+            error: row.error,
+            attempts: row.attempts,
+            callback_url: row.callback_url,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+// Background media work, queued in the `jobs` table so that servers and `rsapp worker`
+// processes share it. Each process with workers claims queued jobs and runs them on the blocking
+// thread pool, at most `workers` at a time plus those reserved for high and low priority jobs, and
+// hands results to the storage. Every change of a job is announced by the database
+// and broadcast in each process as a snapshot of it to whoever subscribed.
+pub struct Jobs {
+    pool: PgPool,
+    events: broadcast::Sender<Job>,
+    workers: usize,
+    // running only jobs of their priority, none unless set with `with_reserved_workers`
+    high_workers: usize,
+    low_workers: usize,
+    // where results are made before they go to `storage`
+    output_dir: PathBuf,
+    storage: Arc<dyn Storage>,
+    canaries: Arc<Canaries>,
+    // what transcodes may run on, software only unless set with `with_hardware`
+    hardware: Hardware,
+    // runs after the first of failing jobs, none unless set with `with_retries`
+    retries: u32,
+    // before the first retry, doubling for each after it up to `MAX_RETRY_BACKOFF`
+    backoff: std::time::Duration,
+    // tell of jobs once done, nobody unless set with `with_webhooks`
+    webhooks: Option<Arc<Webhooks>>,
+    // publishes them once done, see `event::Topic::JobFinished`; none unless set with `with_bus`
+    bus: Option<Arc<Bus>>,
+    // wake idle shared workers, and those reserved for each priority by rank
+    queued: Notify,
+    reserved: [Notify; 3],
+    // progress as last stored, of the jobs running here
+    progress: Mutex<HashMap<i64, f32>>,
+}
+
+impl Jobs {
+    pub fn new(
+        pool: PgPool,
+        workers: usize,
+        output_dir: impl Into<PathBuf>,
+        storage: Arc<dyn Storage>,
+        canaries: Arc<Canaries>,
+    ) -> Self {
+        Jobs {
+            pool,
+            events: broadcast::channel(EVENT_BUFFER).0,
+            workers: workers.max(1),
+            high_workers: 0,
+            low_workers: 0,
+            output_dir: output_dir.into(),
+            storage,
+            canaries,
+            hardware: Hardware::default(),
+            retries: 0,
+            backoff: std::time::Duration::ZERO,
+            webhooks: None,
+            bus: None,
+            queued: Notify::new(),
+            reserved: Default::default(),
+            progress: Mutex::default(),
+        }
+    }
+
+    pub fn with_hardware(mut self, hardware: Hardware) -> Self {
+        self.hardware = hardware;
+        self
+    }
+
+    pub fn with_reserved_workers(mut self, high: usize, low: usize) -> Self {
+        self.high_workers = high;
+        self.low_workers = low;
+        self
+    }
+
+    pub fn with_webhooks(mut self, webhooks: Arc<Webhooks>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    // whether jobs may be submitted with `url` as their callback URL
+    pub fn check_callback(&self, url: &str) -> Result<(), String> {
+        match &self.webhooks {
+            Some(webhooks) => webhooks.check_callback(url),
+            None => Err("jobs aren't delivered anywhere here".to_owned()),
+        }
+    }
+
+    pub fn with_bus(mut self, bus: Arc<Bus>) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32, backoff: std::time::Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    // the wait after failed run `attempt`, counting from 1
+    fn backoff(&self, attempt: i32) -> std::time::Duration {
+        let factor = 2_u32.saturating_pow(attempt.max(1) as u32 - 1);
+        self.backoff.saturating_mul(factor).min(MAX_RETRY_BACKOFF)
+    }
+
+    // the transcoder implementation for a request, see `canary::TRANSCODER`
+    pub fn transcoder(&self, headers: &HeaderMap, user: i64) -> Variant {
+        self.canaries.pick(canary::TRANSCODER, headers, user)
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<Job>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Row>("SELECT * FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(Job::from))
+    }
+
+    // jobs of `user`, of one `state` if given, the latest first
+    pub async fn list(
+        &self,
+        user: i64,
+        state: Option<JobState>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Job>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(
+            "SELECT * FROM jobs WHERE user_id = $1 AND ($2::text IS NULL OR state = $2)
+                ORDER BY id DESC LIMIT $3 OFFSET $4",
+        )
+        .bind(user)
+        .bind(state.map(JobState::as_str))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Job::from).collect())
+    }
+
+    // the states jobs `ids` went through, in order, by job
+    pub async fn history(
+        &self,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<StateChange>>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (i64, String, Option<String>, DateTime<Utc>)>(
+            "SELECT job_id, state, error, at FROM job_history WHERE job_id = ANY($1) ORDER BY id",
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut history: HashMap<i64, Vec<StateChange>> = HashMap::new();
+        for (id, state, error, at) in rows {
+            history.entry(id).or_default().push(StateChange {
+                // the table of jobs allows no other states
+                state: JobState::from_name(&state).unwrap_or(JobState::Failed),
+                error,
+                at,
+            });
+        }
+        Ok(history)
+    }
+
+    // finished jobs of `asset_id`, whose results are derivatives of it, the latest first
+    pub async fn derivatives(&self, asset_id: i64) -> Result<Vec<Job>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(
+            "SELECT * FROM jobs WHERE state = 'finished' AND (kind->>'asset_id')::bigint = $1
+                ORDER BY updated_at DESC",
+        )
+        .bind(asset_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Job::from).collect())
+    }
+
+    // a link to the result of the finished `job`, working for an hour
+    pub fn download_url(&self, job: &Job) -> Result<String, String> {
+        self.storage
+            .download_url(&output_name(job.id, &job.kind), DOWNLOAD_TTL)
+    }
+
+    // snapshots of jobs as they change, wherever they run
+    pub fn subscribe(&self) -> broadcast::Receiver<Job> {
+        self.events.subscribe()
+    }
+
+    pub fn output_dir(&self) -> &std::path::Path {
+        &self.output_dir
+    }
+
+    pub async fn queue_depth(&self) -> Result<QueueDepth, sqlx::Error> {
+        sqlx::query_as::<_, QueueDepth>(
+            "SELECT
+                    count(*) FILTER (WHERE state = 'queued' AND ready AND priority = 2) AS high,
+                    count(*) FILTER (WHERE state = 'queued' AND ready AND priority = 1) AS normal,
+                    count(*) FILTER (WHERE state = 'queued' AND ready AND priority = 0) AS low,
+                    count(*) FILTER (WHERE state = 'queued' AND NOT ready) AS backing_off,
+                    count(*) FILTER (WHERE state = 'running') AS running
+                FROM (
+                    SELECT state, priority, run_after IS NULL OR run_after <= now() AS ready
+                        FROM jobs WHERE state IN ('queued', 'running')
+                ) AS waiting",
+        )
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    // dead jobs, the latest first
+    pub async fn dead(&self, limit: i64, offset: i64) -> Result<Vec<Job>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(
+            "SELECT * FROM jobs WHERE state = 'dead' ORDER BY id DESC LIMIT $1 OFFSET $2",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Job::from).collect())
+    }
+
+    // queues dead job `id` again, with all its retries; None unless it's dead
+    pub async fn requeue(&self, id: i64) -> Result<Option<Job>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Row>(
+            "UPDATE jobs SET state = 'queued', attempts = 0, run_after = NULL, worker = NULL,
+                progress = 0, error = NULL, updated_at = now()
+                WHERE id = $1 AND state = 'dead'
+                RETURNING *",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let job = row.map(Job::from);
+        if let Some(job) = &job {
+            self.wake(job.priority);
+        }
+        Ok(job)
+    }
+
+    // forgets finished and failed jobs last changed before `before`, returns how many; dead
+    // ones stay for admins to look into
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM jobs WHERE state IN ('finished', 'failed') AND updated_at < $1",
+        )
+        .bind(before)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() as usize)
+    }
+
+    // seconds of media the user had transcoded since then, failed attempts aside
+    pub async fn transcode_seconds(
+        &self,
+        user: i64,
+        since: DateTime<Utc>,
+    ) -> Result<f64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(SUM(media_seconds), 0) FROM jobs
+                WHERE user_id = $1 AND created_at > $2 AND state NOT IN ('failed', 'dead')",
+        )
+        .bind(user)
+        .bind(since)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    // queues `kind` to work on the media file at `input`
+    pub async fn submit(
+        &self,
+        user: i64,
+        kind: JobKind,
+        priority: Priority,
+        callback_url: Option<String>,
+        input: PathBuf,
+        media_seconds: Option<f64>,
+        variant: Variant,
+    ) -> Result<Job, sqlx::Error> {
+        let row = sqlx::query_as::<_, Row>(
+            "INSERT INTO jobs (user_id, kind, priority, callback_url, input, variant, media_seconds)
+                VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+        )
+        .bind(user)
+        .bind(Jsonb(&kind))
+        .bind(priority.rank())
+        .bind(callback_url)
+        .bind(input.to_string_lossy())
+        .bind(variant.as_str())
+        .bind(media_seconds)
+        .fetch_one(&self.pool)
+        .await?;
+        self.wake(priority);
+        Ok(row.into())
+    }
+
+    // wakes a shared worker and one reserved for `priority`, whichever gets to the job first
+    fn wake(&self, priority: Priority) {
+        self.queued.notify_one();
+        self.reserved[priority.rank() as usize].notify_one();
+    }
+
+    // broadcasts changes of jobs announced by the database for as long as the jobs are in use,
+    // or until `shutdown` turns true
+    pub fn listen(self: &Arc<Self>, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        let jobs = Arc::downgrade(self);
+        let pool = self.pool.clone();
+        task::spawn("job change listener", task::Kind::Work, async move {
+            let listening = async {
+                while let Err(err) = listen(&jobs, &pool).await {
+                    warn!("listening for job changes failed: {}", err);
+                    sleep(POLL_INTERVAL).await;
+                }
+            };
+            tokio::select! {
+                _ = listening => {}
+                // a dropped sender counts as shutting down too
+                _ = shutdown.wait_for(|stop| *stop) => {}
+            }
+        })
+    }
+
+    async fn announced(&self, payload: &str) {
+        let Ok(id) = payload.parse() else {
+            return;
+        };
+        match self.get(id).await {
+            Ok(Some(job)) => {
+                if job.state == JobState::Queued {
+                    self.wake(job.priority);
+                }
+                // nobody listening is fine
+                let _ = self.events.send(job);
+            }
+            // pruned since
+            Ok(None) => {}
+            Err(err) => warn!("reading job {} failed: {}", id, err),
+        }
+    }
+
+    // Runs queued jobs as instance `worker` until `shutdown` turns true. Jobs running by then
+    // get to finish, the returned handle completes once all of them did. Jobs of an instance
+    // that stopped without finishing them are queued again.
+    pub fn work(self: &Arc<Self>, worker: &str, shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        info!(
+            "running jobs, {} at a time plus {} for high and {} for low priority ones only",
+            self.workers, self.high_workers, self.low_workers
+        );
+        let pools = [
+            (Pool::Shared, self.workers),
+            (Pool::Reserved(Priority::High), self.high_workers),
+            (Pool::Reserved(Priority::Low), self.low_workers),
+        ];
+        let mut tasks = JoinSet::new();
+        for (pool, workers) in pools {
+            for _ in 0..workers {
+                let work = self
+                    .clone()
+                    .work_on(pool, worker.to_owned(), shutdown.clone());
+                tasks.spawn(work);
+            }
+        }
+        tasks.spawn(self.clone().requeue_orphans(shutdown));
+        task::spawn("job workers", task::Kind::Work, async move {
+            while tasks.join_next().await.is_some() {}
+        })
+    }
+
+    async fn work_on(
+        self: Arc<Self>,
+        pool: Pool,
+        worker: String,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let queued = match pool {
+            Pool::Shared => &self.queued,
+            Pool::Reserved(priority) => &self.reserved[priority.rank() as usize],
+        };
+        while !*shutdown.borrow() {
+            match self.claim(pool, &worker).await {
+                Ok(Some(job)) => {
+                    self.execute(job).await;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err) => warn!("looking for queued jobs failed: {}", err),
+            }
+            tokio::select! {
+                _ = queued.notified() => {}
+                _ = sleep(POLL_INTERVAL) => {}
+                // a dropped sender counts as shutting down too
+                changed = shutdown.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+
+    // the oldest queued job of the highest priority in `pool` not backing off, now running on
+    // `worker`
+    async fn claim(&self, pool: Pool, worker: &str) -> Result<Option<Job>, sqlx::Error> {
+        let (lowest, highest) = pool.ranks();
+        let row = sqlx::query_as::<_, Row>(
+            "UPDATE jobs SET state = 'running', worker = $1, attempts = attempts + 1,
+                run_after = NULL, updated_at = now()
+                WHERE id = (
+                    SELECT id FROM jobs
+                        WHERE state = 'queued' AND (run_after IS NULL OR run_after <= now())
+                            AND priority BETWEEN $2 AND $3
+                        ORDER BY priority DESC, id LIMIT 1 FOR UPDATE SKIP LOCKED
+                )
+                RETURNING *",
+        )
+        .bind(worker)
+        .bind(lowest)
+        .bind(highest)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Job::from))
+    }
+
+    async fn execute(self: &Arc<Self>, job: Job) {
+        let id = job.id;
+        let key = output_name(id, &job.kind);
+        let output = self.output_dir.join(&key);
+        let transcode = matches!(job.kind, JobKind::Transcode { .. });
+        let (variant, attempts) = (job.variant, job.attempts);
+        let started = Instant::now();
+        let _job = leak::hold(leak::Kind::Job, format!("job {}", id));
+        let temp_file = leak::hold(
+            leak::Kind::TempFile,
+            format!("job {}, at {}", id, output.display()),
+        );
+        let worker = self.clone();
+        let handle = Handle::current();
+        let result = {
+            let output = output.clone();
+            tokio::task::spawn_blocking(move || worker.run(&job, &handle, output))
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()))
+        };
+        if transcode {
+            let took = started.elapsed();
+            let ok = result.is_ok();
+            self.canaries.record(canary::TRANSCODER, variant, took, ok);
+        }
+        self.progress.lock().unwrap().remove(&id);
+
+        let stored = match result {
+            Ok(()) => self
+                .storage
+                .put(&key, &output)
+                .await
+                .map_err(|err| format!("storing the result failed: {}", err)),
+            Err(err) => Err(err),
+        };
+        if stored.is_err() {
+            // whatever was made of it, if anything
+            let _ = tokio::fs::remove_file(&output).await;
+        }
+        drop(temp_file);
+        let (state, output, error) = match stored {
+            Ok(location) => (JobState::Finished, Some(location), None),
+            Err(err) if self.retries > 0 && attempts <= self.retries as i32 => {
+                let backoff = self.backoff(attempts);
+                warn!("job {} failed, retrying in {:?}: {}", id, backoff, err);
+                self.retry(id, &err, backoff).await;
+                return;
+            }
+            Err(err) if self.retries > 0 => {
+                warn!("job {} failed its last retry: {}", id, err);
+                (JobState::Dead, None, Some(err))
+            }
+            Err(err) => {
+                warn!("job {} failed: {}", id, err);
+                (JobState::Failed, None, Some(err))
+            }
+        };
+        let stored = sqlx::query_as::<_, Row>(
+            "UPDATE jobs SET state = $2, output = $3, error = $4, updated_at = now(),
+                progress = CASE WHEN $2 = 'finished' THEN 1 ELSE progress END
+                WHERE id = $1
+                RETURNING *",
+        )
+        .bind(id)
+        .bind(state.as_str())
+        .bind(output)
+        .bind(error)
+        .fetch_one(&self.pool)
+        .await;
+        match stored {
+            Ok(row) => self.done(row.into()),
+            Err(err) => warn!("storing the outcome of job {} failed: {}", id, err),
+        }
+    }
+
+    // delivers `job`, which finished, failed or died, to its webhook and the bus
+    fn done(&self, job: Job) {
+        if let Some(bus) = &self.bus {
+            let published: v1::Job = job.clone().to_version();
+            let data = serde_json::to_value(published).unwrap_or_default();
+            bus.publish(Topic::JobFinished, &data);
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(job);
+        }
+    }
+
+    // queues job `id` again once `backoff` passed, with why it failed
+    async fn retry(&self, id: i64, error: &str, backoff: std::time::Duration) {
+        let queued = sqlx::query(
+            "UPDATE jobs SET state = 'queued', worker = NULL, progress = 0, error = $2,
+                run_after = now() + make_interval(secs => $3), updated_at = now()
+                WHERE id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .bind(backoff.as_secs_f64())
+        .execute(&self.pool)
+        .await;
+        if let Err(err) = queued {
+            warn!("queueing job {} for a retry failed: {}", id, err);
+        }
+    }
+
+    fn run(&self, job: &Job, handle: &Handle, output: PathBuf) -> Result<(), String> {
+        std::fs::create_dir_all(&self.output_dir).map_err(|err| err.to_string())?;
+        let mut progress = |done: f32| self.progress(job.id, done, handle);
+        let (kind, variant) = (&job.kind, job.variant);
+        perform(
+            kind,
+            variant,
+            &self.hardware,
+            &job.input,
+            &output,
+            &mut progress,
+        )
+    }
+
+    // stores progress of a job running on this blocking thread, only per percent since media
+    // work reports far more often than that
+    fn progress(&self, id: i64, done: f32, handle: &Handle) {
+        if !self.progressed(id, done) {
+            return;
+        }
+        let stored = handle.block_on(
+            sqlx::query("UPDATE jobs SET progress = $2, updated_at = now() WHERE id = $1")
+                .bind(id)
+                .bind(done)
+                .execute(&self.pool),
+        );
+        if let Err(err) = stored {
+            warn!("storing the progress of job {} failed: {}", id, err);
+        }
+    }
+
+    // whether `done` is at least a percent past the progress last stored for the job, which it
+    // then becomes
+    fn progressed(&self, id: i64, done: f32) -> bool {
+        let percent = |progress: f32| (progress * 100.0) as u32;
+        let mut progress = self.progress.lock().unwrap();
+        let current = progress.entry(id).or_insert(0.0);
+        if percent(done) > percent(*current) {
+            *current = done;
+            true
+        } else {
+            false
+        }
+    }
+
+    // queues jobs again whose instance stopped heartbeating while running them; their work so
+    // far is lost. With retries, that counts as a failed run: jobs that take down their
+    // instance every time end up dead.
+    async fn requeue_orphans(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        while !*shutdown.borrow() {
+            let requeued = sqlx::query_as::<_, Row>(
+                "UPDATE jobs SET worker = NULL, progress = 0, updated_at = now(),
+                        state = CASE WHEN $2 > 0 AND attempts > $2 THEN 'dead' ELSE 'queued' END,
+                        error = CASE WHEN $2 > 0 AND attempts > $2
+                            THEN 'its instance stopped while running it' ELSE error END
+                    WHERE state = 'running'
+                        AND updated_at < now() - make_interval(secs => $1)
+                        AND worker NOT IN (
+                            SELECT id FROM instances
+                                WHERE last_seen_at > now() - make_interval(secs => $1)
+                        )
+                    RETURNING *",
+            )
+            .bind(instance::ACTIVE_SECS)
+            .bind(self.retries as i32)
+            .fetch_all(&self.pool)
+            .await;
+            match requeued {
+                Ok(rows) if !rows.is_empty() => {
+                    warn!(
+                        "queued {} jobs of instances that are gone again",
+                        rows.len()
+                    );
+                    for job in rows.into_iter().map(Job::from) {
+                        match job.state {
+                            JobState::Queued => self.wake(job.priority),
+                            _ => self.done(job),
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => warn!("looking for abandoned jobs failed: {}", err),
+            }
+            tokio::select! {
+                _ = sleep(ORPHAN_INTERVAL) => {}
+                changed = shutdown.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+}
+
+async fn listen(jobs: &Weak<Jobs>, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    let _connection = leak::hold(leak::Kind::DbConnection, "the job change listener");
+    listener.listen(CHANNEL).await?;
+    loop {
+        let notification = listener.recv().await?;
+        let Some(jobs) = jobs.upgrade() else {
+            return Ok(());
+        };
+        jobs.announced(notification.payload()).await;
+    }
+}
+
+// the media work of a job, on the current thread; transcodes on hardware that fail are done
+// again in software
+pub fn perform(
+    kind: &JobKind,
+    variant: Variant,
+    hardware: &Hardware,
+    input: &std::path::Path,
+    output: &std::path::Path,
+    progress: &mut dyn FnMut(f32),
+) -> Result<(), String> {
+    if let JobKind::Transcode {
+        codec,
+        overlay,
+        hwaccel,
+        ..
+    } = kind
+    {
+        if let Some(hwaccel) = hardware.pick(*hwaccel, *codec) {
+            let on = Some((hwaccel, hardware));
+            match media::transcode_cli(input, output, *codec, overlay.as_ref(), on, progress) {
+                Ok(()) => return Ok(()),
+                Err(err) => warn!(
+                    "transcoding on {} failed, in software instead: {}",
+                    hwaccel.name(),
+                    err
+                ),
+            }
+        }
+    }
+    match (kind, variant) {
+        (JobKind::Transcode { codec, overlay, .. }, Variant::Stable) => {
+            media::transcode(input, output, *codec, overlay.as_ref(), progress)
+                .map_err(|err| err.to_string())
+        }
+        (JobKind::Transcode { codec, overlay, .. }, Variant::Canary) => {
+            media::transcode_cli(input, output, *codec, overlay.as_ref(), None, progress)
+        }
+        (
+            JobKind::Thumbnail {
+                time,
+                width,
+                format,
+                ..
+            },
+            _,
+        ) => media::thumbnail(input, output, *time, *width, *format).map_err(|err| err.to_string()),
+        (
+            JobKind::Sprites {
+                count,
+                columns,
+                width,
+                format,
+                ..
+            },
+            _,
+        ) => media::sprite_sheet(input, output, *count, *columns, *width, *format, progress)
+            .map_err(|err| err.to_string()),
+    }
+}
+
+fn output_name(id: i64, kind: &JobKind) -> String {
+    format!("{}.{}", id, kind.extension())
+}
+
+impl JobKind {
+    pub fn asset_id(&self) -> i64 {
+        match self {
+            JobKind::Transcode { asset_id, .. }
+            | JobKind::Thumbnail { asset_id, .. }
+            | JobKind::Sprites { asset_id, .. } => *asset_id,
+        }
+    }
+
+    // of the result
+    pub fn extension(&self) -> &'static str {
+        match self {
+            JobKind::Transcode { .. } => "mp4",
+            JobKind::Thumbnail { format, .. } | JobKind::Sprites { format, .. } => {
+                format.extension()
+            }
+        }
+    }
+
+    // what can't be worked on, whatever the media
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            JobKind::Sprites {
+                count,
+                columns,
+                width,
+                ..
+            } => {
+                if !(1..=MAX_SPRITES).contains(count) {
+                    return Err(format!("count must be from 1 to {}", MAX_SPRITES));
+                }
+                if *columns == 0 {
+                    return Err("columns must be at least 1".to_owned());
+                }
+                if !(16..=MAX_SPRITE_WIDTH).contains(width) {
+                    return Err(format!("width must be from 16 to {}", MAX_SPRITE_WIDTH));
+                }
+                Ok(())
+            }
+            JobKind::Transcode {
+                overlay: Some(overlay),
+                ..
+            } => overlay.check(),
+            JobKind::Transcode { .. } | JobKind::Thumbnail { .. } => Ok(()),
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/:id", get(get_job))
+        .route("/jobs/:id/download", get(download_output))
+        .route("/jobs/:id/thumbnails.vtt", get(thumbnails_track))
+}
+
+// the progress stream is for browsers and not versioned with the JSON API
+pub fn ws_routes() -> Router<AppState> {
+    Router::new().route("/ws/jobs/:id", get(watch_job))
+}
+
+pub fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/jobs/dead", get(dead_jobs))
+        .route("/admin/jobs/:id/requeue", post(requeue_job))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/jobs",
+    request_body = SubmitJob,
+    responses(
+        (status = 202, description = "Job queued", body = v1::Job),
+        (status = 422, description = "Unknown asset or overlay image, media that can't be read, sprite sheet or overlay bounds exceeded, or a callback URL that isn't HTTP, isn't public or isn't signed for"),
+        (status = 429, description = "Over the rate plan's transcode minutes for the day"),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, priority = ?submitted.priority))]
+async fn submit_job(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(jobs): State<Arc<Jobs>>,
+    State(plans): State<Arc<RatePlans>>,
+    State(probes): State<Arc<Probes>>,
+    State(clock): State<Arc<dyn Clock>>,
+    CurrentUser(user): CurrentUser,
+    headers: HeaderMap,
+    Json(submitted): Json<SubmitJob>,
+) -> Result<(StatusCode, Json<v1::Job>), Response> {
+    let SubmitJob {
+        kind,
+        priority,
+        callback_url,
+    } = submitted;
+    kind.check()
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err).into_response())?;
+    if let Some(url) = &callback_url {
+        jobs.check_callback(url)
+            .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err).into_response())?;
+    }
+    let file = media.find(kind.asset_id()).await.map_err(|err| match err {
+        sqlx::Error::RowNotFound => {
+            (StatusCode::UNPROCESSABLE_ENTITY, "unknown asset".to_owned()).into_response()
+        }
+        err => db_error(err).into_response(),
+    })?;
+    let input = PathBuf::from(&file.path);
+    if let JobKind::Transcode {
+        overlay: Some(overlay),
+        ..
+    } = &kind
+    {
+        media
+            .find_by_path(&overlay.image)
+            .await
+            .map_err(|err| match err {
+                sqlx::Error::RowNotFound => {
+                    let message = "unknown overlay image".to_owned();
+                    (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+                }
+                err => db_error(err).into_response(),
+            })?;
+    }
+
+    let mut media_seconds = None;
+    let mut variant = Variant::Stable;
+    if let JobKind::Transcode { .. } = kind {
+        variant = jobs.transcoder(&headers, user);
+        let seconds = asset::metadata(&*media, &probes, file, false)
+            .await
+            .map_err(IntoResponse::into_response)?
+            .duration
+            .unwrap_or(0.0);
+        if let Some(plan) = plans.for_user(user) {
+            let used = jobs
+                .transcode_seconds(user, clock.now() - Duration::days(1))
+                .await
+                .map_err(|err| db_error(err).into_response())?;
+            plan.check_transcode(used, seconds)
+                .map_err(IntoResponse::into_response)?;
+        }
+        media_seconds = Some(seconds);
+    }
+
+    let job = jobs
+        .submit(
+            user,
+            kind,
+            priority,
+            callback_url,
+            input,
+            media_seconds,
+            variant,
+        )
+        .await
+        .map_err(|err| db_error(err).into_response())?;
+    Ok((StatusCode::ACCEPTED, Json(job.to_version())))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs",
+    params(ListJobs),
+    responses(
+        (status = 200, description = "Jobs of the user, the latest first, with every state each got into and why it failed", body = [v1::JobWithHistory]),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, state = ?query.state))]
+async fn list_jobs(
+    State(jobs): State<Arc<Jobs>>,
+    CurrentUser(user): CurrentUser,
+    Query(query): Query<ListJobs>,
+) -> Result<(Extension<v2::Meta>, Json<Vec<v1::JobWithHistory>>), (StatusCode, String)> {
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    let listed = jobs
+        .list(user, query.state, limit, offset)
+        .await
+        .map_err(db_error)?;
+    let ids: Vec<i64> = listed.iter().map(|job| job.id).collect();
+    let mut history = jobs.history(&ids).await.map_err(db_error)?;
+    let listed: Vec<v1::JobWithHistory> = listed
+        .into_iter()
+        .map(|job| {
+            let history = history.remove(&job.id).unwrap_or_default();
+            (job, history).to_version()
+        })
+        .collect();
+
+    // the page, for clients of v2
+    let meta = v2::Meta(json!({ "limit": limit, "offset": offset, "count": listed.len() }));
+    Ok((Extension(meta), Json(listed)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}",
+    params(("id" = i64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job's current state", body = v1::Job),
+        (status = 404, description = "No such job"),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn get_job(
+    State(jobs): State<Arc<Jobs>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Json<v1::Job>, (StatusCode, String)> {
+    Ok(Json(own_job(&jobs, user, id).await?.to_version()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}/download",
+    params(("id" = i64, Path, description = "Job id")),
+    responses(
+        (status = 307, description = "Redirect to a link to the result, working for an hour"),
+        (status = 404, description = "No such job, or it hasn't finished"),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn download_output(
+    State(jobs): State<Arc<Jobs>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let job = own_job(&jobs, user, id).await?;
+    if job.state != JobState::Finished {
+        return Err((StatusCode::NOT_FOUND, "the job hasn't finished".to_owned()));
+    }
+    let url = jobs
+        .download_url(&job)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    Ok(Redirect::temporary(&url))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/jobs/{id}/thumbnails.vtt",
+    params(("id" = i64, Path, description = "Job id, of a sprite sheet")),
+    responses(
+        (status = 200, description = "WebVTT thumbnails track of the sprite sheet, cues pointing at its tiles by `#xywh=`, working for an hour", content_type = "text/vtt"),
+        (status = 404, description = "No such job, not a sprite sheet, or it hasn't finished"),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn thumbnails_track(
+    State(jobs): State<Arc<Jobs>>,
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<([(header::HeaderName, &'static str); 2], String), (StatusCode, String)> {
+    let job = own_job(&jobs, user, id).await?;
+    let JobKind::Sprites {
+        asset_id,
+        count,
+        columns,
+        width,
+        ..
+    } = job.kind
+    else {
+        return Err((StatusCode::NOT_FOUND, "not a sprite sheet".to_owned()));
+    };
+    if job.state != JobState::Finished {
+        return Err((StatusCode::NOT_FOUND, "the job hasn't finished".to_owned()));
+    }
+    let file = media.find(asset_id).await.map_err(db_error)?;
+    let metadata = asset::metadata(&*media, &probes, file, false).await?;
+    // laid out from the video as the sheet was
+    let (source_width, source_height) = metadata
+        .streams
+        .iter()
+        .find(|stream| stream.medium == "video")
+        .and_then(|stream| Some((stream.width?, stream.height?)))
+        .ok_or((StatusCode::NOT_FOUND, "the asset has no video".to_owned()))?;
+    let layout = SpriteLayout::new(source_width, source_height, count, columns, width);
+    let url = jobs
+        .download_url(&job)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let track = thumbnails_vtt(&url, &layout, metadata.duration.unwrap_or(0.0));
+    let headers = [
+        (header::CONTENT_TYPE, "text/vtt; charset=utf-8"),
+        // the link in it expires
+        (header::CACHE_CONTROL, "private, max-age=600"),
+    ];
+    Ok((headers, track))
+}
+
+// a WebVTT track with a cue per tile of the sprite sheet at `url`, over `duration` seconds
+fn thumbnails_vtt(url: &str, layout: &SpriteLayout, duration: f64) -> String {
+    let times = media::sprite_times(duration, layout.count);
+    let mut track = "WEBVTT\n".to_owned();
+    for (i, start) in times.iter().enumerate() {
+        let end = times.get(i + 1).copied().unwrap_or(duration.max(*start));
+        let (x, y) = layout.tile(i as u32);
+        track.push_str(&format!(
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            cue_time(*start),
+            cue_time(end),
+            url,
+            x,
+            y,
+            layout.tile_width,
+            layout.tile_height
+        ));
+    }
+    track
+}
+
+// like `01:02:03.456`
+fn cue_time(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs/dead",
+    params(ListDeadJobs),
+    responses(
+        (status = 200, description = "Jobs that failed every retry, the latest first, with every state each got into and why it failed", body = [v1::JobWithHistory]),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+#[instrument(skip_all)]
+async fn dead_jobs(
+    _: Admin,
+    State(jobs): State<Arc<Jobs>>,
+    Query(query): Query<ListDeadJobs>,
+) -> Result<Json<Vec<v1::JobWithHistory>>, (StatusCode, String)> {
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    let dead = jobs.dead(limit, offset).await.map_err(db_error)?;
+    let ids: Vec<i64> = dead.iter().map(|job| job.id).collect();
+    let mut history = jobs.history(&ids).await.map_err(db_error)?;
+    let dead = dead
+        .into_iter()
+        .map(|job| {
+            let history = history.remove(&job.id).unwrap_or_default();
+            (job, history).to_version()
+        })
+        .collect();
+    Ok(Json(dead))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/jobs/{id}/requeue",
+    params(("id" = i64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job, queued again with all its retries", body = v1::Job),
+        (status = 404, description = "No such dead job"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn requeue_job(
+    _: Admin,
+    State(jobs): State<Arc<Jobs>>,
+    Path(id): Path<i64>,
+) -> Result<Json<v1::Job>, (StatusCode, String)> {
+    let job = jobs.requeue(id).await.map_err(db_error)?;
+    let job = job.ok_or((StatusCode::NOT_FOUND, "no such dead job".to_owned()))?;
+    Ok(Json(job.to_version()))
+}
+
+// jobs of other users don't exist as far as the caller is concerned
+async fn own_job(jobs: &Jobs, user: i64, id: i64) -> Result<Job, (StatusCode, String)> {
+    jobs.get(id)
+        .await
+        .map_err(db_error)?
+        .filter(|job| job.user_id == user)
+        .ok_or((StatusCode::NOT_FOUND, "not found".to_owned()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/ws/jobs/{id}",
+    params(("id" = i64, Path, description = "Job id")),
+    responses(
+        (status = 101, description = "WebSocket sending the job as JSON text messages whenever it changes, closed once it finished or failed"),
+        (status = 404, description = "No such job"),
+    ),
+    security(("user_id" = [])),
+    tag = "jobs"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn watch_job(
+    State(jobs): State<Arc<Jobs>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    own_job(&jobs, user, id).await?;
+    Ok(ws.on_upgrade(move |socket| stream_job(socket, jobs, id)))
+}
+
+async fn stream_job(mut socket: WebSocket, jobs: Arc<Jobs>, id: i64) {
+    let mut events = jobs.subscribe();
+    // taken after subscribing, so no change falls in between
+    let Ok(Some(mut job)) = jobs.get(id).await else {
+        return;
+    };
+    if send(&mut socket, job.clone()).await.is_err() {
+        return;
+    }
+    while !job.state.is_done() {
+        tokio::select! {
+            event = events.recv() => {
+                job = match event {
+                    Ok(event) if event.id == id => event,
+                    Ok(_) => continue,
+                    // fell behind, carry on from the current state
+                    Err(broadcast::error::RecvError::Lagged(_)) => match jobs.get(id).await {
+                        Ok(Some(job)) => job,
+                        _ => break,
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if send(&mut socket, job.clone()).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // nothing to say to us, pings are answered by axum
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn send(socket: &mut WebSocket, job: Job) -> Result<(), axum::Error> {
+    let v1: v1::Job = job.to_version();
+    let text = serde_json::to_string(&v1).unwrap_or_default();
+    socket.send(Message::Text(text)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SystemClock, storage};
+
+    #[test]
+    fn kinds() {
+        let kind: JobKind =
+            serde_json::from_str(r#"{"type": "thumbnail", "asset_id": 3, "width": 320}"#).unwrap();
+        assert_eq!(
+            kind,
+            JobKind::Thumbnail {
+                asset_id: 3,
+                time: 0.0,
+                width: Some(320),
+                format: ImageFormat::Jpeg,
+            }
+        );
+        assert_eq!(output_name(7, &kind), "7.jpg");
+        let kind: JobKind =
+            serde_json::from_str(r#"{"type": "thumbnail", "asset_id": 3, "format": "webp"}"#)
+                .unwrap();
+        assert_eq!(output_name(7, &kind), "7.webp");
+        let kind: JobKind = serde_json::from_str(r#"{"type": "sprites", "asset_id": 3}"#).unwrap();
+        assert_eq!(
+            kind,
+            JobKind::Sprites {
+                asset_id: 3,
+                count: 100,
+                columns: 10,
+                width: 160,
+                format: ImageFormat::Jpeg,
+            }
+        );
+        assert_eq!(kind.check(), Ok(()));
+        let kind: JobKind =
+            serde_json::from_str(r#"{"type": "sprites", "asset_id": 3, "count": 0}"#).unwrap();
+        assert!(kind.check().is_err());
+        let json = r#"{"type": "transcode", "asset_id": 3, "codec": "av1", "hwaccel": "nvenc"}"#;
+        let kind: JobKind = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            kind,
+            JobKind::Transcode {
+                asset_id: 3,
+                codec: VideoCodec::Av1,
+                overlay: None,
+                hwaccel: Some(Hwaccel::Nvenc),
+            }
+        );
+        assert!(!JobState::Running.is_done());
+        assert!(JobState::Failed.is_done());
+        assert!(JobState::Dead.is_done());
+    }
+
+    #[test]
+    fn priorities() {
+        let json = r#"{"type": "thumbnail", "asset_id": 3, "priority": "high"}"#;
+        let submitted: SubmitJob = serde_json::from_str(json).unwrap();
+        assert_eq!(submitted.priority, Priority::High);
+        assert_eq!(submitted.kind.asset_id(), 3);
+        let submitted: SubmitJob =
+            serde_json::from_str(r#"{"type": "sprites", "asset_id": 3}"#).unwrap();
+        assert_eq!(submitted.priority, Priority::Normal);
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            assert_eq!(Priority::from_rank(priority.rank()), priority);
+        }
+        assert_eq!(Pool::Shared.ranks(), (0, 2));
+        assert_eq!(Pool::Reserved(Priority::High).ranks(), (2, 2));
+    }
+
+    #[test]
+    fn thumbnails_tracks() {
+        let layout = SpriteLayout::new(1920, 1080, 3, 2, 160);
+        let track = thumbnails_vtt("https://cdn/5.jpg?sig=x", &layout, 3725.5);
+        assert_eq!(
+            track,
+            "WEBVTT\n\
+            \n00:00:00.000 --> 00:20:41.833\nhttps://cdn/5.jpg?sig=x#xywh=0,0,160,90\n\
+            \n00:20:41.833 --> 00:41:23.667\nhttps://cdn/5.jpg?sig=x#xywh=160,0,160,90\n\
+            \n00:41:23.667 --> 01:02:05.500\nhttps://cdn/5.jpg?sig=x#xywh=0,90,160,90\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_is_stored_per_percent() {
+        let pool = PgPool::connect_lazy("postgres://localhost/rsapp").unwrap();
+        let dir = std::env::temp_dir();
+        let storage = storage::open(&storage::Settings::default(), &dir, Arc::new(SystemClock));
+        let jobs = Jobs::new(pool, 1, dir, storage, Arc::default());
+        let stored: Vec<f32> = [0.001, 0.5, 0.501, 0.509, 0.51]
+            .into_iter()
+            .filter(|done| jobs.progressed(1, *done))
+            .collect();
+        assert_eq!(stored, vec![0.5, 0.51]);
+        // per job
+        assert!(jobs.progressed(2, 0.02));
+    }
+
+    #[tokio::test]
+    async fn retries_back_off() {
+        let pool = PgPool::connect_lazy("postgres://localhost/rsapp").unwrap();
+        let dir = std::env::temp_dir();
+        let storage = storage::open(&storage::Settings::default(), &dir, Arc::new(SystemClock));
+        let backoff = std::time::Duration::from_secs(30);
+        let jobs = Jobs::new(pool, 1, dir, storage, Arc::default()).with_retries(3, backoff);
+        let waits: Vec<u64> = (1..=4).map(|run| jobs.backoff(run).as_secs()).collect();
+        assert_eq!(waits, vec![30, 60, 120, 240]);
+        assert_eq!(jobs.backoff(100), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn states() {
+        for state in [
+            JobState::Queued,
+            JobState::Running,
+            JobState::Finished,
+            JobState::Failed,
+            JobState::Dead,
+        ] {
+            assert_eq!(JobState::from_name(state.as_str()), Some(state));
+            assert_eq!(
+                serde_json::to_value(state).unwrap(),
+                serde_json::Value::from(state.as_str())
+            );
+        }
+        assert_eq!(JobState::from_name("done"), None);
+    }
+}
@@ -0,0 +1,106 @@
+// Keyframes and scene changes of videos, for editing UIs to snap cuts and seeks to. Keyframes
+// alone come from the packets, scene changes need every frame decoded, in the same pass.
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{app::AppState, db_error, media, repository::MediaRepository, validation};
+
+#[derive(Deserialize, IntoParams)]
+pub struct KeyframesQuery {
+    // path of a registered asset
+    file: String,
+    // also find scene changes, which decodes the whole video
+    #[serde(default)]
+    scenes: bool,
+    // how different a frame must be from the one before to start a scene, from 0 to 1
+    #[serde(default = "default_threshold")]
+    threshold: f64,
+}
+
+fn default_threshold() -> f64 {
+    0.3
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct Keyframes {
+    // seconds, in order
+    keyframes: Vec<f64>,
+    // null unless asked for
+    scenes: Option<Vec<SceneChange>>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct SceneChange {
+    // seconds
+    time: f64,
+    // how much the frame differs from the one before, from 0 to 1
+    score: f64,
+}
+
+impl From<media::Cuts> for Keyframes {
+    fn from(cuts: media::Cuts) -> Self {
+        Keyframes {
+            keyframes: cuts.keyframes,
+            scenes: cuts.scenes.map(|scenes| {
+                scenes
+                    .into_iter()
+                    .map(|scene| SceneChange {
+                        time: scene.time,
+                        score: scene.score,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/video/keyframes", get(keyframes))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/video/keyframes",
+    params(KeyframesQuery),
+    responses(
+        (status = 200, description = "Keyframe times, and scene changes with `scenes=true`", body = Keyframes),
+        (status = 404, description = "The file isn't a registered asset"),
+        (status = 422, description = "Invalid path or threshold, or no video to decode"),
+    ),
+    tag = "media"
+)]
+async fn keyframes(
+    State(media): State<Arc<dyn MediaRepository>>,
+    Query(query): Query<KeyframesQuery>,
+) -> Result<Json<Keyframes>, (StatusCode, String)> {
+    validation::media_path(&query.file)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    if !(0.0..=1.0).contains(&query.threshold) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "threshold must be from 0 to 1".to_owned(),
+        ));
+    }
+    let file = media.find_by_path(&query.file).await.map_err(db_error)?;
+    let path = PathBuf::from(file.path);
+    let threshold = query.scenes.then_some(query.threshold);
+    // decoding stops when the client goes away
+    let cancel = media::Cancel::default();
+    let _abandoned = cancel.guard();
+    let cuts = tokio::task::spawn_blocking(move || media::cuts(&path, threshold, &cancel))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map_err(|err| {
+            let message = format!("can't decode the video: {}", err);
+            (StatusCode::UNPROCESSABLE_ENTITY, message)
+        })?;
+    Ok(Json(Keyframes::from(cuts)))
+}
@@ -0,0 +1,148 @@
+// Resources that should all be given back by the time the process stops. Whatever takes one
+// holds a `Held` for as long as it has it, saying who it is; when shutting down, `report` warns
+// about those still held, so leaks show up in staging instead of as slowly growing usage.
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+// how long resources get to be given back after shutdown before they count as leaked, as
+// connections go back to the pool and blocking media work ends in the background
+const SETTLE: Duration = Duration::from_secs(2);
+const SETTLE_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    // held for longer than a query, like a listener's
+    DbConnection,
+    Job,
+    TempFile,
+    // opened by the ffmpeg libraries, or an ffmpeg process
+    Ffmpeg,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::DbConnection => "database connection",
+            Kind::Job => "running job",
+            Kind::TempFile => "temporary file",
+            Kind::Ffmpeg => "ffmpeg context",
+        }
+    }
+}
+
+struct Holder {
+    kind: Kind,
+    owner: String,
+    since: Instant,
+}
+
+static HELD: Mutex<BTreeMap<u64, Holder>> = Mutex::new(BTreeMap::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+// a resource taken by `owner`, given back when dropped
+#[must_use]
+pub struct Held(u64);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn hold(kind: Kind, owner: impl Into<String>) -> Held {
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    let holder = Holder {
+        kind,
+        owner: owner.into(),
+        since: Instant::now(),
+    };
+    HELD.lock().unwrap().insert(id, holder);
+    Held(id)
+}
+
+// Logs a warning per resource still held once everything should have stopped, after waiting up
+// to SETTLE for them to be given back. Connections of `pool` in use count too, those not held
+// through `hold` with an unknown owner.
+pub async fn report(pool: &PgPool) {
+    let deadline = Instant::now() + SETTLE;
+    let mut leaks = still_held(in_use(pool));
+    while !leaks.is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(SETTLE_POLL).await;
+        leaks = still_held(in_use(pool));
+    }
+    if leaks.is_empty() {
+        info!("all resources were given back");
+    }
+    for leak in &leaks {
+        warn!("leaked on shutdown: {}", leak);
+    }
+}
+
+fn in_use(pool: &PgPool) -> usize {
+    (pool.size() as usize).saturating_sub(pool.num_idle())
+}
+
+// what is still held, oldest first per kind, given `connections` of the pool in use
+fn still_held(connections: usize) -> Vec<String> {
+    let held = HELD.lock().unwrap();
+    let mut holders: Vec<&Holder> = held.values().collect();
+    holders.sort_by_key(|holder| (holder.kind, holder.since));
+    let mut leaks: Vec<String> = holders
+        .iter()
+        .map(|holder| {
+            format!(
+                "{} held by {} for {:.1?}",
+                holder.kind.as_str(),
+                holder.owner,
+                holder.since.elapsed()
+            )
+        })
+        .collect();
+    let known = holders
+        .iter()
+        .filter(|holder| holder.kind == Kind::DbConnection)
+        .count();
+    if connections > known {
+        leaks.push(format!(
+            "{} database connections in use by unknown owners",
+            connections - known
+        ));
+    }
+    leaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_held() {
+        // other tests may hold resources at the same time, though no connections
+        let mine = |leaks: Vec<String>| -> Vec<String> {
+            leaks
+                .into_iter()
+                .filter(|leak| leak.contains("leak test") || leak.contains("unknown"))
+                .collect()
+        };
+        let file = hold(Kind::TempFile, "leak test file");
+        let connection = hold(Kind::DbConnection, "leak test listener");
+        let leaks = mine(still_held(3));
+        assert_eq!(leaks.len(), 3);
+        assert!(leaks[0].starts_with("database connection held by leak test listener for"));
+        assert!(leaks[1].starts_with("temporary file held by leak test file for"));
+        assert_eq!(leaks[2], "2 database connections in use by unknown owners");
+
+        drop(file);
+        drop(connection);
+        assert_eq!(mine(still_held(0)), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,1636 @@
+// The server and the other commands of rsapp, which the binary runs with `run`. Embedders build
+// the app, and serve it themselves, with `app::App::builder`.
+use std::{
+    collections::HashMap, fmt::Display, fmt::Formatter, fmt::Result, path::Path, str::FromStr,
+    sync::Arc, time::Duration,
+};
+
+use app::App;
+use auth::{Admin, CurrentUser};
+use axum::{
+    extract::{Path as UrlPath, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use canary::Canaries;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use clock::{Clock, SystemClock};
+use config::Config;
+use db::DbExecutor;
+use event::{Events, Topic};
+use experiment::Experiments;
+use job::Jobs;
+use library::Library;
+use listen::Listen;
+use media::Capabilities;
+use metering::Metering;
+use negotiate::{Format, Negotiated};
+use probe::Probes;
+use rate_plan::RatePlans;
+use repository::{
+    idempotency::{Claim, PgIdempotency},
+    library::PgLibrary,
+    upload::PgUploads,
+    IdempotencyRepository, MediaRepository, UserRepository,
+};
+use serde_derive::{Deserialize, Serialize};
+use session::Sessions;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgPool,
+};
+use tokio::{signal, sync::watch, time::sleep};
+use tracing::{error, info, instrument, warn};
+use tracing_subscriber::filter::LevelFilter;
+use upload::Uploads;
+use utoipa::{IntoParams, ToSchema};
+use validation::Valid;
+use validator::Validate;
+use webhook::Webhooks;
+
+use api::{v1, ToVersion};
+
+mod admin;
+mod api;
+pub mod app;
+mod asset;
+mod audio;
+mod auth;
+mod bookmark;
+mod boot;
+mod bus;
+mod canary;
+mod cli;
+pub mod clock;
+mod conditional;
+mod correlation;
+mod db;
+mod delivery;
+mod deprecation;
+mod dev;
+mod event;
+mod experiment;
+mod federation;
+mod fractional_index;
+mod graphql;
+mod grpc;
+#[cfg(unix)]
+mod handover;
+mod instance;
+mod job;
+mod keyframes;
+mod leak;
+mod library;
+mod listen;
+mod logging;
+mod mail;
+mod media;
+mod meta_query;
+mod metering;
+mod migrate;
+mod negotiate;
+mod oidc;
+mod openapi;
+mod panics;
+mod password;
+mod playlist;
+mod probe;
+mod rate_plan;
+mod read_only;
+mod redis;
+mod remux;
+mod repository;
+mod scheduler;
+mod service;
+mod session;
+mod shed;
+mod snapshot;
+mod status;
+mod storage;
+mod tag;
+mod task;
+#[cfg(test)]
+mod testing;
+mod timeout;
+mod upload;
+mod validation;
+mod webhook;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Conf {
+    name: String,
+    // of servers and workers, again on SIGHUP; `PUT /admin/log-level` changes it in between
+    #[serde(default = "default_log_level")]
+    log_level: String,
+    postgres: Pg,
+    #[serde(default)]
+    server: Server,
+    #[serde(default)]
+    admin: AdminConf,
+    #[serde(default)]
+    jobs: JobsConf,
+    #[serde(default)]
+    scheduler: SchedulerConf,
+    // where job results are kept
+    #[serde(default)]
+    storage: storage::Settings,
+    #[serde(default)]
+    probes: probe::Settings,
+    #[serde(default)]
+    read_only: read_only::Settings,
+    // of expensive admin aggregates
+    #[serde(default)]
+    snapshots: snapshot::Settings,
+    // shared by the instances, with the `redis` feature
+    redis: Option<redis::Settings>,
+    // where events are published, with the `nats` or `kafka` feature
+    bus: Option<bus::Settings>,
+    // alternate implementations to try on part of the traffic, by canary name
+    #[serde(default)]
+    canaries: HashMap<String, canary::Settings>,
+    // A/B tests, by experiment name
+    #[serde(default)]
+    experiments: HashMap<String, experiment::Settings>,
+    #[serde(default)]
+    boot_report: boot::Settings,
+    // deployments sharing playlists with this one
+    #[serde(default)]
+    federation: federation::Settings,
+    // for services calling this one from inside the network
+    #[serde(default)]
+    grpc: grpc::Settings,
+    // media files scanned from a directory
+    #[serde(default)]
+    library: library::Settings,
+    // resumable uploads
+    #[serde(default)]
+    uploads: upload::Settings,
+    // where jobs are POSTed once done
+    #[serde(default)]
+    webhooks: webhook::Settings,
+    // how mail such as password reset links is sent
+    #[serde(default)]
+    mail: mail::Settings,
+    // changes and reset links
+    #[serde(default)]
+    passwords: password::Settings,
+    // cookie sessions of browsers
+    #[serde(default)]
+    sessions: session::Settings,
+    #[serde(default)]
+    auth: auth::Settings,
+    // routes and fields answered with `Deprecation` headers
+    #[serde(default)]
+    deprecations: Vec<deprecation::Settings>,
+    // of the merged settings, to compare with other instances
+    #[serde(skip)]
+    fingerprint: String,
+    // the merged settings, for the boot report
+    #[serde(skip)]
+    settings: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Pg {
+    dsn: String,
+    // a read-only replica for handlers that only read, same pool settings as the primary
+    replica_dsn: Option<String>,
+    #[serde(default = "default_max_connections")]
+    max_connections: u32,
+    #[serde(default)]
+    min_connections: u32,
+    // seconds to wait for a free connection before the request fails
+    #[serde(default = "default_acquire_timeout")]
+    acquire_timeout: u64,
+    // seconds an unused connection above `min_connections` is kept, 0 keeps it forever
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+    // milliseconds a statement may run before Postgres cancels it, 0 for no limit
+    #[serde(default = "default_statement_timeout")]
+    statement_timeout: u64,
+    // tries to reach the database at startup before giving up
+    #[serde(default = "default_connect_attempts")]
+    connect_attempts: u32,
+    // seconds to wait after the first failed try, doubling after every further one
+    #[serde(default = "default_connect_backoff")]
+    connect_backoff: u64,
+    #[serde(default)]
+    tls: db::tls::Settings,
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_max_connections() -> u32 {
+    5
+}
+
+fn default_acquire_timeout() -> u64 {
+    30
+}
+
+fn default_idle_timeout() -> u64 {
+    600
+}
+
+fn default_statement_timeout() -> u64 {
+    30_000
+}
+
+fn default_connect_attempts() -> u32 {
+    5
+}
+
+fn default_connect_backoff() -> u64 {
+    1
+}
+
+// longest wait between startup connection attempts
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+impl Conf {
+    fn validate(&self) -> std::result::Result<(), String> {
+        logging::parse(&self.log_level)?;
+        self.postgres.validate()?;
+        self.federation.validate()?;
+        self.grpc.validate()?;
+        self.library.validate()?;
+        self.uploads.validate()?;
+        self.webhooks.validate()?;
+        self.mail.validate()?;
+        self.passwords.validate()?;
+        self.sessions.validate()?;
+        if let Some(oidc) = &self.auth.oidc {
+            oidc.validate()?;
+            // browsers coming back from the provider wouldn't send the cookie
+            if !self.sessions.enabled || self.sessions.same_site == session::SameSiteSetting::Strict
+            {
+                return Err("auth.oidc needs sessions, with same_site = \"lax\"".to_owned());
+            }
+        }
+        self.server.validate()?;
+        if let Some(bus) = &self.bus {
+            bus.validate()?;
+        }
+        Experiments::new(self.experiments.clone(), None)?;
+        for (name, canary) in &self.canaries {
+            if canary.percent > 100 {
+                return Err(format!("canaries.{}.percent goes up to 100", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Pg {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_connections == 0 {
+            return Err("postgres.max_connections must be at least 1".to_owned());
+        }
+        if self.min_connections > self.max_connections {
+            return Err(format!(
+                "postgres.min_connections ({}) is above max_connections ({})",
+                self.min_connections, self.max_connections
+            ));
+        }
+        if self.acquire_timeout == 0 {
+            return Err("postgres.acquire_timeout must be at least 1 second".to_owned());
+        }
+        if self.connect_attempts == 0 {
+            return Err("postgres.connect_attempts must be at least 1".to_owned());
+        }
+        self.tls.validate()
+    }
+
+    async fn connect(&self) -> std::result::Result<PgPool, sqlx::Error> {
+        self.pool_options()
+            .connect_with(self.connect_options(&self.dsn)?)
+            .await
+    }
+
+    // Connects and checks the database answers queries, trying `connect_attempts` times with
+    // growing waits in between, since it often starts along with the server.
+    async fn connect_checked(&self) -> std::result::Result<PgPool, sqlx::Error> {
+        let mut attempt = 1;
+        loop {
+            let result = match self.connect().await {
+                Ok(pool) => sqlx::query("SELECT 1").execute(&pool).await.map(|_| pool),
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(pool) => {
+                    info!("connected to postgres");
+                    return Ok(pool);
+                }
+                Err(err) if attempt < self.connect_attempts => {
+                    let wait = self.backoff(attempt);
+                    warn!(
+                        "postgres is not reachable (attempt {} of {}): {}, retrying in {:?}",
+                        attempt, self.connect_attempts, err, wait
+                    );
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // the wait after failed attempt `attempt`, counting from 1
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2_u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_secs(self.connect_backoff.saturating_mul(factor)).min(MAX_CONNECT_BACKOFF)
+    }
+
+    // a pool that connects once the database is there, for starting without it
+    fn connect_lazy(&self) -> std::result::Result<PgPool, sqlx::Error> {
+        Ok(self
+            .pool_options()
+            .connect_lazy_with(self.connect_options(&self.dsn)?))
+    }
+
+    // the replica may well be down at startup, its connections are made as needed
+    fn connect_replica(&self) -> std::result::Result<Option<PgPool>, sqlx::Error> {
+        match &self.replica_dsn {
+            Some(dsn) => Ok(Some(
+                self.pool_options()
+                    .connect_lazy_with(self.connect_options(dsn)?),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn connect_options(&self, dsn: &str) -> std::result::Result<PgConnectOptions, sqlx::Error> {
+        let mut options = PgConnectOptions::from_str(dsn)?;
+        if self.statement_timeout > 0 {
+            let timeout = format!("{}ms", self.statement_timeout);
+            options = options.options([("statement_timeout", timeout.as_str())]);
+        }
+        Ok(self.tls.apply(options))
+    }
+
+    fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(Duration::from_secs(self.acquire_timeout))
+            .idle_timeout((self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout)))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct AdminConf {
+    // bearer token for the /admin routes, which only admins signed in reach without one
+    token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+struct JobsConf {
+    // media jobs running at the same time, per process running them, highest priority first
+    workers: usize,
+    // more of them, running only high priority jobs, such as interactive thumbnails
+    high_workers: usize,
+    // more of them, running only low priority jobs, so bulk work goes on while the others are busy
+    low_workers: usize,
+    // where job results such as transcodes and thumbnails are written
+    output_dir: String,
+    // whether the server runs jobs too, or leaves them to `rsapp worker` processes
+    in_server: bool,
+    // what transcodes run on unless a job asks for other, found working at startup or software
+    hwaccel: media::Hwaccel,
+    // the render node of the GPU for `vaapi`
+    vaapi_device: String,
+    // runs after the first of failing jobs before they're dead, 0 to fail them right away
+    max_retries: u32,
+    // seconds before the first retry, doubling for each after it up to an hour
+    retry_backoff: u64,
+}
+
+impl Default for JobsConf {
+    fn default() -> Self {
+        JobsConf {
+            workers: 2,
+            high_workers: 1,
+            low_workers: 0,
+            output_dir: "data/jobs".to_owned(),
+            in_server: true,
+            hwaccel: media::Hwaccel::Software,
+            vaapi_device: "/dev/dri/renderD128".to_owned(),
+            max_retries: 3,
+            retry_backoff: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct SchedulerConf {
+    // periodic maintenance, none by default
+    #[serde(default)]
+    tasks: Vec<scheduler::Entry>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct Server {
+    // where to take connections instead of all interfaces on the port of the command, an address
+    // or a Unix socket, see `listen`
+    listen: Option<String>,
+    // where to serve the admin endpoints instead, say a port the firewall keeps internal, see
+    // `admin::split`
+    admin_listen: Option<String>,
+    // permissions of the Unix sockets, like 0o660
+    socket_mode: Option<u32>,
+    #[serde(default)]
+    http: listen::Http,
+    // requests handled at a time, more are shed with 503, see `shed`
+    #[serde(default)]
+    limits: shed::Limits,
+    // how long routes have to answer, see `timeout`
+    #[serde(default)]
+    timeouts: timeout::Timeouts,
+    #[serde(default)]
+    compression: Compression,
+    // directory with a bundled web UI to serve for paths no route matches
+    static_dir: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+struct Compression {
+    // compress responses with gzip or br, depending on the client's `Accept-Encoding`
+    enabled: bool,
+    // responses smaller than this are not worth compressing
+    min_size: u16,
+    // accept gzip or br encoded request bodies
+    decompress_requests: bool,
+}
+
+impl Server {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if let Some(listen) = &self.listen {
+            listen
+                .parse::<Listen>()
+                .map_err(|err| format!("server.listen: {}", err))?;
+        }
+        if let Some(listen) = &self.admin_listen {
+            listen
+                .parse::<Listen>()
+                .map_err(|err| format!("server.admin_listen: {}", err))?;
+        }
+        if let Some(mode) = self.socket_mode {
+            listen::validate_mode(mode).map_err(|err| format!("server.socket_mode: {}", err))?;
+        }
+        self.timeouts.validate()?;
+        self.http.validate()
+    }
+
+    // what `listen` says, or all interfaces on `port`
+    fn listen(&self, port: &str) -> std::result::Result<Listen, String> {
+        match &self.listen {
+            Some(listen) => listen.parse(),
+            None => format!("0.0.0.0:{}", port).parse(),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            enabled: true,
+            min_size: 1024,
+            decompress_requests: true,
+        }
+    }
+}
+
+impl Display for Conf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "name: {}, postgres: {}", self.name, self.postgres)
+    }
+}
+
+impl Display for Pg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "dsn: *, replica: {}, connections: {}..={}",
+            self.replica_dsn.is_some(),
+            self.min_connections,
+            self.max_connections
+        )
+    }
+}
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Commands,
+    // how subcommands other than `server` report, see `cli`
+    #[arg(long, global = true, value_enum, default_value_t = cli::Output::Text)]
+    output: cli::Output,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Commands {
+    Server {
+        #[arg(short, long)]
+        port: Option<String>,
+        // start even if the database can't be reached, requests needing it fail until it can
+        #[arg(long)]
+        no_db: bool,
+        // serve on the listening socket of this descriptor, passed on by a supervisor, instead of
+        // binding one; systemd's socket activation needs none
+        #[arg(long)]
+        fd: Option<i32>,
+        // the same for the admin endpoints, see `server.admin_listen`
+        #[arg(long)]
+        admin_fd: Option<i32>,
+        // and for gRPC, see `[grpc]`
+        #[arg(long)]
+        grpc_fd: Option<i32>,
+        // told once listening by a server handing over to this one, see `handover`
+        #[arg(long, hide = true)]
+        ready_fd: Option<i32>,
+    },
+    // for development: serves with Postgres in a throwaway docker container, migrated and
+    // seeded with demo users and media
+    Up {
+        #[arg(short, long, default_value = "9009")]
+        port: String,
+        #[arg(long)]
+        no_seed: bool,
+    },
+    // runs the server as a service of the OS, on Windows and macOS
+    Service {
+        #[command(subcommand)]
+        action: service::Action,
+    },
+    // runs queued media jobs and scheduled tasks, without serving HTTP
+    Worker,
+    // runs one media job of `job_type` (transcode or thumbnail) with `payload` as JSON, e.g.
+    // '{"asset_id": 3}', then exits: 0 when done, 5 when worth retrying, another code when not.
+    // For external schedulers, instead of queueing it for the workers
+    RunJob {
+        job_type: String,
+        payload: String,
+    },
+    // prints what ffmpeg finds in a local media file
+    Probe {
+        file: std::path::PathBuf,
+    },
+    // exits non-zero unless the server at `url` is ready, or with `--db` the database answers;
+    // for container health checks
+    Healthcheck {
+        #[arg(long, default_value = "http://127.0.0.1:9009/readyz")]
+        url: String,
+        #[arg(long)]
+        db: bool,
+    },
+    // checks the configuration, or prints it as read
+    Config {
+        #[command(subcommand)]
+        action: cli::ConfigAction,
+    },
+    // manages accounts directly in the database
+    User {
+        #[command(subcommand)]
+        action: cli::UserAction,
+    },
+    // scans a directory into the media library
+    Library {
+        #[command(subcommand)]
+        action: cli::LibraryAction,
+    },
+    // applies or rolls back database migrations without starting the server, `up` by default
+    Migrate {
+        #[command(subcommand)]
+        action: Option<migrate::Action>,
+    },
+}
+
+// the rsapp command, run by the binary
+pub async fn run() {
+    let args = Cli::parse();
+
+    match args.cmd {
+        Commands::Server {
+            port,
+            no_db,
+            fd,
+            admin_fd,
+            grpc_fd,
+            ready_fd,
+        } => {
+            let port = port.unwrap_or("9009".to_owned());
+            let fds = listen::Fds {
+                public: fd,
+                admin: admin_fd,
+                grpc: grpc_fd,
+                ready: ready_fd,
+            };
+            let served = match configure() {
+                Ok(conf) => serve(conf, &port, no_db, fds, true, shutdown_signal()).await,
+                Err(err) => Err(err),
+            };
+            exit_on_error(served)
+        }
+        Commands::Up { port, no_seed } => dev::up(&port, !no_seed).await,
+        Commands::Worker => exit_on_error(work().await),
+        Commands::Service { action } => match action {
+            service::Action::Install { port } => cli::finish(args.output, service::install(&port)),
+            service::Action::Uninstall => cli::finish(args.output, service::uninstall()),
+            service::Action::Run { port, dir } => {
+                if let Err(failure) = service::run(&port, dir).await {
+                    cli::finish(args.output, Err(failure))
+                }
+            }
+        },
+        Commands::RunJob { job_type, payload } => {
+            cli::finish(args.output, cli::run_job(&job_type, &payload).await)
+        }
+        Commands::Probe { file } => cli::finish(args.output, cli::probe(&file)),
+        Commands::Healthcheck { url, db } => {
+            cli::finish(args.output, cli::healthcheck(&url, db).await)
+        }
+        Commands::Config { action } => cli::finish(args.output, cli::config(action)),
+        Commands::User { action } => cli::finish(args.output, cli::user(action).await),
+        Commands::Library { action } => cli::finish(args.output, cli::library(action).await),
+        Commands::Migrate { action } => cli::finish(
+            args.output,
+            cli::migrate(action.unwrap_or(migrate::Action::Up)).await,
+        ),
+    };
+}
+
+// the validated configuration, and the settings it was read from as merged from all sources
+fn read_conf() -> std::result::Result<(Conf, serde_json::Value), String> {
+    let settings = Config::builder()
+        .add_source(config::File::with_name("config.toml"))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let merged: serde_json::Value = settings
+        .clone()
+        .try_deserialize()
+        .map_err(|err| err.to_string())?;
+    let mut conf = settings
+        .try_deserialize::<Conf>()
+        .map_err(|err| err.to_string())?;
+    conf.fingerprint = instance::fingerprint(merged.to_string().as_bytes());
+    conf.settings = merged.clone();
+    conf.validate()?;
+    Ok((conf, merged))
+}
+
+fn load_conf() -> std::result::Result<Conf, String> {
+    read_conf()
+        .map(|(conf, _)| conf)
+        .map_err(|err| format!("invalid configuration in config.toml: {}", err))
+}
+
+// the configuration, logging from its `log_level` up; from info when it's invalid, to tell why
+fn configure() -> std::result::Result<Conf, String> {
+    let conf = load_conf();
+    // validated with the rest of the configuration
+    let level = match &conf {
+        Ok(conf) => logging::parse(&conf.log_level).unwrap_or(LevelFilter::INFO),
+        Err(_) => LevelFilter::INFO,
+    };
+    logging::init(level);
+    conf
+}
+
+// exits non-zero, logging why, when the server or worker didn't start or stopped on an error
+fn exit_on_error(result: std::result::Result<(), String>) {
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+// whether the ffmpeg libraries work, which every media request and job needs
+fn init_media() -> std::result::Result<(), String> {
+    media::init().map_err(|err| {
+        format!(
+            "can't initialize ffmpeg: {}; check that its libraries are installed",
+            err
+        )
+    })
+}
+
+// serves until `shutdown` completes, on the listening sockets of `fds` or systemd's when there are;
+// with `hands_over` also until it handed over to a new server on SIGUSR2. Err tells why it
+// couldn't start, naming the setting, or why serving failed
+async fn serve(
+    conf: Conf,
+    port: &str,
+    no_db: bool,
+    fds: listen::Fds,
+    hands_over: bool,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::result::Result<(), String> {
+    info!(name = %conf.name, postgres = %conf.postgres, "starting");
+    panics::install_hook();
+    init_media()?;
+    // before anything runs programs, which would get the sockets too
+    let inherited = fds
+        .or_systemd()
+        .take()
+        .map_err(|err| format!("can't take over the listening sockets passed on: {}", err))?;
+
+    let (pool, degraded) = match conf.postgres.connect_checked().await {
+        Ok(pool) => (pool, false),
+        Err(err) if no_db => {
+            warn!(
+                "starting without postgres: {}. Requests needing it fail until it's back, \
+                 migrations and rate plans are left out until a restart",
+                err
+            );
+            let pool = conf
+                .postgres
+                .connect_lazy()
+                .map_err(|err| format!("invalid postgres.dsn: {}", err))?;
+            (pool, true)
+        }
+        Err(err) => {
+            return Err(format!(
+                "can't reach postgres: {}; start with --no-db to serve without it",
+                err
+            ))
+        }
+    };
+
+    if !degraded {
+        migrate::MIGRATOR
+            .run(&pool)
+            .await
+            .map_err(|err| format!("can't migrate the database: {}", err))?;
+    }
+    let app = App::builder()
+        .config(conf.clone())
+        .pool(pool.clone())
+        .degraded(degraded)
+        .build()
+        .await
+        .map_err(|err| format!("can't build the app: {}", err))?;
+    app.db.watch_replica();
+    app.status.sample();
+    app.instances.heartbeat(pool.clone());
+    let mut report = boot_report(
+        &conf,
+        "serve",
+        app.instances.id(),
+        (!degraded).then_some(&pool),
+    )
+    .await;
+    report.ffmpeg = app.capabilities.as_ref().clone();
+
+    let (stop, stopped) = watch::channel(false);
+    let rpc = grpc::start(&conf.grpc, app.services, inherited.grpc, stopped.clone())
+        .await
+        .map_err(|err| format!("can't start gRPC on grpc.listen: {}", err))?;
+    let listening = app.jobs.listen(stopped.clone());
+    app.events.forward_jobs(&app.jobs);
+    let workers = conf
+        .jobs
+        .in_server
+        .then(|| app.jobs.work(app.instances.id(), stopped.clone()));
+    let scheduler = scheduler::start(
+        conf.scheduler.tasks,
+        scheduler::Context {
+            jobs: app.jobs,
+            metering: app.metering,
+            idempotency: app.idempotency,
+            library: app.library,
+            uploads: app.uploads,
+            sessions: app.sessions,
+        },
+        stopped,
+    );
+    #[cfg(unix)]
+    task::spawn(
+        "reload on hangup",
+        task::Kind::Background,
+        reload_on_hangup(pool.clone(), app.rate_plans, app.events),
+    );
+
+    let listener = match inherited.public {
+        Some(listener) => listener,
+        None => {
+            let listen = conf
+                .server
+                .listen(port)
+                .map_err(|err| format!("can't listen on port {}: {}", port, err))?;
+            listen
+                .bind(conf.server.socket_mode)
+                .await
+                .map_err(|err| format!("can't listen on {}: {}", listen, err))?
+        }
+    };
+    info!(listen = %listener.address(), "listening");
+    report.listeners = vec![listener.address()];
+    let admin_listener = match (inherited.admin, &conf.server.admin_listen) {
+        (Some(listener), _) => Some(listener),
+        (None, Some(listen)) => {
+            let listen = listen
+                .parse::<Listen>()
+                .map_err(|err| format!("invalid server.admin_listen: {}", err))?;
+            let listener = listen.bind(conf.server.socket_mode).await.map_err(|err| {
+                format!("can't listen on server.admin_listen {}: {}", listen, err)
+            })?;
+            Some(listener)
+        }
+        (None, None) => None,
+    };
+    #[cfg(unix)]
+    let handover = handover::Handover {
+        port: port.to_owned(),
+        no_db,
+        fds: listen::Fds {
+            public: Some(listener.fd()),
+            admin: admin_listener.as_ref().map(listen::Listener::fd),
+            grpc: rpc.as_ref().map(|rpc| rpc.fd),
+            ready: None,
+        },
+    };
+    let served = match admin_listener {
+        Some(admin_listener) => {
+            info!(listen = %admin_listener.address(), "listening for admin endpoints");
+            report
+                .listeners
+                .push(format!("{} (admin)", admin_listener.address()));
+            let (public, admin) = admin::split(app.router);
+            vec![(listener, public), (admin_listener, admin)]
+        }
+        None => vec![(listener, app.router)],
+    };
+    if let Some(rpc) = &rpc {
+        report.listeners.push(format!("{} (gRPC)", rpc.address));
+    }
+    report.emit(&conf.boot_report);
+    #[cfg(unix)]
+    if let Some(fd) = fds.ready {
+        handover::ready(fd);
+    }
+    #[cfg(unix)]
+    let shutdown = async move {
+        if !hands_over {
+            return shutdown.await;
+        }
+        tokio::select! {
+            _ = shutdown => {},
+            _ = handover.on_signal() => {},
+        }
+    };
+    #[cfg(not(unix))]
+    let _ = hands_over;
+    let result = listen::serve_all(served, &conf.server.http, app.connections, shutdown)
+        .await
+        .map_err(|err| format!("serving failed: {}", err));
+
+    // let scheduled tasks and jobs that are running finish too
+    let _ = stop.send(true);
+    let _ = scheduler.await;
+    if let Some(workers) = workers {
+        let _ = workers.await;
+    }
+    let _ = listening.await;
+    if let Some(rpc) = rpc {
+        let _ = rpc.task.await;
+    }
+    task::shutdown(task::GRACE).await;
+    leak::report(&pool).await;
+    result
+}
+
+// what `command` started with, but for the listeners; `pool` tells the migration when there's
+// a database
+async fn boot_report(
+    conf: &Conf,
+    command: &'static str,
+    instance: &str,
+    pool: Option<&PgPool>,
+) -> boot::Report {
+    let mut report = boot::Report::new(command, instance, &conf.fingerprint, &conf.settings);
+    if let Some(pool) = pool {
+        report.migration = migrate::current(pool).await.ok().flatten();
+    }
+    let in_process = command == "worker" || conf.jobs.in_server;
+    report.workers = boot::Workers {
+        jobs: if in_process {
+            conf.jobs.workers + conf.jobs.high_workers + conf.jobs.low_workers
+        } else {
+            0
+        },
+        max_db_connections: conf.postgres.max_connections,
+    };
+    report
+}
+
+// the bus of `[bus]` if configured and reachable, events aren't published otherwise
+async fn open_bus(conf: &Conf) -> Option<Arc<bus::Bus>> {
+    let settings = conf.bus.as_ref()?;
+    match bus::Bus::open(settings).await {
+        Ok(bus) => Some(bus),
+        Err(err) => {
+            warn!("not publishing events: {}", err);
+            None
+        }
+    }
+}
+
+// runs jobs queued by the servers, so they can be scaled apart from them
+async fn work() -> std::result::Result<(), String> {
+    let conf = configure()?;
+    panics::install_hook();
+    init_media()?;
+    let pool = conf
+        .postgres
+        .connect_checked()
+        .await
+        .map_err(|err| format!("can't reach postgres: {}", err))?;
+    migrate::MIGRATOR
+        .run(&pool)
+        .await
+        .map_err(|err| format!("can't migrate the database: {}", err))?;
+    // other instances tell from its heartbeat whether it's still working on its jobs
+    let instances = Arc::new(instance::Instances::new(conf.fingerprint.clone()));
+    instances.heartbeat(pool.clone());
+    let hardware = media::Hardware::detect(conf.jobs.hwaccel, &conf.jobs.vaapi_device);
+    let mut report = boot_report(&conf, "worker", instances.id(), Some(&pool)).await;
+    report.ffmpeg.hwaccel = hardware.available.clone();
+    report.emit(&conf.boot_report);
+
+    let bus = open_bus(&conf).await;
+    let mut jobs = Jobs::new(
+        pool.clone(),
+        conf.jobs.workers,
+        &conf.jobs.output_dir,
+        storage::open(
+            &conf.storage,
+            Path::new(&conf.jobs.output_dir),
+            Arc::new(SystemClock),
+        ),
+        Arc::new(Canaries::new(conf.canaries)),
+    )
+    .with_hardware(hardware)
+    .with_reserved_workers(conf.jobs.high_workers, conf.jobs.low_workers)
+    .with_webhooks(Arc::new(Webhooks::new(conf.webhooks)))
+    .with_retries(
+        conf.jobs.max_retries,
+        Duration::from_secs(conf.jobs.retry_backoff),
+    );
+    if let Some(bus) = bus {
+        jobs = jobs.with_bus(bus);
+    }
+    let jobs = Arc::new(jobs);
+    let db = Arc::new(DbExecutor::new(pool.clone(), None));
+    let library = Library::new(
+        conf.library,
+        Arc::new(PgLibrary::new(db.clone())),
+        Arc::new(Probes::new(&conf.probes, None)),
+    );
+    let uploads = Uploads::new(conf.uploads, Arc::new(PgUploads::new(db.clone())));
+    let (stop, stopped) = watch::channel(false);
+    let listening = jobs.listen(stopped.clone());
+    let workers = jobs.work(instances.id(), stopped.clone());
+    let scheduler = scheduler::start(
+        conf.scheduler.tasks,
+        scheduler::Context {
+            jobs,
+            metering: Arc::new(Metering::default()),
+            idempotency: Arc::new(PgIdempotency::new(db)),
+            library: Arc::new(library),
+            uploads: Arc::new(uploads),
+            sessions: Arc::new(Sessions::new(conf.sessions, pool.clone())),
+        },
+        stopped,
+    );
+
+    shutdown_signal().await;
+    info!("stopping, jobs that are running get to finish");
+    let _ = stop.send(true);
+    let _ = scheduler.await;
+    let _ = workers.await;
+    let _ = listening.await;
+    task::shutdown(task::GRACE).await;
+    leak::report(&pool).await;
+    Ok(())
+}
+
+// `kill -HUP` reloads the configuration kept in the database, i.e. the rate plans, and resets the
+// log level to `log_level` of the config
+#[cfg(unix)]
+async fn reload_on_hangup(pool: PgPool, rate_plans: Arc<RatePlans>, events: Arc<Events>) {
+    let Ok(mut hangup) = signal::unix::signal(signal::unix::SignalKind::hangup()) else {
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        // the level set through the admin API until then is dropped
+        let level = read_conf().and_then(|(conf, _)| logging::parse(&conf.log_level));
+        match level.and_then(logging::set_level) {
+            Ok(()) => info!("log level reset to {}", logging::level()),
+            Err(err) => warn!("resetting the log level failed: {}", err),
+        }
+        match rate_plans.reload(&pool).await {
+            Ok(()) => {
+                info!("configuration reloaded");
+                events.publish(Topic::ConfigReloaded, None, serde_json::json!({}));
+            }
+            Err(err) => warn!("reloading configuration failed: {}", err),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// basic handler that responds with a static string
+#[utoipa::path(get, path = "/", responses((status = 200, description = "Greeting", body = String)))]
+async fn root() -> &'static str {
+    "Hello, World!"
+}
+
+// for graceful shutdown. When running this request, ctrl+c will wait this request finish.
+#[utoipa::path(
+    get,
+    path = "/longtime",
+    responses((status = 200, description = "Answers after 10 seconds", body = String))
+)]
+async fn long_time_request() -> &'static str {
+    sleep(Duration::from_secs(10)).await;
+
+    "Long time request."
+}
+
+#[derive(Deserialize, ToSchema)]
+struct VideoMeta {
+    file: String,
+}
+
+// what transcode, remux and clip options work, before submitting them
+#[utoipa::path(
+    get,
+    path = "/api/v1/media/capabilities",
+    responses(
+        (status = 200, description = "The ffmpeg version, codecs, containers and hardware", body = Capabilities),
+    ),
+    tag = "media"
+)]
+async fn media_capabilities(State(capabilities): State<Arc<Capabilities>>) -> Json<Capabilities> {
+    Json(capabilities.as_ref().clone())
+}
+
+// answers from the metadata stored when the file was registered as an asset
+#[utoipa::path(
+    get,
+    path = "/api/v1/video/metadata",
+    request_body = VideoMeta,
+    params(asset::Refresh),
+    responses(
+        (status = 200, description = "Format and streams of the file", body = v1::MediaMetadata,
+            content_type = ["application/json", "application/msgpack"]),
+        (status = 304, description = "Unchanged since If-None-Match"),
+        (status = 404, description = "The file isn't a registered asset"),
+        (status = 422, description = "The file can't be read"),
+    ),
+    tag = "media"
+)]
+#[instrument(skip_all, fields(file = %payload.file))]
+async fn video_metadata(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(probes): State<Arc<Probes>>,
+    Query(query): Query<asset::Refresh>,
+    format: Format,
+    Json(payload): Json<VideoMeta>,
+) -> std::result::Result<Negotiated<v1::MediaMetadata>, (StatusCode, String)> {
+    let file = media.find_by_path(&payload.file).await.map_err(db_error)?;
+    let metadata = asset::metadata(&*media, &probes, file, query.refresh).await?;
+    Ok(Negotiated(format, metadata.to_version()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repository::{idempotency::MemoryIdempotency, user::MemoryUsers};
+    use testing::TestApp;
+
+    #[tokio::test]
+    async fn it_works() {
+        let Some(app) = TestApp::with_db().await else {
+            return;
+        };
+        let username = format!("jd{}", chrono::Utc::now().timestamp_micros());
+        let response = app
+            .client
+            .post(app.url("/users"))
+            .json(&serde_json::json!({ "username": username }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+
+        let user: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(user["username"], username);
+    }
+
+    #[test]
+    fn pool_settings() {
+        let pg: Pg =
+            serde_json::from_value(serde_json::json!({"dsn": "postgres://localhost/db"})).unwrap();
+        assert_eq!((pg.min_connections, pg.max_connections), (0, 5));
+        assert_eq!(pg.validate(), Ok(()));
+
+        let pg: Pg = serde_json::from_value(serde_json::json!({
+            "dsn": "postgres://localhost/db",
+            "min_connections": 6,
+        }))
+        .unwrap();
+        assert!(pg.validate().is_err());
+    }
+
+    #[test]
+    fn connect_backoff() {
+        let pg: Pg = serde_json::from_value(serde_json::json!({
+            "dsn": "postgres://localhost/db",
+            "connect_backoff": 2,
+        }))
+        .unwrap();
+        assert_eq!(pg.connect_attempts, 5);
+        assert_eq!(pg.backoff(1), Duration::from_secs(2));
+        assert_eq!(pg.backoff(3), Duration::from_secs(8));
+        assert_eq!(pg.backoff(10), MAX_CONNECT_BACKOFF);
+        assert_eq!(pg.backoff(100), MAX_CONNECT_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn users_without_database() {
+        let users: Arc<dyn UserRepository> = Arc::new(MemoryUsers::default());
+        let events = Arc::new(Events::default());
+        let keys: Arc<dyn IdempotencyRepository> = Arc::new(MemoryIdempotency::default());
+        let (status, Json(created)) = create_user(
+            State(users.clone()),
+            State(events),
+            State(keys),
+            HeaderMap::new(),
+            Valid(CreateUser {
+                username: "jd".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created.username, "jd");
+
+        let get = |id, include_deleted, admin| {
+            get_user(
+                State(users.clone()),
+                admin,
+                UrlPath(id),
+                Query(GetUser { include_deleted }),
+            )
+        };
+        let ([(_, modified)], Json(found)) = get(created.id, false, None).await.unwrap();
+        assert_eq!(found, created);
+        assert!(modified.ends_with(" GMT"));
+        let missing = get(created.id + 1, false, None).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+        let forbidden = get(created.id, true, None).await;
+        assert_eq!(forbidden.unwrap_err().0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn soft_deletes_users() {
+        let users: Arc<dyn UserRepository> = Arc::new(MemoryUsers::default());
+        let clock: Arc<dyn Clock> = Arc::new(clock::FixedClock::at("2024-03-07T12:00:00Z"));
+        let jd = users.create("jd").await.unwrap();
+        let other = users.create("other").await.unwrap();
+        let delete = |id, admin, user| {
+            delete_user(
+                State(users.clone()),
+                State(clock.clone()),
+                admin,
+                user,
+                UrlPath(id),
+            )
+        };
+        let list = |include_deleted, admin| {
+            list_users(
+                State(users.clone()),
+                admin,
+                Query(ListUsers {
+                    include_deleted,
+                    limit: 100,
+                    offset: 0,
+                }),
+            )
+        };
+
+        let refused = delete(other.id, None, Some(CurrentUser(jd.id))).await;
+        assert_eq!(refused.unwrap_err().0, StatusCode::FORBIDDEN);
+        let deleted = delete(jd.id, None, Some(CurrentUser(jd.id))).await;
+        assert_eq!(deleted.unwrap(), StatusCode::NO_CONTENT);
+        let again = delete(jd.id, Some(Admin), None).await;
+        assert_eq!(again.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let Json(listed) = list(false, None).await.unwrap();
+        assert_eq!(listed, vec![User::from(other.clone())]);
+        assert_eq!(list(true, None).await.unwrap_err().0, StatusCode::FORBIDDEN);
+        let Json(listed) = list(true, Some(Admin)).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].deleted_at, Some(clock.now()));
+        assert!(matches!(
+            users.find(jd.id, false).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let Json(restored) = restore_user(Admin, State(users.clone()), UrlPath(jd.id))
+            .await
+            .unwrap();
+        assert_eq!(restored, User::from(jd.clone()));
+        assert_eq!(users.find(jd.id, false).await.unwrap(), jd);
+        let live = restore_user(Admin, State(users.clone()), UrlPath(jd.id)).await;
+        assert_eq!(live.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn retried_creates_replay() {
+        let users: Arc<dyn UserRepository> = Arc::new(MemoryUsers::default());
+        let events = Arc::new(Events::default());
+        let keys: Arc<dyn IdempotencyRepository> = Arc::new(MemoryIdempotency::default());
+        let create = |key: &str, username: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("idempotency-key", key.parse().unwrap());
+            create_user(
+                State(users.clone()),
+                State(events.clone()),
+                State(keys.clone()),
+                headers,
+                Valid(CreateUser {
+                    username: username.to_owned(),
+                }),
+            )
+        };
+
+        let (status, Json(first)) = create("k1", "jd").await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, Json(retried)) = create("k1", "jd").await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(retried, first);
+        assert!(matches!(
+            users.find(2, false).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let reused = create("k1", "other").await.unwrap_err();
+        assert_eq!(reused.0, StatusCode::UNPROCESSABLE_ENTITY);
+        let (_, Json(second)) = create("k2", "jd2").await.unwrap();
+        assert_eq!(second.id, first.id + 1);
+    }
+
+    #[tokio::test]
+    async fn video_metadata() {
+        let Some(app) = TestApp::with_db().await else {
+            return;
+        };
+        let response = app
+            .client
+            .get(app.url("/video/metadata"))
+            .json(&serde_json::json!({"file": "/home/jd/new.mp4"}))
+            .send()
+            .await
+            .unwrap();
+        // only registered files are answered for
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users",
+    request_body = CreateUser,
+    params(("Idempotency-Key" = Option<String>, Header,
+        description = "Retries with the same key get the first answer, for 24 hours")),
+    responses(
+        (status = 201, description = "User created", body = User),
+        (status = 409, description = "A request with the key is still being handled"),
+        (status = 422, description = "Invalid username, by field; or the key came with another request", body = Invalid),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all, fields(username = %payload.username))]
+async fn create_user(
+    State(users): State<Arc<dyn UserRepository>>,
+    State(events): State<Arc<Events>>,
+    State(keys): State<Arc<dyn IdempotencyRepository>>,
+    headers: HeaderMap,
+    // this argument tells axum to parse the request body
+    // as JSON into a `CreateUser` type, and check it
+    Valid(payload): Valid<CreateUser>,
+) -> std::result::Result<(StatusCode, Json<User>), (StatusCode, String)> {
+    let key = idempotency_key(&headers)?;
+    if let Some(key) = &key {
+        let request = format!("{:016x}", instance::fnv1a(payload.username.as_bytes()));
+        match keys
+            .claim(CREATE_USER, key, &request)
+            .await
+            .map_err(db_error)?
+        {
+            Claim::First => {}
+            Claim::Done { status, body } => {
+                let status = StatusCode::from_u16(status).map_err(internal_error)?;
+                let user: User = serde_json::from_value(body).map_err(internal_error)?;
+                info!("user {} replayed for a retry", user.id);
+                return Ok((status, Json(user)));
+            }
+            Claim::Pending => {
+                return Err((
+                    StatusCode::CONFLICT,
+                    "a request with this Idempotency-Key is still being handled".to_owned(),
+                ))
+            }
+            Claim::Mismatch => {
+                return Err((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "this Idempotency-Key came with another request".to_owned(),
+                ))
+            }
+        }
+    }
+
+    let user = match users.create(&payload.username).await {
+        Ok(user) => User::from(user),
+        Err(err) => {
+            // a retry tries again
+            if let Some(key) = &key {
+                if let Err(err) = keys.release(CREATE_USER, key).await {
+                    warn!("can't release Idempotency-Key {}: {}", key, err);
+                }
+            }
+            return Err(db_error(err));
+        }
+    };
+    info!("user {} created", user.id);
+    events.publish(Topic::UserCreated, None, &user);
+    if let Some(key) = &key {
+        let body = serde_json::to_value(&user).map_err(internal_error)?;
+        let stored = keys
+            .complete(CREATE_USER, key, StatusCode::CREATED.as_u16(), &body)
+            .await;
+        if let Err(err) = stored {
+            warn!(
+                "can't store the answer for Idempotency-Key {}: {}",
+                key, err
+            );
+        }
+    }
+
+    // this will be converted into a JSON response
+    // with a status code of `201 Created`
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+// `Idempotency-Key`s of `create_user` are remembered under this
+const CREATE_USER: &str = "POST /users";
+const MAX_IDEMPOTENCY_KEY: usize = 255;
+
+fn idempotency_key(
+    headers: &HeaderMap,
+) -> std::result::Result<Option<String>, (StatusCode, String)> {
+    let Some(key) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY => Ok(Some(key.to_owned())),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Idempotency-Key is 1 to {} visible ASCII characters",
+                MAX_IDEMPOTENCY_KEY
+            ),
+        )),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct GetUser {
+    // deleted users too, for admins
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ListUsers {
+    // deleted users too, for admins
+    #[serde(default)]
+    include_deleted: bool,
+    #[serde(default = "default_users_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_users_limit() -> i64 {
+    100
+}
+
+// deleted users are only shown to admins, by the admin token or an admin session
+fn include_deleted(
+    asked: bool,
+    admin: Option<Admin>,
+) -> std::result::Result<bool, (StatusCode, String)> {
+    if asked && admin.is_none() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "include_deleted is only for admins".to_owned(),
+        ));
+    }
+    Ok(asked)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    params(ListUsers),
+    responses(
+        (status = 200, description = "Users by id", body = [User]),
+        (status = 403, description = "include_deleted by a non-admin"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn list_users(
+    State(users): State<Arc<dyn UserRepository>>,
+    admin: Option<Admin>,
+    Query(query): Query<ListUsers>,
+) -> std::result::Result<Json<Vec<User>>, (StatusCode, String)> {
+    let include_deleted = include_deleted(query.include_deleted, admin)?;
+    let users = users
+        .list(
+            include_deleted,
+            query.limit.clamp(1, 1000),
+            query.offset.max(0),
+        )
+        .await
+        .map_err(db_error)?;
+    Ok(Json(users.into_iter().map(User::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{id}",
+    params(("id" = i64, Path, description = "User id"), GetUser),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 304, description = "Unchanged since If-None-Match or If-Modified-Since"),
+        (status = 403, description = "include_deleted by a non-admin"),
+        (status = 404, description = "No such user, or it's deleted"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn get_user(
+    State(users): State<Arc<dyn UserRepository>>,
+    admin: Option<Admin>,
+    UrlPath(id): UrlPath<i64>,
+    Query(query): Query<GetUser>,
+) -> std::result::Result<([(header::HeaderName, String); 1], Json<User>), (StatusCode, String)> {
+    let include_deleted = include_deleted(query.include_deleted, admin)?;
+    let user = users.find(id, include_deleted).await.map_err(db_error)?;
+    // usernames don't change, deleting is the only change
+    let modified = conditional::http_date(user.deleted_at.unwrap_or(user.created_at));
+    Ok(([(header::LAST_MODIFIED, modified)], Json(User::from(user))))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/users/{id}",
+    params(("id" = i64, Path, description = "User id")),
+    responses(
+        (status = 204, description = "User deleted, admins can restore it"),
+        (status = 403, description = "Another user, by a non-admin"),
+        (status = 404, description = "No such user, or it's deleted already"),
+    ),
+    security(("user_id" = []), ("admin" = [])),
+    tag = "users"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn delete_user(
+    State(users): State<Arc<dyn UserRepository>>,
+    State(clock): State<Arc<dyn Clock>>,
+    admin: Option<Admin>,
+    user: Option<CurrentUser>,
+    UrlPath(id): UrlPath<i64>,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    if admin.is_none() && user.map(|CurrentUser(user)| user) != Some(id) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "only admins delete other users".to_owned(),
+        ));
+    }
+    // they can't sign in anymore, and sessions signed in before end with their next request
+    users.delete(id, clock.now()).await.map_err(db_error)?;
+    info!("user {} deleted", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/restore",
+    params(("id" = i64, Path, description = "User id")),
+    responses(
+        (status = 200, description = "User restored", body = User),
+        (status = 401, description = "Missing or invalid admin token"),
+        (status = 404, description = "No deleted user with the id"),
+    ),
+    security(("admin" = [])),
+    tag = "users"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn restore_user(
+    _: Admin,
+    State(users): State<Arc<dyn UserRepository>>,
+    UrlPath(id): UrlPath<i64>,
+) -> std::result::Result<Json<User>, (StatusCode, String)> {
+    let user = users.restore(id).await.map_err(|err| match err {
+        sqlx::Error::RowNotFound => (StatusCode::NOT_FOUND, format!("no deleted user {}", id)),
+        err => db_error(err),
+    })?;
+    info!("user {} restored", id);
+    Ok(Json(User::from(user)))
+}
+
+// the input to our `create_user` handler
+#[derive(Deserialize, ToSchema, Validate)]
+struct CreateUser {
+    #[validate(length(min = 2, max = 32), custom = "validation::username")]
+    username: String,
+}
+
+// the output to our `create_user` handler
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+struct User {
+    id: i64,
+    username: String,
+    // only for deleted users, which only admins see
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl From<repository::user::User> for User {
+    fn from(user: repository::user::User) -> Self {
+        User {
+            id: user.id,
+            username: user.username,
+            deleted_at: user.deleted_at,
+        }
+    }
+}
+
+/// Utility function for mapping any error into a `500 Internal Server Error`
+/// response.
+fn internal_error<E>(err: E) -> (StatusCode, String)
+where
+    E: std::error::Error,
+{
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+/// Maps database errors to a response: missing rows become `404 Not Found`, constraint
+/// violations caused by the request become `409 Conflict` or `422 Unprocessable Entity`, and
+/// anything else is a `500 Internal Server Error`.
+fn db_error(err: sqlx::Error) -> (StatusCode, String) {
+    match &err {
+        sqlx::Error::RowNotFound => (StatusCode::NOT_FOUND, "not found".to_owned()),
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            (StatusCode::CONFLICT, db.message().to_owned())
+        }
+        sqlx::Error::Database(db) if db.is_foreign_key_violation() => {
+            (StatusCode::UNPROCESSABLE_ENTITY, db.message().to_owned())
+        }
+        _ => internal_error(err),
+    }
+}
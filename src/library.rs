@@ -0,0 +1,868 @@
+// The media library: every media file under a root directory, found by scanning it. Scans walk
+// the root, probe the files that are new or changed since the last scan, and register them as
+// assets with their metadata; files that are gone are marked missing rather than forgotten, as
+// bookmarks and playlists may point at them. Scans run from `rsapp library scan <dir>`, as the
+// `scan_library` scheduled task, or by `POST /admin/library/scan`; `GET /api/v1/library`
+// lists what they found, `GET /api/v1/library/search` finds it by words, best matches first.
+// Scans checksum what they probe, `GET /api/v1/library/duplicates` groups files by them.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, DurationRound, Utc};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    api::v2,
+    app::AppState,
+    asset,
+    auth::Admin,
+    db_error,
+    media::{self, Metadata},
+    probe::Probes,
+    repository::{
+        library::{Checksums, Found, LibraryFile, Ranked, Search},
+        LibraryRepository,
+    },
+    task,
+};
+
+// what streams can be searched by
+const MEDIA: &[&str] = &["video", "audio", "subtitle", "data", "attachment"];
+
+// `[library]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    // the directory scheduled and admin scans walk, they're off without it
+    pub root: Option<String>,
+    // of the files that count as media, without the dot; case doesn't matter
+    #[serde(default = "default_extensions")]
+    pub extensions: Vec<String>,
+    // also hash a frame of new and changed videos, to find those that look alike; that decodes
+    // some of each
+    #[serde(default)]
+    pub frame_hashes: bool,
+}
+
+fn default_extensions() -> Vec<String> {
+    [
+        "mp4", "m4v", "mkv", "mov", "webm", "avi", "ts", "mp3", "m4a", "aac", "flac", "ogg",
+        "opus", "wav",
+    ]
+    .map(str::to_owned)
+    .to_vec()
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            root: None,
+            extensions: default_extensions(),
+            frame_hashes: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        match self
+            .extensions
+            .iter()
+            .find(|extension| extension.is_empty() || extension.contains('.'))
+        {
+            Some(extension) => Err(format!(
+                "library.extensions: {:?} isn't an extension without the dot",
+                extension
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// what a scan did
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ScanReport {
+    pub root: String,
+    // media files there are
+    pub found: usize,
+    pub added: usize,
+    pub changed: usize,
+    // missing before, back now
+    pub returned: usize,
+    pub missing: usize,
+    // registered without metadata, as probing them failed
+    pub unreadable: usize,
+}
+
+impl ScanReport {
+    // how many files the library has news of
+    pub fn updated(&self) -> usize {
+        self.added + self.changed + self.returned + self.missing
+    }
+}
+
+pub struct Library {
+    settings: Settings,
+    files: Arc<dyn LibraryRepository>,
+    probes: Arc<Probes>,
+    scanning: AtomicBool,
+}
+
+impl Library {
+    pub fn new(settings: Settings, files: Arc<dyn LibraryRepository>, probes: Arc<Probes>) -> Self {
+        Library {
+            settings,
+            files,
+            probes,
+            scanning: AtomicBool::new(false),
+        }
+    }
+
+    // scans the configured root
+    pub async fn scan_root(&self) -> Result<ScanReport, String> {
+        let root = self
+            .settings
+            .root
+            .as_ref()
+            .ok_or("library.root isn't set")?;
+        self.scan(Path::new(root)).await
+    }
+
+    // one scan at a time, others are refused while it runs
+    pub async fn scan(&self, root: &Path) -> Result<ScanReport, String> {
+        self.scan_with_progress(root, &|_, _| {}).await
+    }
+
+    // like `scan`, telling `progress` how many of the media files found were checked so far, and
+    // how many there are
+    pub async fn scan_with_progress(
+        &self,
+        root: &Path,
+        progress: &(dyn Fn(usize, usize) + Sync),
+    ) -> Result<ScanReport, String> {
+        if self.scanning.swap(true, Ordering::AcqRel) {
+            return Err("a scan is running already".to_owned());
+        }
+        let scanned = self.scan_now(root, progress).await;
+        self.scanning.store(false, Ordering::Release);
+        scanned
+    }
+
+    async fn scan_now(
+        &self,
+        root: &Path,
+        progress: &(dyn Fn(usize, usize) + Sync),
+    ) -> Result<ScanReport, String> {
+        let root = root
+            .canonicalize()
+            .map_err(|err| format!("can't scan {}: {}", root.display(), err))?;
+        let extensions: Vec<String> = self
+            .settings
+            .extensions
+            .iter()
+            .map(|extension| extension.to_lowercase())
+            .collect();
+        let found = {
+            let root = root.clone();
+            tokio::task::spawn_blocking(move || walk(&root, &extensions))
+                .await
+                .map_err(|err| err.to_string())?
+        };
+        let root = root.to_string_lossy().into_owned();
+        let known = self
+            .files
+            .known(&root)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut known: HashMap<String, LibraryFile> = known
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect();
+
+        let scanned_at = Utc::now();
+        let mut report = ScanReport {
+            root,
+            found: found.len(),
+            ..ScanReport::default()
+        };
+        for (checked, found) in found.into_iter().enumerate() {
+            progress(checked, report.found);
+            let probe = match known.remove(&found.path) {
+                None => {
+                    report.added += 1;
+                    true
+                }
+                Some(file) if file.size != found.size || file.modified_at != found.modified_at => {
+                    report.changed += 1;
+                    true
+                }
+                Some(file) if file.missing_since.is_some() => {
+                    report.returned += 1;
+                    false
+                }
+                Some(_) => continue,
+            };
+            let metadata = if probe {
+                match self.probes.probe(PathBuf::from(&found.path), false).await {
+                    Ok(metadata) => Some(metadata),
+                    Err(err) => {
+                        warn!("can't probe {}: {}", found.path, err);
+                        report.unreadable += 1;
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let checksums = if probe {
+                match self.checksums(&found.path, metadata.as_deref()).await {
+                    Ok(checksums) => Some(checksums),
+                    Err(err) => {
+                        warn!("can't checksum {}: {}", found.path, err);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let title = asset::title_from_path(&found.path);
+            let (metadata, checksums) = (metadata.as_deref(), checksums.as_ref());
+            self.files
+                .store(&found, &title, metadata, checksums, scanned_at)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        progress(report.found, report.found);
+        // what's left wasn't found
+        let gone: Vec<i64> = known
+            .values()
+            .filter(|file| file.missing_since.is_none())
+            .map(|file| file.asset_id)
+            .collect();
+        report.missing = gone.len();
+        if !gone.is_empty() {
+            self.files
+                .mark_missing(&gone, scanned_at)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        info!(
+            "scanned {}: {} media files, {} added, {} changed, {} back, {} missing",
+            report.root,
+            report.found,
+            report.added,
+            report.changed,
+            report.returned,
+            report.missing
+        );
+        Ok(report)
+    }
+
+    // Hashes the whole file at `path`, and a frame a tenth into it, past fades from black, when
+    // it's a video and frame hashes are asked for. Frames that can't be decoded are left out.
+    async fn checksums(
+        &self,
+        path: &str,
+        metadata: Option<&Metadata>,
+    ) -> Result<Checksums, String> {
+        let path = PathBuf::from(path);
+        let video = metadata.is_some_and(|metadata| {
+            metadata
+                .streams
+                .iter()
+                .any(|stream| stream.medium == "video")
+        });
+        let duration = metadata.and_then(|metadata| metadata.duration);
+        let frame_at =
+            (self.settings.frame_hashes && video).then(|| duration.unwrap_or(0.0) / 10.0);
+        tokio::task::spawn_blocking(move || {
+            let sha256 = sha256(&path).map_err(|err| err.to_string())?;
+            let frame_hash = frame_at.and_then(|time| match media::frame_hash(&path, time) {
+                Ok(hash) => Some(hash),
+                Err(err) => {
+                    warn!("can't hash a frame of {}: {}", path.display(), err);
+                    None
+                }
+            });
+            Ok(Checksums { sha256, frame_hash })
+        })
+        .await
+        .map_err(|err| err.to_string())?
+    }
+}
+
+// of the contents of the file at `path`, in hex
+fn sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// The media files under `root` by their extension, without following symbolic links or going
+// into hidden directories. Directories that can't be read are skipped.
+fn walk(root: &Path, extensions: &[String]) -> Vec<Found> {
+    let mut found = Vec::new();
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("can't scan {}: {}", dir.display(), err);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() && !hidden {
+                dirs.push(path);
+                continue;
+            }
+            let media = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .is_some_and(|extension| extensions.contains(&extension));
+            if !file_type.is_file() || !media {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push(Found {
+                path: path.to_string_lossy().into_owned(),
+                size: metadata.len() as i64,
+                modified_at: stored_time(modified),
+            });
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    found
+}
+
+// as precise as Postgres keeps it, so unchanged files compare equal to what was stored
+fn stored_time(time: SystemTime) -> DateTime<Utc> {
+    let time = DateTime::<Utc>::from(time);
+    time.duration_trunc(chrono::Duration::microseconds(1))
+        .unwrap_or(time)
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct SearchLibrary {
+    // in the title or path, ignoring case
+    q: Option<String>,
+    // video, audio, subtitle, data or attachment: files with such a stream
+    medium: Option<String>,
+    // files scans didn't find anymore, instead of those they do
+    #[serde(default)]
+    missing: bool,
+    // files with the tag of this name, ignoring case
+    tag: Option<String>,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct SearchText {
+    // words of the title, tags, container format or codecs: all of them unless joined by `or`,
+    // `"in a row"` when quoted, and none that start with `-`
+    q: String,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct LibraryEntry {
+    asset_id: i64,
+    path: String,
+    title: String,
+    // bytes
+    size: i64,
+    modified_at: DateTime<Utc>,
+    missing_since: Option<DateTime<Utc>>,
+    // seconds, null when unknown
+    duration: Option<f64>,
+    // of its streams, like `["video", "audio"]`, empty when it couldn't be probed
+    media: Vec<String>,
+    // names of its tags, ordered
+    tags: Vec<String>,
+    // of its contents when last scanned, in hex
+    sha256: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    entry: LibraryEntry,
+    // how well it matched, higher is better; words of the title count most, then tags
+    rank: f32,
+}
+
+impl From<Ranked> for SearchHit {
+    fn from(ranked: Ranked) -> Self {
+        SearchHit {
+            entry: LibraryEntry::from(ranked.file),
+            rank: ranked.rank,
+        }
+    }
+}
+
+impl From<LibraryFile> for LibraryEntry {
+    fn from(file: LibraryFile) -> Self {
+        let mut media: Vec<String> = Vec::new();
+        if let Some(metadata) = &file.metadata {
+            for stream in &metadata.streams {
+                if !media.contains(&stream.medium) {
+                    media.push(stream.medium.clone());
+                }
+            }
+        }
+        LibraryEntry {
+            asset_id: file.asset_id,
+            path: file.path,
+            title: file.title,
+            size: file.size,
+            modified_at: file.modified_at,
+            missing_since: file.missing_since,
+            duration: file.metadata.and_then(|metadata| metadata.duration),
+            media,
+            tags: file.tags,
+            sha256: file.checksums.map(|checksums| checksums.sha256),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct FindDuplicates {
+    // also videos that look alike, by the hashes of a frame that scans with
+    // `library.frame_hashes` took
+    #[serde(default)]
+    similar: bool,
+    // how many of the 64 bits of two frame hashes may differ for the videos to look alike
+    #[serde(default = "default_distance")]
+    distance: u32,
+}
+
+fn default_distance() -> u32 {
+    6
+}
+
+const MAX_DISTANCE: u32 = 32;
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sameness {
+    // byte for byte
+    Identical,
+    // by a frame, though their bytes differ
+    Similar,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct Duplicates {
+    sameness: Sameness,
+    // by path
+    files: Vec<LibraryEntry>,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/library", get(search))
+        .route("/library/search", get(search_text))
+        .route("/library/duplicates", get(duplicates))
+}
+
+pub fn admin_routes() -> Router<AppState> {
+    Router::new().route("/admin/library/scan", post(scan))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library",
+    params(SearchLibrary),
+    responses(
+        (status = 200, description = "Media files of the library, ordered by path", body = [LibraryEntry]),
+        (status = 400, description = "Unknown medium"),
+    ),
+    tag = "library"
+)]
+async fn search(
+    State(files): State<Arc<dyn LibraryRepository>>,
+    Query(query): Query<SearchLibrary>,
+) -> Result<Json<Vec<LibraryEntry>>, (StatusCode, String)> {
+    if let Some(medium) = &query.medium {
+        if !MEDIA.contains(&medium.as_str()) {
+            let message = format!("unknown medium {:?}, one of {}", medium, MEDIA.join(", "));
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+    }
+    let search = Search {
+        text: query.q.filter(|text| !text.trim().is_empty()),
+        medium: query.medium,
+        missing: query.missing,
+        tag: query.tag,
+        limit: query.limit.clamp(1, 1000),
+        offset: query.offset.max(0),
+    };
+    let found = files.search(&search).await.map_err(db_error)?;
+    Ok(Json(found.into_iter().map(LibraryEntry::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library/search",
+    params(SearchText),
+    responses(
+        (status = 200, description = "Media files of the library that are there, best matches first", body = [SearchHit]),
+        (status = 400, description = "No words to search for"),
+    ),
+    tag = "library"
+)]
+async fn search_text(
+    State(files): State<Arc<dyn LibraryRepository>>,
+    Query(query): Query<SearchText>,
+) -> Result<(Extension<v2::Meta>, Json<Vec<SearchHit>>), (StatusCode, String)> {
+    if query.q.trim().is_empty() {
+        let message = "q needs words to search for".to_owned();
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    let found = files
+        .search_text(&query.q, limit, offset)
+        .await
+        .map_err(db_error)?;
+
+    // the page, for clients of v2
+    let meta = v2::Meta(json!({ "limit": limit, "offset": offset, "count": found.len() }));
+    Ok((
+        Extension(meta),
+        Json(found.into_iter().map(SearchHit::from).collect()),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library/duplicates",
+    params(FindDuplicates),
+    responses(
+        (status = 200, description = "Groups of files there are with the same contents, then with frames that look alike", body = [Duplicates]),
+        (status = 422, description = "Distance out of range"),
+    ),
+    tag = "library"
+)]
+async fn duplicates(
+    State(files): State<Arc<dyn LibraryRepository>>,
+    Query(query): Query<FindDuplicates>,
+) -> Result<Json<Vec<Duplicates>>, (StatusCode, String)> {
+    if query.distance > MAX_DISTANCE {
+        let message = format!("distance must be from 0 to {}", MAX_DISTANCE);
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+    let identical = files.identical().await.map_err(db_error)?;
+    let mut found = Vec::new();
+    let sha256 = |file: &LibraryFile| file.checksums.as_ref().map(|sums| sums.sha256.clone());
+    for group in identical.chunk_by(|a, b| sha256(a) == sha256(b)) {
+        found.push(Duplicates {
+            sameness: Sameness::Identical,
+            files: group.iter().cloned().map(LibraryEntry::from).collect(),
+        });
+    }
+    if query.similar {
+        let hashed = files.frame_hashed().await.map_err(db_error)?;
+        for group in look_alike(hashed, query.distance) {
+            found.push(Duplicates {
+                sameness: Sameness::Similar,
+                files: group.into_iter().map(LibraryEntry::from).collect(),
+            });
+        }
+    }
+    Ok(Json(found))
+}
+
+// Groups of files whose frame hashes are at most `distance` bits apart from another of the
+// group, leaving out those all with the same contents. In the order of `files`.
+fn look_alike(files: Vec<LibraryFile>, distance: u32) -> Vec<Vec<LibraryFile>> {
+    let hash = |file: &LibraryFile| file.checksums.as_ref().and_then(|sums| sums.frame_hash);
+    // the first file of its group, for each file
+    let mut group: Vec<usize> = (0..files.len()).collect();
+    fn first(group: &mut [usize], mut file: usize) -> usize {
+        while group[file] != file {
+            group[file] = group[group[file]];
+            file = group[file];
+        }
+        file
+    }
+    for (i, a) in files.iter().enumerate() {
+        for (j, b) in files.iter().enumerate().skip(i + 1) {
+            let (Some(a), Some(b)) = (hash(a), hash(b)) else {
+                continue;
+            };
+            if (a ^ b).count_ones() <= distance {
+                let (i, j) = (first(&mut group, i), first(&mut group, j));
+                group[i.max(j)] = i.min(j);
+            }
+        }
+    }
+    let mut groups: Vec<Vec<LibraryFile>> = Vec::new();
+    // where each group is in `groups`, by its first file
+    let mut positions: HashMap<usize, usize> = HashMap::new();
+    for (i, file) in files.into_iter().enumerate() {
+        let position = *positions.entry(first(&mut group, i)).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[position].push(file);
+    }
+    groups.retain(|group| {
+        let sha256 = |file: &LibraryFile| file.checksums.as_ref().map(|sums| sums.sha256.clone());
+        group.len() > 1 && group.iter().any(|file| sha256(file) != sha256(&group[0]))
+    });
+    groups
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/library/scan",
+    responses(
+        (status = 202, description = "Scanning `library.root` in the background"),
+        (status = 409, description = "A scan is running already"),
+        (status = 503, description = "`library.root` isn't set"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn scan(
+    _: Admin,
+    State(library): State<Arc<Library>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if library.settings.root.is_none() {
+        let message = "library.root isn't set".to_owned();
+        return Err((StatusCode::SERVICE_UNAVAILABLE, message));
+    }
+    if library.scanning.load(Ordering::Acquire) {
+        let message = "a scan is running already".to_owned();
+        return Err((StatusCode::CONFLICT, message));
+    }
+    let library = library.clone();
+    task::spawn("library scan", task::Kind::Work, async move {
+        if let Err(err) = library.scan_root().await {
+            warn!("library scan failed: {}", err);
+        }
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::library::MemoryLibrary;
+
+    fn library(files: Arc<MemoryLibrary>) -> Library {
+        Library::new(Settings::default(), files, Arc::new(Probes::default()))
+    }
+
+    #[tokio::test]
+    async fn scans() {
+        let root = std::env::temp_dir().join(format!("rsapp-library-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("shows/.trash")).unwrap();
+        std::fs::write(root.join("a.mp4"), b"not really").unwrap();
+        std::fs::write(root.join("shows/b.MKV"), b"not really").unwrap();
+        std::fs::write(root.join("shows/notes.txt"), b"skipped").unwrap();
+        std::fs::write(root.join("shows/.trash/c.mp4"), b"hidden").unwrap();
+
+        let files = Arc::new(MemoryLibrary::default());
+        let library = library(files.clone());
+        let report = library.scan(&root).await.unwrap();
+        assert_eq!((report.found, report.added, report.missing), (2, 2, 0));
+        let checked = std::sync::Mutex::new(Vec::new());
+        let again = library
+            .scan_with_progress(&root, &|done, of| checked.lock().unwrap().push((done, of)))
+            .await
+            .unwrap();
+        assert_eq!((again.found, again.updated()), (2, 0));
+        assert_eq!(checked.into_inner().unwrap(), [(0, 2), (1, 2), (2, 2)]);
+
+        std::fs::remove_file(root.join("a.mp4")).unwrap();
+        std::fs::write(root.join("shows/b.MKV"), b"longer than it was").unwrap();
+        let report = library.scan(&root).await.unwrap();
+        assert_eq!((report.changed, report.missing), (1, 1));
+
+        let search = |text: &str, missing: bool| Search {
+            text: Some(text.to_owned()),
+            missing,
+            limit: 10,
+            ..Search::default()
+        };
+        let present = files.search(&search("B", false)).await.unwrap();
+        assert_eq!(present.len(), 1);
+        assert_eq!(present[0].title, "b");
+        let gone = files.search(&search("a.mp4", true)).await.unwrap();
+        assert_eq!(gone.len(), 1);
+        assert!(gone[0].missing_since.is_some());
+
+        std::fs::write(root.join("a.mp4"), b"not really").unwrap();
+        let report = library.scan(&root).await.unwrap();
+        assert_eq!(report.updated(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn metadata(artist: &str, codec: &str) -> media::Metadata {
+        media::Metadata {
+            schema: media::schema::VERSION,
+            format: "mov,mp4,m4a".to_owned(),
+            duration: Some(60.0),
+            bit_rate: None,
+            tags: [("artist".to_owned(), artist.to_owned())].into(),
+            streams: vec![media::StreamInfo {
+                index: 0,
+                medium: "audio".to_owned(),
+                codec: codec.to_owned(),
+                duration: Some(60.0),
+                width: None,
+                height: None,
+                frame_rate: None,
+                sample_rate: Some(48000),
+                channels: Some(2),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn searches_text() {
+        let files = Arc::new(MemoryLibrary::default());
+        let now = Utc::now();
+        for (path, title, artist) in [
+            ("/media/b.m4a", "Blue Train", "John Coltrane"),
+            ("/media/a.m4a", "Giant Steps", "John Coltrane"),
+            ("/media/c.m4a", "Coltrane Live", "Someone Else"),
+        ] {
+            let found = Found {
+                path: path.to_owned(),
+                size: 1,
+                modified_at: now,
+            };
+            let metadata = metadata(artist, "aac");
+            files
+                .store(&found, title, Some(&metadata), None, now)
+                .await
+                .unwrap();
+        }
+        let search = |q: &str| {
+            let files: Arc<dyn LibraryRepository> = files.clone();
+            let query = SearchText {
+                q: q.to_owned(),
+                limit: 10,
+                offset: 0,
+            };
+            search_text(State(files), Query(query))
+        };
+
+        // the title weighs more than the tags, ties go by path
+        let (_, Json(hits)) = search("coltrane").await.unwrap();
+        let titles: Vec<&str> = hits.iter().map(|hit| hit.entry.title.as_str()).collect();
+        assert_eq!(titles, ["Coltrane Live", "Giant Steps", "Blue Train"]);
+        let (Extension(meta), Json(hits)) = search("Train AAC").await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(meta.0["count"], 1);
+        assert!(search("train flac").await.unwrap().1 .0.is_empty());
+        assert_eq!(search(" ").await.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn finds_identical_files() {
+        let root = std::env::temp_dir().join(format!("rsapp-duplicates-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("copies")).unwrap();
+        std::fs::write(root.join("a.mp4"), b"the same").unwrap();
+        std::fs::write(root.join("copies/a.mp4"), b"the same").unwrap();
+        std::fs::write(root.join("b.mp4"), b"another").unwrap();
+        let files = Arc::new(MemoryLibrary::default());
+        library(files.clone()).scan(&root).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let query = FindDuplicates {
+            similar: true,
+            distance: 6,
+        };
+        let Json(found) = duplicates(State(files), Query(query)).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].sameness, Sameness::Identical);
+        let paths: Vec<&str> = found[0].files.iter().map(|file| &file.path[..]).collect();
+        assert!(paths[0].ends_with("/a.mp4") && paths[1].ends_with("/copies/a.mp4"));
+        // printf "the same" | sha256sum
+        assert_eq!(
+            found[0].files[0].sha256.as_deref(),
+            Some("fa0487f2dc91544ab4b9f5b1d59b7eb2bf68807a6e42cdf22fce4e0bc4b84cb2")
+        );
+    }
+
+    #[test]
+    fn groups_look_alikes() {
+        let file = |path: &str, sha256: &str, frame_hash: u64| LibraryFile {
+            asset_id: 0,
+            path: path.to_owned(),
+            title: path.to_owned(),
+            size: 1,
+            modified_at: Utc::now(),
+            missing_since: None,
+            metadata: None,
+            tags: Vec::new(),
+            checksums: Some(Checksums {
+                sha256: sha256.to_owned(),
+                frame_hash: Some(frame_hash),
+            }),
+        };
+        let files = vec![
+            file("a", "1", 0b1111),
+            file("b", "2", 0xff00),
+            // two bits from a, and from d
+            file("c", "3", 0b1100),
+            file("d", "4", 0b0000),
+            // like b, but the same bytes
+            file("e", "2", 0xff01),
+        ];
+        let groups: Vec<Vec<String>> = look_alike(files, 2)
+            .into_iter()
+            .map(|group| group.into_iter().map(|file| file.path).collect())
+            .collect();
+        assert_eq!(groups, [["a", "c", "d"]]);
+    }
+
+    #[test]
+    fn validates() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+        let settings = Settings {
+            root: None,
+            extensions: vec![".mp4".to_owned()],
+            frame_hashes: false,
+        };
+        assert!(settings.validate().is_err());
+    }
+}
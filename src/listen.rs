@@ -0,0 +1,740 @@
+// Where the server takes connections and how it talks on them. It listens on all interfaces on the
+// port of the command by default, on the address of `server.listen`, or with
+// `server.listen = "unix:/run/rsapp.sock"` on a Unix socket, say for nginx on the same host; the
+// socket gets the permissions of `server.socket_mode`.
+//
+// With `server.admin_listen` the admin endpoints are served there instead, see `admin::split`.
+//
+// Instead of binding, the server takes over listening sockets it's started with: those of systemd's
+// socket activation, or the descriptors of `--fd`, `--admin-fd` and `--grpc-fd` from a supervisor
+// or a server handing over to a new one (see `handover`), so restarts don't refuse connections in
+// between.
+//
+// HTTP/1.1 and HTTP/2 are tuned with `[server.http]`: hyper's defaults suit short requests, while
+// media downloads keep connections open for long. The connections taken and how they went are
+// counted for `GET /admin/runtime`.
+use std::{
+    fmt::Display,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    async_trait,
+    http::{Request, StatusCode, Version},
+    response::IntoResponse,
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use serde_derive::{Deserialize, Serialize};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(listen: &str) -> Result<Self, Self::Err> {
+        match listen.strip_prefix("unix:") {
+            Some("") => Err("unix: needs the path of the socket".to_owned()),
+            #[cfg(unix)]
+            Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err("unix sockets are only there on unix".to_owned()),
+            None => listen
+                .parse()
+                .map(Listen::Tcp)
+                .map_err(|err| format!("{}: {}", listen, err)),
+        }
+    }
+}
+
+impl Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// permissions of files go up to rwxrwxrwx
+pub fn validate_mode(mode: u32) -> Result<(), String> {
+    if mode > 0o777 {
+        return Err(format!("{:o} isn't a mode like 0o660", mode));
+    }
+    Ok(())
+}
+
+// `[server.http]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Http {
+    // HTTP/2 besides HTTP/1.1, for clients starting with it such as proxies and gRPC tools
+    pub http2: bool,
+    // HTTP/1.1 connections kept open between requests
+    pub keep_alive: bool,
+    // seconds HTTP/1.1 clients have to send the headers of a request, 0 for no limit
+    pub header_read_timeout: u64,
+    // requests at the same time on an HTTP/2 connection
+    pub max_concurrent_streams: u32,
+    // seconds between pings on HTTP/2 connections, finding out about peers gone while a download
+    // stalls, 0 for none
+    pub keep_alive_interval: u64,
+    // seconds the answer to a ping may take before the connection is closed
+    pub keep_alive_timeout: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            http2: true,
+            keep_alive: true,
+            header_read_timeout: 30,
+            max_concurrent_streams: 200,
+            keep_alive_interval: 20,
+            keep_alive_timeout: 20,
+        }
+    }
+}
+
+impl Http {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent_streams == 0 {
+            return Err("server.http.max_concurrent_streams must be at least 1".to_owned());
+        }
+        if self.keep_alive_interval > 0 && self.keep_alive_timeout == 0 {
+            return Err("server.http.keep_alive_timeout must be at least 1 second".to_owned());
+        }
+        Ok(())
+    }
+
+    fn builder(&self) -> Builder<TokioExecutor> {
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(seconds(self.header_read_timeout));
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.max_concurrent_streams)
+            .keep_alive_interval(seconds(self.keep_alive_interval))
+            .keep_alive_timeout(Duration::from_secs(self.keep_alive_timeout));
+        builder
+    }
+}
+
+// none for 0
+fn seconds(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+// the connections taken so far and how they went
+#[derive(Default)]
+pub struct Connections {
+    accepted: AtomicU64,
+    open: AtomicU64,
+    http1_requests: AtomicU64,
+    http2_requests: AtomicU64,
+    // of the connections closed
+    closed: AtomicU64,
+    closed_requests: AtomicU64,
+    closed_millis: AtomicU64,
+    longest_millis: AtomicU64,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct ConnectionStats {
+    pub accepted: u64,
+    pub open: u64,
+    pub http1_requests: u64,
+    pub http2_requests: u64,
+    // on average over the connections closed
+    pub requests_per_connection: f64,
+    pub seconds_per_connection: f64,
+    // the longest a closed connection was open
+    pub longest_seconds: f64,
+}
+
+impl Connections {
+    pub fn stats(&self) -> ConnectionStats {
+        let closed = self.closed.load(Ordering::Relaxed).max(1) as f64;
+        let millis = |millis: &AtomicU64| millis.load(Ordering::Relaxed) as f64 / 1000.0;
+        ConnectionStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+            http1_requests: self.http1_requests.load(Ordering::Relaxed),
+            http2_requests: self.http2_requests.load(Ordering::Relaxed),
+            requests_per_connection: self.closed_requests.load(Ordering::Relaxed) as f64 / closed,
+            seconds_per_connection: millis(&self.closed_millis) / closed,
+            longest_seconds: millis(&self.longest_millis),
+        }
+    }
+
+    fn opened(self: &Arc<Self>, peer: String) -> Open {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.open.fetch_add(1, Ordering::Relaxed);
+        Open {
+            connections: self.clone(),
+            peer,
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+        }
+    }
+}
+
+// a connection, closed once this is dropped with the last request on it
+struct Open {
+    connections: Arc<Connections>,
+    peer: String,
+    started: Instant,
+    requests: AtomicU64,
+}
+
+impl Open {
+    fn request(&self, version: Version) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let requests = match version {
+            Version::HTTP_2 => &self.connections.http2_requests,
+            _ => &self.connections.http1_requests,
+        };
+        requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        let connections = &self.connections;
+        let requests = self.requests.load(Ordering::Relaxed);
+        let millis = self.started.elapsed().as_millis() as u64;
+        connections.open.fetch_sub(1, Ordering::Relaxed);
+        connections.closed.fetch_add(1, Ordering::Relaxed);
+        connections
+            .closed_requests
+            .fetch_add(requests, Ordering::Relaxed);
+        connections
+            .closed_millis
+            .fetch_add(millis, Ordering::Relaxed);
+        connections
+            .longest_millis
+            .fetch_max(millis, Ordering::Relaxed);
+        debug!(peer = %self.peer, requests, millis, "connection closed");
+    }
+}
+
+impl Listen {
+    // `mode` is for sockets, which are otherwise as the umask has it
+    pub async fn bind(&self, mode: Option<u32>) -> io::Result<Listener> {
+        match self {
+            Listen::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            Listen::Unix(path) => Ok(Listener::Unix(bind_unix(path, mode)?, Some(path.clone()))),
+        }
+    }
+}
+
+// the first descriptor systemd passes on
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// set once the sockets are handed over to a new server, which goes on serving on them
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+// keeps the Unix sockets the server bound when it's done with them, for the server it handed over to
+pub fn hand_over() {
+    HANDED_OVER.store(true, Ordering::Relaxed);
+}
+
+// descriptors the server was started with: of listening sockets, and the one to tell the server
+// handing over that it listens too
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fds {
+    pub public: Option<i32>,
+    pub admin: Option<i32>,
+    pub grpc: Option<i32>,
+    pub ready: Option<i32>,
+}
+
+// the listeners taken over, see `Fds::take`
+pub struct Inherited {
+    pub public: Option<Listener>,
+    pub admin: Option<Listener>,
+    pub grpc: Option<TcpListener>,
+}
+
+impl Fds {
+    // those of systemd's socket activation without any listening sockets given
+    pub fn or_systemd(self) -> Self {
+        if self.public.or(self.admin).or(self.grpc).is_some() {
+            return self;
+        }
+        Fds {
+            ready: self.ready,
+            ..systemd()
+        }
+    }
+
+    // the listeners of the descriptors there are
+    pub fn take(self) -> io::Result<Inherited> {
+        let grpc = match self.grpc.map(inherit).transpose()? {
+            Some(Listener::Tcp(listener)) => Some(listener),
+            #[cfg(unix)]
+            Some(Listener::Unix(..)) => {
+                let message = "gRPC is only served on TCP sockets";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            None => None,
+        };
+        Ok(Inherited {
+            public: self.public.map(inherit).transpose()?,
+            admin: self.admin.map(inherit).transpose()?,
+            grpc,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn systemd() -> Fds {
+    let var = |name| std::env::var(name).unwrap_or_default();
+    let fds = activated(
+        &var("LISTEN_PID"),
+        &var("LISTEN_FDS"),
+        &var("LISTEN_FDNAMES"),
+    );
+    // not meant for the programs the server runs
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    fds
+}
+
+#[cfg(not(unix))]
+fn systemd() -> Fds {
+    Fds::default()
+}
+
+// the descriptors of systemd's socket activation when meant for this process: those named `admin`
+// and `grpc` with `FileDescriptorName=` serve the admin endpoints and gRPC, the first of the others
+// the rest
+#[cfg(unix)]
+fn activated(pid: &str, fds: &str, names: &str) -> Fds {
+    if pid.parse() != Ok(std::process::id()) {
+        return Fds::default();
+    }
+    let mut names = names.split(':');
+    let mut activated = Fds::default();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds.parse().unwrap_or(0) {
+        match names.next() {
+            Some("admin") => activated.admin = activated.admin.or(Some(fd)),
+            Some("grpc") => activated.grpc = activated.grpc.or(Some(fd)),
+            _ => activated.public = activated.public.or(Some(fd)),
+        }
+    }
+    activated
+}
+
+// the listening socket of descriptor `fd`, TCP or Unix
+#[cfg(unix)]
+fn inherit(fd: i32) -> io::Result<Listener> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // SAFETY: the descriptor was passed on for the server to take over, nothing else owns it
+    let passed = unsafe { OwnedFd::from_raw_fd(fd) };
+    // a duplicate that's closed on exec, so the programs the server runs don't keep the socket
+    let unix = std::os::unix::net::UnixListener::from(passed.try_clone()?);
+    drop(passed);
+    // the address of a TCP socket isn't a Unix one
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(Listener::Unix(UnixListener::from_std(unix)?, None));
+    }
+    let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+    tcp.local_addr()?;
+    tcp.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+}
+
+#[cfg(not(unix))]
+fn inherit(_: i32) -> io::Result<Listener> {
+    let message = "sockets are only passed on on unix";
+    Err(io::Error::new(io::ErrorKind::Unsupported, message))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // the path of sockets bound by the server, which removes them when done
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    // as the boot report lists it
+    pub fn address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|err| err.to_string(), |address| address.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => match listener.local_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_owned(),
+                },
+                Err(err) => err.to_string(),
+            },
+        }
+    }
+
+    // the descriptor of the listening socket, to hand over
+    #[cfg(unix)]
+    pub fn fd(&self) -> i32 {
+        use std::os::fd::AsRawFd;
+
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+
+    // serves `router` until `shutdown` completes and the connections open by then are done
+    pub fn serve(
+        self,
+        router: Router,
+        http: &Http,
+        connections: Arc<Connections>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> impl Future<Output = io::Result<()>> {
+        let server = Server {
+            router,
+            builder: http.builder(),
+            http2: http.http2,
+            connections,
+        };
+        async move {
+            match self {
+                Listener::Tcp(listener) => {
+                    server.run(listener, shutdown).await;
+                    Ok(())
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener, path) => {
+                    server.run(listener, shutdown).await;
+                    match path {
+                        Some(path) if !HANDED_OVER.load(Ordering::Relaxed) => {
+                            std::fs::remove_file(path)
+                        }
+                        _ => Ok(()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// serves each router on its listener until `shutdown` completes and the connections open by then
+// are done
+pub async fn serve_all(
+    served: Vec<(Listener, Router)>,
+    http: &Http,
+    connections: Arc<Connections>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let (quit, quitting) = watch::channel(());
+    let serving: Vec<_> = served
+        .into_iter()
+        .map(|(listener, router)| {
+            let mut quitting = quitting.clone();
+            let quit = async move {
+                let _ = quitting.changed().await;
+            };
+            tokio::spawn(listener.serve(router, http, connections.clone(), quit))
+        })
+        .collect();
+    shutdown.await;
+    let _ = quit.send(());
+    for serving in serving {
+        serving.await.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+#[async_trait]
+trait Accept: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    // a connection, and who is on the other end of it
+    async fn accept(&self) -> io::Result<(Self::Stream, String)>;
+}
+
+#[async_trait]
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, peer) = TcpListener::accept(self).await?;
+        // responses go out as written instead of waiting to fill packets, as `axum::serve` has it
+        stream.set_nodelay(true)?;
+        Ok((stream, peer.to_string()))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(UnixStream, String)> {
+        let (stream, _) = UnixListener::accept(self).await?;
+        Ok((stream, "unix".to_owned()))
+    }
+}
+
+// the address a request's connection is from, without the port; "unix" for Unix sockets
+#[derive(Debug, Clone)]
+pub struct RemoteAddress(pub Arc<str>);
+
+struct Server {
+    router: Router,
+    builder: Builder<TokioExecutor>,
+    http2: bool,
+    connections: Arc<Connections>,
+}
+
+impl Server {
+    async fn run(self, listener: impl Accept, shutdown: impl Future<Output = ()>) {
+        let graceful = GracefulShutdown::new();
+        let mut shutdown = pin!(shutdown);
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // such as too many open files, which waiting may fix
+                        warn!("accepting a connection failed: {}", err);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let remote = match peer.parse::<SocketAddr>() {
+                Ok(address) => RemoteAddress(address.ip().to_string().into()),
+                Err(_) => RemoteAddress(peer.as_str().into()),
+            };
+            let open = Arc::new(self.connections.opened(peer));
+            let (router, http2) = (self.router.clone(), self.http2);
+            let service = tower::service_fn(move |mut request: Request<Incoming>| {
+                open.request(request.version());
+                request.extensions_mut().insert(remote.clone());
+                let router = router.clone();
+                async move {
+                    // serving upgrades, as websockets need, hyper takes HTTP/2 from the first
+                    // bytes whatever it's told, so it's refused here
+                    if !http2 && request.version() == Version::HTTP_2 {
+                        return Ok(StatusCode::HTTP_VERSION_NOT_SUPPORTED.into_response());
+                    }
+                    router.oneshot(request).await
+                }
+            });
+            let connection = self
+                .builder
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    TowerToHyperService::new(service),
+                )
+                .into_owned();
+            let connection = graceful.watch(connection);
+            tokio::spawn(async move {
+                if let Err(err) = connection.await {
+                    debug!("connection failed: {}", err);
+                }
+            });
+        }
+        drop(listener);
+        graceful.shutdown().await;
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    // a socket left behind by a server that didn't exit cleanly, unless one still answers on it
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            let message = format!("{} is there and isn't a socket", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            let message = format!("another server listens on {}", path.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    #[test]
+    fn parses() {
+        assert_eq!(
+            "127.0.0.1:9009".parse(),
+            Ok(Listen::Tcp(([127, 0, 0, 1], 9009).into()))
+        );
+        assert!("localhost".parse::<Listen>().is_err());
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!(validate_mode(0o660).is_ok());
+        assert!(validate_mode(0o1777).is_err());
+        assert!(Http::default().validate().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_systemd_descriptors_of_this_process() {
+        let pid = std::process::id().to_string();
+        let all = Fds {
+            public: Some(4),
+            admin: Some(3),
+            grpc: Some(5),
+            ready: None,
+        };
+        assert_eq!(activated(&pid, "3", "admin:http:grpc"), all);
+        assert_eq!(activated(&pid, "1", "").public, Some(3));
+        assert_eq!(activated("1", "1", ""), Fds::default());
+        assert_eq!(activated("", "", ""), Fds::default());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn inherits_listeners() {
+        use std::os::fd::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap().to_string();
+        let fds = Fds {
+            public: Some(tcp.into_raw_fd()),
+            ..Fds::default()
+        };
+        let inherited = fds.take().unwrap();
+        assert_eq!(inherited.public.unwrap().address(), address);
+        assert!(inherited.admin.is_none() && inherited.grpc.is_none());
+
+        let path = std::env::temp_dir().join(format!("rsapp-fd-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = inherit(unix.into_raw_fd()).unwrap();
+        assert_eq!(listener.address(), format!("unix:{}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // sends a request on `stream` and reads the response until the server closes it
+    async fn get_root<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: rsapp\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn counts_connections() {
+        let listener = "127.0.0.1:0".parse::<Listen>().unwrap().bind(None).await;
+        let listener = listener.unwrap();
+        let address = listener.address();
+        let connections = Arc::new(Connections::default());
+        let router = Router::new().route("/", get(|| async { "Hello, World!" }));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving =
+            tokio::spawn(
+                listener.serve(router, &Http::default(), connections.clone(), async {
+                    let _ = stopped.await;
+                }),
+            );
+
+        for _ in 0..2 {
+            let response = get_root(TcpStream::connect(&address).await.unwrap()).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        }
+        let _ = stop.send(());
+        serving.await.unwrap().unwrap();
+        let stats = connections.stats();
+        assert_eq!((stats.accepted, stats.open), (2, 0));
+        assert_eq!((stats.http1_requests, stats.http2_requests), (2, 0));
+        assert_eq!(stats.requests_per_connection, 1.0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_on_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("rsapp-test-{}.sock", std::process::id()));
+        let listen: Listen = format!("unix:{}", path.display()).parse().unwrap();
+        let listener = listen.bind(Some(0o660)).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // a second server doesn't take the socket away from the first
+        assert!(listen.bind(None).await.is_err());
+
+        let router = Router::new().route("/", get(|| async { "Hello, World!" }));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving =
+            tokio::spawn(
+                listener.serve(router, &Http::default(), Arc::default(), async {
+                    let _ = stopped.await;
+                }),
+            );
+        let response = get_root(UnixStream::connect(&path).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("Hello, World!"));
+
+        let _ = stop.send(());
+        serving.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,67 @@
+// Logging of the commands that keep running, whose level changes while they run: `log_level` of
+// the config at startup and on SIGHUP, `PUT /admin/log-level` in between. Events and spans of the
+// app and of the libraries, and messages of those still on `log`, are filtered alike.
+use std::{str::FromStr, sync::OnceLock};
+
+use tracing_subscriber::{filter::LevelFilter, fmt, fmt::MakeWriter, prelude::*, reload, Registry};
+
+static HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+pub fn parse(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level).map_err(|_| format!("unknown log level {}", level))
+}
+
+// logs to stdout from `level` up
+pub fn init(level: LevelFilter) {
+    init_to(level, std::io::stdout, true);
+}
+
+// logs to `writer` from `level` up, colored if `ansi`
+pub fn init_to<W>(level: LevelFilter, writer: W, ansi: bool)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(level);
+    let output = fmt::layer().with_writer(writer).with_ansi(ansi);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+    let _ = HANDLE.set(handle);
+}
+
+pub fn level() -> LevelFilter {
+    HANDLE
+        .get()
+        .and_then(|handle| handle.clone_current())
+        .unwrap_or_else(LevelFilter::current)
+}
+
+// logs from `level` up from now on
+pub fn set_level(level: LevelFilter) -> Result<(), String> {
+    let handle = HANDLE
+        .get()
+        .ok_or("logging of this process can't be changed")?;
+    handle.reload(level).map_err(|err| err.to_string())?;
+    // the bridge from `log` filters by its own level, set by it from ours at startup
+    log::set_max_level(
+        log::LevelFilter::from_str(&level.to_string()).unwrap_or(log::LevelFilter::Info),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        assert_eq!(parse("debug"), Ok(LevelFilter::DEBUG));
+        assert_eq!(parse("WARN"), Ok(LevelFilter::WARN));
+        assert!(parse("loud").is_err());
+        assert_eq!(
+            log::LevelFilter::from_str(&LevelFilter::OFF.to_string()),
+            Ok(log::LevelFilter::Off)
+        );
+    }
+}
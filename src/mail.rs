@@ -0,0 +1,121 @@
+// Mail the server sends, such as password reset links, through a `Mailer`. With `mail.url` set
+// each mail is POSTed there as JSON for a relay to send, which is how most mail services take
+// them:
+//
+//     {"from": "rsapp@example.com", "to": "jd@example.com", "subject": "...", "text": "..."}
+//
+// and `mail.token`, if any, as `Authorization: Bearer <token>`. Without it mail is only logged,
+// which suits development; other ways of sending go in as further implementations.
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::webhook;
+
+// `[mail]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // of the relay taking mail as JSON, mail is logged without one
+    pub url: Option<String>,
+    pub token: Option<String>,
+    // the sender of every mail
+    pub from: String,
+    // seconds the relay gets to answer
+    pub timeout: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            url: None,
+            token: None,
+            from: "rsapp@localhost".to_owned(),
+            timeout: 10,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.url {
+            webhook::check_url(url).map_err(|err| format!("mail.url: {}", err))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), String>;
+}
+
+// the mailer of `settings`
+pub fn open(settings: &Settings) -> Arc<dyn Mailer> {
+    match &settings.url {
+        Some(_) => Arc::new(HttpMailer {
+            settings: settings.clone(),
+            client: reqwest::Client::new(),
+        }),
+        None => {
+            warn!("no mail.url, mail is logged instead of sent");
+            Arc::new(LogMailer)
+        }
+    }
+}
+
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: Mail) -> Result<(), String> {
+        info!(to = %mail.to, subject = %mail.subject, "not sending mail:\n{}", mail.text);
+        Ok(())
+    }
+}
+
+struct HttpMailer {
+    settings: Settings,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct Relayed<'a> {
+    from: &'a str,
+    #[serde(flatten)]
+    mail: &'a Mail,
+}
+
+#[async_trait]
+impl Mailer for HttpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), String> {
+        // set when opened
+        let url = self.settings.url.as_deref().unwrap_or_default();
+        let body = Relayed {
+            from: &self.settings.from,
+            mail: &mail,
+        };
+        let mut request = self
+            .client
+            .post(url)
+            .timeout(Duration::from_secs(self.settings.timeout))
+            .json(&body);
+        if let Some(token) = &self.settings.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("the relay answered {}", status)),
+        }
+    }
+}
@@ -0,0 +1,2025 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CStr,
+    fmt,
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Command, Stdio},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+use ffmpeg_next as ffmpeg;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+
+use ffmpeg::{
+    codec, decoder, encoder, filter, format, frame, media, picture,
+    software::scaling::{self, Flags},
+    Packet, Rational,
+};
+
+use crate::leak;
+
+pub mod schema;
+
+// What the container tells about a media file, without decoding it, in the shape of
+// `schema::VERSION` whichever ffmpeg produced it. Stored with the asset as JSON, probed when the
+// asset is registered; older documents are upgraded when read, see `schema::upgrade`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub schema: u32,
+    pub format: String,
+    // seconds, None when unknown
+    pub duration: Option<f64>,
+    pub bit_rate: Option<i64>,
+    // keys in lower case
+    pub tags: BTreeMap<String, String>,
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub index: usize,
+    // video, audio, subtitle, data, attachment or unknown
+    pub medium: String,
+    // as named by current ffmpeg, or unknown
+    pub codec: String,
+    // seconds, None when unknown
+    pub duration: Option<f64>,
+    // video only
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    // audio only
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+// for people, one line per fact, unknown values left out
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "format    {}", self.format)?;
+        if let Some(duration) = self.duration {
+            writeln!(f, "duration  {:.2} s", duration)?;
+        }
+        if let Some(bit_rate) = self.bit_rate {
+            writeln!(f, "bit rate  {} b/s", bit_rate)?;
+        }
+        for (key, value) in &self.tags {
+            writeln!(f, "tag       {} = {}", key, value)?;
+        }
+        for stream in &self.streams {
+            write!(
+                f,
+                "stream {:<2} {} {}",
+                stream.index, stream.medium, stream.codec
+            )?;
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                write!(f, " {}x{}", width, height)?;
+            }
+            if let Some(frame_rate) = stream.frame_rate {
+                write!(f, " {:.3} fps", frame_rate)?;
+            }
+            if let Some(sample_rate) = stream.sample_rate {
+                write!(f, " {} Hz", sample_rate)?;
+            }
+            if let Some(channels) = stream.channels {
+                write!(f, " {} channels", channels)?;
+            }
+            if let Some(duration) = stream.duration {
+                write!(f, " {:.2} s", duration)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// what the linked ffmpeg libraries and the ffmpeg command can do, of what jobs need
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct Capabilities {
+    pub version: String,
+    pub decoders: Vec<&'static str>,
+    pub encoders: Vec<&'static str>,
+    // containers remuxes and clips can write
+    pub muxers: Vec<Container>,
+    // hardware transcodes can run on besides software, as found at startup by the process
+    // running jobs; none until then
+    pub hwaccel: Vec<Hwaccel>,
+    // first line of `ffmpeg -version`, which the canary transcoder runs, when there is one
+    pub cli: Option<String>,
+}
+
+// codecs of the media that's probed and transcoded, and of thumbnails
+const CODECS: &[(&str, codec::Id)] = &[
+    ("h264", codec::Id::H264),
+    ("hevc", codec::Id::HEVC),
+    ("av1", codec::Id::AV1),
+    ("vp9", codec::Id::VP9),
+    ("aac", codec::Id::AAC),
+    ("opus", codec::Id::OPUS),
+    ("mjpeg", codec::Id::MJPEG),
+    ("webp", codec::Id::WEBP),
+];
+
+// what transcodes encode video to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoCodec {
+    #[default]
+    H264,
+    // about a third smaller at the same quality, not every player decodes it
+    Av1,
+}
+
+impl VideoCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Av1 => "av1",
+        }
+    }
+
+    fn id(self) -> codec::Id {
+        match self {
+            VideoCodec::H264 => codec::Id::H264,
+            VideoCodec::Av1 => codec::Id::AV1,
+        }
+    }
+
+    // the encoder of the ffmpeg command
+    fn cli_encoder(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::Av1 => "libsvtav1",
+        }
+    }
+}
+
+// what thumbnails are encoded as
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    // smaller than JPEG, not for old browsers
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+// an image laid over the video of a transcode, for branding
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Overlay {
+    // path of a registered asset, a PNG keeps its transparency
+    pub image: String,
+    #[serde(default)]
+    pub position: Position,
+    // from 0, invisible, to 1
+    #[serde(default = "opaque")]
+    pub opacity: f32,
+    // its width, as a share of the video's
+    #[serde(default = "default_overlay_scale")]
+    pub scale: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+fn opaque() -> f32 {
+    1.0
+}
+
+fn default_overlay_scale() -> f32 {
+    0.15
+}
+
+impl Overlay {
+    pub fn check(&self) -> Result<(), String> {
+        crate::validation::media_path(&self.image)
+            .map_err(|_| "overlay.image must be the path of an asset".to_owned())?;
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err("overlay.opacity must be from 0 to 1".to_owned());
+        }
+        if !(self.scale > 0.0 && self.scale <= 1.0) {
+            return Err("overlay.scale must be above 0 and up to 1".to_owned());
+        }
+        Ok(())
+    }
+
+    // A filter graph description laying the image over a video `width` pixels wide, from the
+    // pad `[in]` to its last filter, which may be followed by others; the ffmpeg command takes it
+    // as `-vf` too. It keeps off the edges by a fiftieth of the width.
+    pub fn filter(&self, width: u32) -> String {
+        let image_width = ((width as f32 * self.scale).round() as u32).max(1);
+        let margin = width / 50;
+        let (x, y) = match self.position {
+            Position::TopLeft => (margin.to_string(), margin.to_string()),
+            Position::TopRight => (format!("main_w-overlay_w-{}", margin), margin.to_string()),
+            Position::BottomLeft => (margin.to_string(), format!("main_h-overlay_h-{}", margin)),
+            Position::BottomRight => (
+                format!("main_w-overlay_w-{}", margin),
+                format!("main_h-overlay_h-{}", margin),
+            ),
+            Position::Center => (
+                "(main_w-overlay_w)/2".to_owned(),
+                "(main_h-overlay_h)/2".to_owned(),
+            ),
+        };
+        let mut image = format!(
+            "movie=filename={},format=rgba,scale={}:-1",
+            filter_escape(&self.image),
+            image_width
+        );
+        if self.opacity < 1.0 {
+            image.push_str(&format!(",colorchannelmixer=aa={}", self.opacity));
+        }
+        format!("{}[overlay];[in][overlay]overlay={}:{}", image, x, y)
+    }
+}
+
+// `value` as an option of a filter in a graph description: escaped for the option, then for
+// the description
+fn filter_escape(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    let option = escape(value, &['\\', '\'', ':']);
+    escape(&option, &['\\', '\'', '[', ']', ',', ';'])
+}
+
+// what remuxes copy streams into
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Container {
+    Mp4,
+    // Matroska, holds about any codec
+    Mkv,
+    Mov,
+    // VP8, VP9 and AV1 video with Opus or Vorbis audio only
+    Webm,
+}
+
+impl Container {
+    const ALL: [Container; 4] = [
+        Container::Mp4,
+        Container::Mkv,
+        Container::Mov,
+        Container::Webm,
+    ];
+
+    // the ffmpeg muxer writing it
+    fn muxer(self) -> &'static CStr {
+        match self {
+            Container::Mp4 => c"mp4",
+            Container::Mkv => c"matroska",
+            Container::Mov => c"mov",
+            Container::Webm => c"webm",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Mov => "mov",
+            Container::Webm => "webm",
+        }
+    }
+}
+
+// What transcodes encode on: the CPU, or hardware through the ffmpeg command, which sets up the
+// device. The linked libraries only do software.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Hwaccel {
+    #[default]
+    Software,
+    // Intel and AMD GPUs on Linux, through `jobs.vaapi_device`
+    Vaapi,
+    // NVIDIA GPUs
+    Nvenc,
+    // macOS, H.264 only
+    Videotoolbox,
+    // the first of the others found working at startup, software without
+    Auto,
+}
+
+impl Hwaccel {
+    const HARDWARE: [Hwaccel; 3] = [Hwaccel::Vaapi, Hwaccel::Nvenc, Hwaccel::Videotoolbox];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hwaccel::Software => "software",
+            Hwaccel::Vaapi => "vaapi",
+            Hwaccel::Nvenc => "nvenc",
+            Hwaccel::Videotoolbox => "videotoolbox",
+            Hwaccel::Auto => "auto",
+        }
+    }
+
+    // the encoder of the ffmpeg command for `video` on it, None if it has none
+    fn encoder(self, video: VideoCodec) -> Option<&'static str> {
+        match (self, video) {
+            (Hwaccel::Vaapi, VideoCodec::H264) => Some("h264_vaapi"),
+            (Hwaccel::Vaapi, VideoCodec::Av1) => Some("av1_vaapi"),
+            (Hwaccel::Nvenc, VideoCodec::H264) => Some("h264_nvenc"),
+            (Hwaccel::Nvenc, VideoCodec::Av1) => Some("av1_nvenc"),
+            (Hwaccel::Videotoolbox, VideoCodec::H264) => Some("h264_videotoolbox"),
+            _ => None,
+        }
+    }
+
+    // arguments of the ffmpeg command before the input, to decode on it too
+    fn decode_args(self) -> &'static [&'static str] {
+        match self {
+            Hwaccel::Vaapi => &["-hwaccel", "vaapi"],
+            Hwaccel::Nvenc => &["-hwaccel", "cuda"],
+            Hwaccel::Videotoolbox => &["-hwaccel", "videotoolbox"],
+            Hwaccel::Software | Hwaccel::Auto => &[],
+        }
+    }
+
+    // the filter moving frames onto the device for its encoder, if they must be
+    fn upload(self) -> Option<&'static str> {
+        (self == Hwaccel::Vaapi).then_some("format=nv12,hwupload")
+    }
+}
+
+// hardware transcodes may run on, and what they run on unless a job asks for other
+#[derive(Debug, Clone, Default)]
+pub struct Hardware {
+    pub default: Hwaccel,
+    // the VAAPI render node
+    pub vaapi_device: String,
+    // what encoded a test video at startup
+    pub available: Vec<Hwaccel>,
+}
+
+impl Hardware {
+    // finds out what works by encoding a short test video on each
+    pub fn detect(default: Hwaccel, vaapi_device: &str) -> Self {
+        let available: Vec<Hwaccel> = Hwaccel::HARDWARE
+            .into_iter()
+            .filter(|hwaccel| encodes_on(*hwaccel, vaapi_device))
+            .collect();
+        let names: Vec<&str> = available.iter().map(|hwaccel| hwaccel.name()).collect();
+        match names.is_empty() {
+            true => info!("no hardware to transcode on, transcodes run in software"),
+            false => info!("hardware to transcode on: {}", names.join(", ")),
+        }
+        Hardware {
+            default,
+            vaapi_device: vaapi_device.to_owned(),
+            available,
+        }
+    }
+
+    // the hardware to transcode to `video` on when a job asks for `asked`, None for software
+    pub fn pick(&self, asked: Option<Hwaccel>, video: VideoCodec) -> Option<Hwaccel> {
+        let usable = |hwaccel: &Hwaccel| {
+            self.available.contains(hwaccel) && hwaccel.encoder(video).is_some()
+        };
+        match asked.unwrap_or(self.default) {
+            Hwaccel::Software => None,
+            Hwaccel::Auto => self.available.iter().copied().find(usable),
+            hwaccel if usable(&hwaccel) => Some(hwaccel),
+            hwaccel => {
+                warn!(
+                    "no {} to transcode to {} on, transcoding in software",
+                    hwaccel.name(),
+                    video.name()
+                );
+                None
+            }
+        }
+    }
+
+    // the `-vaapi_device` argument and the like, of the ffmpeg command
+    fn device_args(&self, hwaccel: Hwaccel) -> Vec<String> {
+        match hwaccel {
+            Hwaccel::Vaapi => vec!["-vaapi_device".to_owned(), self.vaapi_device.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// whether the ffmpeg command encodes a tenth of a second of H.264 on `hwaccel`
+fn encodes_on(hwaccel: Hwaccel, vaapi_device: &str) -> bool {
+    let Some(encoder) = hwaccel.encoder(VideoCodec::H264) else {
+        return false;
+    };
+    let hardware = Hardware {
+        vaapi_device: vaapi_device.to_owned(),
+        ..Hardware::default()
+    };
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-hide_banner", "-nostats", "-loglevel", "error"])
+        .args(hardware.device_args(hwaccel))
+        .args(["-f", "lavfi", "-i", "color=black:size=256x256:duration=0.1"]);
+    if let Some(upload) = hwaccel.upload() {
+        command.args(["-vf", upload]);
+    }
+    command
+        .args(["-c:v", encoder, "-f", "null", "-"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// Initializes the ffmpeg libraries the first time, and answers how that went every time after.
+// Servers and workers call it at startup, so that a broken installation stops them right away
+// rather than failing every request.
+pub fn init() -> Result<(), ffmpeg::Error> {
+    static INIT: OnceLock<Result<(), ffmpeg::Error>> = OnceLock::new();
+    *INIT.get_or_init(ffmpeg::init)
+}
+
+pub fn capabilities() -> Capabilities {
+    let available = |find: fn(codec::Id) -> Option<codec::Codec>| -> Vec<&'static str> {
+        if init().is_err() {
+            return Vec::new();
+        }
+        CODECS
+            .iter()
+            .filter(|(_, id)| find(*id).is_some())
+            .map(|(name, _)| *name)
+            .collect()
+    };
+    let cli = Command::new("ffmpeg")
+        .arg("-version")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let version = String::from_utf8_lossy(&output.stdout);
+            version.lines().next().map(str::to_owned)
+        });
+    let muxers = Container::ALL
+        .into_iter()
+        .filter(|container| {
+            // SAFETY: the name is nul terminated, the other arguments may be null
+            let muxer = unsafe {
+                ffmpeg::ffi::av_guess_format(container.muxer().as_ptr(), ptr::null(), ptr::null())
+            };
+            !muxer.is_null()
+        })
+        .collect();
+    Capabilities {
+        version: ffmpeg_version(),
+        decoders: available(decoder::find),
+        encoders: available(encoder::find),
+        muxers,
+        hwaccel: Vec::new(),
+        cli,
+    }
+}
+
+// the version of the ffmpeg libraries linked in, like `6.1.1`
+pub fn ffmpeg_version() -> String {
+    // SAFETY: ffmpeg returns a static, nul terminated string
+    let version = unsafe { ffmpeg::ffi::av_version_info() };
+    if version.is_null() {
+        return "unknown".to_owned();
+    }
+    unsafe { CStr::from_ptr(version) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Tells media work done for a request that the request went away, so that it stops instead of
+// running on for nobody: probing, remuxing, clipping, keyframes and waveforms take one. Work
+// that's cancelled fails with `ffmpeg::Error::Exit`.
+#[derive(Clone, Default, Debug)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // cancels once dropped, like with the future of a request whose client disconnected
+    pub fn guard(&self) -> CancelGuard {
+        CancelGuard(self.clone())
+    }
+
+    // for work to stop at, between packets
+    fn check(&self) -> Result<(), ffmpeg::Error> {
+        match self.is_cancelled() {
+            true => Err(ffmpeg::Error::Exit),
+            false => Ok(()),
+        }
+    }
+}
+
+#[must_use = "the work is cancelled when the guard is dropped"]
+pub struct CancelGuard(Cancel);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+// Opens `input`, which may take long on slow storage or files to search for their streams, until
+// `cancel`. Reading packets isn't interrupted, since the packet iterators retry failed reads
+// forever; loops check `cancel` themselves.
+fn open(input: &Path, cancel: &Cancel) -> Result<format::context::Input, ffmpeg::Error> {
+    let opening = Arc::new(AtomicBool::new(true));
+    let interrupt = {
+        let (opening, cancel) = (opening.clone(), cancel.clone());
+        move || opening.load(Ordering::Relaxed) && cancel.is_cancelled()
+    };
+    let ictx = format::input_with_interrupt(&input, interrupt);
+    opening.store(false, Ordering::Relaxed);
+    ictx
+}
+
+#[instrument(skip_all, fields(input = %input.display()))]
+pub fn probe(input: &Path, cancel: &Cancel) -> Result<Metadata, ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(leak::Kind::Ffmpeg, format!("probing {}", input.display()));
+    let ictx = open(input, cancel)?;
+    let mut streams = Vec::new();
+    for stream in ictx.streams() {
+        let codec = codec::context::Context::from_parameters(stream.parameters())?;
+        let mut info = StreamInfo {
+            index: stream.index(),
+            medium: medium_name(codec.medium()).to_owned(),
+            codec: schema::codec(codec.id().name()),
+            duration: schema::positive(stream.duration() as f64 * f64::from(stream.time_base())),
+            width: None,
+            height: None,
+            frame_rate: None,
+            sample_rate: None,
+            channels: None,
+        };
+        match codec.medium() {
+            media::Type::Video => {
+                let video = codec.decoder().video()?;
+                info.width = schema::known(video.width());
+                info.height = schema::known(video.height());
+                let rate = stream.avg_frame_rate();
+                info.frame_rate = (rate.denominator() != 0)
+                    .then(|| f64::from(rate))
+                    .and_then(schema::positive);
+            }
+            media::Type::Audio => {
+                let audio = codec.decoder().audio()?;
+                info.sample_rate = schema::known(audio.rate());
+                info.channels = schema::known(audio.channels());
+            }
+            _ => {}
+        }
+        streams.push(info);
+    }
+    Ok(Metadata {
+        schema: schema::VERSION,
+        format: schema::name(ictx.format().name()),
+        duration: schema::positive(ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)),
+        bit_rate: schema::known(ictx.bit_rate()),
+        tags: schema::tags(ictx.metadata().iter()),
+        streams,
+    })
+}
+
+fn medium_name(medium: media::Type) -> &'static str {
+    match medium {
+        media::Type::Video => "video",
+        media::Type::Audio => "audio",
+        media::Type::Subtitle => "subtitle",
+        media::Type::Data => "data",
+        media::Type::Attachment => "attachment",
+        media::Type::Unknown => "unknown",
+    }
+}
+
+// `transcode` by running the ffmpeg command line tool, which has to be on the PATH, for trying
+// it against the linked libraries
+#[instrument(
+    skip_all,
+    fields(input = %input.display(), output = %output.display(), video = video.name())
+)]
+pub fn transcode_cli(
+    input: &Path,
+    output: &Path,
+    video: VideoCodec,
+    overlay: Option<&Overlay>,
+    hardware: Option<(Hwaccel, &Hardware)>,
+    progress: &mut dyn FnMut(f32),
+) -> Result<(), String> {
+    let metadata = probe(input, &Cancel::default()).map_err(|err| err.to_string())?;
+    let duration = metadata.duration;
+    let width = metadata
+        .streams
+        .iter()
+        .find_map(|stream| stream.width)
+        .unwrap_or(0);
+    let _process = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("the ffmpeg command transcoding {}", input.display()),
+    );
+    let mut encoder = video.cli_encoder();
+    let mut filters: Vec<String> = overlay
+        .map(|overlay| overlay.filter(width))
+        .into_iter()
+        .collect();
+    let mut command = Command::new("ffmpeg");
+    command.args(["-hide_banner", "-nostats", "-loglevel", "error", "-y"]);
+    if let Some((hwaccel, hardware)) = hardware {
+        command
+            .args(hardware.device_args(hwaccel))
+            .args(hwaccel.decode_args());
+        filters.extend(hwaccel.upload().map(str::to_owned));
+        encoder = hwaccel
+            .encoder(video)
+            .ok_or_else(|| format!("{} doesn't encode {}", hwaccel.name(), video.name()))?;
+    }
+    command
+        .arg("-i")
+        .arg(input)
+        .args(["-map", "0:v?", "-map", "0:a?", "-map", "0:s?"])
+        .args(["-c", "copy", "-c:v", encoder, "-progress", "pipe:1"]);
+    if !filters.is_empty() {
+        command.arg("-vf").arg(filters.join(","));
+    }
+    let mut child = command
+        .arg(output)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("can't run ffmpeg: {}", err))?;
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            let line = line.map_err(|err| err.to_string())?;
+            if let Some(done) = cli_progress(&line, duration) {
+                progress(done);
+            }
+        }
+    }
+    let result = child.wait_with_output().map_err(|err| err.to_string())?;
+    if !result.status.success() {
+        let error = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("ffmpeg {}: {}", result.status, error.trim()));
+    }
+    Ok(())
+}
+
+// the share done from a `-progress` line, which reports the output time in microseconds
+fn cli_progress(line: &str, duration: Option<f64>) -> Option<f32> {
+    let done: f64 = line.strip_prefix("out_time_us=")?.parse().ok()?;
+    Some((done / 1e6 / duration?).clamp(0.0, 1.0) as f32)
+}
+
+// re-encodes the video streams of `input` to `video`, with `overlay` laid over them, and copies
+// audio and subtitles, into a container picked from the extension of `output`. `progress` is
+// called with the share of the input done so far, from 0 to 1.
+#[instrument(
+    skip_all,
+    fields(input = %input.display(), output = %output.display(), video = video.name())
+)]
+pub fn transcode(
+    input: &Path,
+    output: &Path,
+    video: VideoCodec,
+    overlay: Option<&Overlay>,
+    progress: &mut dyn FnMut(f32),
+) -> Result<(), ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("transcoding {}", input.display()),
+    );
+    let mut ictx = format::input(&input)?;
+    let mut octx = format::output(&output)?;
+    let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+
+    // input stream index -> output stream index, and the video ones with their transcoder
+    let mut mapping = vec![None; ictx.nb_streams() as usize];
+    let mut transcoders = Vec::new();
+    for ist in ictx.streams() {
+        let medium = ist.parameters().medium();
+        if !matches!(
+            medium,
+            media::Type::Video | media::Type::Audio | media::Type::Subtitle
+        ) {
+            continue;
+        }
+        let ost_index = if medium == media::Type::Video {
+            let transcoder =
+                VideoTranscoder::new(&ist, &mut octx, video, global_header, None, overlay)?;
+            let index = transcoder.ost_index;
+            transcoders.push((ist.index(), transcoder));
+            index
+        } else {
+            copy_stream(&ist, &mut octx)?
+        };
+        mapping[ist.index()] = Some(ost_index);
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header()?;
+    let ost_time_bases: Vec<_> = octx.streams().map(|ost| ost.time_base()).collect();
+
+    for (ist, mut packet) in ictx.packets() {
+        let Some(ost_index) = mapping[ist.index()] else {
+            continue;
+        };
+        if duration > 0.0 {
+            if let Some(pts) = packet.pts() {
+                let done = pts as f64 * f64::from(ist.time_base()) / duration;
+                progress(done.clamp(0.0, 1.0) as f32);
+            }
+        }
+        match transcoders
+            .iter_mut()
+            .find(|(index, _)| *index == ist.index())
+        {
+            Some((_, transcoder)) => {
+                transcoder.decoder.send_packet(&packet)?;
+                transcoder.drain_decoder(&mut octx, ost_time_bases[ost_index])?;
+            }
+            None => {
+                packet.rescale_ts(ist.time_base(), ost_time_bases[ost_index]);
+                packet.set_position(-1);
+                packet.set_stream(ost_index);
+                packet.write_interleaved(&mut octx)?;
+            }
+        }
+    }
+
+    for (_, transcoder) in &mut transcoders {
+        transcoder.finish(&mut octx, ost_time_bases[transcoder.ost_index])?;
+    }
+    octx.write_trailer()?;
+    progress(1.0);
+    Ok(())
+}
+
+// adds a stream to `octx` to copy `ist` into as it is, returns its index
+fn copy_stream(
+    ist: &format::stream::Stream,
+    octx: &mut format::context::Output,
+) -> Result<usize, ffmpeg::Error> {
+    let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+    ost.set_parameters(ist.parameters());
+    // the input's codec tag may not be valid in the output container
+    unsafe {
+        (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+    }
+    Ok(ost.index())
+}
+
+// Copies the video, audio and subtitle streams of `input` into the container picked from the
+// extension of `output`, without decoding them: fast, and nothing is lost, but it fails when the
+// container can't hold a codec, like H.264 in WebM.
+#[instrument(skip_all, fields(input = %input.display(), output = %output.display()))]
+pub fn remux(input: &Path, output: &Path, cancel: &Cancel) -> Result<(), ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(leak::Kind::Ffmpeg, format!("remuxing {}", input.display()));
+    let mut ictx = open(input, cancel)?;
+    let mut octx = format::output(&output)?;
+
+    let mut mapping = vec![None; ictx.nb_streams() as usize];
+    for ist in ictx.streams() {
+        if matches!(
+            ist.parameters().medium(),
+            media::Type::Video | media::Type::Audio | media::Type::Subtitle
+        ) {
+            mapping[ist.index()] = Some(copy_stream(&ist, &mut octx)?);
+        }
+    }
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header()?;
+    let ost_time_bases: Vec<_> = octx.streams().map(|ost| ost.time_base()).collect();
+
+    for (ist, mut packet) in ictx.packets() {
+        cancel.check()?;
+        let Some(ost_index) = mapping[ist.index()] else {
+            continue;
+        };
+        packet.rescale_ts(ist.time_base(), ost_time_bases[ost_index]);
+        packet.set_position(-1);
+        packet.set_stream(ost_index);
+        packet.write_interleaved(&mut octx)?;
+    }
+    octx.write_trailer()
+}
+
+// how `clip` cut
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Cut {
+    // the streams copied, the start was on a keyframe
+    Copy,
+    // the video re-encoded, to start at a frame that isn't a keyframe
+    Reencode,
+}
+
+impl Cut {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cut::Copy => "copy",
+            Cut::Reencode => "reencode",
+        }
+    }
+}
+
+// how far from a keyframe a clip may start and still be copied, in seconds
+const KEYFRAME_TOLERANCE: f64 = 0.001;
+
+// Writes `start` to `end` seconds of `input` into the container picked from the extension of
+// `output`. When `start` is on a keyframe the streams are copied as `remux` does; otherwise the
+// video is re-encoded to `video` so the clip starts with the frame at `start`, and audio and
+// subtitles are copied.
+#[instrument(
+    skip(input, output, video, cancel),
+    fields(input = %input.display(), output = %output.display(), video = video.name())
+)]
+pub fn clip(
+    input: &Path,
+    output: &Path,
+    start: f64,
+    end: f64,
+    video: VideoCodec,
+    cancel: &Cancel,
+) -> Result<Cut, ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(leak::Kind::Ffmpeg, format!("clipping {}", input.display()));
+    let mut ictx = open(input, cancel)?;
+    let best = ictx.streams().best(media::Type::Video);
+    let best = best.map(|stream| (stream.index(), stream.time_base()));
+    // every audio packet is a keyframe
+    let cut = match best {
+        Some((index, time_base)) if !keyframe_at(&mut ictx, index, time_base, start)? => {
+            Cut::Reencode
+        }
+        _ => Cut::Copy,
+    };
+    let mut octx = format::output(&output)?;
+    let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+    // input stream index -> output stream index, and timestamps of the clip in its time base
+    let mut mapping = vec![None; ictx.nb_streams() as usize];
+    let mut windows = vec![(0, 0); ictx.nb_streams() as usize];
+    let mut transcoders = Vec::new();
+    for ist in ictx.streams() {
+        let medium = ist.parameters().medium();
+        if !matches!(
+            medium,
+            media::Type::Video | media::Type::Audio | media::Type::Subtitle
+        ) {
+            continue;
+        }
+        let time_base = f64::from(ist.time_base());
+        let window = ((start / time_base) as i64, (end / time_base) as i64);
+        windows[ist.index()] = window;
+        let ost_index = if cut == Cut::Reencode && medium == media::Type::Video {
+            let transcoder =
+                VideoTranscoder::new(&ist, &mut octx, video, global_header, Some(window), None)?;
+            let index = transcoder.ost_index;
+            transcoders.push((ist.index(), transcoder));
+            index
+        } else {
+            copy_stream(&ist, &mut octx)?
+        };
+        mapping[ist.index()] = Some(ost_index);
+    }
+
+    octx.set_metadata(ictx.metadata().to_owned());
+    octx.write_header()?;
+    let ost_time_bases: Vec<_> = octx.streams().map(|ost| ost.time_base()).collect();
+
+    // lands on the keyframe before `start`, what comes before it is dropped
+    let target = (start * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+    ictx.seek(target, ..target)?;
+    let mut ended = vec![false; mapping.len()];
+    for (ist, mut packet) in ictx.packets() {
+        cancel.check()?;
+        let Some(ost_index) = mapping[ist.index()] else {
+            continue;
+        };
+        let (from, until) = windows[ist.index()];
+        // packets come in decoding order, later ones may still be shown before `end`
+        if packet
+            .dts()
+            .or(packet.pts())
+            .is_some_and(|dts| dts >= until)
+        {
+            ended[ist.index()] = true;
+            let done = mapping
+                .iter()
+                .zip(&ended)
+                .all(|(ost, ended)| ost.is_none() || *ended);
+            if done {
+                break;
+            }
+            continue;
+        }
+        match transcoders
+            .iter_mut()
+            .find(|(index, _)| *index == ist.index())
+        {
+            Some((_, transcoder)) => {
+                transcoder.decoder.send_packet(&packet)?;
+                transcoder.drain_decoder(&mut octx, ost_time_bases[ost_index])?;
+            }
+            None => {
+                let Some(pts) = packet.pts().filter(|pts| (from..until).contains(pts)) else {
+                    continue;
+                };
+                packet.set_pts(Some(pts - from));
+                packet.set_dts(packet.dts().map(|dts| dts - from));
+                packet.rescale_ts(ist.time_base(), ost_time_bases[ost_index]);
+                packet.set_position(-1);
+                packet.set_stream(ost_index);
+                packet.write_interleaved(&mut octx)?;
+            }
+        }
+    }
+
+    for (_, transcoder) in &mut transcoders {
+        transcoder.finish(&mut octx, ost_time_bases[transcoder.ost_index])?;
+    }
+    octx.write_trailer()?;
+    Ok(cut)
+}
+
+// whether stream `index` has a keyframe shown at `time` seconds
+fn keyframe_at(
+    ictx: &mut format::context::Input,
+    index: usize,
+    time_base: Rational,
+    time: f64,
+) -> Result<bool, ffmpeg::Error> {
+    // lands on the keyframe at or before `time`
+    let target = (time * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+    ictx.seek(target, ..target)?;
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == index {
+            let shown = packet.pts().unwrap_or(0) as f64 * f64::from(time_base);
+            return Ok(packet.is_key() && (shown - time).abs() <= KEYFRAME_TOLERANCE);
+        }
+    }
+    Ok(false)
+}
+
+struct VideoTranscoder {
+    ost_index: usize,
+    // frames keep the timestamps of the input stream, in its time base
+    time_base: Rational,
+    // the frames to keep, from and until timestamps of the input stream; they're moved to start
+    // at 0. All when None.
+    window: Option<(i64, i64)>,
+    decoder: decoder::Video,
+    // what frames go through before they're encoded, from `in` to `out`
+    filter: Option<filter::Graph>,
+    encoder: encoder::video::Encoder,
+}
+
+impl VideoTranscoder {
+    fn new(
+        ist: &format::stream::Stream,
+        octx: &mut format::context::Output,
+        video: VideoCodec,
+        global_header: bool,
+        window: Option<(i64, i64)>,
+        overlay: Option<&Overlay>,
+    ) -> Result<Self, ffmpeg::Error> {
+        let decoder = codec::context::Context::from_parameters(ist.parameters())?
+            .decoder()
+            .video()?;
+        let codec = encoder::find(video.id()).ok_or(ffmpeg::Error::EncoderNotFound)?;
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        encoder.set_width(decoder.width());
+        encoder.set_height(decoder.height());
+        encoder.set_aspect_ratio(decoder.aspect_ratio());
+        encoder.set_format(decoder.format());
+        encoder.set_frame_rate(decoder.frame_rate());
+        encoder.set_time_base(ist.time_base());
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+        let filter = match overlay {
+            Some(overlay) => Some(overlay_graph(&decoder, ist.time_base(), overlay)?),
+            None => None,
+        };
+
+        Ok(VideoTranscoder {
+            ost_index: ost.index(),
+            time_base: ist.time_base(),
+            window,
+            decoder,
+            filter,
+            encoder,
+        })
+    }
+
+    fn drain_decoder(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) -> Result<(), ffmpeg::Error> {
+        let mut frame = frame::Video::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let mut timestamp = frame.timestamp();
+            if let Some((from, until)) = self.window {
+                match timestamp {
+                    Some(shown) if (from..until).contains(&shown) => timestamp = Some(shown - from),
+                    _ => continue,
+                }
+            }
+            frame.set_pts(timestamp);
+            frame.set_kind(picture::Type::None);
+            match &mut self.filter {
+                Some(graph) => {
+                    graph
+                        .get("in")
+                        .expect("the graph has an input")
+                        .source()
+                        .add(&frame)?;
+                    self.drain_filter(octx, ost_time_base)?;
+                }
+                None => self.encode(&frame, octx, ost_time_base)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn drain_filter(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) -> Result<(), ffmpeg::Error> {
+        let Some(graph) = &mut self.filter else {
+            return Ok(());
+        };
+        let mut filtered = frame::Video::empty();
+        while graph
+            .get("out")
+            .expect("the graph has an output")
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            self.encoder.send_frame(&filtered)?;
+            drain_encoder(
+                &mut self.encoder,
+                octx,
+                self.ost_index,
+                self.time_base,
+                ost_time_base,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn encode(
+        &mut self,
+        frame: &frame::Video,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) -> Result<(), ffmpeg::Error> {
+        self.encoder.send_frame(frame)?;
+        drain_encoder(
+            &mut self.encoder,
+            octx,
+            self.ost_index,
+            self.time_base,
+            ost_time_base,
+        )
+    }
+
+    // encodes what's left once the input ended
+    fn finish(
+        &mut self,
+        octx: &mut format::context::Output,
+        ost_time_base: Rational,
+    ) -> Result<(), ffmpeg::Error> {
+        self.decoder.send_eof()?;
+        self.drain_decoder(octx, ost_time_base)?;
+        if let Some(graph) = &mut self.filter {
+            graph
+                .get("in")
+                .expect("the graph has an input")
+                .source()
+                .flush()?;
+            self.drain_filter(octx, ost_time_base)?;
+        }
+        self.encoder.send_eof()?;
+        drain_encoder(
+            &mut self.encoder,
+            octx,
+            self.ost_index,
+            self.time_base,
+            ost_time_base,
+        )
+    }
+}
+
+// a graph laying `overlay` over the frames `decoder` decodes, in the same size and pixel format
+fn overlay_graph(
+    decoder: &decoder::Video,
+    time_base: Rational,
+    overlay: &Overlay,
+) -> Result<filter::Graph, ffmpeg::Error> {
+    let mut graph = filter::Graph::new();
+    let aspect = decoder.aspect_ratio();
+    let args = format!(
+        "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+        decoder.width(),
+        decoder.height(),
+        ffmpeg::ffi::AVPixelFormat::from(decoder.format()) as i32,
+        time_base.numerator(),
+        time_base.denominator(),
+        aspect.numerator(),
+        aspect.denominator().max(1),
+    );
+    let buffer = filter::find("buffer").ok_or(ffmpeg::Error::FilterNotFound)?;
+    graph.add(&buffer, "in", &args)?;
+    let sink = filter::find("buffersink").ok_or(ffmpeg::Error::FilterNotFound)?;
+    graph.add(&sink, "out", "")?;
+    graph
+        .get("out")
+        .expect("the graph has an output")
+        .set_pixel_format(decoder.format());
+    graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(&overlay.filter(decoder.width()))?;
+    graph.validate()?;
+    Ok(graph)
+}
+
+fn drain_encoder(
+    encoder: &mut encoder::video::Encoder,
+    octx: &mut format::context::Output,
+    ost_index: usize,
+    time_base: Rational,
+    ost_time_base: Rational,
+) -> Result<(), ffmpeg::Error> {
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(ost_index);
+        packet.rescale_ts(time_base, ost_time_base);
+        packet.write_interleaved(octx)?;
+    }
+    Ok(())
+}
+
+// writes the first frame at or after `time` seconds into `input` as an `image`, scaled to
+// `width` pixels wide (the source width by default) keeping the aspect ratio
+#[instrument(
+    skip(input, output, image),
+    fields(input = %input.display(), output = %output.display())
+)]
+pub fn thumbnail(
+    input: &Path,
+    output: &Path,
+    time: f64,
+    width: Option<u32>,
+    image: ImageFormat,
+) -> Result<(), ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("a thumbnail of {}", input.display()),
+    );
+    let mut ictx = format::input(&input)?;
+    let (index, time_base, mut decoder) = video_decoder(&ictx)?;
+    let picked = frame_at(&mut ictx, index, time_base, &mut decoder, time)?;
+
+    let (width, height) = scaled_size(decoder.width(), decoder.height(), width);
+    let (id, pixel) = image_codec(image);
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        pixel,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+    let mut scaled = frame::Video::empty();
+    scaler.run(&picked, &mut scaled)?;
+    write_image(output, scaled, id)
+}
+
+// Writes a sprite sheet of `count` frames evenly spaced across the video in `output`, for seek
+// previews. The tiles are `width` pixels wide, laid out as `SpriteLayout` says.
+#[instrument(
+    skip(input, output, image, progress),
+    fields(input = %input.display(), output = %output.display())
+)]
+pub fn sprite_sheet(
+    input: &Path,
+    output: &Path,
+    count: u32,
+    columns: u32,
+    width: u32,
+    image: ImageFormat,
+    progress: &mut dyn FnMut(f32),
+) -> Result<(), ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("a sprite sheet of {}", input.display()),
+    );
+    let mut ictx = format::input(&input)?;
+    let (index, time_base, mut decoder) = video_decoder(&ictx)?;
+    let duration = ictx.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE);
+    let layout = SpriteLayout::new(decoder.width(), decoder.height(), count, columns, width);
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        format::Pixel::RGB24,
+        layout.tile_width,
+        layout.tile_height,
+        Flags::BILINEAR,
+    )?;
+    // tiled in RGB, where a pixel is 3 bytes of one plane
+    let (sheet_width, sheet_height) = layout.size();
+    let mut sheet = frame::Video::new(format::Pixel::RGB24, sheet_width, sheet_height);
+    sheet.data_mut(0).fill(0);
+    let mut tile = frame::Video::empty();
+    for (i, time) in sprite_times(duration, count).into_iter().enumerate() {
+        match frame_at(&mut ictx, index, time_base, &mut decoder, time) {
+            Ok(frame) => scaler.run(&frame, &mut tile)?,
+            // the container said it's longer than its frames are, the last one again
+            Err(_) if i > 0 => {}
+            Err(err) => return Err(err),
+        }
+        let (x, y) = layout.tile(i as u32);
+        let row = layout.tile_width as usize * 3;
+        for line in 0..layout.tile_height as usize {
+            let from = line * tile.stride(0);
+            let to = (y as usize + line) * sheet.stride(0) + x as usize * 3;
+            sheet.data_mut(0)[to..to + row].copy_from_slice(&tile.data(0)[from..from + row]);
+        }
+        progress((i + 1) as f32 / count as f32);
+    }
+
+    let (id, pixel) = image_codec(image);
+    let mut converter = scaling::Context::get(
+        format::Pixel::RGB24,
+        sheet_width,
+        sheet_height,
+        pixel,
+        sheet_width,
+        sheet_height,
+        Flags::BILINEAR,
+    )?;
+    let mut converted = frame::Video::empty();
+    converter.run(&sheet, &mut converted)?;
+    write_image(output, converted, id)
+}
+
+// How a sprite sheet of `count` tiles lays them out, `columns` per row from the top left. Tiles
+// keep the aspect of the video.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteLayout {
+    pub count: u32,
+    pub columns: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+}
+
+impl SpriteLayout {
+    pub fn new(
+        source_width: u32,
+        source_height: u32,
+        count: u32,
+        columns: u32,
+        width: u32,
+    ) -> Self {
+        let (tile_width, tile_height) = scaled_size(source_width, source_height, Some(width));
+        SpriteLayout {
+            count: count.max(1),
+            columns: columns.clamp(1, count.max(1)),
+            tile_width,
+            tile_height,
+        }
+    }
+
+    // of the whole sheet
+    pub fn size(&self) -> (u32, u32) {
+        let rows = self.count.div_ceil(self.columns);
+        (self.columns * self.tile_width, rows * self.tile_height)
+    }
+
+    // the top left corner of tile `i`
+    pub fn tile(&self, i: u32) -> (u32, u32) {
+        (
+            i % self.columns * self.tile_width,
+            i / self.columns * self.tile_height,
+        )
+    }
+}
+
+// where the tiles of a sprite sheet are taken, in seconds: each at the start of its share of
+// `duration`
+pub fn sprite_times(duration: f64, count: u32) -> Vec<f64> {
+    let step = duration.max(0.0) / f64::from(count.max(1));
+    (0..count.max(1)).map(|i| f64::from(i) * step).collect()
+}
+
+// the best video stream of `ictx`, its time base and a decoder for it
+fn video_decoder(
+    ictx: &format::context::Input,
+) -> Result<(usize, Rational, decoder::Video), ffmpeg::Error> {
+    let stream = ictx
+        .streams()
+        .best(media::Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let decoder = codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .video()?;
+    Ok((stream.index(), stream.time_base(), decoder))
+}
+
+// the frame shown at `time` seconds of stream `index`, decoded from the keyframe before it
+fn frame_at(
+    ictx: &mut format::context::Input,
+    index: usize,
+    time_base: Rational,
+    decoder: &mut decoder::Video,
+    time: f64,
+) -> Result<frame::Video, ffmpeg::Error> {
+    // lands on the keyframe before `time`, decoding continues from there
+    let target = (time * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+    ictx.seek(target, ..target)?;
+    decoder.flush();
+    let target_pts = (time / f64::from(time_base)) as i64;
+
+    let mut picked = None;
+    let mut decoded = frame::Video::empty();
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let reached = decoded.timestamp().unwrap_or(0) >= target_pts;
+            picked = Some(std::mem::replace(&mut decoded, frame::Video::empty()));
+            if reached {
+                break;
+            }
+        }
+        if picked
+            .as_ref()
+            .is_some_and(|frame| frame.timestamp().unwrap_or(0) >= target_pts)
+        {
+            break;
+        }
+    }
+    if picked.is_none() {
+        decoder.send_eof()?;
+        if decoder.receive_frame(&mut decoded).is_ok() {
+            picked = Some(decoded);
+        }
+    }
+    // past the end, or a stream without decodable frames
+    picked.ok_or(ffmpeg::Error::StreamNotFound)
+}
+
+// the encoder of `image` and the pixel format it takes; the JPEG one wants full range
+fn image_codec(image: ImageFormat) -> (codec::Id, format::Pixel) {
+    match image {
+        ImageFormat::Jpeg => (codec::Id::MJPEG, format::Pixel::YUVJ420P),
+        ImageFormat::Webp => (codec::Id::WEBP, format::Pixel::YUV420P),
+    }
+}
+
+// encodes a single frame into an image file
+fn write_image(path: &Path, mut frame: frame::Video, id: codec::Id) -> Result<(), ffmpeg::Error> {
+    let mut octx = format::output(&path)?;
+    let codec = encoder::find(id).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let time_base = Rational(1, 25);
+    let (ost_index, mut encoder) = {
+        let mut ost = octx.add_stream(codec)?;
+        let mut encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_format(frame.format());
+        encoder.set_time_base(time_base);
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+        (ost.index(), encoder)
+    };
+    octx.write_header()?;
+    let ost_time_base = octx
+        .stream(ost_index)
+        .map(|ost| ost.time_base())
+        .unwrap_or(time_base);
+
+    frame.set_pts(Some(0));
+    encoder.send_frame(&frame)?;
+    encoder.send_eof()?;
+    drain_encoder(&mut encoder, &mut octx, ost_index, time_base, ost_time_base)?;
+    octx.write_trailer()
+}
+
+// Where a video's keyframes are, in seconds, and where its scenes change when asked for
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Cuts {
+    pub keyframes: Vec<f64>,
+    pub scenes: Option<Vec<SceneChange>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SceneChange {
+    pub time: f64,
+    // how much the frame differs from the one before, from 0 to 1
+    pub score: f64,
+}
+
+// how small frames are compared for scene changes, enough to ignore noise and grain
+const SCENE_WIDTH: u32 = 64;
+const SCENE_HEIGHT: u32 = 36;
+
+// Lists the keyframes of the best video stream of `input`. With a `threshold`, also the frames
+// that differ from the one before by at least that much, in the same pass; that decodes every
+// frame, while keyframes alone only need the packets.
+#[instrument(skip(input, cancel), fields(input = %input.display()))]
+pub fn cuts(input: &Path, threshold: Option<f64>, cancel: &Cancel) -> Result<Cuts, ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("the keyframes of {}", input.display()),
+    );
+    let mut ictx = open(input, cancel)?;
+    let (index, time_base, mut decoder) = video_decoder(&ictx)?;
+    let seconds = |pts: Option<i64>| pts.map(|pts| pts as f64 * f64::from(time_base));
+    let Some(threshold) = threshold else {
+        let mut keyframes: Vec<f64> = ictx
+            .packets()
+            .take_while(|_| !cancel.is_cancelled())
+            .filter(|(stream, packet)| stream.index() == index && packet.is_key())
+            .filter_map(|(_, packet)| seconds(packet.pts().or(packet.dts())))
+            .collect();
+        cancel.check()?;
+        keyframes.sort_by(f64::total_cmp);
+        return Ok(Cuts {
+            keyframes,
+            scenes: None,
+        });
+    };
+
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        format::Pixel::GRAY8,
+        SCENE_WIDTH,
+        SCENE_HEIGHT,
+        Flags::AREA,
+    )?;
+    let mut cuts = Cuts {
+        keyframes: Vec::new(),
+        scenes: Some(Vec::new()),
+    };
+    let mut previous: Option<Vec<u8>> = None;
+    let mut small = frame::Video::empty();
+    let mut decoded = frame::Video::empty();
+    let mut look = |decoded: &frame::Video, cuts: &mut Cuts| -> Result<(), ffmpeg::Error> {
+        let Some(time) = seconds(decoded.timestamp()) else {
+            return Ok(());
+        };
+        if decoded.is_key() {
+            cuts.keyframes.push(time);
+        }
+        scaler.run(decoded, &mut small)?;
+        let pixels = packed(&small);
+        if let Some(previous) = &previous {
+            let score = scene_score(previous, &pixels);
+            if score >= threshold {
+                let scenes = cuts.scenes.get_or_insert_with(Vec::new);
+                scenes.push(SceneChange { time, score });
+            }
+        }
+        previous = Some(pixels);
+        Ok(())
+    };
+    for (stream, packet) in ictx.packets() {
+        cancel.check()?;
+        if stream.index() != index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            look(&decoded, &mut cuts)?;
+        }
+    }
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        look(&decoded, &mut cuts)?;
+    }
+    Ok(cuts)
+}
+
+// how different two frames of gray pixels are, from 0 for the same to 1 for black and white
+fn scene_score(previous: &[u8], current: &[u8]) -> f64 {
+    if current.is_empty() {
+        return 0.0;
+    }
+    let difference: u64 = previous
+        .iter()
+        .zip(current)
+        .map(|(a, b)| u64::from(a.abs_diff(*b)))
+        .sum();
+    difference as f64 / (current.len() as f64 * 255.0)
+}
+
+// the pixels of a frame with a single plane of one byte each, without the padding of its lines
+fn packed(frame: &frame::Video) -> Vec<u8> {
+    let row = frame.width() as usize;
+    (0..frame.height() as usize)
+        .flat_map(|line| {
+            let start = line * frame.stride(0);
+            frame.data(0)[start..start + row].iter().copied()
+        })
+        .collect()
+}
+
+// A perceptual hash of the frame shown at `time` seconds of `input`'s video: frames that look
+// alike have hashes few bits apart, whatever their size, quality or codec. Compare them with
+// `(a ^ b).count_ones()`.
+#[instrument(skip(input), fields(input = %input.display()))]
+pub fn frame_hash(input: &Path, time: f64) -> Result<u64, ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("a frame hash of {}", input.display()),
+    );
+    let mut ictx = format::input(&input)?;
+    let (index, time_base, mut decoder) = video_decoder(&ictx)?;
+    let picked = frame_at(&mut ictx, index, time_base, &mut decoder, time)?;
+    let mut scaler = scaling::Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        format::Pixel::GRAY8,
+        HASH_WIDTH + 1,
+        HASH_HEIGHT,
+        Flags::AREA,
+    )?;
+    let mut small = frame::Video::empty();
+    scaler.run(&picked, &mut small)?;
+    Ok(difference_hash(&packed(&small)))
+}
+
+// 8 by 8 bits, from a gray frame one pixel wider
+const HASH_WIDTH: u32 = 8;
+const HASH_HEIGHT: u32 = 8;
+
+// a bit per pixel but the last of each line, set when it's brighter than the one on its right
+fn difference_hash(pixels: &[u8]) -> u64 {
+    let row = HASH_WIDTH as usize + 1;
+    let mut hash = 0;
+    for line in pixels.chunks_exact(row).take(HASH_HEIGHT as usize) {
+        for pair in line.windows(2) {
+            hash = hash << 1 | u64::from(pair[0] > pair[1]);
+        }
+    }
+    hash
+}
+
+// The quietest and loudest sample of every `samples_per_pixel` long slice of an audio track,
+// over all of its channels, for drawing a waveform. Samples are 16 bit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peaks {
+    pub sample_rate: u32,
+    pub samples_per_pixel: u32,
+    // min and max of each slice, one after the other
+    pub data: Vec<i16>,
+}
+
+impl Peaks {
+    // in the binary format of audiowaveform, which players like peaks.js read: a header of
+    // version, flags, sample rate, samples per pixel and slices, then the peaks, little endian
+    pub fn to_dat(&self) -> Vec<u8> {
+        let mut dat = Vec::with_capacity(20 + self.data.len() * 2);
+        dat.extend_from_slice(&1i32.to_le_bytes());
+        // 16 bit samples
+        dat.extend_from_slice(&0u32.to_le_bytes());
+        dat.extend_from_slice(&(self.sample_rate as i32).to_le_bytes());
+        dat.extend_from_slice(&(self.samples_per_pixel as i32).to_le_bytes());
+        dat.extend_from_slice(&((self.data.len() / 2) as u32).to_le_bytes());
+        for peak in &self.data {
+            dat.extend_from_slice(&peak.to_le_bytes());
+        }
+        dat
+    }
+}
+
+// gathers samples into the peaks of their slices
+struct PeakBuilder {
+    per_slice: u64,
+    seen: u64,
+    min: i16,
+    max: i16,
+    data: Vec<i16>,
+}
+
+impl PeakBuilder {
+    fn new(per_slice: u32) -> Self {
+        PeakBuilder {
+            per_slice: u64::from(per_slice.max(1)),
+            seen: 0,
+            min: i16::MAX,
+            max: i16::MIN,
+            data: Vec::new(),
+        }
+    }
+
+    // one sample of every channel at a time, from `frame`
+    fn push(&mut self, frame: &[i16]) {
+        for &sample in frame {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+        }
+        self.seen += 1;
+        if self.seen == self.per_slice {
+            self.close();
+        }
+    }
+
+    fn close(&mut self) {
+        if self.seen > 0 {
+            self.data.extend([self.min, self.max]);
+        }
+        self.seen = 0;
+        self.min = i16::MAX;
+        self.max = i16::MIN;
+    }
+
+    // with the last slice, however short
+    fn finish(mut self) -> Vec<i16> {
+        self.close();
+        self.data
+    }
+}
+
+// decodes the best audio stream of `input` into its peaks, `samples_per_second` slices a second
+#[instrument(skip(input, cancel), fields(input = %input.display()))]
+pub fn peaks(
+    input: &Path,
+    samples_per_second: u32,
+    cancel: &Cancel,
+) -> Result<Peaks, ffmpeg::Error> {
+    init()?;
+    let _context = leak::hold(
+        leak::Kind::Ffmpeg,
+        format!("the waveform of {}", input.display()),
+    );
+    let mut ictx = open(input, cancel)?;
+    let (index, mut decoder) = {
+        let stream = ictx
+            .streams()
+            .best(media::Type::Audio)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let decoder = codec::context::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+        (stream.index(), decoder)
+    };
+    let sample_rate = decoder.rate();
+    let samples_per_pixel = (sample_rate / samples_per_second.max(1)).max(1);
+    let mut peaks = PeakBuilder::new(samples_per_pixel);
+    let mut decoded = frame::Audio::empty();
+    let mut samples = Vec::new();
+    for (stream, packet) in ictx.packets() {
+        cancel.check()?;
+        if stream.index() != index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            push_samples(&decoded, &mut samples, &mut peaks);
+        }
+    }
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        push_samples(&decoded, &mut samples, &mut peaks);
+    }
+    Ok(Peaks {
+        sample_rate,
+        samples_per_pixel,
+        data: peaks.finish(),
+    })
+}
+
+// the samples of `frame` as 16 bit, channel by channel, into `peaks`; `samples` is scratch space
+fn push_samples(frame: &frame::Audio, samples: &mut Vec<i16>, peaks: &mut PeakBuilder) {
+    let channels = usize::from(frame.channels()).max(1);
+    let count = frame.samples();
+    let planar = frame.is_planar();
+    let per_plane = if planar { count } else { count * channels };
+    // every plane's samples in order, a plane per channel when planar
+    samples.clear();
+    for plane in 0..frame.planes() {
+        let data = frame.data(plane);
+        match frame.format() {
+            format::Sample::U8(_) => {
+                let wide = data
+                    .iter()
+                    .take(per_plane)
+                    .map(|&b| (i16::from(b) - 128) << 8);
+                samples.extend(wide);
+            }
+            format::Sample::I16(_) => read(data, per_plane, samples, i16::from_ne_bytes),
+            format::Sample::I32(_) => read(data, per_plane, samples, |b: [u8; 4]| {
+                (i32::from_ne_bytes(b) >> 16) as i16
+            }),
+            format::Sample::I64(_) => read(data, per_plane, samples, |b: [u8; 8]| {
+                (i64::from_ne_bytes(b) >> 48) as i16
+            }),
+            format::Sample::F32(_) => read(data, per_plane, samples, |b: [u8; 4]| {
+                float_sample(f64::from(f32::from_ne_bytes(b)))
+            }),
+            format::Sample::F64(_) => read(data, per_plane, samples, |b: [u8; 8]| {
+                float_sample(f64::from_ne_bytes(b))
+            }),
+            format::Sample::None => {}
+        }
+    }
+    let mut at_once = vec![0; channels];
+    for i in 0..count {
+        for (channel, sample) in at_once.iter_mut().enumerate() {
+            let at = if planar {
+                channel * count + i
+            } else {
+                i * channels + channel
+            };
+            *sample = samples.get(at).copied().unwrap_or(0);
+        }
+        peaks.push(&at_once);
+    }
+}
+
+fn read<const N: usize>(
+    data: &[u8],
+    count: usize,
+    samples: &mut Vec<i16>,
+    convert: impl Fn([u8; N]) -> i16,
+) {
+    let chunks = data.chunks_exact(N).take(count);
+    samples.extend(chunks.map(|bytes| convert(bytes.try_into().unwrap())));
+}
+
+fn float_sample(value: f64) -> i16 {
+    (value.clamp(-1.0, 1.0) * f64::from(i16::MAX)) as i16
+}
+
+// even dimensions, which yuv420 needs, at `width` keeping the aspect ratio
+fn scaled_size(source_width: u32, source_height: u32, width: Option<u32>) -> (u32, u32) {
+    let width = width.unwrap_or(source_width).clamp(2, source_width.max(2)) & !1;
+    let height = u64::from(source_height) * u64::from(width) / u64::from(source_width.max(1));
+    (width, (height as u32).max(2) & !1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_filters() {
+        let overlay = Overlay {
+            image: "/media/logo.png".to_owned(),
+            position: Position::BottomRight,
+            opacity: 0.5,
+            scale: 0.1,
+        };
+        assert_eq!(
+            overlay.filter(1920),
+            "movie=filename=/media/logo.png,format=rgba,scale=192:-1,colorchannelmixer=aa=0.5\
+            [overlay];[in][overlay]overlay=main_w-overlay_w-38:main_h-overlay_h-38"
+        );
+        let overlay = Overlay {
+            image: "/media/it's [a]:logo.png".to_owned(),
+            position: Position::Center,
+            opacity: 1.0,
+            ..overlay
+        };
+        assert_eq!(
+            overlay.filter(640),
+            concat!(
+                r"movie=filename=/media/it\\\'s \[a\]\\:logo.png,format=rgba,scale=64:-1",
+                "[overlay];[in][overlay]overlay=(main_w-overlay_w)/2:(main_h-overlay_h)/2"
+            )
+        );
+        assert!(overlay.check().is_ok());
+        assert!(Overlay {
+            opacity: 1.5,
+            ..overlay.clone()
+        }
+        .check()
+        .is_err());
+        assert!(Overlay {
+            scale: 0.0,
+            ..overlay
+        }
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn cancels_when_abandoned() {
+        let cancel = Cancel::default();
+        let abandoned = cancel.guard();
+        assert_eq!(cancel.check(), Ok(()));
+        drop(abandoned);
+        assert!(cancel.is_cancelled());
+        assert_eq!(cancel.check(), Err(ffmpeg::Error::Exit));
+    }
+
+    #[test]
+    fn picks_hardware() {
+        let hardware = Hardware {
+            default: Hwaccel::Auto,
+            vaapi_device: "/dev/dri/renderD128".to_owned(),
+            available: vec![Hwaccel::Videotoolbox],
+        };
+        let h264 = VideoCodec::H264;
+        assert_eq!(hardware.pick(None, h264), Some(Hwaccel::Videotoolbox));
+        // it has no AV1 encoder
+        assert_eq!(hardware.pick(None, VideoCodec::Av1), None);
+        assert_eq!(hardware.pick(Some(Hwaccel::Software), h264), None);
+        assert_eq!(hardware.pick(Some(Hwaccel::Nvenc), h264), None);
+        let software = Hardware {
+            default: Hwaccel::Software,
+            ..hardware
+        };
+        assert_eq!(software.pick(None, h264), None);
+        let asked = Some(Hwaccel::Videotoolbox);
+        assert_eq!(software.pick(asked, h264), Some(Hwaccel::Videotoolbox));
+    }
+
+    #[test]
+    fn thumbnail_sizes() {
+        assert_eq!(scaled_size(1920, 1080, None), (1920, 1080));
+        assert_eq!(scaled_size(1920, 1080, Some(320)), (320, 180));
+        // never upscaled, and even for the chroma planes
+        assert_eq!(scaled_size(640, 360, Some(4000)), (640, 360));
+        assert_eq!(scaled_size(853, 480, Some(301)), (300, 168));
+    }
+
+    #[test]
+    fn sprite_layouts() {
+        let layout = SpriteLayout::new(1920, 1080, 25, 10, 160);
+        assert_eq!((layout.tile_width, layout.tile_height), (160, 90));
+        assert_eq!(layout.size(), (1600, 270));
+        assert_eq!(layout.tile(0), (0, 0));
+        assert_eq!(layout.tile(9), (1440, 0));
+        assert_eq!(layout.tile(24), (640, 180));
+        // no more columns than tiles
+        assert_eq!(SpriteLayout::new(1920, 1080, 3, 10, 160).size(), (480, 90));
+        assert_eq!(sprite_times(10.0, 4), vec![0.0, 2.5, 5.0, 7.5]);
+    }
+
+    #[test]
+    fn difference_hashes() {
+        let gradient: Vec<u8> = (0..72).map(|pixel| 255 - (pixel % 9) as u8 * 20).collect();
+        assert_eq!(difference_hash(&gradient), u64::MAX);
+        let flat = vec![128; 72];
+        assert_eq!(difference_hash(&flat), 0);
+        // brighter all over, the same picture
+        let brighter: Vec<u8> = gradient
+            .iter()
+            .map(|pixel| pixel.saturating_add(3))
+            .collect();
+        assert_eq!(difference_hash(&brighter), difference_hash(&gradient));
+    }
+
+    #[test]
+    fn scene_scores() {
+        assert_eq!(scene_score(&[10, 20, 30], &[10, 20, 30]), 0.0);
+        assert_eq!(scene_score(&[0, 0], &[255, 255]), 1.0);
+        assert_eq!(scene_score(&[0, 255], &[51, 255]), 0.1);
+    }
+
+    #[test]
+    fn peaks_per_slice() {
+        let mut peaks = PeakBuilder::new(2);
+        for frame in [[10, -3], [4, 8], [-20, 0], [1, 1], [7, 7]] {
+            peaks.push(&frame);
+        }
+        assert_eq!(peaks.finish(), vec![-3, 10, -20, 1, 7, 7]);
+        assert_eq!(float_sample(2.0), i16::MAX);
+        assert_eq!(float_sample(-0.5), -16383);
+
+        let dat = Peaks {
+            sample_rate: 44100,
+            samples_per_pixel: 441,
+            data: vec![-3, 10],
+        }
+        .to_dat();
+        assert_eq!(dat.len(), 24);
+        assert_eq!(dat[8..12], 44100i32.to_le_bytes());
+        assert_eq!(dat[16..20], 1u32.to_le_bytes());
+        assert_eq!(dat[20..], [0xfd, 0xff, 10, 0]);
+    }
+
+    #[test]
+    fn cli_progress_lines() {
+        assert_eq!(cli_progress("out_time_us=5000000", Some(10.0)), Some(0.5));
+        assert_eq!(cli_progress("out_time_us=20000000", Some(10.0)), Some(1.0));
+        assert_eq!(cli_progress("out_time_us=N/A", Some(10.0)), None);
+        assert_eq!(cli_progress("out_time_us=5000000", None), None);
+        assert_eq!(cli_progress("frame=12", Some(10.0)), None);
+    }
+
+    #[test]
+    fn readable_metadata() {
+        let metadata = Metadata {
+            schema: schema::VERSION,
+            format: "mov,mp4,m4a,3gp,3g2,mj2".to_owned(),
+            duration: Some(12.5),
+            bit_rate: None,
+            tags: BTreeMap::from([("title".to_owned(), "movie".to_owned())]),
+            streams: vec![
+                StreamInfo {
+                    index: 0,
+                    medium: "video".to_owned(),
+                    codec: "h264".to_owned(),
+                    duration: Some(12.5),
+                    width: Some(1920),
+                    height: Some(1080),
+                    frame_rate: Some(25.0),
+                    sample_rate: None,
+                    channels: None,
+                },
+                StreamInfo {
+                    index: 1,
+                    medium: "audio".to_owned(),
+                    codec: "aac".to_owned(),
+                    duration: None,
+                    width: None,
+                    height: None,
+                    frame_rate: None,
+                    sample_rate: Some(48000),
+                    channels: Some(2),
+                },
+            ],
+        };
+        assert_eq!(
+            metadata.to_string(),
+            "format    mov,mp4,m4a,3gp,3g2,mj2
+duration  12.50 s
+tag       title = movie
+stream 0  video h264 1920x1080 25.000 fps 12.50 s
+stream 1  audio aac 48000 Hz 2 channels
+"
+        );
+    }
+}
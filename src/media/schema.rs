@@ -0,0 +1,207 @@
+// The stable shape of stored media metadata. ffmpeg renames codecs between versions and builds,
+// reports unknown values as 0 or negative numbers, and tag keys come in whatever case the file
+// was written with; everything goes through here on the way in, so stored documents only differ
+// by what was known about the file.
+//
+// Each document records the `schema` it was written in. When the shape changes, VERSION goes up
+// and `upgrade` learns to bring older documents along; they are rewritten as they are read
+// instead of all at once.
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::Metadata;
+
+// 1: no `schema` field, unknown numbers as 0, tags as a list of pairs, codec names as probed
+// 2: unknown numbers as null, tags as an object with lower case keys, normalized codec names
+pub const VERSION: u32 = 2;
+
+pub const UNKNOWN: &str = "unknown";
+
+// codec names some ffmpeg versions or builds use, and the name current ffmpeg has for them
+const CODEC_ALIASES: &[(&str, &str)] = &[
+    ("h265", "hevc"),
+    ("libx264", "h264"),
+    ("libx265", "hevc"),
+    ("libvpx", "vp8"),
+    ("libvpx-vp9", "vp9"),
+    ("libaom-av1", "av1"),
+    ("libdav1d", "av1"),
+    ("libopus", "opus"),
+    ("libvorbis", "vorbis"),
+    ("libfdk_aac", "aac"),
+    ("mp3float", "mp3"),
+];
+
+// lower case, UNKNOWN when empty
+pub fn name(name: &str) -> String {
+    match name.trim() {
+        "" | "none" => UNKNOWN.to_owned(),
+        name => name.to_lowercase(),
+    }
+}
+
+pub fn codec(codec: &str) -> String {
+    let codec = name(codec);
+    CODEC_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == codec)
+        .map(|(_, name)| (*name).to_owned())
+        .unwrap_or(codec)
+}
+
+// None for values ffmpeg uses to say it doesn't know: 0, negative numbers, NaN
+pub fn positive(value: f64) -> Option<f64> {
+    (value.is_finite() && value > 0.0).then_some(value)
+}
+
+pub fn known<T: Default + PartialOrd>(value: T) -> Option<T> {
+    (value > T::default()).then_some(value)
+}
+
+// lower case keys, the first of several spellings wins
+pub fn tags<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> BTreeMap<String, String> {
+    let mut normalized = BTreeMap::new();
+    for (key, value) in tags {
+        let key = key.trim().to_lowercase();
+        if !key.is_empty() {
+            normalized.entry(key).or_insert_with(|| value.to_owned());
+        }
+    }
+    normalized
+}
+
+// Reads a stored document of any schema version; true when it was older than VERSION and
+// should be stored again. Documents from a newer version, written by a newer release, are read
+// as far as this one understands them and left alone.
+pub fn upgrade(mut document: Value) -> Result<(Metadata, bool), serde_json::Error> {
+    let version = document.get("schema").and_then(Value::as_u64).unwrap_or(1);
+    if version < 2 {
+        document = v1_to_v2(document);
+    }
+    let metadata = serde_json::from_value(document)?;
+    Ok((metadata, version < u64::from(VERSION)))
+}
+
+fn v1_to_v2(mut document: Value) -> Value {
+    let Some(fields) = document.as_object_mut() else {
+        return document;
+    };
+    fields.insert("schema".to_owned(), Value::from(2));
+    if let Some(format) = fields.get("format").and_then(Value::as_str) {
+        fields.insert("format".to_owned(), Value::from(name(format)));
+    }
+    zero_to_null(fields, &["duration", "bit_rate"]);
+    let tags = match fields.remove("tags") {
+        Some(Value::Array(pairs)) => tags(
+            pairs
+                .iter()
+                .filter_map(|pair| Some((pair.get(0)?.as_str()?, pair.get(1)?.as_str()?))),
+        ),
+        _ => BTreeMap::new(),
+    };
+    fields.insert("tags".to_owned(), Value::from_iter(tags));
+    if let Some(Value::Array(streams)) = fields.get_mut("streams") {
+        for stream in streams.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(name) = stream.get("codec").and_then(Value::as_str) {
+                stream.insert("codec".to_owned(), Value::from(codec(name)));
+            }
+            zero_to_null(
+                stream,
+                &[
+                    "duration",
+                    "width",
+                    "height",
+                    "frame_rate",
+                    "sample_rate",
+                    "channels",
+                ],
+            );
+        }
+    }
+    document
+}
+
+fn zero_to_null(fields: &mut serde_json::Map<String, Value>, keys: &[&str]) {
+    for key in keys {
+        if let Some(value) = fields.get_mut(*key) {
+            if value.as_f64().and_then(positive).is_none() {
+                *value = Value::Null;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::media::StreamInfo;
+
+    #[test]
+    fn names() {
+        assert_eq!(codec("H264"), "h264");
+        assert_eq!(codec("libx265"), "hevc");
+        assert_eq!(codec(""), UNKNOWN);
+        assert_eq!(known(0_u32), None);
+        assert_eq!(known(-1_i64), None);
+        assert_eq!(positive(f64::NAN), None);
+        assert_eq!(
+            tags([("TITLE", "a"), ("title", "b"), (" ", "c")]),
+            BTreeMap::from([("title".to_owned(), "a".to_owned())])
+        );
+    }
+
+    #[test]
+    fn upgrades_v1_documents() {
+        let v1 = json!({
+            "format": "Matroska,WebM",
+            "duration": 0.0,
+            "bit_rate": 1000,
+            "tags": [["TITLE", "movie"], ["encoder", "lavf"]],
+            "streams": [{
+                "index": 0,
+                "medium": "video",
+                "codec": "libx264",
+                "duration": 0.0,
+                "width": 1920,
+                "height": 0,
+                "frame_rate": null,
+                "sample_rate": null,
+                "channels": null,
+            }],
+        });
+        let (metadata, upgraded) = upgrade(v1).unwrap();
+        assert!(upgraded);
+        assert_eq!(
+            metadata,
+            Metadata {
+                schema: VERSION,
+                format: "matroska,webm".to_owned(),
+                duration: None,
+                bit_rate: Some(1000),
+                tags: BTreeMap::from([
+                    ("encoder".to_owned(), "lavf".to_owned()),
+                    ("title".to_owned(), "movie".to_owned()),
+                ]),
+                streams: vec![StreamInfo {
+                    index: 0,
+                    medium: "video".to_owned(),
+                    codec: "h264".to_owned(),
+                    duration: None,
+                    width: Some(1920),
+                    height: None,
+                    frame_rate: None,
+                    sample_rate: None,
+                    channels: None,
+                }],
+            }
+        );
+
+        // current documents read back as they are
+        let (again, upgraded) = upgrade(serde_json::to_value(&metadata).unwrap()).unwrap();
+        assert!(!upgraded);
+        assert_eq!(again, metadata);
+    }
+}
@@ -0,0 +1,220 @@
+// Filters on the stored media metadata, from query parameters like
+//
+//     meta.format=matroska,webm&meta.duration>=3600&meta.video.codec=h264&meta.video.height>=1080
+//
+// `meta.<field>` tests the file as a whole, `meta.<medium>.<field>` a stream of that medium.
+// Conditions on the same medium have to hold for the same stream. Each scope becomes one SQL/JSON
+// path, which `metadata @? path` checks with the help of the GIN index on `assets.metadata`.
+
+use crate::media::schema;
+
+const PREFIX: &str = "meta.";
+const MEDIA: &[&str] = &["video", "audio", "subtitle", "data", "attachment"];
+// field, numeric
+const FILE_FIELDS: &[(&str, bool)] = &[("format", false), ("duration", true), ("bit_rate", true)];
+const STREAM_FIELDS: &[(&str, bool)] = &[
+    ("index", true),
+    ("codec", false),
+    ("duration", true),
+    ("width", true),
+    ("height", true),
+    ("frame_rate", true),
+    ("sample_rate", true),
+    ("channels", true),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn json_path(self) -> &'static str {
+        match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Condition<'a> {
+    // None for the file as a whole
+    medium: Option<&'a str>,
+    field: &'a str,
+    op: Op,
+    // a JSON path literal
+    value: String,
+}
+
+// The SQL/JSON paths the metadata has to match, one per scope, from decoded query pairs.
+// Parameters not starting with `meta.` are left alone.
+pub fn json_paths(pairs: &[(String, String)]) -> Result<Vec<String>, String> {
+    let mut conditions = Vec::new();
+    for (key, value) in pairs {
+        if key.starts_with(PREFIX) {
+            conditions.push(condition(key, value)?);
+        }
+    }
+
+    let mut scopes: Vec<Option<&str>> = Vec::new();
+    for condition in &conditions {
+        if !scopes.contains(&condition.medium) {
+            scopes.push(condition.medium);
+        }
+    }
+    Ok(scopes
+        .into_iter()
+        .map(|scope| {
+            let mut tests: Vec<String> = conditions
+                .iter()
+                .filter(|condition| condition.medium == scope)
+                .map(|condition| {
+                    format!(
+                        "@.{} {} {}",
+                        condition.field,
+                        condition.op.json_path(),
+                        condition.value
+                    )
+                })
+                .collect();
+            match scope {
+                None => format!("$ ? ({})", tests.join(" && ")),
+                Some(medium) => {
+                    tests.insert(0, format!("@.medium == \"{}\"", medium));
+                    format!("$.streams[*] ? ({})", tests.join(" && "))
+                }
+            }
+        })
+        .collect())
+}
+
+// Query strings split pairs at the first `=`, so `a>=1` arrives as (`a>`, `1`) and `a>1` as
+// (`a>1`, ``); both are put back together here.
+fn condition<'a>(key: &'a str, value: &str) -> Result<Condition<'a>, String> {
+    let (name, op, value) = match key.find(['<', '>']) {
+        Some(at) if at + 1 == key.len() => {
+            let op = if key.ends_with('<') { Op::Le } else { Op::Ge };
+            (&key[..at], op, value.to_owned())
+        }
+        Some(at) if value.is_empty() => {
+            let op = if key[at..].starts_with('<') {
+                Op::Lt
+            } else {
+                Op::Gt
+            };
+            (&key[..at], op, key[at + 1..].to_owned())
+        }
+        Some(_) => return Err(format!("can't read the condition {}={}", key, value)),
+        None => match key.strip_suffix('!') {
+            Some(name) => (name, Op::Ne, value.to_owned()),
+            None => (key, Op::Eq, value.to_owned()),
+        },
+    };
+
+    let path = &name[PREFIX.len()..];
+    let (medium, field, fields) = match path.split_once('.') {
+        Some((medium, field)) if MEDIA.contains(&medium) => (Some(medium), field, STREAM_FIELDS),
+        Some(_) => return Err(format!("unknown medium in {}", name)),
+        None => (None, path, FILE_FIELDS),
+    };
+    let numeric = fields
+        .iter()
+        .find(|(known, _)| *known == field)
+        .map(|(_, numeric)| *numeric)
+        .ok_or_else(|| format!("unknown metadata field {}", name))?;
+    let value = if numeric {
+        let number: f64 = value
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite())
+            .ok_or_else(|| format!("{} takes a number", name))?;
+        number.to_string()
+    } else {
+        // spelled the way stored documents have it
+        let value = match field {
+            "codec" => schema::codec(&value),
+            _ => schema::name(&value),
+        };
+        // JSON string escapes are valid in SQL/JSON paths too
+        serde_json::Value::String(value).to_string()
+    };
+    Ok(Condition {
+        medium,
+        field,
+        op,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn operators() {
+        let parsed =
+            |key, value| condition(key, value).map(|condition| (condition.op, condition.value));
+        assert_eq!(parsed("meta.duration", "60"), Ok((Op::Eq, "60".to_owned())));
+        assert_eq!(
+            parsed("meta.duration!", "60"),
+            Ok((Op::Ne, "60".to_owned()))
+        );
+        assert_eq!(
+            parsed("meta.duration>", "60"),
+            Ok((Op::Ge, "60".to_owned()))
+        );
+        assert_eq!(
+            parsed("meta.duration<", "60"),
+            Ok((Op::Le, "60".to_owned()))
+        );
+        assert_eq!(
+            parsed("meta.duration>60", ""),
+            Ok((Op::Gt, "60".to_owned()))
+        );
+        assert_eq!(
+            parsed("meta.duration<60.5", ""),
+            Ok((Op::Lt, "60.5".to_owned()))
+        );
+        assert!(parsed("meta.duration>", "long").is_err());
+        assert!(parsed("meta.video.colour", "red").is_err());
+        assert!(parsed("meta.sound.codec", "aac").is_err());
+    }
+
+    #[test]
+    fn paths_per_scope() {
+        let paths = json_paths(&pairs(&[
+            ("limit", "10"),
+            ("meta.video.codec", "libx264"),
+            ("meta.video.height>", "1080"),
+            ("meta.format", "Matroska,WebM"),
+            ("meta.audio.codec!", "Say \"hi\""),
+        ]))
+        .unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                r#"$.streams[*] ? (@.medium == "video" && @.codec == "h264" && @.height >= 1080)"#,
+                r#"$ ? (@.format == "matroska,webm")"#,
+                r#"$.streams[*] ? (@.medium == "audio" && @.codec != "say \"hi\"")"#,
+            ]
+        );
+        assert_eq!(json_paths(&pairs(&[("limit", "10")])), Ok(Vec::new()));
+    }
+}
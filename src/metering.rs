@@ -0,0 +1,217 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use chrono::Utc;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    app::AppState,
+    auth::CurrentUser,
+    rate_plan::{RatePlan, RatePlans},
+};
+
+// per-minute buckets are kept for this long
+const WINDOW_MINUTES: i64 = 24 * 60;
+
+// request counters per user, kept in memory for the last day
+#[derive(Default)]
+pub struct Metering {
+    users: Mutex<HashMap<i64, Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    // (minute since epoch, requests, bytes sent), oldest first
+    buckets: VecDeque<(i64, u64, u64)>,
+}
+
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub last_minute: u64,
+    pub last_hour: u64,
+    pub last_day: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageReport {
+    user_id: i64,
+    requests: Totals,
+    // response body bytes, as far as the length was known up front
+    bytes_sent: Totals,
+    // the limits these count against, if any
+    rate_plan: Option<RatePlan>,
+}
+
+impl Usage {
+    fn record(&mut self, minute: i64, bytes: u64) {
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.0 == minute => {
+                bucket.1 += 1;
+                bucket.2 += bytes;
+            }
+            _ => self.buckets.push_back((minute, 1, bytes)),
+        }
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.0 > minute - WINDOW_MINUTES {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn totals(&self, minute: i64) -> (Totals, Totals) {
+        let mut requests = Totals::default();
+        let mut bytes = Totals::default();
+        for (at, count, sent) in &self.buckets {
+            let age = minute - at;
+            if age < WINDOW_MINUTES {
+                requests.last_day += count;
+                bytes.last_day += sent;
+            }
+            if age < 60 {
+                requests.last_hour += count;
+                bytes.last_hour += sent;
+            }
+            if age < 1 {
+                requests.last_minute += count;
+                bytes.last_minute += sent;
+            }
+        }
+        (requests, bytes)
+    }
+}
+
+impl Metering {
+    pub fn record(&self, user: i64, bytes: u64) {
+        let mut users = self.users.lock().unwrap();
+        users.entry(user).or_default().record(now_minute(), bytes);
+    }
+
+    // (requests, bytes sent)
+    pub fn totals(&self, user: i64) -> (Totals, Totals) {
+        let users = self.users.lock().unwrap();
+        users
+            .get(&user)
+            .map(|usage| usage.totals(now_minute()))
+            .unwrap_or_default()
+    }
+
+    // forgets users without requests in the window, returns how many
+    pub fn prune(&self) -> usize {
+        let minute = now_minute();
+        let mut users = self.users.lock().unwrap();
+        let count = users.len();
+        users.retain(|_, usage| {
+            usage
+                .buckets
+                .back()
+                .is_some_and(|bucket| bucket.0 > minute - WINDOW_MINUTES)
+        });
+        count - users.len()
+    }
+
+    pub fn report(&self, user: i64, rate_plan: Option<RatePlan>) -> UsageReport {
+        let (requests, bytes_sent) = self.totals(user);
+        UsageReport {
+            user_id: user,
+            requests,
+            bytes_sent,
+            rate_plan,
+        }
+    }
+}
+
+pub fn now_minute() -> i64 {
+    Utc::now().timestamp() / 60
+}
+
+// counts requests that carry a user identity
+pub async fn track(
+    State(metering): State<Arc<Metering>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let user = CurrentUser::from_request_parts(&mut parts, &()).await.ok();
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    if let Some(CurrentUser(user)) = user {
+        let bytes = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        metering.record(user, bytes);
+    }
+    response
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/me/usage", get(usage))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/usage",
+    responses((status = 200, description = "The caller's recent usage", body = UsageReport)),
+    security(("user_id" = [])),
+    tag = "users"
+)]
+async fn usage(
+    State(metering): State<Arc<Metering>>,
+    State(plans): State<Arc<RatePlans>>,
+    CurrentUser(user): CurrentUser,
+) -> Json<UsageReport> {
+    let plan = plans.for_user(user).map(|plan| RatePlan::clone(&plan));
+    Json(metering.report(user, plan))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_windows() {
+        let mut usage = Usage::default();
+        usage.record(0, 100);
+        usage.record(100, 10);
+        usage.record(1000, 1);
+        usage.record(1000, 1);
+
+        let (requests, bytes) = usage.totals(1000);
+        assert_eq!(
+            requests,
+            Totals {
+                last_minute: 2,
+                last_hour: 2,
+                last_day: 4
+            }
+        );
+        assert_eq!(bytes.last_day, 112);
+
+        // the first bucket falls out of the window
+        usage.record(WINDOW_MINUTES, 0);
+        assert_eq!(usage.buckets.len(), 3);
+        let (requests, _) = usage.totals(WINDOW_MINUTES + 30);
+        assert_eq!(
+            requests,
+            Totals {
+                last_minute: 0,
+                last_hour: 1,
+                last_day: 4
+            }
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use clap::Subcommand;
+use serde_derive::Serialize;
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migrator},
+    PgPool,
+};
+
+// the migrations in `migrations/`, built into the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Action {
+    // applies the pending migrations
+    Up,
+    // rolls back the last applied migration, or all after `--to`
+    Down {
+        #[arg(long)]
+        to: Option<i64>,
+    },
+    // lists the migrations and whether they are applied
+    Status,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MigrationState {
+    pub version: i64,
+    pub description: String,
+    // applied, pending, or unknown for ones applied by a newer build, which this one can't roll
+    // back
+    pub state: &'static str,
+}
+
+// does what `action` says, then tells the state of every migration
+pub async fn run(pool: &PgPool, action: Action) -> Result<Vec<MigrationState>, MigrateError> {
+    match action {
+        Action::Up => {
+            MIGRATOR.run(pool).await?;
+        }
+        Action::Down { to } => {
+            let applied = applied(pool).await?;
+            if let Some(target) = to.or_else(|| rollback_target(&applied)) {
+                MIGRATOR.undo(pool, target).await?;
+            }
+        }
+        Action::Status => {}
+    }
+    status(pool).await
+}
+
+// the last migration applied, none before the first
+pub async fn current(pool: &PgPool) -> Result<Option<i64>, MigrateError> {
+    Ok(applied(pool).await?.last().copied())
+}
+
+async fn applied(pool: &PgPool) -> Result<Vec<i64>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let mut versions: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+// the version to roll back to for undoing the last applied migration, 0 for all of them
+fn rollback_target(applied: &[i64]) -> Option<i64> {
+    match applied {
+        [] => None,
+        [_] => Some(0),
+        [.., previous, _] => Some(*previous),
+    }
+}
+
+async fn status(pool: &PgPool) -> Result<Vec<MigrationState>, MigrateError> {
+    let applied = applied(pool).await?;
+    let mut states: Vec<MigrationState> = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| MigrationState {
+            version: migration.version,
+            description: migration.description.to_string(),
+            state: if applied.contains(&migration.version) {
+                "applied"
+            } else {
+                "pending"
+            },
+        })
+        .collect();
+    for version in applied {
+        if MIGRATOR
+            .iter()
+            .all(|migration| migration.version != version)
+        {
+            states.push(MigrationState {
+                version,
+                description: String::new(),
+                state: "unknown",
+            });
+        }
+    }
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+
+    async fn tables(pool: &PgPool) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT tablename::text FROM pg_tables
+                WHERE schemaname = 'public' AND tablename <> '_sqlx_migrations' ORDER BY tablename",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn rolls_back_one_at_a_time() {
+        assert_eq!(rollback_target(&[]), None);
+        assert_eq!(rollback_target(&[20240210000001]), Some(0));
+        assert_eq!(
+            rollback_target(&[20240210000001, 20240210000002, 20240212000001]),
+            Some(20240210000002)
+        );
+    }
+
+    #[tokio::test]
+    async fn migrations_roll_back_cleanly() {
+        // not the database of RSAPP_TEST_DSN, its tables would be dropped
+        let Some(db) = TestDb::fresh().await else {
+            return;
+        };
+        let pool = PgPool::connect(&db.dsn).await.unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+        let created = tables(&pool).await;
+        for table in ["assets", "jobs", "users"] {
+            assert!(
+                created.iter().any(|name| name == table),
+                "no {} table",
+                table
+            );
+        }
+        let users: i64 = sqlx::query_scalar("SELECT count(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(users, 0);
+
+        MIGRATOR.undo(&pool, 0).await.unwrap();
+        assert_eq!(tables(&pool).await, Vec::<String>::new());
+        // and apply again on top
+        MIGRATOR.run(&pool).await.unwrap();
+        assert_eq!(tables(&pool).await, created);
+    }
+}
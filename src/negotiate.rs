@@ -0,0 +1,135 @@
+// Content negotiation for the endpoints with large answers, like media metadata: clients sending
+// `Accept: application/msgpack` get MessagePack instead of JSON, which internal services decode
+// faster and which is a good deal smaller. Handlers take the `Format` asked for and answer with
+// `Negotiated`. Everything else, /api/v2 envelopes included, stays JSON.
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+pub const MSGPACK: &str = "application/msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    // the format `accept` prefers, JSON when it doesn't care or names neither. Of two with the
+    // same quality a named one beats a wildcard, and the first named wins otherwise.
+    fn preferred(accept: &str) -> Self {
+        let mut best = (Format::Json, 0.0, false);
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let (format, named) = match media.as_str() {
+                "application/msgpack" | "application/x-msgpack" => (Format::MessagePack, true),
+                "application/json" => (Format::Json, true),
+                "application/*" | "*/*" => (Format::Json, false),
+                _ => continue,
+            };
+            if quality > best.1 || (quality == best.1 && named && !best.2) {
+                best = (format, quality, named);
+            }
+        }
+        best.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Format
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let accept: Vec<&str> = parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        Ok(Format::preferred(&accept.join(",")))
+    }
+}
+
+// `T` in the format the request asked for
+pub struct Negotiated<T>(pub Format, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let mut response = match self.0 {
+            Format::Json => Json(self.1).into_response(),
+            Format::MessagePack => match rmp_serde::to_vec_named(&self.1) {
+                Ok(body) => ([(header::CONTENT_TYPE, MSGPACK)], body).into_response(),
+                Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+            },
+        };
+        // the same URL answers differently by Accept
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept"));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn prefers() {
+        assert_eq!(
+            Format::preferred("application/msgpack"),
+            Format::MessagePack
+        );
+        assert_eq!(Format::preferred("*/*"), Format::Json);
+        assert_eq!(
+            Format::preferred("application/json;q=0.5, application/x-msgpack"),
+            Format::MessagePack
+        );
+        assert_eq!(
+            Format::preferred("application/msgpack;q=0.2, */*;q=0.8"),
+            Format::Json
+        );
+        assert_eq!(
+            Format::preferred("application/msgpack, application/json"),
+            Format::MessagePack
+        );
+        assert_eq!(
+            Format::preferred("*/*, application/msgpack"),
+            Format::MessagePack
+        );
+        assert_eq!(Format::preferred("text/html"), Format::Json);
+    }
+
+    #[tokio::test]
+    async fn encodes() {
+        let value = BTreeMap::from([("format", "mp4")]);
+        let response = Negotiated(Format::MessagePack, &value).into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], MSGPACK);
+        assert_eq!(response.headers()[header::VARY], "accept");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let decoded: BTreeMap<String, String> = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(decoded["format"], "mp4");
+
+        let response = Negotiated(Format::Json, &value).into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    }
+}
@@ -0,0 +1,735 @@
+// Signing in through an OpenID Connect provider such as Google or Keycloak, configured in
+// `[auth.oidc]`, with the authorization code flow and PKCE:
+//
+// - `GET /auth/oidc/login` sends the browser to the provider, with a state and nonce kept in its
+//   session;
+// - the provider sends it back to `GET /auth/oidc/callback` with a code, which is traded for an ID
+//   token at the provider's token endpoint. The token comes straight from the provider over TLS,
+//   which the issuer and the endpoints it discovers must be https for, so its claims are checked
+//   but not its signature, as the spec allows.
+//
+// The subject of the token is then mapped to a local account: the one that signed in as them
+// before, with `link_by_email` the one with their verified email, or else a new one named after
+// `username_claim` when `create_users` is on. With `role_claim` the role is set from the claim on
+// every sign-in, so taking someone out of the admin group at the provider demotes them here.
+// Deleted accounts are refused. The browser ends up in a cookie session, see `session`.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Redirect,
+    routing::get,
+    Router,
+};
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tower_sessions::Session;
+use tracing::{info, instrument, warn};
+use utoipa::IntoParams;
+
+use crate::{
+    app::AppState,
+    auth::{self, Role},
+    clock::Clock,
+    db_error, internal_error,
+    repository::{
+        identity::{IdentityRepository, NewIdentity},
+        password::Account,
+        UserRepository,
+    },
+    session,
+};
+
+// where the flow under way is kept in the session
+const PENDING: &str = "oidc";
+
+// `[auth.oidc]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    // like "https://accounts.google.com" or "https://keycloak.example.com/realms/rsapp"
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // the callback as registered with the provider, ending in /api/v1/auth/oidc/callback
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    // what new accounts are named after, their email or subject without it
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    // a claim listing roles or groups, dotted for nested ones like Keycloak's "realm_access.roles"
+    pub role_claim: Option<String>,
+    // values of `role_claim` that make admins, everyone else is a user
+    #[serde(default)]
+    pub admin_roles: Vec<String>,
+    // accounts for people signing in for the first time, or only those linked before
+    #[serde(default = "default_create_users")]
+    pub create_users: bool,
+    // signing in to the account with the same email, when the provider verified it
+    #[serde(default)]
+    pub link_by_email: bool,
+    // seconds the provider gets to answer
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_owned(),
+        "email".to_owned(),
+        "profile".to_owned(),
+    ]
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+fn default_create_users() -> bool {
+    true
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        https(&self.issuer).map_err(|err| format!("auth.oidc.issuer {}", err))?;
+        let redirect_url = reqwest::Url::parse(&self.redirect_url)
+            .map_err(|err| format!("auth.oidc.redirect_url: invalid url: {}", err))?;
+        if !matches!(redirect_url.scheme(), "http" | "https") {
+            return Err("auth.oidc.redirect_url must be an http or https url".to_owned());
+        }
+        if !self.scopes.iter().any(|scope| scope == "openid") {
+            return Err("auth.oidc.scopes must have openid".to_owned());
+        }
+        if !self.admin_roles.is_empty() && self.role_claim.is_none() {
+            return Err("auth.oidc.admin_roles needs a role_claim".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// what the provider's discovery document says
+#[derive(Deserialize, Debug, Clone)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+// a flow under way, between sending the browser to the provider and it coming back
+#[derive(Serialize, Deserialize, Debug)]
+struct Pending {
+    state: String,
+    nonce: String,
+    // the PKCE code verifier
+    verifier: String,
+    // where the browser goes once signed in
+    redirect: String,
+}
+
+// who the ID token says signed in
+#[derive(Debug, Clone, PartialEq)]
+struct Claims {
+    subject: String,
+    username: String,
+    // when the provider verified it
+    email: Option<String>,
+    // None without `role_claim`
+    role: Option<Role>,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    id_token: String,
+}
+
+pub struct Oidc {
+    settings: Settings,
+    client: reqwest::Client,
+    identities: Arc<dyn IdentityRepository>,
+    clock: Arc<dyn Clock>,
+    // discovered on the first sign-in
+    provider: OnceCell<Provider>,
+}
+
+impl Oidc {
+    pub fn new(
+        settings: Settings,
+        identities: Arc<dyn IdentityRepository>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Oidc {
+            settings,
+            client: reqwest::Client::new(),
+            identities,
+            clock,
+            provider: OnceCell::new(),
+        }
+    }
+
+    async fn provider(&self) -> Result<&Provider, (StatusCode, String)> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.settings.issuer.trim_end_matches('/')
+                );
+                let provider: Provider = self.get_json(self.client.get(&url)).await?;
+                for (name, endpoint) in [
+                    ("authorization_endpoint", &provider.authorization_endpoint),
+                    ("token_endpoint", &provider.token_endpoint),
+                ] {
+                    https(endpoint)
+                        .map_err(|err| bad_gateway(format!("the provider's {} {}", name, err)))?;
+                }
+                if provider.issuer.trim_end_matches('/')
+                    != self.settings.issuer.trim_end_matches('/')
+                {
+                    return Err(bad_gateway(format!(
+                        "the provider says its issuer is {}",
+                        provider.issuer
+                    )));
+                }
+                Ok(provider)
+            })
+            .await
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, (StatusCode, String)> {
+        let response = request
+            .timeout(std::time::Duration::from_secs(self.settings.timeout))
+            .send()
+            .await
+            .map_err(|err| bad_gateway(format!("can't reach the provider: {}", err)))?;
+        if !response.status().is_success() {
+            return Err(bad_gateway(format!(
+                "the provider answered {}",
+                response.status()
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|err| bad_gateway(format!("the provider answered: {}", err)))
+    }
+
+    // the flow to keep in the session, and the provider's page to send the browser to
+    async fn start(&self, redirect: String) -> Result<(Pending, String), (StatusCode, String)> {
+        let provider = self.provider().await?;
+        let pending = Pending {
+            state: auth::random_token(),
+            nonce: auth::random_token(),
+            verifier: auth::random_token(),
+            redirect,
+        };
+        let mut url = reqwest::Url::parse(&provider.authorization_endpoint)
+            .map_err(|err| bad_gateway(format!("invalid authorization endpoint: {}", err)))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.settings.client_id)
+            .append_pair("redirect_uri", &self.settings.redirect_url)
+            .append_pair("scope", &self.settings.scopes.join(" "))
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &challenge(&pending.verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok((pending, url.into()))
+    }
+
+    // the claims of the ID token `code` is traded for
+    async fn exchange(
+        &self,
+        code: &str,
+        pending: &Pending,
+    ) -> Result<Claims, (StatusCode, String)> {
+        let provider = self.provider().await?;
+        let request = self.client.post(&provider.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.settings.redirect_url.as_str()),
+            ("client_id", self.settings.client_id.as_str()),
+            ("client_secret", self.settings.client_secret.as_str()),
+            ("code_verifier", pending.verifier.as_str()),
+        ]);
+        let tokens: Tokens = self.get_json(request).await?;
+        self.claims(provider, &tokens.id_token, &pending.nonce)
+    }
+
+    // the claims of `id_token` if it's for us, current and of this flow
+    fn claims(
+        &self,
+        provider: &Provider,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Claims, (StatusCode, String)> {
+        let invalid = |why: &str| bad_gateway(format!("invalid ID token: {}", why));
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| invalid("not a JWT"))?;
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .map_err(|_| invalid("not base64"))?;
+        let claims: Value = serde_json::from_slice(&payload).map_err(|_| invalid("not JSON"))?;
+
+        if claims["iss"].as_str() != Some(provider.issuer.as_str()) {
+            return Err(invalid("another issuer"));
+        }
+        let audience = match &claims["aud"] {
+            Value::Array(audience) => audience.iter().any(|aud| aud == &self.settings.client_id),
+            aud => aud.as_str() == Some(self.settings.client_id.as_str()),
+        };
+        if !audience {
+            return Err(invalid("for another client"));
+        }
+        let expired = claims["exp"]
+            .as_i64()
+            .map_or(true, |exp| exp <= self.clock.now().timestamp());
+        if expired {
+            return Err(invalid("expired"));
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err(invalid("of another sign-in"));
+        }
+        let subject = claims["sub"]
+            .as_str()
+            .ok_or_else(|| invalid("no subject"))?
+            .to_owned();
+
+        let email = match claims["email_verified"] {
+            // some providers send it as a string
+            Value::Bool(true) => claims["email"].as_str(),
+            Value::String(ref verified) if verified == "true" => claims["email"].as_str(),
+            _ => None,
+        };
+        let name = claims[self.settings.username_claim.as_str()]
+            .as_str()
+            .or_else(|| email.and_then(|email| email.split('@').next()))
+            .unwrap_or(&subject);
+        let role = self.settings.role_claim.as_deref().map(|path| {
+            let value = path.split('.').fold(&claims, |value, key| &value[key]);
+            let values = match value {
+                Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+                value => value.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            if values
+                .iter()
+                .any(|value| self.settings.admin_roles.iter().any(|admin| admin == value))
+            {
+                Role::Admin
+            } else {
+                Role::User
+            }
+        });
+        Ok(Claims {
+            username: username(name),
+            email: email.map(str::to_owned),
+            subject,
+            role,
+        })
+    }
+
+    // the local account of who signed in
+    async fn account(&self, claims: Claims) -> Result<Account, (StatusCode, String)> {
+        let issuer = self.provider().await?.issuer.clone();
+        let mut account = self
+            .identities
+            .find(&issuer, &claims.subject)
+            .await
+            .map_err(db_error)?;
+        let by_email = match &claims.email {
+            Some(email) if account.is_none() => self
+                .identities
+                .find_by_email(email)
+                .await
+                .map_err(db_error)?,
+            _ => None,
+        };
+        if let Some(linked) = by_email.clone().filter(|_| self.settings.link_by_email) {
+            if linked.deleted {
+                return Err(deleted());
+            }
+            self.identities
+                .link(linked.id, &issuer, &claims.subject)
+                .await
+                .map_err(db_error)?;
+            info!(
+                "linked user {} to {} at {}",
+                linked.id, claims.subject, issuer
+            );
+            account = Some(linked);
+        }
+        let account = match account {
+            Some(account) if account.deleted => return Err(deleted()),
+            Some(account) => {
+                if let Some(role) = claims.role {
+                    self.identities
+                        .set_role(account.id, role)
+                        .await
+                        .map_err(db_error)?;
+                }
+                account
+            }
+            None if self.settings.create_users => {
+                let identity = NewIdentity {
+                    issuer,
+                    subject: claims.subject,
+                    username: claims.username,
+                    // another account has it, which isn't linked
+                    email: claims.email.filter(|_| by_email.is_none()),
+                    role: claims.role.unwrap_or(Role::User),
+                };
+                self.create(identity).await?
+            }
+            None => {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    "there's no account for you here, ask an admin for one".to_owned(),
+                ))
+            }
+        };
+        Ok(account)
+    }
+
+    // creates the account, with a suffix to the username should it be taken
+    async fn create(&self, mut identity: NewIdentity) -> Result<Account, (StatusCode, String)> {
+        let name = identity.username.clone();
+        for _ in 0..3 {
+            if let Some(account) = self.identities.create(&identity).await.map_err(db_error)? {
+                info!("created user {} for {}", account.id, identity.subject);
+                return Ok(account);
+            }
+            let suffix = &auth::random_token()[..6];
+            identity.username = format!("{}-{}", &name[..name.len().min(25)], suffix);
+        }
+        Err((
+            StatusCode::CONFLICT,
+            format!("couldn't find a free username after {}", name),
+        ))
+    }
+}
+
+// Fails unless `url` is https: ID tokens aren't checked for signatures, which only holds up when
+// nobody in between can change them.
+fn https(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if url.scheme() == "https" => Ok(()),
+        Ok(_) => Err("must be an https url".to_owned()),
+        Err(err) => Err(format!("is an invalid url: {}", err)),
+    }
+}
+
+// the S256 PKCE challenge of `verifier`
+fn challenge(verifier: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+// `name` as a username, see `validation::username`
+fn username(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') => c,
+            _ => '_',
+        })
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(32)
+        .collect();
+    if name.len() < 2 {
+        return "user".to_owned();
+    }
+    name
+}
+
+fn deleted() -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        "your account here is deleted, ask an admin to restore it".to_owned(),
+    )
+}
+
+fn bad_gateway(message: String) -> (StatusCode, String) {
+    warn!("signing in through the provider failed: {}", message);
+    (StatusCode::BAD_GATEWAY, message)
+}
+
+// the provider, when one is configured
+fn configured(oidc: Option<Arc<Oidc>>) -> Result<Arc<Oidc>, (StatusCode, String)> {
+    oidc.ok_or((
+        StatusCode::NOT_FOUND,
+        "no auth.oidc provider is configured".to_owned(),
+    ))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/oidc/login", get(login))
+        .route("/auth/oidc/callback", get(callback))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct LoginQuery {
+    // a path of this server to end up on, / by default
+    redirect: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/oidc/login",
+    params(LoginQuery),
+    responses(
+        (status = 303, description = "Off to sign in at the provider"),
+        (status = 400, description = "The redirect isn't a path of this server"),
+        (status = 404, description = "No provider is configured, or sessions are disabled"),
+        (status = 502, description = "The provider can't be reached"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn login(
+    State(oidc): State<Option<Arc<Oidc>>>,
+    session: Option<Session>,
+    Query(query): Query<LoginQuery>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let oidc = configured(oidc)?;
+    let session = session::enabled(session)?;
+    let redirect = query.redirect.unwrap_or_else(|| "/".to_owned());
+    // elsewhere would make this an open redirect
+    if !redirect.starts_with('/') || redirect.starts_with("//") || redirect.starts_with("/\\") {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "redirect must be a path of this server".to_owned(),
+        ));
+    }
+    let (pending, url) = oidc.start(redirect).await?;
+    session
+        .insert(PENDING, &pending)
+        .await
+        .map_err(internal_error)?;
+    Ok(Redirect::to(&url))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    // what the provider says went wrong instead
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/oidc/callback",
+    params(CallbackQuery),
+    responses(
+        (status = 303, description = "Signed in, off to the redirect of the login"),
+        (status = 400, description = "Not the sign-in this session started, or the provider refused it"),
+        (status = 403, description = "No account for this person and `create_users` is off, or it's deleted"),
+        (status = 404, description = "No provider is configured, or sessions are disabled"),
+        (status = 502, description = "The provider can't be reached, or sent an invalid ID token"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn callback(
+    State(oidc): State<Option<Arc<Oidc>>>,
+    State(users): State<Arc<dyn UserRepository>>,
+    session: Option<Session>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let oidc = configured(oidc)?;
+    let session = session::enabled(session)?;
+    let pending = session
+        .remove::<Pending>(PENDING)
+        .await
+        .map_err(internal_error)?;
+    let Some(pending) = pending.filter(|pending| query.state.as_ref() == Some(&pending.state))
+    else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "not the sign-in this session started, try again".to_owned(),
+        ));
+    };
+    if let Some(error) = query.error {
+        let description = query.error_description.unwrap_or_default();
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("the provider refused: {} {}", error, description),
+        ));
+    }
+    let code = query.code.ok_or((
+        StatusCode::BAD_REQUEST,
+        "no code from the provider".to_owned(),
+    ))?;
+    let claims = oidc.exchange(&code, &pending).await?;
+    let account = oidc.account(claims).await?;
+    session::sign_in(&session, users.as_ref(), account.id, account.username).await?;
+    Ok(Redirect::to(&pending.redirect))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{clock::FixedClock, repository::identity::MemoryIdentities};
+
+    fn settings() -> Settings {
+        Settings {
+            issuer: "https://id.example.com".to_owned(),
+            client_id: "rsapp".to_owned(),
+            client_secret: "secret".to_owned(),
+            redirect_url: "http://localhost:9009/api/v1/auth/oidc/callback".to_owned(),
+            scopes: default_scopes(),
+            username_claim: default_username_claim(),
+            role_claim: Some("realm_access.roles".to_owned()),
+            admin_roles: vec!["rsapp-admin".to_owned()],
+            create_users: true,
+            link_by_email: true,
+            timeout: 10,
+        }
+    }
+
+    fn oidc(settings: Settings, identities: Arc<MemoryIdentities>) -> Oidc {
+        let oidc = Oidc::new(
+            settings,
+            identities,
+            Arc::new(FixedClock::at("2024-03-06T12:00:00Z")),
+        );
+        oidc.provider
+            .set(Provider {
+                issuer: "https://id.example.com".to_owned(),
+                authorization_endpoint: "https://id.example.com/auth".to_owned(),
+                token_endpoint: "https://id.example.com/token".to_owned(),
+            })
+            .unwrap();
+        oidc
+    }
+
+    fn token(claims: Value) -> String {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        format!(
+            "{}.{}.signature",
+            engine.encode(r#"{"alg":"RS256"}"#),
+            engine.encode(claims.to_string())
+        )
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://id.example.com",
+            "aud": ["rsapp", "other"],
+            "exp": 1709730000,
+            "nonce": "n",
+            "sub": "248289761001",
+            "preferred_username": "Jane Doe",
+            "email": "jd@example.com",
+            "email_verified": true,
+            "realm_access": {"roles": ["offline_access", "rsapp-admin"]},
+        })
+    }
+
+    #[test]
+    fn checks_id_tokens() {
+        let oidc = oidc(settings(), Arc::default());
+        let provider = oidc.provider.get().unwrap();
+        let claims = oidc.claims(provider, &token(claims()), "n").unwrap();
+        assert_eq!(
+            claims,
+            Claims {
+                subject: "248289761001".to_owned(),
+                username: "Jane_Doe".to_owned(),
+                email: Some("jd@example.com".to_owned()),
+                role: Some(Role::Admin),
+            }
+        );
+
+        let check = |change: Value, nonce| {
+            let mut claims = self::claims();
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(change.as_object().unwrap().clone());
+            oidc.claims(provider, &token(claims), nonce)
+        };
+        assert!(check(json!({}), "other").is_err());
+        assert!(check(json!({"iss": "https://evil.example.com"}), "n").is_err());
+        assert!(check(json!({"aud": "other"}), "n").is_err());
+        assert!(check(json!({"exp": 1709726400}), "n").is_err());
+        let unverified = check(json!({"email_verified": false}), "n").unwrap();
+        assert_eq!(unverified.email, None);
+        let user = check(json!({"realm_access": {"roles": []}}), "n").unwrap();
+        assert_eq!(user.role, Some(Role::User));
+    }
+
+    #[tokio::test]
+    async fn maps_claims_to_accounts() {
+        let identities = Arc::new(MemoryIdentities::default().with_account(Account {
+            id: 1,
+            username: "jd".to_owned(),
+            email: Some("JD@example.com".to_owned()),
+            deleted: false,
+        }));
+        let oidc = oidc(settings(), identities.clone());
+        let provider = oidc.provider.get().unwrap();
+        let claims = oidc.claims(provider, &token(claims()), "n").unwrap();
+
+        // linked by email, and made an admin
+        let account = oidc.account(claims.clone()).await.unwrap();
+        assert_eq!(account.id, 1);
+        assert_eq!(identities.role(1), Some(Role::Admin));
+        let again = oidc.account(claims.clone()).await.unwrap();
+        assert_eq!(again.id, 1);
+
+        let new = Claims {
+            subject: "other".to_owned(),
+            username: "jd".to_owned(),
+            email: None,
+            role: Some(Role::User),
+        };
+        let created = oidc.account(new.clone()).await.unwrap();
+        assert_ne!(created.id, 1);
+        assert!(created.username.starts_with("jd-"));
+
+        let closed = oidc(
+            Settings {
+                create_users: false,
+                ..settings()
+            },
+            Arc::default(),
+        );
+        let refused = closed.account(new).await;
+        assert_eq!(refused.unwrap_err().0, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert_eq!(settings().validate(), Ok(()));
+        let plain = Settings {
+            issuer: "http://id.example.com".to_owned(),
+            ..settings()
+        };
+        assert!(plain.validate().unwrap_err().contains("https"));
+        assert!(https("https://id.example.com/token").is_ok());
+        assert!(https("http://id.example.com/token").is_err());
+    }
+
+    #[test]
+    fn makes_usernames() {
+        assert_eq!(username("jane.doe"), "jane.doe");
+        assert_eq!(username("_Jané"), "Jan_");
+        assert_eq!(username("é"), "user");
+        assert_eq!(username(&"a".repeat(40)).len(), 32);
+    }
+}
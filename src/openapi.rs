@@ -0,0 +1,302 @@
+use axum::Router;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    admin,
+    api::{v1, v2},
+    app::AppState,
+    asset, audio, bookmark, canary, delivery, deprecation, event, experiment, federation, instance,
+    job, keyframes, library, listen, media, metering, oidc, password, playlist, probe, rate_plan,
+    read_only, remux, session, status, storage, tag, task, upload, validation,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "rsapp",
+        description = "Paths are documented under /api/v1. /api/v2 serves the same ones with \
+            every JSON answer, errors included, wrapped in an Envelope."
+    ),
+    paths(
+        crate::root,
+        crate::long_time_request,
+        crate::create_user,
+        crate::list_users,
+        crate::get_user,
+        crate::delete_user,
+        crate::restore_user,
+        crate::video_metadata,
+        crate::media_capabilities,
+        asset::create_asset,
+        asset::list_assets,
+        asset::get_asset,
+        asset::asset_metadata,
+        asset::asset_metadata_diff,
+        asset::file_content,
+        asset::delete_asset,
+        audio::waveform,
+        keyframes::keyframes,
+        remux::remux,
+        remux::clip,
+        library::search,
+        library::search_text,
+        library::duplicates,
+        library::scan,
+        tag::list_tags,
+        tag::create_tag,
+        tag::get_tag,
+        tag::rename_tag,
+        tag::delete_tag,
+        tag::file_tags,
+        tag::tag_file,
+        tag::untag_file,
+        upload::capabilities,
+        upload::create_upload,
+        upload::upload_offset,
+        upload::append,
+        upload::terminate,
+        bookmark::create_bookmark,
+        bookmark::asset_bookmarks,
+        bookmark::markers,
+        bookmark::list_bookmarks,
+        bookmark::update_bookmark,
+        bookmark::delete_bookmark,
+        canary::list_canaries,
+        delivery::video,
+        delivery::thumbnail,
+        canary::update_canary,
+        canary::rollback,
+        deprecation::usage,
+        task::list_tasks,
+        admin::runtime,
+        admin::config,
+        admin::log_level,
+        admin::set_log_level,
+        event::subscribe,
+        experiment::my_experiments,
+        federation::shared_collections,
+        federation::shared_collection,
+        federation::shared_asset,
+        federation::shared_media,
+        federation::list_peers,
+        federation::peer_collections,
+        federation::peer_collection,
+        federation::peer_asset,
+        federation::peer_media,
+        federation::replicate,
+        instance::ready,
+        instance::list_instances,
+        job::submit_job,
+        job::list_jobs,
+        job::get_job,
+        job::dead_jobs,
+        job::requeue_job,
+        job::watch_job,
+        job::download_output,
+        job::thumbnails_track,
+        metering::usage,
+        playlist::create_playlist,
+        playlist::list_playlists,
+        playlist::get_playlist,
+        playlist::update_playlist,
+        playlist::delete_playlist,
+        playlist::add_item,
+        playlist::move_item,
+        playlist::remove_item,
+        playlist::add_collaborator,
+        playlist::remove_collaborator,
+        playlist::export_m3u,
+        password::change_password,
+        password::request_reset,
+        password::confirm_reset,
+        session::login,
+        session::logout,
+        session::current_session,
+        oidc::login,
+        oidc::callback,
+        probe::stats,
+        read_only::read_only,
+        read_only::set_read_only,
+        rate_plan::list_plans,
+        rate_plan::create_plan,
+        rate_plan::update_plan,
+        rate_plan::delete_plan,
+        rate_plan::assign_plan,
+        rate_plan::unassign_plan,
+        status::status,
+        status::create_incident,
+        status::update_incident,
+        status::uptime_report,
+        storage::download,
+    ),
+    components(schemas(
+        crate::CreateUser,
+        crate::User,
+        crate::VideoMeta,
+        v1::Asset,
+        v1::Bookmark,
+        v1::Playlist,
+        v1::PlaylistItem,
+        v1::PlaylistDetail,
+        v1::Job,
+        v1::JobWithHistory,
+        v1::ExperimentAssignment,
+        v1::MediaMetadata,
+        v1::MediaStream,
+        v1::MetadataDiff,
+        v1::MetadataChange,
+        instance::Instance,
+        instance::InstancesReport,
+        instance::Readiness,
+        job::JobKind,
+        job::JobState,
+        job::Priority,
+        job::SubmitJob,
+        job::StateChange,
+        media::VideoCodec,
+        media::Overlay,
+        media::Hwaccel,
+        media::Capabilities,
+        media::Position,
+        media::ImageFormat,
+        asset::CreateAsset,
+        audio::Waveform,
+        audio::PeaksFormat,
+        keyframes::Keyframes,
+        keyframes::SceneChange,
+        library::LibraryEntry,
+        library::SearchHit,
+        library::Duplicates,
+        library::Sameness,
+        remux::Remux,
+        remux::Clip,
+        remux::StoredClip,
+        media::Cut,
+        media::Container,
+        tag::Tag,
+        tag::TagName,
+        bookmark::Marker,
+        bookmark::CreateBookmark,
+        bookmark::UpdateBookmark,
+        canary::Settings,
+        canary::VariantStats,
+        canary::CanaryReport,
+        deprecation::DeprecationUsage,
+        task::RunningTask,
+        admin::RuntimeReport,
+        admin::PoolStats,
+        listen::ConnectionStats,
+        admin::LogLevel,
+        job::QueueDepth,
+        task::Kind,
+        federation::Collection,
+        federation::CollectionDetail,
+        federation::CollectionItem,
+        federation::SharedAsset,
+        federation::PeerInfo,
+        metering::Totals,
+        metering::UsageReport,
+        playlist::CreatePlaylist,
+        playlist::UpdatePlaylist,
+        playlist::AddItem,
+        playlist::MoveItem,
+        password::ChangePassword,
+        password::PasswordReset,
+        password::ConfirmPasswordReset,
+        session::Login,
+        session::SessionUser,
+        probe::ProbeStats,
+        read_only::Settings,
+        validation::Invalid,
+        v2::Envelope,
+        v2::ApiError,
+        rate_plan::RatePlan,
+        rate_plan::AssignPlan,
+        status::Overall,
+        status::Uptime,
+        status::ComponentStatus,
+        status::StatusReport,
+        status::Incident,
+        status::CreateIncident,
+        status::UpdateIncident,
+        status::UptimeReport,
+        status::ComponentUptime,
+        status::Outage,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+// documents the session cookie and `X-User-Id` header `CurrentUser` reads, the latter only with
+// `auth.trusted_proxy`, and the admin token `Admin` checks
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "user_id",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                    "X-User-Id",
+                    "set by the proxy in front, only taken with auth.trusted_proxy",
+                ))),
+            );
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                    "rsapp_session",
+                    "set by POST /api/v1/auth/login, changes also need its csrf_token as \
+                     X-CSRF-Token",
+                ))),
+            );
+            components.add_security_scheme(
+                "admin",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+const SPEC_PATH: &str = "/api-docs/openapi.json";
+
+// serves the spec, plus Swagger UI at `/swagger-ui` when built with the `swagger-ui` feature
+pub fn routes() -> Router<AppState> {
+    #[cfg(feature = "swagger-ui")]
+    {
+        Router::from(
+            utoipa_swagger_ui::SwaggerUi::new("/swagger-ui").url(SPEC_PATH, ApiDoc::openapi()),
+        )
+    }
+
+    #[cfg(not(feature = "swagger-ui"))]
+    {
+        Router::new().route(
+            SPEC_PATH,
+            axum::routing::get(|| async { axum::Json(ApiDoc::openapi()) }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_is_complete() {
+        let spec = ApiDoc::openapi();
+        // every documented body refers to a registered schema
+        let json = spec.to_json().unwrap();
+        let schemas = spec.components.unwrap().schemas;
+        for reference in json.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas.contains_key(name), "missing schema {}", name);
+        }
+        assert!(spec
+            .paths
+            .paths
+            .contains_key("/api/v1/playlists/{id}/items/{item_id}"));
+    }
+}
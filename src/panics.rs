@@ -0,0 +1,110 @@
+// Panics of handlers: instead of the connection being dropped, the client gets a 500 with the
+// request id in the body, shaped like the errors of `/api/v2`, and the panic is logged with a
+// backtrace and the same request id by the hook `install_hook` sets.
+use std::{
+    backtrace::Backtrace,
+    cell::RefCell,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    task::Poll,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use tracing::error;
+
+use crate::{api::v2, clock::IdGenerator};
+
+thread_local! {
+    // the request whose handler this thread is polling, for the panic hook
+    static HANDLING: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+// logs panics with a backtrace, and the id of the request being handled if any, instead of
+// printing them to stderr
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        match HANDLING.with(|handling| handling.borrow().clone()) {
+            Some(request_id) => error!("request {} {}\n{}", request_id, info, backtrace),
+            None => error!("{}\n{}", info, backtrace),
+        }
+    }));
+}
+
+// middleware answering 500 for requests whose handler panicked
+pub async fn catch(
+    State(ids): State<Arc<dyn IdGenerator>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id: Arc<str> = v2::request_id(&mut request, &*ids).into();
+    let mut handling = Box::pin(next.run(request));
+    let handled = std::future::poll_fn(|cx| {
+        let outer = HANDLING.with(|handling| handling.replace(Some(request_id.clone())));
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| handling.as_mut().poll(cx)));
+        HANDLING.with(|handling| handling.replace(outer));
+        match polled {
+            Ok(Poll::Ready(response)) => Poll::Ready(Some(response)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(_) => Poll::Ready(None),
+        }
+    })
+    .await;
+    handled.unwrap_or_else(|| panicked(&request_id))
+}
+
+fn panicked(request_id: &str) -> Response {
+    v2::error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "the server failed to handle the request",
+        request_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SequentialIds;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn answers_500_with_the_request_id() {
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/boom", get(|| async { panic!("on purpose") }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(SequentialIds::default()) as Arc<dyn IdGenerator>,
+                catch,
+            ));
+        let request = Request::get("/boom")
+            .header(v2::REQUEST_ID, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()[v2::REQUEST_ID], "abc");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["request_id"], "abc");
+        assert_eq!(body["error"]["status"], 500);
+
+        let request = Request::get("/ok").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // made up when the client sent none
+        let request = Request::get("/boom").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[v2::REQUEST_ID], "id-1");
+    }
+}
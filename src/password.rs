@@ -0,0 +1,549 @@
+// Passwords of accounts, hashed with Argon2id:
+//
+// - `POST /users/{id}/password` changes one's own, given the current one, or with the admin token
+//   anyone's. Accounts without a password yet, such as those made by `POST /users`, get their first
+//   one through a reset link or from an admin, not from whoever claims to be them;
+// - `POST /auth/password-reset` mails a link to the account with that username or email, valid
+//   for `passwords.reset_ttl` minutes, answering 202 whether there's one or not so it doesn't tell
+//   which accounts exist; the link's page sends its token to `POST /auth/password-reset/confirm`
+//   with the new password. Setting a password voids the links mailed before.
+//
+// Each client address gets `passwords.attempts` of either per hour and account or login, past that
+// 429, and as many failed sign-ins, see `session`. Reset requests answer before the account is
+// looked up, so how long that takes doesn't tell either.
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, OnceLock,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Router,
+};
+use moka::sync::Cache;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app::AppState,
+    auth::{self, Admin, ClientAddress, CurrentUser},
+    clock::Clock,
+    db_error, internal_error,
+    mail::{Mail, Mailer},
+    repository::{password::Account, PasswordRepository},
+    task,
+    validation::Valid,
+};
+
+// the window `passwords.attempts` are counted in
+const ATTEMPTS_WINDOW: std::time::Duration = std::time::Duration::from_secs(3600);
+// the client address and account or login pairs counted at once, the oldest forgotten past it
+const ATTEMPTS_KEPT: u64 = 100_000;
+
+// Verified against for sign-ins of accounts that don't exist or have no password, so those take
+// as long as wrong passwords and how long it takes doesn't tell which accounts exist.
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        auth::hash_password(&auth::random_token()).expect("Argon2 hashes with its defaults")
+    })
+}
+
+// `[passwords]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // the page of reset links, which gets the token appended
+    pub reset_url: String,
+    // minutes reset links work for
+    pub reset_ttl: i64,
+    // changes and resets per client address, account or login and hour
+    pub attempts: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            reset_url: "http://localhost:9009/reset-password?token=".to_owned(),
+            reset_ttl: 60,
+            attempts: 5,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.reset_ttl < 1 {
+            return Err("passwords.reset_ttl must be at least 1 minute".to_owned());
+        }
+        if self.attempts == 0 {
+            return Err("passwords.attempts must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct ChangePassword {
+    // needed unless an admin sets it
+    current_password: Option<String>,
+    // at least `auth::MIN_PASSWORD_LENGTH` characters
+    #[validate(length(min = 8, max = 1024))]
+    new_password: String,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct PasswordReset {
+    // the username or email of the account
+    #[validate(length(min = 1, max = 320))]
+    login: String,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct ConfirmPasswordReset {
+    // of the link mailed
+    #[validate(length(min = 1, max = 128))]
+    token: String,
+    #[validate(length(min = 8, max = 1024))]
+    new_password: String,
+}
+
+pub struct Passwords {
+    settings: Settings,
+    repository: Arc<dyn PasswordRepository>,
+    mailer: Arc<dyn Mailer>,
+    clock: Arc<dyn Clock>,
+    // by client address and account or login, within ATTEMPTS_WINDOW of the first
+    attempts: Cache<String, Arc<AtomicU32>>,
+}
+
+impl Passwords {
+    pub fn new(
+        settings: Settings,
+        repository: Arc<dyn PasswordRepository>,
+        mailer: Arc<dyn Mailer>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Passwords {
+            settings,
+            repository,
+            mailer,
+            clock,
+            attempts: Cache::builder()
+                .max_capacity(ATTEMPTS_KEPT)
+                .time_to_live(ATTEMPTS_WINDOW)
+                .build(),
+        }
+    }
+
+    // counts an attempt of `key`, refused past `passwords.attempts`
+    fn attempt(&self, key: String) -> Result<(), (StatusCode, String)> {
+        let attempts = self.attempts.get_with(key, Arc::default);
+        if attempts.fetch_add(1, Ordering::Relaxed) >= self.settings.attempts {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many attempts, try again in an hour".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn change(
+        &self,
+        client: &ClientAddress,
+        id: i64,
+        current: Option<&str>,
+        new: &str,
+    ) -> Result<(), (StatusCode, String)> {
+        self.attempt(format!("user {} from {}", id, client.0))?;
+        let Some(hash) = self.repository.hash(id).await.map_err(db_error)? else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "the account has no password yet, set one through a reset link".to_owned(),
+            ));
+        };
+        let current = current.unwrap_or_default().to_owned();
+        let verified = tokio::task::spawn_blocking(move || auth::verify_password(&current, &hash))
+            .await
+            .map_err(internal_error)?;
+        if !verified {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "the current password is wrong".to_owned(),
+            ));
+        }
+        self.set(id, new).await
+    }
+
+    // the account of `login` if `password` is its own
+    pub async fn verify(
+        &self,
+        client: &ClientAddress,
+        login: &str,
+        password: &str,
+    ) -> Result<Account, (StatusCode, String)> {
+        // only failures count, signing in every day is fine
+        let key = format!("sign-in {} from {}", login.to_lowercase(), client.0);
+        let failed = self.attempts.get(&key);
+        if failed.is_some_and(|failed| failed.load(Ordering::Relaxed) >= self.settings.attempts) {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many failed attempts, try again in an hour".to_owned(),
+            ));
+        }
+        let account = self
+            .repository
+            .find_account(login)
+            .await
+            .map_err(db_error)?;
+        let hash = match &account {
+            Some(account) => self.repository.hash(account.id).await.map_err(db_error)?,
+            None => None,
+        };
+        let password = password.to_owned();
+        let verified = tokio::task::spawn_blocking(move || match hash {
+            Some(hash) => auth::verify_password(&password, &hash),
+            None => {
+                auth::verify_password(&password, dummy_hash());
+                false
+            }
+        })
+        .await
+        .map_err(internal_error)?;
+        match account {
+            Some(account) if verified => Ok(account),
+            _ => {
+                self.attempts
+                    .get_with(key, Arc::default)
+                    .fetch_add(1, Ordering::Relaxed);
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    "wrong login or password".to_owned(),
+                ))
+            }
+        }
+    }
+
+    // Counts the attempt and mails a reset link for the account of `login` in the background, if
+    // there's one with an email. Whether there is isn't looked up before answering, so how long
+    // that takes doesn't tell.
+    fn request_reset(
+        self: &Arc<Self>,
+        client: &ClientAddress,
+        login: &str,
+    ) -> Result<(), (StatusCode, String)> {
+        self.attempt(format!("login {} from {}", login.to_lowercase(), client.0))?;
+        let passwords = self.clone();
+        let login = login.to_owned();
+        task::spawn("password reset mail", task::Kind::Work, async move {
+            let mail = match passwords.reset_mail(&login).await {
+                Ok(Some(mail)) => mail,
+                Ok(None) => return,
+                Err((_, err)) => {
+                    warn!("making a password reset link failed: {}", err);
+                    return;
+                }
+            };
+            let to = mail.to.clone();
+            match passwords.mailer.send(mail).await {
+                Ok(()) => info!("password reset mailed to {}", to),
+                Err(err) => warn!("mailing the password reset to {} failed: {}", to, err),
+            }
+        });
+        Ok(())
+    }
+
+    // the mail with a reset link for the account of `login`, None without one or its email
+    async fn reset_mail(&self, login: &str) -> Result<Option<Mail>, (StatusCode, String)> {
+        let account = self
+            .repository
+            .find_account(login)
+            .await
+            .map_err(db_error)?;
+        let Some((account, email)) =
+            account.and_then(|account| account.email.clone().map(|email| (account, email)))
+        else {
+            return Ok(None);
+        };
+        let token = auth::random_token();
+        let expires_at = self.clock.now() + chrono::Duration::minutes(self.settings.reset_ttl);
+        self.repository
+            .create_reset(&token_hash(&token), account.id, expires_at)
+            .await
+            .map_err(db_error)?;
+        Ok(Some(Mail {
+            to: email,
+            subject: "Resetting your password".to_owned(),
+            text: format!(
+                "Someone, hopefully you, asked to reset the password of {}. To choose a new one, \
+                 open\n\n{}{}\n\nwithin {} minutes. Otherwise there's nothing to do.\n",
+                account.username, self.settings.reset_url, token, self.settings.reset_ttl
+            ),
+        }))
+    }
+
+    async fn reset(&self, token: &str, new: &str) -> Result<(), (StatusCode, String)> {
+        let id = self
+            .repository
+            .take_reset(&token_hash(token), self.clock.now())
+            .await
+            .map_err(db_error)?
+            .ok_or((
+                StatusCode::BAD_REQUEST,
+                "the reset link is unknown, used or expired".to_owned(),
+            ))?;
+        self.set(id, new).await
+    }
+
+    async fn set(&self, id: i64, password: &str) -> Result<(), (StatusCode, String)> {
+        let password = password.to_owned();
+        let hash = tokio::task::spawn_blocking(move || auth::hash_password(&password))
+            .await
+            .map_err(internal_error)?
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+        self.repository
+            .set_hash(id, &hash)
+            .await
+            .map_err(db_error)?;
+        info!("password of user {} set", id);
+        Ok(())
+    }
+}
+
+// how reset tokens are kept
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/users/:id/password", post(change_password))
+        .route("/auth/password-reset", post(request_reset))
+        .route("/auth/password-reset/confirm", post(confirm_reset))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/users/{id}/password",
+    params(("id" = i64, Path, description = "User id, the one asking")),
+    request_body = ChangePassword,
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "Another user's, no password yet, or the current one is wrong"),
+        (status = 404, description = "No such user"),
+        (status = 422, description = "Invalid password", body = Invalid),
+        (status = 429, description = "Too many attempts within the hour"),
+    ),
+    security(("session" = []), ("user_id" = []), ("admin" = [])),
+    tag = "users"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn change_password(
+    State(passwords): State<Arc<Passwords>>,
+    client: ClientAddress,
+    admin: Option<Admin>,
+    user: Result<CurrentUser, (StatusCode, String)>,
+    Path(id): Path<i64>,
+    Valid(payload): Valid<ChangePassword>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if admin.is_some() {
+        passwords.set(id, &payload.new_password).await?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    let CurrentUser(user) = user?;
+    if user != id {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "only your own password can be changed".to_owned(),
+        ));
+    }
+    passwords
+        .change(
+            &client,
+            id,
+            payload.current_password.as_deref(),
+            &payload.new_password,
+        )
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/password-reset",
+    request_body = PasswordReset,
+    responses(
+        (status = 202, description = "A reset link is mailed if the account exists and has an email"),
+        (status = 422, description = "Invalid login", body = Invalid),
+        (status = 429, description = "Too many attempts within the hour"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn request_reset(
+    State(passwords): State<Arc<Passwords>>,
+    client: ClientAddress,
+    Valid(payload): Valid<PasswordReset>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    passwords.request_reset(&client, &payload.login)?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/password-reset/confirm",
+    request_body = ConfirmPasswordReset,
+    responses(
+        (status = 204, description = "Password set"),
+        (status = 400, description = "The token is unknown, used or expired"),
+        (status = 422, description = "Invalid password", body = Invalid),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn confirm_reset(
+    State(passwords): State<Arc<Passwords>>,
+    Valid(payload): Valid<ConfirmPasswordReset>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    passwords
+        .reset(&payload.token, &payload.new_password)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::FixedClock,
+        mail::LogMailer,
+        repository::password::{Account, MemoryPasswords},
+    };
+
+    fn passwords(clock: Arc<FixedClock>) -> (Passwords, Arc<MemoryPasswords>) {
+        let hash = auth::hash_password("correct horse").unwrap();
+        let account = |id, username: &str, email: Option<&str>| Account {
+            id,
+            username: username.to_owned(),
+            email: email.map(str::to_owned),
+            deleted: false,
+        };
+        let deleted = Account {
+            deleted: true,
+            ..account(3, "gone", Some("gone@example.com"))
+        };
+        let repository = Arc::new(
+            MemoryPasswords::default()
+                .with_account(account(1, "jd", Some("jd@example.com")), Some(hash.clone()))
+                .with_account(account(2, "old", None), None)
+                .with_account(deleted, Some(hash)),
+        );
+        let passwords = Passwords::new(
+            Settings::default(),
+            repository.clone(),
+            Arc::new(LogMailer),
+            clock,
+        );
+        (passwords, repository)
+    }
+
+    #[tokio::test]
+    async fn changes_given_the_current_one() {
+        let (passwords, repository) = passwords(Arc::new(FixedClock::at("2024-03-04T12:00:00Z")));
+        let client = ClientAddress("192.0.2.1".to_owned());
+        let wrong = passwords
+            .change(&client, 1, Some("battery"), "new password")
+            .await;
+        assert_eq!(wrong.unwrap_err().0, StatusCode::FORBIDDEN);
+        let missing = passwords.change(&client, 1, None, "new password").await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::FORBIDDEN);
+        passwords
+            .change(&client, 1, Some("correct horse"), "new password")
+            .await
+            .unwrap();
+        let hash = repository.hash(1).await.unwrap().unwrap();
+        assert!(auth::verify_password("new password", &hash));
+
+        // accounts without one get their first one from a reset link or an admin
+        let first = passwords.change(&client, 2, None, "first password").await;
+        assert_eq!(first.unwrap_err().0, StatusCode::FORBIDDEN);
+        assert_eq!(repository.hash(2).await.unwrap(), None);
+        for _ in 0..2 {
+            let _ = passwords.change(&client, 1, None, "new password").await;
+        }
+        let limited = passwords.change(&client, 1, None, "new password").await;
+        assert_eq!(limited.unwrap_err().0, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn verifies_sign_ins() {
+        let (passwords, _) = passwords(Arc::new(FixedClock::at("2024-03-04T12:00:00Z")));
+        let client = ClientAddress("192.0.2.1".to_owned());
+        let account = passwords
+            .verify(&client, "jd@example.com", "correct horse")
+            .await;
+        assert_eq!(account.unwrap().id, 1);
+        // without a password there's nothing to sign in with
+        let old = passwords.verify(&client, "old", "").await;
+        assert_eq!(old.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        for _ in 0..5 {
+            let wrong = passwords.verify(&client, "jd", "battery").await;
+            assert_eq!(wrong.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        }
+        let limited = passwords.verify(&client, "jd", "correct horse").await;
+        assert_eq!(limited.unwrap_err().0, StatusCode::TOO_MANY_REQUESTS);
+        // someone failing from elsewhere doesn't lock the owner out
+        let owner = ClientAddress("198.51.100.7".to_owned());
+        let account = passwords.verify(&owner, "jd", "correct horse").await;
+        assert_eq!(account.unwrap().id, 1);
+        let nobody = passwords.verify(&owner, "nobody", "correct horse").await;
+        assert_eq!(nobody.unwrap_err().0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn deleted_accounts_dont_sign_in() {
+        let (passwords, repository) = passwords(Arc::new(FixedClock::at("2024-03-04T12:00:00Z")));
+        assert_eq!(repository.find_account("gone").await.unwrap(), None);
+        let client = ClientAddress("192.0.2.1".to_owned());
+        let gone = passwords.verify(&client, "gone", "correct horse").await;
+        assert_eq!(gone.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            passwords.reset_mail("gone@example.com").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn resets_with_the_mailed_link() {
+        let clock = Arc::new(FixedClock::at("2024-03-04T12:00:00Z"));
+        let (passwords, repository) = passwords(clock.clone());
+        assert_eq!(passwords.reset_mail("nobody").await.unwrap(), None);
+        assert_eq!(passwords.reset_mail("old").await.unwrap(), None);
+
+        let mail = passwords
+            .reset_mail("JD@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mail.to, "jd@example.com");
+        let start = mail.text.find("token=").unwrap() + "token=".len();
+        let token = &mail.text[start..start + 64];
+        passwords.reset(token, "reset password").await.unwrap();
+        let hash = repository.hash(1).await.unwrap().unwrap();
+        assert!(auth::verify_password("reset password", &hash));
+        let used = passwords.reset(token, "again").await;
+        assert_eq!(used.unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        let mail = passwords.reset_mail("jd").await.unwrap().unwrap();
+        let start = mail.text.find("token=").unwrap() + "token=".len();
+        clock.advance(chrono::Duration::minutes(61));
+        let expired = passwords.reset(&mail.text[start..start + 64], "late").await;
+        assert_eq!(expired.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,698 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    routing::{get, patch, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::Deserialize;
+use sqlx::{PgConnection, PgPool};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    api::{v1, ToVersion},
+    app::AppState,
+    auth::CurrentUser,
+    db::DbExecutor,
+    db_error, fractional_index, internal_error,
+    validation::Valid,
+};
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Playlist {
+    pub id: i64,
+    pub owner_id: i64,
+    pub name: String,
+    pub collaborative: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct PlaylistItem {
+    pub id: i64,
+    pub asset_id: i64,
+    // fractional index, items are ordered by it
+    pub position: String,
+    pub title: String,
+    pub path: String,
+}
+
+pub struct PlaylistDetail {
+    pub playlist: Playlist,
+    pub collaborators: Vec<i64>,
+    pub items: Vec<PlaylistItem>,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct CreatePlaylist {
+    #[validate(length(min = 1, max = 200))]
+    name: String,
+    #[serde(default)]
+    collaborative: bool,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct UpdatePlaylist {
+    #[validate(length(min = 1, max = 200))]
+    name: Option<String>,
+    collaborative: Option<bool>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddItem {
+    asset_id: i64,
+    // neighbours to place the item between; appended to the end when both are missing
+    after: Option<i64>,
+    before: Option<i64>,
+}
+
+// drop target of a drag-reorder: the item lands right after `after` and/or right before `before`
+#[derive(Deserialize, ToSchema)]
+pub struct MoveItem {
+    after: Option<i64>,
+    before: Option<i64>,
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    View,
+    Edit,
+    Own,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/playlists", get(list_playlists).post(create_playlist))
+        .route(
+            "/playlists/:id",
+            get(get_playlist)
+                .patch(update_playlist)
+                .delete(delete_playlist),
+        )
+        .route("/playlists/:id/items", post(add_item))
+        .route(
+            "/playlists/:id/items/:item_id",
+            patch(move_item).delete(remove_item),
+        )
+        .route(
+            "/playlists/:id/collaborators/:user_id",
+            put(add_collaborator).delete(remove_collaborator),
+        )
+        .route("/playlists/:id/export.m3u", get(export_m3u))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/playlists",
+    request_body = CreatePlaylist,
+    responses(
+        (status = 201, description = "Playlist created", body = v1::Playlist),
+        (status = 422, description = "Invalid name", body = Invalid),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn create_playlist(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Valid(payload): Valid<CreatePlaylist>,
+) -> Result<(StatusCode, Json<v1::Playlist>), (StatusCode, String)> {
+    let playlist = sqlx::query_as::<_, Playlist>(
+        "INSERT INTO playlists (owner_id, name, collaborative) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(user)
+    .bind(&payload.name)
+    .bind(payload.collaborative)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok((StatusCode::CREATED, Json(playlist.to_version())))
+}
+
+// playlists the user owns or collaborates on
+#[utoipa::path(
+    get,
+    path = "/api/v1/playlists",
+    responses((status = 200, description = "Owned and shared playlists, most recently changed first", body = [v1::Playlist])),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn list_playlists(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Json<Vec<v1::Playlist>>, (StatusCode, String)> {
+    let playlists = sqlx::query_as::<_, Playlist>(
+        "SELECT * FROM playlists WHERE owner_id = $1 OR id IN (
+            SELECT playlist_id FROM playlist_collaborators WHERE user_id = $1
+        ) ORDER BY updated_at DESC",
+    )
+    .bind(user)
+    .fetch_all(db.read())
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(playlists.to_version()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/playlists/{id}",
+    params(("id" = i64, Path, description = "Playlist id")),
+    responses(
+        (status = 200, description = "The playlist with its items in order", body = v1::PlaylistDetail),
+        (status = 404, description = "No such playlist"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn get_playlist(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<Json<v1::PlaylistDetail>, (StatusCode, String)> {
+    let mut conn = db.read().acquire().await.map_err(internal_error)?;
+    let playlist = authorize(&mut conn, id, user, Access::View, false).await?;
+    let collaborators = collaborators(&mut conn, id).await.map_err(db_error)?;
+    let items = items(&mut conn, id).await.map_err(db_error)?;
+
+    let detail = PlaylistDetail {
+        playlist,
+        collaborators,
+        items,
+    };
+    Ok(Json(detail.to_version()))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/playlists/{id}",
+    params(("id" = i64, Path, description = "Playlist id")),
+    request_body = UpdatePlaylist,
+    responses(
+        (status = 200, description = "Playlist updated", body = v1::Playlist),
+        (status = 403, description = "Only the owner may do this"),
+        (status = 404, description = "No such playlist"),
+        (status = 422, description = "Invalid name", body = Invalid),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn update_playlist(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Valid(payload): Valid<UpdatePlaylist>,
+) -> Result<Json<v1::Playlist>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Own, true).await?;
+    let playlist = sqlx::query_as::<_, Playlist>(
+        "UPDATE playlists SET name = COALESCE($2, name), collaborative = COALESCE($3, collaborative),
+            updated_at = now() WHERE id = $1 RETURNING *",
+    )
+    .bind(id)
+    .bind(payload.name)
+    .bind(payload.collaborative)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(playlist.to_version()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/playlists/{id}",
+    params(("id" = i64, Path, description = "Playlist id")),
+    responses(
+        (status = 204, description = "Playlist removed"),
+        (status = 403, description = "Only the owner may do this"),
+        (status = 404, description = "No such playlist"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn delete_playlist(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Own, true).await?;
+    sqlx::query("DELETE FROM playlists WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/playlists/{id}/items",
+    params(("id" = i64, Path, description = "Playlist id")),
+    request_body = AddItem,
+    responses(
+        (status = 201, description = "Item added", body = v1::PlaylistItem),
+        (status = 403, description = "Not allowed to edit the playlist"),
+        (status = 404, description = "No such playlist"),
+        (status = 409, description = "A concurrent edit took the position, retry"),
+        (status = 422, description = "Unknown asset or neighbour item"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn add_item(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    Json(payload): Json<AddItem>,
+) -> Result<(StatusCode, Json<v1::PlaylistItem>), (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Edit, true).await?;
+    let position = position_between(&mut tx, id, payload.after, payload.before, None).await?;
+    let item_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO playlist_items (playlist_id, asset_id, position) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(id)
+    .bind(payload.asset_id)
+    .bind(&position)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    touch(&mut tx, id).await?;
+    let item = item(&mut tx, id, item_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok((StatusCode::CREATED, Json(item.to_version())))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/playlists/{id}/items/{item_id}",
+    params(("id" = i64, Path, description = "Playlist id"), ("item_id" = i64, Path, description = "Playlist item id")),
+    request_body = MoveItem,
+    responses(
+        (status = 200, description = "Item moved", body = v1::PlaylistItem),
+        (status = 403, description = "Not allowed to edit the playlist"),
+        (status = 404, description = "No such playlist or item"),
+        (status = 409, description = "A concurrent edit took the position, retry"),
+        (status = 422, description = "Unknown neighbour item"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn move_item(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path((id, item_id)): Path<(i64, i64)>,
+    Json(payload): Json<MoveItem>,
+) -> Result<Json<v1::PlaylistItem>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Edit, true).await?;
+    // make sure the item belongs to this playlist before computing its new place
+    item(&mut tx, id, item_id).await?;
+    let position =
+        position_between(&mut tx, id, payload.after, payload.before, Some(item_id)).await?;
+    sqlx::query("UPDATE playlist_items SET position = $3 WHERE playlist_id = $1 AND id = $2")
+        .bind(id)
+        .bind(item_id)
+        .bind(&position)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    touch(&mut tx, id).await?;
+    let item = item(&mut tx, id, item_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(item.to_version()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/playlists/{id}/items/{item_id}",
+    params(("id" = i64, Path, description = "Playlist id"), ("item_id" = i64, Path, description = "Playlist item id")),
+    responses(
+        (status = 204, description = "Item removed"),
+        (status = 403, description = "Not allowed to edit the playlist"),
+        (status = 404, description = "No such playlist or item"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn remove_item(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path((id, item_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Edit, true).await?;
+    let result = sqlx::query("DELETE FROM playlist_items WHERE playlist_id = $1 AND id = $2")
+        .bind(id)
+        .bind(item_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err(db_error(sqlx::Error::RowNotFound));
+    }
+    touch(&mut tx, id).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/playlists/{id}/collaborators/{user_id}",
+    params(("id" = i64, Path, description = "Playlist id"), ("user_id" = i64, Path, description = "Collaborator user id")),
+    responses(
+        (status = 204, description = "Collaborator added"),
+        (status = 403, description = "Only the owner may do this"),
+        (status = 404, description = "No such playlist"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn add_collaborator(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path((id, collaborator)): Path<(i64, i64)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Own, true).await?;
+    sqlx::query(
+        "INSERT INTO playlist_collaborators (playlist_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(id)
+    .bind(collaborator)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/playlists/{id}/collaborators/{user_id}",
+    params(("id" = i64, Path, description = "Playlist id"), ("user_id" = i64, Path, description = "Collaborator user id")),
+    responses(
+        (status = 204, description = "Collaborator removed"),
+        (status = 403, description = "Only the owner may do this"),
+        (status = 404, description = "No such playlist"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn remove_collaborator(
+    State(pool): State<PgPool>,
+    CurrentUser(user): CurrentUser,
+    Path((id, collaborator)): Path<(i64, i64)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    authorize(&mut tx, id, user, Access::Own, true).await?;
+    sqlx::query("DELETE FROM playlist_collaborators WHERE playlist_id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(collaborator)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/playlists/{id}/export.m3u",
+    params(("id" = i64, Path, description = "Playlist id")),
+    responses(
+        (status = 200, description = "Extended M3U playlist", body = String, content_type = "audio/x-mpegurl"),
+        (status = 404, description = "No such playlist"),
+    ),
+    security(("user_id" = [])),
+    tag = "playlists"
+)]
+async fn export_m3u(
+    State(db): State<Arc<DbExecutor>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<([(header::HeaderName, String); 2], String), (StatusCode, String)> {
+    let mut conn = db.read().acquire().await.map_err(internal_error)?;
+    let playlist = authorize(&mut conn, id, user, Access::View, false).await?;
+    let items = items(&mut conn, id).await.map_err(db_error)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "audio/x-mpegurl".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"playlist-{}.m3u\"", id),
+            ),
+        ],
+        to_m3u(&playlist, &items),
+    ))
+}
+
+// loads the playlist and checks the user may access it the given way, optionally locking it so
+// concurrent edits of the same playlist are serialized
+async fn authorize(
+    conn: &mut PgConnection,
+    id: i64,
+    user: i64,
+    access: Access,
+    lock: bool,
+) -> Result<Playlist, (StatusCode, String)> {
+    let sql = if lock {
+        "SELECT * FROM playlists WHERE id = $1 FOR UPDATE"
+    } else {
+        "SELECT * FROM playlists WHERE id = $1"
+    };
+    let playlist = sqlx::query_as::<_, Playlist>(sql)
+        .bind(id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    if playlist.owner_id == user {
+        return Ok(playlist);
+    }
+
+    let collaborator = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM playlist_collaborators WHERE playlist_id = $1 AND user_id = $2)",
+    )
+    .bind(id)
+    .bind(user)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(db_error)?;
+    match access {
+        // don't reveal playlists the user can't see
+        _ if !collaborator => Err(db_error(sqlx::Error::RowNotFound)),
+        Access::View => Ok(playlist),
+        Access::Edit if playlist.collaborative => Ok(playlist),
+        _ => Err((
+            StatusCode::FORBIDDEN,
+            "not allowed to modify this playlist".to_owned(),
+        )),
+    }
+}
+
+// computes a position for an item dropped between `after` and `before`, ignoring the item being
+// moved (if any) when looking up neighbours
+async fn position_between(
+    conn: &mut PgConnection,
+    id: i64,
+    after: Option<i64>,
+    before: Option<i64>,
+    moving: Option<i64>,
+) -> Result<String, (StatusCode, String)> {
+    if moving.is_some() && (after == moving || before == moving) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "an item can't be placed relative to itself".to_owned(),
+        ));
+    }
+
+    let (a, b) = match (after, before) {
+        (Some(after), Some(before)) => (
+            Some(position_of(conn, id, after).await?),
+            Some(position_of(conn, id, before).await?),
+        ),
+        (Some(after), None) => {
+            let a = position_of(conn, id, after).await?;
+            let b = sqlx::query_scalar::<_, String>(
+                "SELECT position FROM playlist_items WHERE playlist_id = $1 AND position > $2
+                    AND id IS DISTINCT FROM $3 ORDER BY position LIMIT 1",
+            )
+            .bind(id)
+            .bind(&a)
+            .bind(moving)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(db_error)?;
+            (Some(a), b)
+        }
+        (None, Some(before)) => {
+            let b = position_of(conn, id, before).await?;
+            let a = sqlx::query_scalar::<_, String>(
+                "SELECT position FROM playlist_items WHERE playlist_id = $1 AND position < $2
+                    AND id IS DISTINCT FROM $3 ORDER BY position DESC LIMIT 1",
+            )
+            .bind(id)
+            .bind(&b)
+            .bind(moving)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(db_error)?;
+            (a, Some(b))
+        }
+        (None, None) => {
+            let a = sqlx::query_scalar::<_, String>(
+                "SELECT position FROM playlist_items WHERE playlist_id = $1
+                    AND id IS DISTINCT FROM $2 ORDER BY position DESC LIMIT 1",
+            )
+            .bind(id)
+            .bind(moving)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(db_error)?;
+            (a, None)
+        }
+    };
+
+    fractional_index::key_between(a.as_deref(), b.as_deref())
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))
+}
+
+async fn position_of(
+    conn: &mut PgConnection,
+    id: i64,
+    item_id: i64,
+) -> Result<String, (StatusCode, String)> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT position FROM playlist_items WHERE playlist_id = $1 AND id = $2",
+    )
+    .bind(id)
+    .bind(item_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("item {} is not in playlist {}", item_id, id),
+        )
+    })
+}
+
+async fn touch(conn: &mut PgConnection, id: i64) -> Result<(), (StatusCode, String)> {
+    sqlx::query("UPDATE playlists SET updated_at = now() WHERE id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+const ITEM_COLUMNS: &str = "i.id, i.asset_id, i.position, a.title, a.path";
+
+async fn item(
+    conn: &mut PgConnection,
+    id: i64,
+    item_id: i64,
+) -> Result<PlaylistItem, (StatusCode, String)> {
+    sqlx::query_as::<_, PlaylistItem>(&format!(
+        "SELECT {} FROM playlist_items i JOIN assets a ON a.id = i.asset_id
+            WHERE i.playlist_id = $1 AND i.id = $2",
+        ITEM_COLUMNS
+    ))
+    .bind(id)
+    .bind(item_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(db_error)
+}
+
+async fn items(conn: &mut PgConnection, id: i64) -> Result<Vec<PlaylistItem>, sqlx::Error> {
+    sqlx::query_as::<_, PlaylistItem>(&format!(
+        "SELECT {} FROM playlist_items i JOIN assets a ON a.id = i.asset_id
+            WHERE i.playlist_id = $1 ORDER BY i.position",
+        ITEM_COLUMNS
+    ))
+    .bind(id)
+    .fetch_all(&mut *conn)
+    .await
+}
+
+async fn collaborators(conn: &mut PgConnection, id: i64) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar::<_, i64>(
+        "SELECT user_id FROM playlist_collaborators WHERE playlist_id = $1 ORDER BY user_id",
+    )
+    .bind(id)
+    .fetch_all(&mut *conn)
+    .await
+}
+
+// extended M3U, readable by VLC, mpv and most other players
+fn to_m3u(playlist: &Playlist, items: &[PlaylistItem]) -> String {
+    // a line break would end the directive early
+    let clean = |s: &str| s.replace(['\r', '\n'], " ");
+
+    let mut m3u = format!("#EXTM3U\n#PLAYLIST:{}\n", clean(&playlist.name));
+    for item in items {
+        // duration is unknown, -1 lets the player figure it out
+        m3u.push_str(&format!(
+            "#EXTINF:-1,{}\n{}\n",
+            clean(&item.title),
+            clean(&item.path)
+        ));
+    }
+    m3u
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn m3u() {
+        let playlist = Playlist {
+            id: 1,
+            owner_id: 1,
+            name: "road\ntrip".to_owned(),
+            collaborative: false,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let items = vec![
+            PlaylistItem {
+                id: 1,
+                asset_id: 7,
+                position: "a0".to_owned(),
+                title: "intro".to_owned(),
+                path: "/media/intro.mp4".to_owned(),
+            },
+            PlaylistItem {
+                id: 2,
+                asset_id: 8,
+                position: "a0V".to_owned(),
+                title: "main".to_owned(),
+                path: "/media/main.mkv".to_owned(),
+            },
+        ];
+
+        assert_eq!(
+            to_m3u(&playlist, &items),
+            "#EXTM3U\n#PLAYLIST:road trip\n#EXTINF:-1,intro\n/media/intro.mp4\n#EXTINF:-1,main\n/media/main.mkv\n"
+        );
+    }
+}
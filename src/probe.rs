@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use axum::{extract::State, routing::get, Json, Router};
+use moka::sync::Cache;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{app::AppState, auth::Admin, media, redis::Redis};
+
+type Probe = Arc<OnceCell<Result<Arc<media::Metadata>, String>>>;
+
+// a file as it was when probed, a changed one has another key
+type Key = (PathBuf, SystemTime, u64);
+
+// `[probes]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    // seconds a probe's result is reused for, 0 to always probe
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    // files whose results are kept, the least recently used go first
+    #[serde(default = "default_cache_size")]
+    pub cache_size: u64,
+}
+
+fn default_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_cache_size() -> u64 {
+    10_000
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            cache_ttl: default_cache_ttl(),
+            cache_size: default_cache_size(),
+        }
+    }
+}
+
+// Probes media files, once for all requests asking about the same file at the same time: the
+// first one runs ffmpeg and the others wait for its result. Results are kept for a while by the
+// file's path, modification time and size, a later request for a file that changed probes
+// again. With Redis they're shared with the other instances.
+pub struct Probes {
+    in_flight: Mutex<HashMap<PathBuf, Probe>>,
+    cache: Cache<Key, Arc<media::Metadata>>,
+    ttl: Duration,
+    redis: Option<Arc<Redis>>,
+    probed: AtomicU64,
+    coalesced: AtomicU64,
+    cached: AtomicU64,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct ProbeStats {
+    // ffmpeg probes run
+    pub probed: u64,
+    // requests answered by a probe another request started
+    pub coalesced: u64,
+    // requests answered by an earlier probe of the unchanged file, here or by another instance
+    pub cached: u64,
+    pub in_flight: usize,
+}
+
+impl Default for Probes {
+    fn default() -> Self {
+        Probes::new(&Settings::default(), None)
+    }
+}
+
+impl Probes {
+    pub fn new(settings: &Settings, redis: Option<Arc<Redis>>) -> Self {
+        let size = if settings.cache_ttl == 0 {
+            0
+        } else {
+            settings.cache_size
+        };
+        let ttl = Duration::from_secs(settings.cache_ttl);
+        Probes {
+            in_flight: Mutex::default(),
+            cache: Cache::builder()
+                .max_capacity(size)
+                .time_to_live(ttl)
+                .build(),
+            ttl,
+            redis: redis.filter(|_| size > 0),
+            probed: AtomicU64::default(),
+            coalesced: AtomicU64::default(),
+            cached: AtomicU64::default(),
+        }
+    }
+
+    // `refresh` probes again even if the file didn't change since
+    pub async fn probe(
+        &self,
+        path: PathBuf,
+        refresh: bool,
+    ) -> Result<Arc<media::Metadata>, String> {
+        let key = key(&path).await;
+        if let (Some(key), false) = (&key, refresh) {
+            if let Some(metadata) = self.cache.get(key) {
+                self.cached.fetch_add(1, Ordering::Relaxed);
+                return Ok(metadata);
+            }
+            if let Some(metadata) = self.shared(key).await {
+                self.cached.fetch_add(1, Ordering::Relaxed);
+                self.cache.insert(key.clone(), metadata.clone());
+                return Ok(metadata);
+            }
+        }
+        let probe = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&path) {
+                Some(probe) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    probe.clone()
+                }
+                None => {
+                    let probe = Probe::default();
+                    in_flight.insert(path.clone(), probe.clone());
+                    probe
+                }
+            }
+        };
+        // whoever gets to initialize runs the probe, which is a waiter taking over when the
+        // request that started it went away; ffmpeg stops probing for that one then
+        probe
+            .get_or_init(|| async {
+                self.probed.fetch_add(1, Ordering::Relaxed);
+                let file = path.clone();
+                let cancel = media::Cancel::default();
+                let _abandoned = cancel.guard();
+                let result = tokio::task::spawn_blocking(move || media::probe(&file, &cancel))
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|result| result.map(Arc::new).map_err(|err| err.to_string()));
+                if let (Ok(metadata), Some(key)) = (&result, key) {
+                    self.share(&key, metadata).await;
+                    self.cache.insert(key, metadata.clone());
+                }
+                self.in_flight.lock().unwrap().remove(&path);
+                result
+            })
+            .await
+            .clone()
+    }
+
+    // what another instance probed of the file, if anything
+    async fn shared(&self, key: &Key) -> Option<Arc<media::Metadata>> {
+        let redis = self.redis.as_ref()?;
+        match redis.get(&redis_key(key)).await {
+            Ok(metadata) => metadata.map(Arc::new),
+            Err(err) => {
+                warn!("reading a probe from redis failed: {}", err);
+                None
+            }
+        }
+    }
+
+    async fn share(&self, key: &Key, metadata: &media::Metadata) {
+        if let Some(redis) = &self.redis {
+            if let Err(err) = redis.set(&redis_key(key), metadata, self.ttl).await {
+                warn!("storing a probe in redis failed: {}", err);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ProbeStats {
+        ProbeStats {
+            probed: self.probed.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            in_flight: self.in_flight.lock().unwrap().len(),
+        }
+    }
+}
+
+fn redis_key((path, modified, size): &Key) -> String {
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("probe:{}:{}:{}", size, modified.as_nanos(), path.display())
+}
+
+// None for files that can't be looked at, which aren't cached
+async fn key(path: &std::path::Path) -> Option<Key> {
+    let file = tokio::fs::metadata(path).await.ok()?;
+    Some((path.to_owned(), file.modified().ok()?, file.len()))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/probes", get(stats))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/probes",
+    responses((status = 200, description = "How many media probes ran and how many requests shared one", body = ProbeStats)),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn stats(_: Admin, State(probes): State<Arc<Probes>>) -> Json<ProbeStats> {
+    Json(probes.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reuses_probes_of_unchanged_files() {
+        let path = std::env::temp_dir().join(format!("rsapp-probe-{}.mp4", std::process::id()));
+        std::fs::write(&path, b"not really a video").unwrap();
+        let probes = Probes::default();
+        let metadata = Arc::new(media::Metadata {
+            schema: media::schema::VERSION,
+            format: "mov,mp4,m4a,3gp,3g2,mj2".to_owned(),
+            duration: Some(1.0),
+            bit_rate: None,
+            tags: Default::default(),
+            streams: Vec::new(),
+        });
+        let probed = key(&path).await.unwrap();
+        assert!(redis_key(&probed).starts_with("probe:18:"));
+        assert!(redis_key(&probed).ends_with(&format!(":{}", path.display())));
+        probes.cache.insert(probed.clone(), metadata.clone());
+        assert_eq!(probes.probe(path.clone(), false).await, Ok(metadata));
+        assert_eq!(probes.stats().cached, 1);
+
+        std::fs::write(&path, b"not really a video either").unwrap();
+        assert_ne!(key(&path).await, Some(probed));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(key(&path).await, None);
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{FromRequestParts, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    app::AppState,
+    auth::{Admin, CurrentUser},
+    db_error,
+    metering::{self, Metering},
+    redis::Redis,
+};
+
+// plan for clients without one of their own
+const DEFAULT_PLAN: &str = "default";
+
+// limits a client may use, missing ones are unlimited
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug, Clone, PartialEq, ToSchema)]
+pub struct RatePlan {
+    #[serde(default)]
+    pub id: i64,
+    pub name: String,
+    pub requests_per_minute: Option<i32>,
+    // minutes of input media, counted when the transcode is submitted
+    pub transcode_minutes_per_day: Option<i32>,
+    // response body bytes
+    pub bandwidth_bytes_per_day: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AssignPlan {
+    plan_id: i64,
+}
+
+// the plans in Postgres, cached in memory and reloaded whenever they are edited
+#[derive(Default)]
+pub struct RatePlans {
+    cache: RwLock<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    plans: HashMap<i64, Arc<RatePlan>>,
+    // user id -> plan id
+    assignments: HashMap<i64, i64>,
+}
+
+impl Cache {
+    fn for_user(&self, user: i64) -> Option<Arc<RatePlan>> {
+        match self.assignments.get(&user) {
+            Some(plan) => self.plans.get(plan).cloned(),
+            None => self
+                .plans
+                .values()
+                .find(|plan| plan.name == DEFAULT_PLAN)
+                .cloned(),
+        }
+    }
+}
+
+impl RatePlans {
+    pub async fn load(pool: &PgPool) -> Result<Self, sqlx::Error> {
+        let plans = RatePlans::default();
+        plans.reload(pool).await?;
+        Ok(plans)
+    }
+
+    pub async fn reload(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let plans = sqlx::query_as::<_, RatePlan>("SELECT * FROM rate_plans")
+            .fetch_all(pool)
+            .await?;
+        let assignments =
+            sqlx::query_as::<_, (i64, i64)>("SELECT user_id, plan_id FROM rate_plan_assignments")
+                .fetch_all(pool)
+                .await?;
+        *self.cache.write().unwrap() = Cache {
+            plans: plans
+                .into_iter()
+                .map(|plan| (plan.id, Arc::new(plan)))
+                .collect(),
+            assignments: assignments.into_iter().collect(),
+        };
+        Ok(())
+    }
+
+    pub fn for_user(&self, user: i64) -> Option<Arc<RatePlan>> {
+        self.cache.read().unwrap().for_user(user)
+    }
+
+    // `plan` assigned to `user`, without Postgres, for tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_plan(self, user: i64, plan: RatePlan) -> Self {
+        {
+            let mut cache = self.cache.write().unwrap();
+            cache.assignments.insert(user, plan.id);
+            cache.plans.insert(plan.id, Arc::new(plan));
+        }
+        self
+    }
+}
+
+// the limit a request ran into, as a `429 Too Many Requests` response
+#[derive(Debug, PartialEq)]
+pub enum Exceeded {
+    Requests { limit: i32, retry_after: i64 },
+    Bandwidth { limit: i64 },
+    TranscodeMinutes { limit: i32 },
+}
+
+impl std::fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exceeded::Requests { limit, .. } => {
+                write!(f, "rate plan allows {} requests per minute", limit)
+            }
+            Exceeded::Bandwidth { limit } => write!(f, "rate plan allows {} bytes per day", limit),
+            Exceeded::TranscodeMinutes { limit } => {
+                write!(f, "rate plan allows {} transcode minutes per day", limit)
+            }
+        }
+    }
+}
+
+impl IntoResponse for Exceeded {
+    fn into_response(self) -> Response {
+        let mut response = (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response();
+        if let Exceeded::Requests { retry_after, .. } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+        response
+    }
+}
+
+impl RatePlan {
+    // checks the caller's usage so far, before the request is served
+    pub fn check_request(
+        &self,
+        requests_last_minute: u64,
+        bytes_last_day: u64,
+    ) -> Result<(), Exceeded> {
+        if let Some(limit) = self.requests_per_minute {
+            if requests_last_minute >= limit as u64 {
+                // usage is counted per calendar minute
+                let retry_after = 60 - Utc::now().timestamp() % 60;
+                return Err(Exceeded::Requests { limit, retry_after });
+            }
+        }
+        if let Some(limit) = self.bandwidth_bytes_per_day {
+            if bytes_last_day >= limit as u64 {
+                return Err(Exceeded::Bandwidth { limit });
+            }
+        }
+        Ok(())
+    }
+
+    // checks whether transcoding `seconds` more media fits into the day's allowance
+    pub fn check_transcode(&self, used_seconds: f64, seconds: f64) -> Result<(), Exceeded> {
+        match self.transcode_minutes_per_day {
+            Some(limit) if used_seconds + seconds > f64::from(limit) * 60.0 => {
+                Err(Exceeded::TranscodeMinutes { limit })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub type Limits = (Arc<RatePlans>, Arc<Metering>, Option<Arc<Redis>>);
+
+// Rejects requests of clients over their plan's request or bandwidth limits. With Redis, requests
+// count across all instances; bandwidth is always counted per instance.
+pub async fn enforce(
+    State((plans, metering, redis)): State<Limits>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    if let Ok(CurrentUser(user)) = CurrentUser::from_request_parts(&mut parts, &()).await {
+        if let Some(plan) = plans.for_user(user) {
+            let (mut requests, bytes) = metering.totals(user);
+            if let Some(redis) = &redis {
+                match redis.count_request(user, metering::now_minute()).await {
+                    // counted before the request is served, unlike locally
+                    Ok(count) => requests.last_minute = count.saturating_sub(1),
+                    Err(err) => warn!("counting requests in redis failed: {}", err),
+                }
+            }
+            if let Err(exceeded) = plan.check_request(requests.last_minute, bytes.last_day) {
+                return exceeded.into_response();
+            }
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/rate-plans", get(list_plans).post(create_plan))
+        .route(
+            "/admin/rate-plans/:id",
+            put(update_plan).delete(delete_plan),
+        )
+        .route(
+            "/admin/users/:user_id/rate-plan",
+            put(assign_plan).delete(unassign_plan),
+        )
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/rate-plans",
+    responses((status = 200, description = "All rate plans", body = [RatePlan])),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn list_plans(
+    _: Admin,
+    State(pool): State<PgPool>,
+) -> Result<Json<Vec<RatePlan>>, (StatusCode, String)> {
+    let plans = sqlx::query_as::<_, RatePlan>("SELECT * FROM rate_plans ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(plans))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/rate-plans",
+    request_body = RatePlan,
+    responses(
+        (status = 201, description = "Plan created; one named `default` applies to everybody without a plan", body = RatePlan),
+        (status = 409, description = "Name is taken"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn create_plan(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(plans): State<Arc<RatePlans>>,
+    Json(payload): Json<RatePlan>,
+) -> Result<(StatusCode, Json<RatePlan>), (StatusCode, String)> {
+    let plan = sqlx::query_as::<_, RatePlan>(
+        "INSERT INTO rate_plans (name, requests_per_minute, transcode_minutes_per_day, bandwidth_bytes_per_day)
+            VALUES ($1, $2, $3, $4) RETURNING *",
+    )
+    .bind(&payload.name)
+    .bind(payload.requests_per_minute)
+    .bind(payload.transcode_minutes_per_day)
+    .bind(payload.bandwidth_bytes_per_day)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+    plans.reload(&pool).await.map_err(db_error)?;
+
+    Ok((StatusCode::CREATED, Json(plan)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/rate-plans/{id}",
+    params(("id" = i64, Path, description = "Rate plan id")),
+    request_body = RatePlan,
+    responses(
+        (status = 200, description = "Plan replaced, effective immediately", body = RatePlan),
+        (status = 404, description = "No such plan"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn update_plan(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(plans): State<Arc<RatePlans>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<RatePlan>,
+) -> Result<Json<RatePlan>, (StatusCode, String)> {
+    let plan = sqlx::query_as::<_, RatePlan>(
+        "UPDATE rate_plans SET name = $2, requests_per_minute = $3, transcode_minutes_per_day = $4,
+            bandwidth_bytes_per_day = $5 WHERE id = $1 RETURNING *",
+    )
+    .bind(id)
+    .bind(&payload.name)
+    .bind(payload.requests_per_minute)
+    .bind(payload.transcode_minutes_per_day)
+    .bind(payload.bandwidth_bytes_per_day)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+    plans.reload(&pool).await.map_err(db_error)?;
+
+    Ok(Json(plan))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/rate-plans/{id}",
+    params(("id" = i64, Path, description = "Rate plan id")),
+    responses(
+        (status = 204, description = "Plan deleted, its clients fall back to the default plan"),
+        (status = 404, description = "No such plan"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn delete_plan(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(plans): State<Arc<RatePlans>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("DELETE FROM rate_plans WHERE id = $1")
+        .bind(id)
+        .execute(&pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "not found".to_owned()));
+    }
+    plans.reload(&pool).await.map_err(db_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{user_id}/rate-plan",
+    params(("user_id" = i64, Path, description = "User id")),
+    request_body = AssignPlan,
+    responses(
+        (status = 204, description = "Plan assigned"),
+        (status = 422, description = "Unknown plan"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn assign_plan(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(plans): State<Arc<RatePlans>>,
+    Path(user_id): Path<i64>,
+    Json(payload): Json<AssignPlan>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sqlx::query(
+        "INSERT INTO rate_plan_assignments (user_id, plan_id) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET plan_id = EXCLUDED.plan_id",
+    )
+    .bind(user_id)
+    .bind(payload.plan_id)
+    .execute(&pool)
+    .await
+    .map_err(db_error)?;
+    plans.reload(&pool).await.map_err(db_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{user_id}/rate-plan",
+    params(("user_id" = i64, Path, description = "User id")),
+    responses((status = 204, description = "The user is back on the default plan")),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn unassign_plan(
+    _: Admin,
+    State(pool): State<PgPool>,
+    State(plans): State<Arc<RatePlans>>,
+    Path(user_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sqlx::query("DELETE FROM rate_plan_assignments WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(db_error)?;
+    plans.reload(&pool).await.map_err(db_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(id: i64, name: &str) -> RatePlan {
+        RatePlan {
+            id,
+            name: name.to_owned(),
+            requests_per_minute: Some(2),
+            transcode_minutes_per_day: Some(10),
+            bandwidth_bytes_per_day: Some(1000),
+        }
+    }
+
+    #[test]
+    fn assigned_plan_or_default() {
+        let mut cache = Cache::default();
+        assert_eq!(cache.for_user(1), None);
+
+        cache.plans.insert(1, Arc::new(plan(1, DEFAULT_PLAN)));
+        cache.plans.insert(2, Arc::new(plan(2, "pro")));
+        cache.assignments.insert(7, 2);
+        assert_eq!(cache.for_user(7).unwrap().name, "pro");
+        assert_eq!(cache.for_user(8).unwrap().name, DEFAULT_PLAN);
+    }
+
+    #[test]
+    fn limits() {
+        let plan = plan(1, "basic");
+        assert!(plan.check_request(1, 999).is_ok());
+        assert!(matches!(
+            plan.check_request(2, 0),
+            Err(Exceeded::Requests { limit: 2, .. })
+        ));
+        assert_eq!(
+            plan.check_request(0, 1000),
+            Err(Exceeded::Bandwidth { limit: 1000 })
+        );
+
+        assert!(plan.check_transcode(300.0, 300.0).is_ok());
+        assert_eq!(
+            plan.check_transcode(300.0, 301.0),
+            Err(Exceeded::TranscodeMinutes { limit: 10 })
+        );
+        let unlimited = RatePlan {
+            transcode_minutes_per_day: None,
+            ..plan
+        };
+        assert!(unlimited.check_transcode(1e9, 1e9).is_ok());
+    }
+}
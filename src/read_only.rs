@@ -0,0 +1,160 @@
+// Read-only mode, for restoring the database or maintaining storage: requests that could change
+// something are answered with `503 Service Unavailable` while reads go on. It's switched on by
+// `[read_only]` in the config or through the admin API, per instance, so a deployment switches
+// all of its instances. Jobs already queued keep running, workers aren't stopped by it.
+use std::sync::{Arc, RwLock};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{app::AppState, auth::Admin, graphql};
+
+// the toggle, which has to work in read-only mode to get out of it
+const TOGGLE: &str = "/admin/read-only";
+// how long clients are asked to wait before trying again, in seconds
+const RETRY_AFTER: &str = "300";
+
+// `[read_only]` in the config, or set through the admin API
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default, PartialEq)]
+#[schema(as = ReadOnlySettings)]
+pub struct Settings {
+    #[serde(default)]
+    pub enabled: bool,
+    // told to clients whose requests are refused, like "restoring the database until 14:00"
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+pub struct ReadOnly {
+    settings: RwLock<Settings>,
+}
+
+impl ReadOnly {
+    pub fn new(settings: Settings) -> Self {
+        if settings.enabled {
+            warn!("starting read-only");
+        }
+        ReadOnly {
+            settings: RwLock::new(settings),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    fn set(&self, settings: Settings) {
+        let mut current = self.settings.write().unwrap();
+        if settings.enabled != current.enabled {
+            warn!(
+                "read-only mode {}",
+                if settings.enabled { "on" } else { "off" }
+            );
+        }
+        *current = settings;
+    }
+
+    // why a `method` request to `path` is refused, if it is
+    fn refusal(&self, method: &Method, path: &str) -> Option<String> {
+        let reads = [Method::GET, Method::HEAD, Method::OPTIONS];
+        // GraphQL queries are POSTs too, its mutations check for themselves
+        if reads.contains(method) || path == TOGGLE || path == graphql::PATH {
+            return None;
+        }
+        self.refusal_of_changes()
+    }
+
+    // why changes are refused, if they are
+    pub fn refusal_of_changes(&self) -> Option<String> {
+        let settings = self.settings.read().unwrap();
+        if !settings.enabled {
+            return None;
+        }
+        Some(match &settings.reason {
+            Some(reason) => format!("read-only mode, changes are refused: {}", reason),
+            None => "read-only mode, changes are refused for now".to_owned(),
+        })
+    }
+}
+
+pub async fn guard(
+    State(read_only): State<Arc<ReadOnly>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match read_only.refusal(request.method(), request.uri().path()) {
+        Some(message) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER)],
+            message,
+        )
+            .into_response(),
+        None => next.run(request).await,
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route(TOGGLE, get(read_only).put(set_read_only))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/read-only",
+    responses((status = 200, description = "Whether this instance refuses changes", body = Settings)),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn read_only(_: Admin, State(read_only): State<Arc<ReadOnly>>) -> Json<Settings> {
+    Json(read_only.settings())
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/read-only",
+    request_body = Settings,
+    responses((status = 200, description = "Switched, effective immediately on this instance", body = Settings)),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn set_read_only(
+    _: Admin,
+    State(read_only): State<Arc<ReadOnly>>,
+    Json(settings): Json<Settings>,
+) -> Json<Settings> {
+    read_only.set(settings);
+    Json(read_only.settings())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_changes() {
+        let read_only = ReadOnly::new(Settings::default());
+        assert_eq!(read_only.refusal(&Method::POST, "/api/v1/users"), None);
+
+        read_only.set(Settings {
+            enabled: true,
+            reason: Some("restoring the database".to_owned()),
+        });
+        assert_eq!(read_only.refusal(&Method::GET, "/api/v1/users/1"), None);
+        assert_eq!(
+            read_only.refusal(&Method::POST, "/api/v1/users").as_deref(),
+            Some("read-only mode, changes are refused: restoring the database")
+        );
+        assert!(read_only
+            .refusal(&Method::DELETE, "/api/v1/assets/1")
+            .is_some());
+        assert_eq!(read_only.refusal(&Method::PUT, TOGGLE), None);
+    }
+}
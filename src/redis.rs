@@ -0,0 +1,114 @@
+// Redis shared by the instances, when built with the `redis` feature and `[redis]` is configured:
+// request counts for rate plans add up across instances, and probes of a file are reused by all
+// of them. Without it each instance counts and caches on its own. Jobs don't need it, their state
+// is shared through Postgres.
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::Deserialize;
+
+// keys are prefixed with this, to share a Redis with other apps
+#[cfg(feature = "redis")]
+const PREFIX: &str = "rsapp:";
+
+// `[redis]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct Settings {
+    // like redis://localhost:6379/0
+    pub url: String,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+}
+
+fn default_max_connections() -> usize {
+    16
+}
+
+#[cfg(feature = "redis")]
+pub struct Redis {
+    pool: deadpool_redis::Pool,
+}
+
+// there is none without the feature
+#[cfg(not(feature = "redis"))]
+pub enum Redis {}
+
+#[cfg(feature = "redis")]
+impl Redis {
+    pub fn open(settings: &Settings) -> Result<Self, String> {
+        let mut config = deadpool_redis::Config::from_url(&settings.url);
+        config.pool = Some(deadpool_redis::PoolConfig::new(settings.max_connections));
+        let pool = config
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .map_err(|err| err.to_string())?;
+        Ok(Redis { pool })
+    }
+
+    // counts a request of `user` in `minute`, returns how many there were in it so far
+    pub async fn count_request(&self, user: i64, minute: i64) -> Result<u64, String> {
+        let key = format!("{}requests:{}:{}", PREFIX, user, minute);
+        let mut connection = self.pool.get().await.map_err(|err| err.to_string())?;
+        let (count,): (u64,) = deadpool_redis::redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            // a minute later nobody asks for it anymore
+            .expire(&key, 120)
+            .ignore()
+            .query_async(&mut connection)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(count)
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, String> {
+        let mut connection = self.pool.get().await.map_err(|err| err.to_string())?;
+        let value: Option<String> = deadpool_redis::redis::cmd("GET")
+            .arg(format!("{}{}", PREFIX, key))
+            .query_async(&mut connection)
+            .await
+            .map_err(|err| err.to_string())?;
+        match value {
+            Some(value) => serde_json::from_str(&value).map_err(|err| err.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    // stores `value` as JSON for `ttl`
+    pub async fn set<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), String> {
+        let value = serde_json::to_string(value).map_err(|err| err.to_string())?;
+        let mut connection = self.pool.get().await.map_err(|err| err.to_string())?;
+        deadpool_redis::redis::cmd("SET")
+            .arg(format!("{}{}", PREFIX, key))
+            .arg(value)
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+impl Redis {
+    pub fn open(_: &Settings) -> Result<Self, String> {
+        Err("built without the redis feature".to_owned())
+    }
+
+    pub async fn count_request(&self, _: i64, _: i64) -> Result<u64, String> {
+        match *self {}
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, _: &str) -> Result<Option<T>, String> {
+        match *self {}
+    }
+
+    pub async fn set<T: Serialize>(&self, _: &str, _: &T, _: Duration) -> Result<(), String> {
+        match *self {}
+    }
+}
@@ -0,0 +1,323 @@
+// Remuxing and clipping: the streams of a video copied into another container, like MKV to MP4
+// for browsers, whole or from one time to another. Nothing is decoded unless a clip starts
+// between keyframes, so it takes about as long as reading the file, and is answered right away
+// instead of through a job. Results are written to `jobs.output_dir` and gone once sent, or
+// handed to storage when a clip is to be kept.
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_derive::{Deserialize, Serialize};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    app::AppState,
+    clock::IdGenerator,
+    db_error, internal_error,
+    job::{self, Jobs},
+    media::{self, Cancel, Container, Cut, VideoCodec},
+    repository::MediaRepository,
+    storage::Storage,
+    validation,
+};
+
+// how a clip was cut, `copy` or `reencode`
+pub const CUT: HeaderName = HeaderName::from_static("x-clip-cut");
+
+#[derive(Deserialize, ToSchema)]
+pub struct Remux {
+    // path of a registered asset
+    file: String,
+    container: Container,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Clip {
+    // path of a registered asset
+    file: String,
+    // seconds
+    start: f64,
+    end: f64,
+    #[serde(default = "default_container")]
+    container: Container,
+    // of the video when the clip doesn't start on a keyframe
+    #[serde(default)]
+    codec: VideoCodec,
+    // keep the clip in storage and answer with where, instead of with the clip
+    #[serde(default)]
+    store: bool,
+}
+
+fn default_container() -> Container {
+    Container::Mp4
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct StoredClip {
+    // the storage key it's kept under
+    key: String,
+    // working for an hour
+    url: String,
+    cut: Cut,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/video/remux", post(remux))
+        .route("/video/clip", post(clip))
+}
+
+// a name for a result no other remux of this process writes to
+fn scratch(dir: &Path, container: Container) -> PathBuf {
+    static REMUXES: AtomicU64 = AtomicU64::new(0);
+    let n = REMUXES.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(
+        "remux-{}-{}.{}",
+        std::process::id(),
+        n,
+        container.extension()
+    ))
+}
+
+// the registered file at `path`, and where in `jobs.output_dir` to write what's made of it
+async fn prepare(
+    media: &dyn MediaRepository,
+    jobs: &Jobs,
+    path: &str,
+    container: Container,
+) -> Result<(PathBuf, PathBuf), (StatusCode, String)> {
+    validation::media_path(path)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    let file = media.find_by_path(path).await.map_err(db_error)?;
+    tokio::fs::create_dir_all(jobs.output_dir())
+        .await
+        .map_err(internal_error)?;
+    Ok((
+        PathBuf::from(file.path),
+        scratch(jobs.output_dir(), container),
+    ))
+}
+
+// runs `write` on a blocking thread, cancelled when the client goes away; what it wrote is
+// removed when it fails
+async fn make<T: Send + 'static, E: Display + Send + 'static>(
+    output: &Path,
+    write: impl FnOnce(&Cancel) -> Result<T, E> + Send + 'static,
+) -> Result<T, (StatusCode, String)> {
+    let cancel = Cancel::default();
+    let _abandoned = cancel.guard();
+    let written = output.to_owned();
+    let made = tokio::task::spawn_blocking(move || {
+        // here, since nobody awaits the rest once cancelled
+        let made = write(&cancel);
+        if made.is_err() {
+            let _ = std::fs::remove_file(written);
+        }
+        made
+    })
+    .await
+    .map_err(internal_error)?;
+    made.map_err(|err| {
+        let message = format!("can't remux the file: {}", err);
+        (StatusCode::UNPROCESSABLE_ENTITY, message)
+    })
+}
+
+// answers with `output` as a download named `name`, and removes it
+async fn attachment(output: &Path, name: &str) -> Response {
+    // the content type comes from the extension; the file is open once answered
+    let response = match ServeFile::new(output)
+        .oneshot(Request::new(Body::empty()))
+        .await
+    {
+        Ok(response) => response.into_response(),
+        Err(never) => match never {},
+    };
+    // where open files can't be removed, `clean_job_outputs` does it later
+    if let Err(err) = tokio::fs::remove_file(output).await {
+        warn!("can't remove remux {}: {}", output.display(), err);
+    }
+    let (mut parts, body) = response.into_parts();
+    let disposition = format!("attachment; filename=\"{}\"", name.replace('"', ""));
+    if let Ok(disposition) = HeaderValue::try_from(disposition) {
+        parts
+            .headers
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Response::from_parts(parts, body)
+}
+
+fn stem(input: &Path) -> String {
+    input
+        .file_stem()
+        .map_or("video".into(), |stem| stem.to_string_lossy().into_owned())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/video/remux",
+    request_body = Remux,
+    responses(
+        (status = 200, description = "The file in the container, as an attachment", content_type = "application/octet-stream"),
+        (status = 404, description = "The file isn't a registered asset"),
+        (status = 422, description = "Invalid path, or a codec the container can't hold"),
+    ),
+    tag = "media"
+)]
+async fn remux(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(jobs): State<Arc<Jobs>>,
+    Json(payload): Json<Remux>,
+) -> Result<Response, (StatusCode, String)> {
+    let (input, output) = prepare(&*media, &jobs, &payload.file, payload.container).await?;
+    let (from, to) = (input.clone(), output.clone());
+    make(&output, move |cancel| media::remux(&from, &to, cancel)).await?;
+    let name = format!("{}.{}", stem(&input), payload.container.extension());
+    Ok(attachment(&output, &name).await)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/video/clip",
+    request_body = Clip,
+    responses(
+        (status = 200, description = "The clip, as an attachment; how it was cut in `X-Clip-Cut`", content_type = "application/octet-stream"),
+        (status = 201, description = "The clip is kept in storage, with `store`", body = StoredClip),
+        (status = 404, description = "The file isn't a registered asset"),
+        (status = 422, description = "Invalid path or times, or a codec the container can't hold"),
+    ),
+    tag = "media"
+)]
+async fn clip(
+    State(media): State<Arc<dyn MediaRepository>>,
+    State(jobs): State<Arc<Jobs>>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(ids): State<Arc<dyn IdGenerator>>,
+    Json(payload): Json<Clip>,
+) -> Result<Response, (StatusCode, String)> {
+    if !(payload.start >= 0.0 && payload.end.is_finite() && payload.end > payload.start) {
+        let message = "start must be 0 or more, and end after it".to_owned();
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+    let (input, output) = prepare(&*media, &jobs, &payload.file, payload.container).await?;
+    let (from, to) = (input.clone(), output.clone());
+    let (start, end, codec) = (payload.start, payload.end, payload.codec);
+    let clip = move |cancel: &Cancel| media::clip(&from, &to, start, end, codec, cancel);
+    let cut = make(&output, clip).await?;
+    let cut_header = (CUT, HeaderValue::from_static(cut.name()));
+
+    if !payload.store {
+        let name = format!(
+            "{}-{}-{}.{}",
+            stem(&input),
+            start,
+            end,
+            payload.container.extension()
+        );
+        let mut response = attachment(&output, &name).await;
+        response.headers_mut().extend([cut_header]);
+        return Ok(response);
+    }
+    let scratch = output.file_name().unwrap_or_default().to_string_lossy();
+    let key = format!("clips/{}-{}", ids.next_id(), scratch);
+    storage
+        .put(&key, &output)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let url = storage
+        .download_url(&key, job::DOWNLOAD_TTL)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let stored = StoredClip { key, url, cut };
+    Ok((StatusCode::CREATED, [cut_header], Json(stored)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        clock::{SequentialIds, SystemClock},
+        repository::media::MemoryMedia,
+        storage,
+    };
+
+    #[tokio::test]
+    async fn refuses_unknown_files_and_times() {
+        let pool = PgPool::connect_lazy("postgres://localhost/rsapp").unwrap();
+        let dir = std::env::temp_dir();
+        let storage = storage::open(&storage::Settings::default(), &dir, Arc::new(SystemClock));
+        let jobs = Arc::new(Jobs::new(pool, 1, dir, storage.clone(), Arc::default()));
+        let media: Arc<dyn MediaRepository> = Arc::new(MemoryMedia::default());
+        let ids: Arc<dyn IdGenerator> = Arc::new(SequentialIds::default());
+        let remux = |file: &str| {
+            let payload = Remux {
+                file: file.to_owned(),
+                container: Container::Mp4,
+            };
+            remux(State(media.clone()), State(jobs.clone()), Json(payload))
+        };
+        let clip = |start: f64, end: f64| {
+            let payload = Clip {
+                file: "/media/movie.mkv".to_owned(),
+                start,
+                end,
+                container: Container::Mkv,
+                codec: VideoCodec::H264,
+                store: false,
+            };
+            clip(
+                State(media.clone()),
+                State(jobs.clone()),
+                State(storage.clone()),
+                State(ids.clone()),
+                Json(payload),
+            )
+        };
+
+        let unknown = remux("/media/movie.mkv").await.unwrap_err();
+        assert_eq!(unknown.0, StatusCode::NOT_FOUND);
+        let outside = remux("/media/../etc/movie.mkv").await.unwrap_err();
+        assert_eq!(outside.0, StatusCode::UNPROCESSABLE_ENTITY);
+        for (start, end) in [
+            (5.0, 5.0),
+            (-1.0, 5.0),
+            (0.0, f64::INFINITY),
+            (f64::NAN, 1.0),
+        ] {
+            let refused = clip(start, end).await.unwrap_err();
+            assert_eq!(
+                refused.0,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{}-{}",
+                start,
+                end
+            );
+        }
+        assert_eq!(clip(0.0, 5.0).await.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn scratch_names() {
+        let dir = Path::new("/data/jobs");
+        let first = scratch(dir, Container::Webm);
+        assert_eq!(first.extension().unwrap(), "webm");
+        assert_ne!(first, scratch(dir, Container::Webm));
+    }
+}
@@ -0,0 +1,31 @@
+// Storage behind traits, so handlers don't care where their data lives. Each repository has a
+// Postgres implementation for the server and an in-memory one for tests that have no database.
+// Handlers take them as `State<Arc<dyn ...Repository>>`, see `app::AppState`.
+//
+// Errors are `sqlx::Error` for either implementation, so `db_error` maps them the same way;
+// the in-memory ones report missing rows as `RowNotFound`.
+//
+// With the `sqlite` feature, users and media also have SQLite implementations, see `sqlite`.
+
+pub mod idempotency;
+pub mod identity;
+pub mod library;
+pub mod media;
+pub mod password;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod sqlite;
+pub mod tag;
+pub mod upload;
+pub mod user;
+
+pub use idempotency::IdempotencyRepository;
+pub use identity::IdentityRepository;
+pub use library::LibraryRepository;
+pub use media::MediaRepository;
+pub use password::PasswordRepository;
+pub use snapshot::SnapshotRepository;
+pub use tag::TagRepository;
+pub use upload::UploadRepository;
+pub use user::UserRepository;
@@ -0,0 +1,228 @@
+// Answers to requests sent with an `Idempotency-Key`, so a client retrying one after a timeout
+// gets the first answer instead of doing it twice. Keys are remembered for KEY_TTL, per scope,
+// the endpoint they were sent to.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::db::DbExecutor;
+
+pub const KEY_TTL: Duration = Duration::hours(24);
+// a request that claimed a key and didn't answer within this is taken to have died with its
+// instance, and a retry runs it again
+const ABANDONED: Duration = Duration::minutes(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Claim {
+    // the first request with the key, which should `complete` or `release` it
+    First,
+    // what the first request answered
+    Done { status: u16, body: Value },
+    // the first request is still being handled
+    Pending,
+    // the key came with another request before
+    Mismatch,
+}
+
+#[async_trait]
+pub trait IdempotencyRepository: Send + Sync {
+    // claims `key` for a request hashing to `request`, unless one claimed it before
+    async fn claim(&self, scope: &str, key: &str, request: &str) -> Result<Claim, sqlx::Error>;
+    async fn complete(
+        &self,
+        scope: &str,
+        key: &str,
+        status: u16,
+        body: &Value,
+    ) -> Result<(), sqlx::Error>;
+    // gives the key back after the request failed, so a retry runs it again
+    async fn release(&self, scope: &str, key: &str) -> Result<(), sqlx::Error>;
+    // forgets keys claimed before `before`, returns how many
+    async fn prune(&self, before: DateTime<Utc>) -> Result<usize, sqlx::Error>;
+}
+
+pub struct PgIdempotency {
+    db: Arc<DbExecutor>,
+}
+
+impl PgIdempotency {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgIdempotency { db }
+    }
+}
+
+#[async_trait]
+impl IdempotencyRepository for PgIdempotency {
+    #[instrument(level = "debug", skip(self, request))]
+    async fn claim(&self, scope: &str, key: &str, request: &str) -> Result<Claim, sqlx::Error> {
+        let now = Utc::now();
+        // expired and abandoned keys are taken over in the same statement, so two retries
+        // racing for one can't both run
+        let claimed = sqlx::query(
+            "INSERT INTO idempotency_keys (scope, key, request_hash) VALUES ($1, $2, $3)
+                ON CONFLICT (scope, key) DO UPDATE
+                SET request_hash = $3, status = NULL, body = NULL, created_at = now()
+                WHERE idempotency_keys.created_at < $4
+                    OR (idempotency_keys.status IS NULL AND idempotency_keys.created_at < $5)
+                RETURNING key",
+        )
+        .bind(scope)
+        .bind(key)
+        .bind(request)
+        .bind(now - KEY_TTL)
+        .bind(now - ABANDONED)
+        .fetch_optional(self.db.write())
+        .await?;
+        if claimed.is_some() {
+            return Ok(Claim::First);
+        }
+        let (hash, status, body): (String, Option<i16>, Option<Value>) = sqlx::query_as(
+            "SELECT request_hash, status, body FROM idempotency_keys
+                WHERE scope = $1 AND key = $2",
+        )
+        .bind(scope)
+        .bind(key)
+        .fetch_one(self.db.write())
+        .await?;
+        Ok(settled(
+            request,
+            &hash,
+            status.map(|status| status as u16),
+            body,
+        ))
+    }
+
+    #[instrument(level = "debug", skip(self, body))]
+    async fn complete(
+        &self,
+        scope: &str,
+        key: &str,
+        status: u16,
+        body: &Value,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE idempotency_keys SET status = $3, body = $4 WHERE scope = $1 AND key = $2",
+        )
+        .bind(scope)
+        .bind(key)
+        .bind(status as i16)
+        .bind(body)
+        .execute(self.db.write())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn release(&self, scope: &str, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys WHERE scope = $1 AND key = $2 AND status IS NULL",
+        )
+        .bind(scope)
+        .bind(key)
+        .execute(self.db.write())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn prune(&self, before: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let done = sqlx::query("DELETE FROM idempotency_keys WHERE created_at < $1")
+            .bind(before)
+            .execute(self.db.write())
+            .await?;
+        Ok(done.rows_affected() as usize)
+    }
+}
+
+// what a request hashing to `request` gets for a key claimed by another one
+fn settled(request: &str, hash: &str, status: Option<u16>, body: Option<Value>) -> Claim {
+    if request != hash {
+        return Claim::Mismatch;
+    }
+    match status {
+        Some(status) => Claim::Done {
+            status,
+            body: body.unwrap_or(Value::Null),
+        },
+        None => Claim::Pending,
+    }
+}
+
+struct Stored {
+    hash: String,
+    status: Option<u16>,
+    body: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+// keeps keys in a HashMap, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryIdempotency {
+    keys: Mutex<HashMap<(String, String), Stored>>,
+}
+
+#[async_trait]
+impl IdempotencyRepository for MemoryIdempotency {
+    async fn claim(&self, scope: &str, key: &str, request: &str) -> Result<Claim, sqlx::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = Utc::now();
+        let id = (scope.to_owned(), key.to_owned());
+        if let Some(stored) = keys.get(&id) {
+            let abandoned = stored.status.is_none() && stored.created_at < now - ABANDONED;
+            if stored.created_at >= now - KEY_TTL && !abandoned {
+                return Ok(settled(
+                    request,
+                    &stored.hash,
+                    stored.status,
+                    stored.body.clone(),
+                ));
+            }
+        }
+        let stored = Stored {
+            hash: request.to_owned(),
+            status: None,
+            body: None,
+            created_at: now,
+        };
+        keys.insert(id, stored);
+        Ok(Claim::First)
+    }
+
+    async fn complete(
+        &self,
+        scope: &str,
+        key: &str,
+        status: u16,
+        body: &Value,
+    ) -> Result<(), sqlx::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(stored) = keys.get_mut(&(scope.to_owned(), key.to_owned())) {
+            stored.status = Some(status);
+            stored.body = Some(body.clone());
+        }
+        Ok(())
+    }
+
+    async fn release(&self, scope: &str, key: &str) -> Result<(), sqlx::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        let id = (scope.to_owned(), key.to_owned());
+        if keys.get(&id).is_some_and(|stored| stored.status.is_none()) {
+            keys.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        let count = keys.len();
+        keys.retain(|_, stored| stored.created_at >= before);
+        Ok(count - keys.len())
+    }
+}
@@ -0,0 +1,216 @@
+// Accounts by who they are at an OpenID Connect provider, the issuer and subject of its ID tokens.
+use std::sync::{Arc, Mutex};
+
+use axum::async_trait;
+use tracing::instrument;
+
+use crate::{auth::Role, db::DbExecutor, repository::password::Account};
+
+// an account to create for someone signing in for the first time
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub role: Role,
+}
+
+#[async_trait]
+pub trait IdentityRepository: Send + Sync {
+    // the account of `subject` at `issuer`, if they signed in before, deleted or not
+    async fn find(&self, issuer: &str, subject: &str) -> Result<Option<Account>, sqlx::Error>;
+    // the account with `email`, ignoring case, deleted or not
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, sqlx::Error>;
+    async fn link(&self, id: i64, issuer: &str, subject: &str) -> Result<(), sqlx::Error>;
+    // None when the username is taken
+    async fn create(&self, identity: &NewIdentity) -> Result<Option<Account>, sqlx::Error>;
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), sqlx::Error>;
+}
+
+pub struct PgIdentities {
+    db: Arc<DbExecutor>,
+}
+
+impl PgIdentities {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgIdentities { db }
+    }
+}
+
+#[async_trait]
+impl IdentityRepository for PgIdentities {
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, issuer: &str, subject: &str) -> Result<Option<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>(
+            "SELECT id, username, email, deleted_at IS NOT NULL AS deleted FROM users
+                WHERE oidc_issuer = $1 AND oidc_subject = $2",
+        )
+        .bind(issuer)
+        .bind(subject)
+        .fetch_optional(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>(
+            "SELECT id, username, email, deleted_at IS NOT NULL AS deleted FROM users
+                WHERE lower(email) = lower($1)",
+        )
+        .bind(email)
+        .fetch_optional(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn link(&self, id: i64, issuer: &str, subject: &str) -> Result<(), sqlx::Error> {
+        let done =
+            sqlx::query("UPDATE users SET oidc_issuer = $2, oidc_subject = $3 WHERE id = $1")
+                .bind(id)
+                .bind(issuer)
+                .bind(subject)
+                .execute(self.db.write())
+                .await?;
+        if done.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn create(&self, identity: &NewIdentity) -> Result<Option<Account>, sqlx::Error> {
+        let created = sqlx::query_as::<_, Account>(
+            "INSERT INTO users (username, email, role, oidc_issuer, oidc_subject)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING id, username, email, deleted_at IS NOT NULL AS deleted",
+        )
+        .bind(&identity.username)
+        .bind(&identity.email)
+        .bind(identity.role.as_str())
+        .bind(&identity.issuer)
+        .bind(&identity.subject)
+        .fetch_one(self.db.write())
+        .await;
+        match created {
+            Ok(account) => Ok(Some(account)),
+            Err(sqlx::Error::Database(err)) if err.constraint() == Some("users_username") => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE users SET role = $2 WHERE id = $1")
+            .bind(id)
+            .bind(role.as_str())
+            .execute(self.db.write())
+            .await?;
+        Ok(())
+    }
+}
+
+// keeps accounts and their identities in memory, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryIdentities {
+    accounts: Mutex<Vec<Stored>>,
+}
+
+struct Stored {
+    account: Account,
+    role: Role,
+    identity: Option<(String, String)>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryIdentities {
+    pub fn with_account(self, account: Account) -> Self {
+        self.accounts.lock().unwrap().push(Stored {
+            account,
+            role: Role::User,
+            identity: None,
+        });
+        self
+    }
+
+    pub fn role(&self, id: i64) -> Option<Role> {
+        let accounts = self.accounts.lock().unwrap();
+        accounts
+            .iter()
+            .find(|stored| stored.account.id == id)
+            .map(|stored| stored.role)
+    }
+}
+
+#[async_trait]
+impl IdentityRepository for MemoryIdentities {
+    async fn find(&self, issuer: &str, subject: &str) -> Result<Option<Account>, sqlx::Error> {
+        let accounts = self.accounts.lock().unwrap();
+        Ok(accounts
+            .iter()
+            .find(|stored| {
+                stored
+                    .identity
+                    .as_ref()
+                    .is_some_and(|(i, s)| i == issuer && s == subject)
+            })
+            .map(|stored| stored.account.clone()))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<Account>, sqlx::Error> {
+        let accounts = self.accounts.lock().unwrap();
+        Ok(accounts
+            .iter()
+            .map(|stored| &stored.account)
+            .find(|account| {
+                account
+                    .email
+                    .as_ref()
+                    .is_some_and(|stored| stored.eq_ignore_ascii_case(email))
+            })
+            .cloned())
+    }
+
+    async fn link(&self, id: i64, issuer: &str, subject: &str) -> Result<(), sqlx::Error> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let stored = accounts
+            .iter_mut()
+            .find(|stored| stored.account.id == id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        stored.identity = Some((issuer.to_owned(), subject.to_owned()));
+        Ok(())
+    }
+
+    async fn create(&self, identity: &NewIdentity) -> Result<Option<Account>, sqlx::Error> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts
+            .iter()
+            .any(|stored| stored.account.username == identity.username)
+        {
+            return Ok(None);
+        }
+        let account = Account {
+            id: accounts.len() as i64 + 1,
+            username: identity.username.clone(),
+            email: identity.email.clone(),
+            deleted: false,
+        };
+        accounts.push(Stored {
+            account: account.clone(),
+            role: identity.role,
+            identity: Some((identity.issuer.clone(), identity.subject.clone())),
+        });
+        Ok(Some(account))
+    }
+
+    async fn set_role(&self, id: i64, role: Role) -> Result<(), sqlx::Error> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if let Some(stored) = accounts.iter_mut().find(|stored| stored.account.id == id) {
+            stored.role = role;
+        }
+        Ok(())
+    }
+}
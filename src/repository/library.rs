@@ -0,0 +1,574 @@
+// Media files of the library, as the scanner last found them. Each is an asset too, registered
+// under its path when first found, with its metadata stored on the asset.
+use std::sync::{Arc, Mutex};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{types::Json, Postgres, QueryBuilder};
+use tracing::instrument;
+
+use crate::{
+    db::DbExecutor,
+    media::{schema, Metadata},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibraryFile {
+    pub asset_id: i64,
+    pub path: String,
+    pub title: String,
+    // bytes
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+    pub missing_since: Option<DateTime<Utc>>,
+    pub metadata: Option<Metadata>,
+    // names, ordered
+    pub tags: Vec<String>,
+    pub checksums: Option<Checksums>,
+}
+
+// of a file's contents, to find duplicates by
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksums {
+    // SHA-256, in hex
+    pub sha256: String,
+    // see `media::frame_hash`, of videos when asked for
+    pub frame_hash: Option<u64>,
+}
+
+// a file as a scan found it on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    pub path: String,
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Search {
+    // in the title or path, ignoring case
+    pub text: Option<String>,
+    // with a stream of this medium, like video or audio
+    pub medium: Option<String>,
+    // those scans don't find anymore instead of those they do
+    pub missing: bool,
+    // with the tag of this name, ignoring case
+    pub tag: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+// a file found by full-text search, and how well it matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ranked {
+    pub file: LibraryFile,
+    pub rank: f32,
+}
+
+#[async_trait]
+pub trait LibraryRepository: Send + Sync {
+    // the files known under `root`, missing ones too
+    async fn known(&self, root: &str) -> Result<Vec<LibraryFile>, sqlx::Error>;
+    // Registers `found` as it is now, as an asset titled `title` unless its path is one already,
+    // and no longer missing. `metadata` and `checksums` replace what's stored, None keeps it.
+    // Returns the asset.
+    async fn store(
+        &self,
+        found: &Found,
+        title: &str,
+        metadata: Option<&Metadata>,
+        checksums: Option<&Checksums>,
+        scanned_at: DateTime<Utc>,
+    ) -> Result<i64, sqlx::Error>;
+    async fn mark_missing(
+        &self,
+        asset_ids: &[i64],
+        since: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+    // ordered by path
+    async fn search(&self, search: &Search) -> Result<Vec<LibraryFile>, sqlx::Error>;
+    // Files there are whose title, tags, container format or codecs have the words of `query`,
+    // in the syntax of web search engines: `"exact phrase"`, `or`, `-excluded`. Best matches
+    // first, then by path.
+    async fn search_text(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Ranked>, sqlx::Error>;
+    // files there are with the same SHA-256 as another one there is, ordered by it and path
+    async fn identical(&self) -> Result<Vec<LibraryFile>, sqlx::Error>;
+    // files there are with a frame hash, ordered by path
+    async fn frame_hashed(&self) -> Result<Vec<LibraryFile>, sqlx::Error>;
+}
+
+pub struct PgLibrary {
+    db: Arc<DbExecutor>,
+}
+
+impl PgLibrary {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgLibrary { db }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+    asset_id: i64,
+    path: String,
+    title: String,
+    size: i64,
+    modified_at: DateTime<Utc>,
+    missing_since: Option<DateTime<Utc>>,
+    metadata: Option<Json<serde_json::Value>>,
+    tags: Vec<String>,
+    sha256: Option<String>,
+    frame_hash: Option<i64>,
+}
+
+impl TryFrom<Row> for LibraryFile {
+    type Error = sqlx::Error;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        let metadata = match row.metadata {
+            Some(Json(document)) => Some(
+                schema::upgrade(document)
+                    .map_err(|err| sqlx::Error::Decode(Box::new(err)))?
+                    .0,
+            ),
+            None => None,
+        };
+        Ok(LibraryFile {
+            asset_id: row.asset_id,
+            path: row.path,
+            title: row.title,
+            size: row.size,
+            modified_at: row.modified_at,
+            missing_since: row.missing_since,
+            metadata,
+            tags: row.tags,
+            // bits as they are, Postgres has no unsigned integers
+            checksums: row.sha256.map(|sha256| Checksums {
+                sha256,
+                frame_hash: row.frame_hash.map(|hash| hash as u64),
+            }),
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RankedRow {
+    #[sqlx(flatten)]
+    file: Row,
+    rank: f32,
+}
+
+const SELECT: &str = "SELECT l.asset_id, a.path, a.title, l.size, l.modified_at, l.missing_since,
+    a.metadata, ARRAY(SELECT t.name FROM asset_tags at JOIN tags t ON t.id = at.tag_id
+        WHERE at.asset_id = l.asset_id ORDER BY lower(t.name)) AS tags, l.sha256, l.frame_hash
+    FROM library_files l JOIN assets a ON a.id = l.asset_id";
+
+#[async_trait]
+impl LibraryRepository for PgLibrary {
+    #[instrument(level = "debug", skip(self))]
+    async fn known(&self, root: &str) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(&format!("{} WHERE starts_with(a.path, $1)", SELECT))
+            .bind(root)
+            .fetch_all(self.db.read())
+            .await?;
+        rows.into_iter().map(LibraryFile::try_from).collect()
+    }
+
+    #[instrument(level = "debug", skip(self, found, metadata, checksums))]
+    async fn store(
+        &self,
+        found: &Found,
+        title: &str,
+        metadata: Option<&Metadata>,
+        checksums: Option<&Checksums>,
+        scanned_at: DateTime<Utc>,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "WITH asset AS (
+                INSERT INTO assets (path, title, metadata) VALUES ($1, $2, $3)
+                    ON CONFLICT (path) DO UPDATE SET
+                        metadata = COALESCE(EXCLUDED.metadata, assets.metadata)
+                    RETURNING id
+            )
+            INSERT INTO library_files (asset_id, size, modified_at, scanned_at, sha256, frame_hash)
+                SELECT id, $4, $5, $6, $7, $8 FROM asset
+                ON CONFLICT (asset_id) DO UPDATE SET size = EXCLUDED.size,
+                    modified_at = EXCLUDED.modified_at, scanned_at = EXCLUDED.scanned_at,
+                    missing_since = NULL,
+                    sha256 = COALESCE(EXCLUDED.sha256, library_files.sha256),
+                    frame_hash = CASE WHEN EXCLUDED.sha256 IS NULL THEN library_files.frame_hash
+                        ELSE EXCLUDED.frame_hash END
+                RETURNING asset_id",
+        )
+        .bind(&found.path)
+        .bind(title)
+        .bind(metadata.map(Json))
+        .bind(found.size)
+        .bind(found.modified_at)
+        .bind(scanned_at)
+        .bind(checksums.map(|checksums| &checksums.sha256))
+        .bind(checksums.and_then(|checksums| checksums.frame_hash.map(|hash| hash as i64)))
+        .fetch_one(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self, asset_ids), fields(files = asset_ids.len()))]
+    async fn mark_missing(
+        &self,
+        asset_ids: &[i64],
+        since: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE library_files SET missing_since = $2
+                WHERE asset_id = ANY($1) AND missing_since IS NULL",
+        )
+        .bind(asset_ids)
+        .bind(since)
+        .execute(self.db.write())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn search(&self, search: &Search) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let mut select = QueryBuilder::<Postgres>::new(SELECT);
+        select.push(if search.missing {
+            " WHERE l.missing_since IS NOT NULL"
+        } else {
+            " WHERE l.missing_since IS NULL"
+        });
+        if let Some(text) = &search.text {
+            let pattern = format!("%{}%", escape_like(text));
+            select
+                .push(" AND (a.title ILIKE ")
+                .push_bind(pattern.clone())
+                .push(" OR a.path ILIKE ")
+                .push_bind(pattern)
+                .push(")");
+        }
+        if let Some(medium) = &search.medium {
+            select
+                .push(" AND a.metadata @? ")
+                .push_bind(format!("$.streams[*] ? (@.medium == {:?})", medium))
+                .push("::jsonpath");
+        }
+        if let Some(tag) = &search.tag {
+            select
+                .push(
+                    " AND EXISTS (SELECT 1 FROM asset_tags at JOIN tags t ON t.id = at.tag_id
+                        WHERE at.asset_id = l.asset_id AND lower(t.name) = lower(",
+                )
+                .push_bind(tag)
+                .push("))");
+        }
+        select
+            .push(" ORDER BY a.path LIMIT ")
+            .push_bind(search.limit)
+            .push(" OFFSET ")
+            .push_bind(search.offset);
+        let rows = select
+            .build_query_as::<Row>()
+            .fetch_all(self.db.read())
+            .await?;
+        rows.into_iter().map(LibraryFile::try_from).collect()
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn search_text(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Ranked>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, RankedRow>(
+            "SELECT l.asset_id, a.path, a.title, l.size, l.modified_at, l.missing_since,
+                    a.metadata, ARRAY(SELECT t.name FROM asset_tags at JOIN tags t
+                        ON t.id = at.tag_id WHERE at.asset_id = l.asset_id
+                        ORDER BY lower(t.name)) AS tags, l.sha256, l.frame_hash,
+                    ts_rank_cd(a.search, query) AS rank
+                FROM library_files l JOIN assets a ON a.id = l.asset_id,
+                    websearch_to_tsquery('simple', $1) query
+                WHERE l.missing_since IS NULL AND a.search @@ query
+                ORDER BY rank DESC, a.path LIMIT $2 OFFSET $3",
+        )
+        .bind(query)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(self.db.read())
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(Ranked {
+                    file: LibraryFile::try_from(row.file)?,
+                    rank: row.rank,
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn identical(&self) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(&format!(
+            "{} WHERE l.missing_since IS NULL AND l.sha256 IN (
+                SELECT sha256 FROM library_files WHERE missing_since IS NULL
+                    GROUP BY sha256 HAVING count(*) > 1
+            ) ORDER BY l.sha256, a.path",
+            SELECT
+        ))
+        .fetch_all(self.db.read())
+        .await?;
+        rows.into_iter().map(LibraryFile::try_from).collect()
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn frame_hashed(&self) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Row>(&format!(
+            "{} WHERE l.missing_since IS NULL AND l.frame_hash IS NOT NULL ORDER BY a.path",
+            SELECT
+        ))
+        .fetch_all(self.db.read())
+        .await?;
+        rows.into_iter().map(LibraryFile::try_from).collect()
+    }
+}
+
+// `text` matching itself in a LIKE pattern
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// keeps library files in a Vec, for tests; they have no tags, those are kept by `MemoryTags`
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryLibrary {
+    files: Mutex<Vec<LibraryFile>>,
+}
+
+#[async_trait]
+impl LibraryRepository for MemoryLibrary {
+    async fn known(&self, root: &str) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .filter(|file| file.path.starts_with(root))
+            .cloned()
+            .collect())
+    }
+
+    async fn store(
+        &self,
+        found: &Found,
+        title: &str,
+        metadata: Option<&Metadata>,
+        checksums: Option<&Checksums>,
+        _scanned_at: DateTime<Utc>,
+    ) -> Result<i64, sqlx::Error> {
+        let mut files = self.files.lock().unwrap();
+        let next = files.len() as i64 + 1;
+        let file = match files.iter_mut().find(|file| file.path == found.path) {
+            Some(file) => file,
+            None => {
+                files.push(LibraryFile {
+                    asset_id: next,
+                    path: found.path.clone(),
+                    title: title.to_owned(),
+                    size: 0,
+                    modified_at: found.modified_at,
+                    missing_since: None,
+                    metadata: None,
+                    tags: Vec::new(),
+                    checksums: None,
+                });
+                files.last_mut().unwrap()
+            }
+        };
+        file.size = found.size;
+        file.modified_at = found.modified_at;
+        file.missing_since = None;
+        if let Some(metadata) = metadata {
+            file.metadata = Some(metadata.clone());
+        }
+        if let Some(checksums) = checksums {
+            file.checksums = Some(checksums.clone());
+        }
+        Ok(file.asset_id)
+    }
+
+    async fn mark_missing(
+        &self,
+        asset_ids: &[i64],
+        since: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut files = self.files.lock().unwrap();
+        for file in files.iter_mut() {
+            if asset_ids.contains(&file.asset_id) && file.missing_since.is_none() {
+                file.missing_since = Some(since);
+            }
+        }
+        Ok(())
+    }
+
+    async fn search(&self, search: &Search) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let text = search.text.as_ref().map(|text| text.to_lowercase());
+        let mut found: Vec<LibraryFile> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|file| file.missing_since.is_some() == search.missing)
+            .filter(|file| {
+                text.as_ref().is_none_or(|text| {
+                    file.title.to_lowercase().contains(text)
+                        || file.path.to_lowercase().contains(text)
+                })
+            })
+            .filter(|file| {
+                search.medium.as_ref().is_none_or(|medium| {
+                    file.metadata.as_ref().is_some_and(|metadata| {
+                        metadata
+                            .streams
+                            .iter()
+                            .any(|stream| &stream.medium == medium)
+                    })
+                })
+            })
+            .filter(|file| {
+                search.tag.as_ref().is_none_or(|tag| {
+                    let tag = tag.to_lowercase();
+                    file.tags.iter().any(|other| other.to_lowercase() == tag)
+                })
+            })
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(found
+            .into_iter()
+            .skip(search.offset.max(0) as usize)
+            .take(search.limit.max(0) as usize)
+            .collect())
+    }
+
+    // every word of `query` must be among those of the file, weighed like in Postgres; no
+    // phrases, `or` or exclusions
+    async fn search_text(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Ranked>, sqlx::Error> {
+        let query = words(query);
+        let mut found: Vec<Ranked> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|file| file.missing_since.is_none() && !query.is_empty())
+            .filter_map(|file| {
+                let weighed = weighed_words(file);
+                let mut rank = 0.0;
+                for word in &query {
+                    let weight = weighed
+                        .iter()
+                        .filter(|(other, _)| other == word)
+                        .map(|(_, weight)| *weight)
+                        .fold(0.0, f32::max);
+                    if weight == 0.0 {
+                        return None;
+                    }
+                    rank += weight;
+                }
+                Some(Ranked {
+                    file: file.clone(),
+                    rank,
+                })
+            })
+            .collect();
+        found.sort_by(|a, b| {
+            b.rank
+                .total_cmp(&a.rank)
+                .then_with(|| a.file.path.cmp(&b.file.path))
+        });
+        Ok(found
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn identical(&self) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let files = self.files.lock().unwrap();
+        let sha256 = |file: &LibraryFile| {
+            let checksums = file
+                .checksums
+                .as_ref()
+                .filter(|_| file.missing_since.is_none());
+            checksums.map(|checksums| checksums.sha256.clone())
+        };
+        let mut found: Vec<LibraryFile> = files
+            .iter()
+            .filter(|file| {
+                sha256(file).is_some()
+                    && files
+                        .iter()
+                        .filter(|other| sha256(other) == sha256(file))
+                        .count()
+                        > 1
+            })
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| (sha256(a), &a.path).cmp(&(sha256(b), &b.path)));
+        Ok(found)
+    }
+
+    async fn frame_hashed(&self) -> Result<Vec<LibraryFile>, sqlx::Error> {
+        let mut found: Vec<LibraryFile> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|file| file.missing_since.is_none())
+            .filter(|file| {
+                let checksums = file.checksums.as_ref();
+                checksums.is_some_and(|checksums| checksums.frame_hash.is_some())
+            })
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(found)
+    }
+}
+
+// lower case, split where the simple text search configuration splits
+#[cfg_attr(not(test), allow(dead_code))]
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// the words of `file` with the weights of ts_rank_cd for A, B and C
+#[cfg_attr(not(test), allow(dead_code))]
+fn weighed_words(file: &LibraryFile) -> Vec<(String, f32)> {
+    let mut weighed: Vec<(String, f32)> = words(&file.title)
+        .into_iter()
+        .map(|word| (word, 1.0))
+        .collect();
+    if let Some(metadata) = &file.metadata {
+        for value in metadata.tags.values() {
+            weighed.extend(words(value).into_iter().map(|word| (word, 0.4)));
+        }
+        let codecs = metadata.streams.iter().map(|stream| stream.codec.as_str());
+        for text in codecs.chain([metadata.format.as_str()]) {
+            weighed.extend(words(text).into_iter().map(|word| (word, 0.2)));
+        }
+    }
+    weighed
+}
@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex};
+
+use axum::async_trait;
+use sqlx::types::Json;
+use tracing::instrument;
+
+use crate::{
+    db::DbExecutor,
+    media::{schema, Metadata},
+};
+
+// an asset's media file and what's known about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    pub asset_id: i64,
+    pub path: String,
+    pub metadata: Option<Metadata>,
+    // the stored document had an older schema, `metadata` is upgraded from it
+    pub upgraded: bool,
+}
+
+#[async_trait]
+pub trait MediaRepository: Send + Sync {
+    async fn find(&self, asset_id: i64) -> Result<MediaFile, sqlx::Error>;
+    async fn find_by_path(&self, path: &str) -> Result<MediaFile, sqlx::Error>;
+    async fn store_metadata(&self, asset_id: i64, metadata: &Metadata) -> Result<(), sqlx::Error>;
+}
+
+pub struct PgMedia {
+    db: Arc<DbExecutor>,
+}
+
+impl PgMedia {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgMedia { db }
+    }
+}
+
+pub(super) type Row = (i64, String, Option<Json<serde_json::Value>>);
+
+pub(super) fn media_file((asset_id, path, document): Row) -> Result<MediaFile, sqlx::Error> {
+    let (metadata, upgraded) = match document {
+        Some(Json(document)) => {
+            let (metadata, upgraded) =
+                schema::upgrade(document).map_err(|err| sqlx::Error::Decode(Box::new(err)))?;
+            (Some(metadata), upgraded)
+        }
+        None => (None, false),
+    };
+    Ok(MediaFile {
+        asset_id,
+        path,
+        metadata,
+        upgraded,
+    })
+}
+
+#[async_trait]
+impl MediaRepository for PgMedia {
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, asset_id: i64) -> Result<MediaFile, sqlx::Error> {
+        sqlx::query_as::<_, Row>("SELECT id, path, metadata FROM assets WHERE id = $1")
+            .bind(asset_id)
+            .fetch_one(self.db.read())
+            .await
+            .and_then(media_file)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find_by_path(&self, path: &str) -> Result<MediaFile, sqlx::Error> {
+        sqlx::query_as::<_, Row>("SELECT id, path, metadata FROM assets WHERE path = $1")
+            .bind(path)
+            .fetch_one(self.db.read())
+            .await
+            .and_then(media_file)
+    }
+
+    #[instrument(level = "debug", skip(self, metadata))]
+    async fn store_metadata(&self, asset_id: i64, metadata: &Metadata) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE assets SET metadata = $2 WHERE id = $1")
+            .bind(asset_id)
+            .bind(Json(metadata))
+            .execute(self.db.write())
+            .await?;
+        Ok(())
+    }
+}
+
+// keeps media files in a Vec, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryMedia {
+    files: Mutex<Vec<MediaFile>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryMedia {
+    pub fn insert(&self, file: MediaFile) {
+        self.files.lock().unwrap().push(file);
+    }
+}
+
+#[async_trait]
+impl MediaRepository for MemoryMedia {
+    async fn find(&self, asset_id: i64) -> Result<MediaFile, sqlx::Error> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .find(|file| file.asset_id == asset_id)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn find_by_path(&self, path: &str) -> Result<MediaFile, sqlx::Error> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .find(|file| file.path == path)
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn store_metadata(&self, asset_id: i64, metadata: &Metadata) -> Result<(), sqlx::Error> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .iter_mut()
+            .find(|file| file.asset_id == asset_id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        file.metadata = Some(metadata.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+// Passwords of accounts and the reset links mailed for them. Links are kept by the SHA-256 of
+// their token, so a leaked table can't be used to reset anything.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::instrument;
+
+use crate::db::DbExecutor;
+
+// who a password reset is asked for
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub id: i64,
+    pub username: String,
+    pub email: Option<String>,
+    // soft deleted, only told by `IdentityRepository`; the others leave deleted users out
+    #[sqlx(default)]
+    pub deleted: bool,
+}
+
+#[async_trait]
+pub trait PasswordRepository: Send + Sync {
+    // the hash of the password of user `id`, None for accounts without one
+    async fn hash(&self, id: i64) -> Result<Option<String>, sqlx::Error>;
+    async fn set_hash(&self, id: i64, hash: &str) -> Result<(), sqlx::Error>;
+    // the account with `login` as username or email, ignoring the case of emails, unless deleted
+    async fn find_account(&self, login: &str) -> Result<Option<Account>, sqlx::Error>;
+    async fn create_reset(
+        &self,
+        token_hash: &str,
+        id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error>;
+    // the user of the reset, which is used up, unless it expired by `now`
+    async fn take_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i64>, sqlx::Error>;
+}
+
+pub struct PgPasswords {
+    db: Arc<DbExecutor>,
+}
+
+impl PgPasswords {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgPasswords { db }
+    }
+}
+
+#[async_trait]
+impl PasswordRepository for PgPasswords {
+    #[instrument(level = "debug", skip(self))]
+    async fn hash(&self, id: i64) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_one(self.db.write())
+            .await
+    }
+
+    #[instrument(level = "debug", skip(self, hash))]
+    async fn set_hash(&self, id: i64, hash: &str) -> Result<(), sqlx::Error> {
+        let done = sqlx::query("UPDATE users SET password_hash = $2 WHERE id = $1")
+            .bind(id)
+            .bind(hash)
+            .execute(self.db.write())
+            .await?;
+        if done.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        // links mailed before are no good anymore
+        sqlx::query("DELETE FROM password_resets WHERE user_id = $1")
+            .bind(id)
+            .execute(self.db.write())
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find_account(&self, login: &str) -> Result<Option<Account>, sqlx::Error> {
+        sqlx::query_as::<_, Account>(
+            "SELECT id, username, email FROM users
+                WHERE (username = $1 OR lower(email) = lower($1)) AND deleted_at IS NULL
+                LIMIT 1",
+        )
+        .bind(login)
+        .fetch_optional(self.db.read())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self, token_hash))]
+    async fn create_reset(
+        &self,
+        token_hash: &str,
+        id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO password_resets (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind(token_hash)
+        .bind(id)
+        .bind(expires_at)
+        .execute(self.db.write())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, token_hash))]
+    async fn take_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar(
+            "DELETE FROM password_resets WHERE token_hash = $1 AND expires_at > $2
+                RETURNING user_id",
+        )
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(self.db.write())
+        .await
+    }
+}
+
+// keeps accounts and resets in memory, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryPasswords {
+    accounts: Mutex<Vec<(Account, Option<String>)>>,
+    resets: Mutex<HashMap<String, (i64, DateTime<Utc>)>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryPasswords {
+    pub fn with_account(self, account: Account, hash: Option<String>) -> Self {
+        self.accounts.lock().unwrap().push((account, hash));
+        self
+    }
+}
+
+#[async_trait]
+impl PasswordRepository for MemoryPasswords {
+    async fn hash(&self, id: i64) -> Result<Option<String>, sqlx::Error> {
+        let accounts = self.accounts.lock().unwrap();
+        accounts
+            .iter()
+            .find(|(account, _)| account.id == id)
+            .map(|(_, hash)| hash.clone())
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn set_hash(&self, id: i64, hash: &str) -> Result<(), sqlx::Error> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let (_, stored) = accounts
+            .iter_mut()
+            .find(|(account, _)| account.id == id)
+            .ok_or(sqlx::Error::RowNotFound)?;
+        *stored = Some(hash.to_owned());
+        self.resets
+            .lock()
+            .unwrap()
+            .retain(|_, (user, _)| *user != id);
+        Ok(())
+    }
+
+    async fn find_account(&self, login: &str) -> Result<Option<Account>, sqlx::Error> {
+        let accounts = self.accounts.lock().unwrap();
+        Ok(accounts
+            .iter()
+            .map(|(account, _)| account)
+            .filter(|account| !account.deleted)
+            .find(|account| {
+                account.username == login
+                    || account
+                        .email
+                        .as_ref()
+                        .is_some_and(|email| email.eq_ignore_ascii_case(login))
+            })
+            .cloned())
+    }
+
+    async fn create_reset(
+        &self,
+        token_hash: &str,
+        id: i64,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        let mut resets = self.resets.lock().unwrap();
+        resets.insert(token_hash.to_owned(), (id, expires_at));
+        Ok(())
+    }
+
+    async fn take_reset(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let mut resets = self.resets.lock().unwrap();
+        Ok(resets
+            .remove(token_hash)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(id, _)| id))
+    }
+}
@@ -0,0 +1,90 @@
+// The last computed value of each expensive aggregate, by name, so a restarted process has
+// something to answer with while it computes them again.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::db::DbExecutor;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub value: Value,
+    pub computed_at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait SnapshotRepository: Send + Sync {
+    async fn load(&self, name: &str) -> Result<Option<Snapshot>, sqlx::Error>;
+    // replaces the one stored under `name`, unless that was computed later
+    async fn save(&self, name: &str, snapshot: &Snapshot) -> Result<(), sqlx::Error>;
+}
+
+pub struct PgSnapshots {
+    db: Arc<DbExecutor>,
+}
+
+impl PgSnapshots {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgSnapshots { db }
+    }
+}
+
+#[async_trait]
+impl SnapshotRepository for PgSnapshots {
+    #[instrument(level = "debug", skip(self))]
+    async fn load(&self, name: &str) -> Result<Option<Snapshot>, sqlx::Error> {
+        let row: Option<(Value, DateTime<Utc>)> =
+            sqlx::query_as("SELECT value, computed_at FROM aggregate_snapshots WHERE name = $1")
+                .bind(name)
+                .fetch_optional(self.db.read())
+                .await?;
+        Ok(row.map(|(value, computed_at)| Snapshot { value, computed_at }))
+    }
+
+    #[instrument(level = "debug", skip(self, snapshot))]
+    async fn save(&self, name: &str, snapshot: &Snapshot) -> Result<(), sqlx::Error> {
+        // instances refreshing at once keep the newest
+        sqlx::query(
+            "INSERT INTO aggregate_snapshots (name, value, computed_at) VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO UPDATE SET value = $2, computed_at = $3
+                WHERE aggregate_snapshots.computed_at < $3",
+        )
+        .bind(name)
+        .bind(&snapshot.value)
+        .bind(snapshot.computed_at)
+        .execute(self.db.write())
+        .await?;
+        Ok(())
+    }
+}
+
+// keeps snapshots in a HashMap, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemorySnapshots {
+    snapshots: Mutex<HashMap<String, Snapshot>>,
+}
+
+#[async_trait]
+impl SnapshotRepository for MemorySnapshots {
+    async fn load(&self, name: &str) -> Result<Option<Snapshot>, sqlx::Error> {
+        Ok(self.snapshots.lock().unwrap().get(name).cloned())
+    }
+
+    async fn save(&self, name: &str, snapshot: &Snapshot) -> Result<(), sqlx::Error> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        match snapshots.get(name) {
+            Some(stored) if stored.computed_at >= snapshot.computed_at => {}
+            _ => {
+                snapshots.insert(name.to_owned(), snapshot.clone());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+// SQLite implementations of the users and media repositories, behind the `sqlite` feature, on the
+// tables of the migrations in `migrations/sqlite`, separate from the Postgres ones. The server
+// doesn't pick them yet: the handlers without repositories still query Postgres.
+use std::str::FromStr;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
+    types::Json,
+};
+use tracing::instrument;
+
+use super::{
+    media::{self, MediaFile, MediaRepository},
+    user::{User, UserRepository, COLUMNS},
+};
+use crate::{auth::Role, media::Metadata};
+
+// the migrations in `migrations/sqlite`, built into the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+// opens the database of `url`, like `sqlite://data/rsapp.db`, making the file if there's none,
+// and applies the pending migrations
+pub async fn connect(url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    let pool = SqlitePoolOptions::new().connect_with(options).await?;
+    MIGRATOR.run(&pool).await?;
+    Ok(pool)
+}
+
+pub struct SqliteUsers {
+    pool: SqlitePool,
+}
+
+impl SqliteUsers {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteUsers { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for SqliteUsers {
+    #[instrument(level = "debug", skip(self))]
+    async fn create(&self, username: &str) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "INSERT INTO users (username) VALUES ($1) RETURNING {}",
+            COLUMNS
+        ))
+        .bind(username)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, id: i64, include_deleted: bool) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+            COLUMNS
+        ))
+        .bind(id)
+        .bind(include_deleted)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list(
+        &self,
+        include_deleted: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "SELECT {} FROM users WHERE $1 OR deleted_at IS NULL
+                ORDER BY id LIMIT $2 OFFSET $3",
+            COLUMNS
+        ))
+        .bind(include_deleted)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn delete(&self, id: i64, at: DateTime<Utc>) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+            COLUMNS
+        ))
+        .bind(id)
+        .bind(at)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn restore(&self, id: i64) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL
+                RETURNING {}",
+            COLUMNS
+        ))
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn role(&self, id: i64) -> Result<Option<Role>, sqlx::Error> {
+        let role: Option<String> =
+            sqlx::query_scalar("SELECT role FROM users WHERE id = $1 AND deleted_at IS NULL")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(role.as_deref().map(Role::from_name))
+    }
+}
+
+pub struct SqliteMedia {
+    pool: SqlitePool,
+}
+
+impl SqliteMedia {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteMedia { pool }
+    }
+}
+
+#[async_trait]
+impl MediaRepository for SqliteMedia {
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, asset_id: i64) -> Result<MediaFile, sqlx::Error> {
+        sqlx::query_as::<_, media::Row>("SELECT id, path, metadata FROM assets WHERE id = $1")
+            .bind(asset_id)
+            .fetch_one(&self.pool)
+            .await
+            .and_then(media::media_file)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find_by_path(&self, path: &str) -> Result<MediaFile, sqlx::Error> {
+        sqlx::query_as::<_, media::Row>("SELECT id, path, metadata FROM assets WHERE path = $1")
+            .bind(path)
+            .fetch_one(&self.pool)
+            .await
+            .and_then(media::media_file)
+    }
+
+    #[instrument(level = "debug", skip(self, metadata))]
+    async fn store_metadata(&self, asset_id: i64, metadata: &Metadata) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE assets SET metadata = $2 WHERE id = $1")
+            .bind(asset_id)
+            .bind(Json(metadata))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::media::schema;
+
+    // in memory, each connection would have a database of its own
+    async fn pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn keeps_users() {
+        let users = SqliteUsers::new(pool().await);
+        let jd = users.create("jd").await.unwrap();
+        let old = users.create("old").await.unwrap();
+        assert_eq!(users.find(jd.id, false).await.unwrap(), jd);
+        assert_eq!(users.role(jd.id).await.unwrap(), Some(Role::User));
+
+        let at = "2024-03-10T12:00:00Z".parse().unwrap();
+        let deleted = users.delete(old.id, at).await.unwrap();
+        assert_eq!(deleted.deleted_at, Some(at));
+        assert!(matches!(
+            users.delete(old.id, at).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(users.find(old.id, false).await.is_err());
+        assert_eq!(users.list(false, 10, 0).await.unwrap(), vec![jd.clone()]);
+        assert_eq!(users.list(true, 10, 0).await.unwrap().len(), 2);
+        assert_eq!(users.role(old.id).await.unwrap(), None);
+        assert_eq!(users.restore(old.id).await.unwrap().deleted_at, None);
+        assert!(users.create("jd").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_media_metadata() {
+        let pool = pool().await;
+        sqlx::query("INSERT INTO assets (path, title) VALUES ('media/a.mp4', 'A')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let media = SqliteMedia::new(pool);
+        let file = media.find_by_path("media/a.mp4").await.unwrap();
+        assert_eq!(file.metadata, None);
+
+        let metadata = Metadata {
+            schema: schema::VERSION,
+            format: "mov,mp4,m4a,3gp,3g2,mj2".to_owned(),
+            duration: Some(10.0),
+            bit_rate: Some(1_000_000),
+            tags: BTreeMap::new(),
+            streams: Vec::new(),
+        };
+        media
+            .store_metadata(file.asset_id, &metadata)
+            .await
+            .unwrap();
+        let file = media.find(file.asset_id).await.unwrap();
+        assert_eq!(file.metadata, Some(metadata));
+        assert!(!file.upgraded);
+        assert!(matches!(
+            media.find(file.asset_id + 1).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+// Tags shared by everyone to organize the library with, and which of its files have them. Names
+// are unique ignoring case.
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::instrument;
+
+use crate::db::DbExecutor;
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    // how many files have it
+    pub files: i64,
+}
+
+#[async_trait]
+pub trait TagRepository: Send + Sync {
+    // ordered by name
+    async fn list(&self) -> Result<Vec<Tag>, sqlx::Error>;
+    async fn find(&self, id: i64) -> Result<Tag, sqlx::Error>;
+    // None when the name is taken
+    async fn create(&self, name: &str) -> Result<Option<Tag>, sqlx::Error>;
+    // None when the name is taken by another tag
+    async fn rename(&self, id: i64, name: &str) -> Result<Option<Tag>, sqlx::Error>;
+    // takes it off the files too
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error>;
+    // of a file of the library, ordered by name
+    async fn of_file(&self, asset_id: i64) -> Result<Vec<Tag>, sqlx::Error>;
+    // RowNotFound when there's no such file or tag, nothing happens when it's tagged already
+    async fn tag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error>;
+    // RowNotFound when the file doesn't have the tag
+    async fn untag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error>;
+}
+
+pub struct PgTags {
+    db: Arc<DbExecutor>,
+}
+
+impl PgTags {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgTags { db }
+    }
+}
+
+const SELECT: &str = "SELECT t.id, t.name, t.created_at,
+    (SELECT count(*) FROM asset_tags at WHERE at.tag_id = t.id) AS files FROM tags t";
+
+#[async_trait]
+impl TagRepository for PgTags {
+    #[instrument(level = "debug", skip(self))]
+    async fn list(&self) -> Result<Vec<Tag>, sqlx::Error> {
+        sqlx::query_as::<_, Tag>(&format!("{} ORDER BY lower(t.name)", SELECT))
+            .fetch_all(self.db.read())
+            .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, id: i64) -> Result<Tag, sqlx::Error> {
+        sqlx::query_as::<_, Tag>(&format!("{} WHERE t.id = $1", SELECT))
+            .bind(id)
+            .fetch_one(self.db.read())
+            .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn create(&self, name: &str) -> Result<Option<Tag>, sqlx::Error> {
+        sqlx::query_as::<_, Tag>(
+            "INSERT INTO tags (name) VALUES ($1) ON CONFLICT DO NOTHING
+                RETURNING id, name, created_at, 0::bigint AS files",
+        )
+        .bind(name)
+        .fetch_optional(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn rename(&self, id: i64, name: &str) -> Result<Option<Tag>, sqlx::Error> {
+        let renamed = sqlx::query("UPDATE tags SET name = $2 WHERE id = $1")
+            .bind(id)
+            .bind(name)
+            .execute(self.db.write())
+            .await;
+        match renamed {
+            Ok(done) if done.rows_affected() == 0 => Err(sqlx::Error::RowNotFound),
+            Ok(_) => self.find(id).await.map(Some),
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM tags WHERE id = $1")
+            .bind(id)
+            .execute(self.db.write())
+            .await?;
+        match deleted.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn of_file(&self, asset_id: i64) -> Result<Vec<Tag>, sqlx::Error> {
+        sqlx::query("SELECT 1 FROM library_files WHERE asset_id = $1")
+            .bind(asset_id)
+            .fetch_one(self.db.read())
+            .await?;
+        sqlx::query_as::<_, Tag>(&format!(
+            "{} JOIN asset_tags a ON a.tag_id = t.id WHERE a.asset_id = $1 ORDER BY lower(t.name)",
+            SELECT
+        ))
+        .bind(asset_id)
+        .fetch_all(self.db.read())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn tag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error> {
+        let found: bool = sqlx::query_scalar(
+            "WITH tagged AS (
+                INSERT INTO asset_tags (asset_id, tag_id)
+                    SELECT l.asset_id, t.id FROM library_files l, tags t
+                        WHERE l.asset_id = $1 AND t.id = $2
+                    ON CONFLICT DO NOTHING
+            )
+            SELECT EXISTS (SELECT 1 FROM library_files WHERE asset_id = $1)
+                AND EXISTS (SELECT 1 FROM tags WHERE id = $2)",
+        )
+        .bind(asset_id)
+        .bind(tag_id)
+        .fetch_one(self.db.write())
+        .await?;
+        match found {
+            true => Ok(()),
+            false => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn untag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM asset_tags WHERE asset_id = $1 AND tag_id = $2")
+            .bind(asset_id)
+            .bind(tag_id)
+            .execute(self.db.write())
+            .await?;
+        match deleted.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+// keeps tags in a Vec, for tests; every asset counts as a file of the library
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryTags {
+    tags: Mutex<Vec<Tag>>,
+    // asset and tag ids
+    tagged: Mutex<BTreeSet<(i64, i64)>>,
+}
+
+impl MemoryTags {
+    fn counted(&self, tag: &Tag) -> Tag {
+        let tagged = self.tagged.lock().unwrap();
+        Tag {
+            files: tagged.iter().filter(|(_, id)| *id == tag.id).count() as i64,
+            ..tag.clone()
+        }
+    }
+
+    fn taken(tags: &[Tag], name: &str, by_other_than: i64) -> bool {
+        tags.iter()
+            .any(|tag| tag.id != by_other_than && tag.name.to_lowercase() == name.to_lowercase())
+    }
+}
+
+#[async_trait]
+impl TagRepository for MemoryTags {
+    async fn list(&self) -> Result<Vec<Tag>, sqlx::Error> {
+        let mut tags: Vec<Tag> = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|tag| self.counted(tag))
+            .collect();
+        tags.sort_by_key(|tag| tag.name.to_lowercase());
+        Ok(tags)
+    }
+
+    async fn find(&self, id: i64) -> Result<Tag, sqlx::Error> {
+        let tags = self.tags.lock().unwrap();
+        let tag = tags.iter().find(|tag| tag.id == id);
+        tag.map(|tag| self.counted(tag))
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn create(&self, name: &str) -> Result<Option<Tag>, sqlx::Error> {
+        let mut tags = self.tags.lock().unwrap();
+        if Self::taken(&tags, name, 0) {
+            return Ok(None);
+        }
+        let tag = Tag {
+            id: tags.iter().map(|tag| tag.id).max().unwrap_or(0) + 1,
+            name: name.to_owned(),
+            created_at: Utc::now(),
+            files: 0,
+        };
+        tags.push(tag.clone());
+        Ok(Some(tag))
+    }
+
+    async fn rename(&self, id: i64, name: &str) -> Result<Option<Tag>, sqlx::Error> {
+        let mut tags = self.tags.lock().unwrap();
+        if !tags.iter().any(|tag| tag.id == id) {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        if Self::taken(&tags, name, id) {
+            return Ok(None);
+        }
+        let tag = tags.iter_mut().find(|tag| tag.id == id).unwrap();
+        tag.name = name.to_owned();
+        Ok(Some(self.counted(tag)))
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut tags = self.tags.lock().unwrap();
+        let before = tags.len();
+        tags.retain(|tag| tag.id != id);
+        if tags.len() == before {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        self.tagged.lock().unwrap().retain(|(_, tag)| *tag != id);
+        Ok(())
+    }
+
+    async fn of_file(&self, asset_id: i64) -> Result<Vec<Tag>, sqlx::Error> {
+        let ids: Vec<i64> = self
+            .tagged
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(asset, _)| *asset == asset_id)
+            .map(|(_, tag)| *tag)
+            .collect();
+        let mut tags = self.list().await?;
+        tags.retain(|tag| ids.contains(&tag.id));
+        Ok(tags)
+    }
+
+    async fn tag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error> {
+        self.find(tag_id).await?;
+        self.tagged.lock().unwrap().insert((asset_id, tag_id));
+        Ok(())
+    }
+
+    async fn untag(&self, asset_id: i64, tag_id: i64) -> Result<(), sqlx::Error> {
+        match self.tagged.lock().unwrap().remove(&(asset_id, tag_id)) {
+            true => Ok(()),
+            false => Err(sqlx::Error::RowNotFound),
+        }
+    }
+}
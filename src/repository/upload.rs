@@ -0,0 +1,307 @@
+// Resumable uploads: who is uploading what, and the asset it became once complete. The bytes
+// themselves are in files, see `upload`.
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::{instrument, warn};
+
+use crate::db::DbExecutor;
+
+// how long a lease on an upload lasts unless renewed, which its holder does every third of it
+const LEASE: Duration = Duration::from_secs(60);
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct Upload {
+    pub id: i64,
+    pub user_id: i64,
+    // as the client named it, without directories
+    pub file_name: String,
+    pub title: Option<String>,
+    // bytes it has once complete
+    pub length: i64,
+    pub asset_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+// held against other requests appending to the upload, in any process, until dropped
+pub struct UploadLock {
+    // releases it when dropped
+    _held: Box<dyn Send>,
+}
+
+#[async_trait]
+pub trait UploadRepository: Send + Sync {
+    async fn create(
+        &self,
+        user_id: i64,
+        file_name: &str,
+        title: Option<&str>,
+        length: i64,
+    ) -> Result<Upload, sqlx::Error>;
+    async fn find(&self, id: i64) -> Result<Upload, sqlx::Error>;
+    // None while another request holds it
+    async fn lock(&self, id: i64) -> Result<Option<UploadLock>, sqlx::Error>;
+    // registers the complete upload, moved to `path`, as an asset titled `title`; returns it
+    async fn complete(&self, id: i64, path: &str, title: &str) -> Result<i64, sqlx::Error>;
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error>;
+    // incomplete uploads created before `before`
+    async fn stale(&self, before: DateTime<Utc>) -> Result<Vec<Upload>, sqlx::Error>;
+}
+
+pub struct PgUploads {
+    db: Arc<DbExecutor>,
+}
+
+impl PgUploads {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgUploads { db }
+    }
+}
+
+#[async_trait]
+impl UploadRepository for PgUploads {
+    #[instrument(level = "debug", skip(self))]
+    async fn create(
+        &self,
+        user_id: i64,
+        file_name: &str,
+        title: Option<&str>,
+        length: i64,
+    ) -> Result<Upload, sqlx::Error> {
+        sqlx::query_as::<_, Upload>(
+            "INSERT INTO uploads (user_id, file_name, title, length) VALUES ($1, $2, $3, $4)
+                RETURNING *",
+        )
+        .bind(user_id)
+        .bind(file_name)
+        .bind(title)
+        .bind(length)
+        .fetch_one(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, id: i64) -> Result<Upload, sqlx::Error> {
+        // from the primary, a PATCH right after the POST must find it
+        sqlx::query_as::<_, Upload>("SELECT * FROM uploads WHERE id = $1")
+            .bind(id)
+            .fetch_one(self.db.write())
+            .await
+    }
+
+    // A lease on the upload's row, renewed while it's held and given up once dropped. The pool
+    // is only used for those short queries, however long appending takes; the lease of a server
+    // that went away runs out after LEASE.
+    #[instrument(level = "debug", skip(self))]
+    async fn lock(&self, id: i64) -> Result<Option<UploadLock>, sqlx::Error> {
+        let lease = OsRng.next_u64() as i64;
+        let leased = sqlx::query(
+            "UPDATE uploads SET lease = $2, leased_until = now() + make_interval(secs => $3)
+                WHERE id = $1 AND (leased_until IS NULL OR leased_until < now())",
+        )
+        .bind(id)
+        .bind(lease)
+        .bind(LEASE.as_secs_f64())
+        .execute(self.db.write())
+        .await?;
+        if leased.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let pool = self.db.write().clone();
+        let renewing = tokio::spawn(renew(pool.clone(), id, lease));
+        Ok(Some(UploadLock {
+            _held: Box::new(PgLease {
+                pool,
+                id,
+                lease,
+                renewing,
+            }),
+        }))
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn complete(&self, id: i64, path: &str, title: &str) -> Result<i64, sqlx::Error> {
+        let mut tx = self.db.write().begin().await?;
+        let asset_id: i64 =
+            sqlx::query_scalar("INSERT INTO assets (path, title) VALUES ($1, $2) RETURNING id")
+                .bind(path)
+                .bind(title)
+                .fetch_one(&mut *tx)
+                .await?;
+        sqlx::query("UPDATE uploads SET asset_id = $2 WHERE id = $1")
+            .bind(id)
+            .bind(asset_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(asset_id)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM uploads WHERE id = $1")
+            .bind(id)
+            .execute(self.db.write())
+            .await?;
+        match deleted.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn stale(&self, before: DateTime<Utc>) -> Result<Vec<Upload>, sqlx::Error> {
+        sqlx::query_as::<_, Upload>(
+            "SELECT * FROM uploads WHERE asset_id IS NULL AND created_at < $1",
+        )
+        .bind(before)
+        .fetch_all(self.db.write())
+        .await
+    }
+}
+
+// a lease taken by `PgUploads::lock`, given up when dropped
+struct PgLease {
+    pool: PgPool,
+    id: i64,
+    lease: i64,
+    renewing: JoinHandle<()>,
+}
+
+impl Drop for PgLease {
+    fn drop(&mut self) {
+        self.renewing.abort();
+        let (pool, id, lease) = (self.pool.clone(), self.id, self.lease);
+        // in the background, it runs out by itself should that fail
+        tokio::spawn(async move {
+            let released = sqlx::query(
+                "UPDATE uploads SET lease = NULL, leased_until = NULL WHERE id = $1 AND lease = $2",
+            )
+            .bind(id)
+            .bind(lease)
+            .execute(&pool)
+            .await;
+            if let Err(err) = released {
+                warn!("giving up the lease on upload {} failed: {}", id, err);
+            }
+        });
+    }
+}
+
+// extends the lease until aborted
+async fn renew(pool: PgPool, id: i64, lease: i64) {
+    let mut ticks = tokio::time::interval(LEASE / 3);
+    // the first tick is right away
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let renewed = sqlx::query(
+            "UPDATE uploads SET leased_until = now() + make_interval(secs => $3)
+                WHERE id = $1 AND lease = $2",
+        )
+        .bind(id)
+        .bind(lease)
+        .bind(LEASE.as_secs_f64())
+        .execute(&pool)
+        .await;
+        if let Err(err) = renewed {
+            warn!("renewing the lease on upload {} failed: {}", id, err);
+        }
+    }
+}
+
+// keeps uploads in a Vec, for tests; completing one makes up an asset id
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryUploads {
+    uploads: Mutex<Vec<Upload>>,
+    locked: Arc<Mutex<HashSet<i64>>>,
+}
+
+// an entry in `locked` until dropped
+struct MemoryLock {
+    locked: Arc<Mutex<HashSet<i64>>>,
+    id: i64,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locked.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl UploadRepository for MemoryUploads {
+    async fn create(
+        &self,
+        user_id: i64,
+        file_name: &str,
+        title: Option<&str>,
+        length: i64,
+    ) -> Result<Upload, sqlx::Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = Upload {
+            id: uploads.len() as i64 + 1,
+            user_id,
+            file_name: file_name.to_owned(),
+            title: title.map(str::to_owned),
+            length,
+            asset_id: None,
+            created_at: Utc::now(),
+        };
+        uploads.push(upload.clone());
+        Ok(upload)
+    }
+
+    async fn find(&self, id: i64) -> Result<Upload, sqlx::Error> {
+        let uploads = self.uploads.lock().unwrap();
+        let upload = uploads.iter().find(|upload| upload.id == id);
+        upload.cloned().ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn lock(&self, id: i64) -> Result<Option<UploadLock>, sqlx::Error> {
+        let locked = self.locked.lock().unwrap().insert(id);
+        Ok(locked.then(|| UploadLock {
+            _held: Box::new(MemoryLock {
+                locked: self.locked.clone(),
+                id,
+            }),
+        }))
+    }
+
+    async fn complete(&self, id: i64, _path: &str, _title: &str) -> Result<i64, sqlx::Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads.iter_mut().find(|upload| upload.id == id);
+        let upload = upload.ok_or(sqlx::Error::RowNotFound)?;
+        let asset_id = 100 + id;
+        upload.asset_id = Some(asset_id);
+        Ok(asset_id)
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), sqlx::Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let before = uploads.len();
+        uploads.retain(|upload| upload.id != id);
+        match uploads.len() == before {
+            true => Err(sqlx::Error::RowNotFound),
+            false => Ok(()),
+        }
+    }
+
+    async fn stale(&self, before: DateTime<Utc>) -> Result<Vec<Upload>, sqlx::Error> {
+        let uploads = self.uploads.lock().unwrap();
+        Ok(uploads
+            .iter()
+            .filter(|upload| upload.asset_id.is_none() && upload.created_at < before)
+            .cloned()
+            .collect())
+    }
+}
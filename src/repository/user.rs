@@ -0,0 +1,224 @@
+use std::sync::{Arc, Mutex};
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use tracing::instrument;
+
+use crate::{auth::Role, db::DbExecutor};
+
+// users are soft deleted, so admins can restore them: `deleted_at` is set and they're left out
+// unless asked for
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+pub(super) const COLUMNS: &str = "id, username, created_at, deleted_at";
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn create(&self, username: &str) -> Result<User, sqlx::Error>;
+    async fn find(&self, id: i64, include_deleted: bool) -> Result<User, sqlx::Error>;
+    // by id
+    async fn list(
+        &self,
+        include_deleted: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, sqlx::Error>;
+    // marks the user deleted at `at`, `RowNotFound` if there's none or it's deleted already
+    async fn delete(&self, id: i64, at: DateTime<Utc>) -> Result<User, sqlx::Error>;
+    // `RowNotFound` if there's no deleted user `id`
+    async fn restore(&self, id: i64) -> Result<User, sqlx::Error>;
+    // None if there's no user `id`, or it's deleted
+    async fn role(&self, id: i64) -> Result<Option<Role>, sqlx::Error>;
+}
+
+pub struct PgUsers {
+    db: Arc<DbExecutor>,
+}
+
+impl PgUsers {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        PgUsers { db }
+    }
+
+    // an account that can log in, for bootstrapping from the command line
+    #[instrument(level = "debug", skip(self, password_hash))]
+    pub async fn create_account(
+        &self,
+        username: &str,
+        password_hash: &str,
+        role: Role,
+        email: Option<&str>,
+    ) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "INSERT INTO users (username, password_hash, role, email) VALUES ($1, $2, $3, $4)
+                RETURNING {}",
+            COLUMNS
+        ))
+        .bind(username)
+        .bind(password_hash)
+        .bind(role.as_str())
+        .bind(email)
+        .fetch_one(self.db.write())
+        .await
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUsers {
+    #[instrument(level = "debug", skip(self))]
+    async fn create(&self, username: &str) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "INSERT INTO users (username) VALUES ($1) RETURNING {}",
+            COLUMNS
+        ))
+        .bind(username)
+        .fetch_one(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn find(&self, id: i64, include_deleted: bool) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+            COLUMNS
+        ))
+        .bind(id)
+        .bind(include_deleted)
+        .fetch_one(self.db.read())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn list(
+        &self,
+        include_deleted: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "SELECT {} FROM users WHERE $1 OR deleted_at IS NULL
+                ORDER BY id LIMIT $2 OFFSET $3",
+            COLUMNS
+        ))
+        .bind(include_deleted)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(self.db.read())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn delete(&self, id: i64, at: DateTime<Utc>) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET deleted_at = $2 WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+            COLUMNS
+        ))
+        .bind(id)
+        .bind(at)
+        .fetch_one(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn restore(&self, id: i64) -> Result<User, sqlx::Error> {
+        sqlx::query_as::<_, User>(&format!(
+            "UPDATE users SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL
+                RETURNING {}",
+            COLUMNS
+        ))
+        .bind(id)
+        .fetch_one(self.db.write())
+        .await
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn role(&self, id: i64) -> Result<Option<Role>, sqlx::Error> {
+        let role: Option<String> =
+            sqlx::query_scalar("SELECT role FROM users WHERE id = $1 AND deleted_at IS NULL")
+                .bind(id)
+                .fetch_optional(self.db.write())
+                .await?;
+        Ok(role.as_deref().map(Role::from_name))
+    }
+}
+
+// keeps users in a Vec, for tests
+#[derive(Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryUsers {
+    users: Mutex<Vec<User>>,
+}
+
+#[async_trait]
+impl UserRepository for MemoryUsers {
+    async fn create(&self, username: &str) -> Result<User, sqlx::Error> {
+        let mut users = self.users.lock().unwrap();
+        let user = User {
+            id: users.len() as i64 + 1,
+            username: username.to_owned(),
+            created_at: Utc::now(),
+            deleted_at: None,
+        };
+        users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn find(&self, id: i64, include_deleted: bool) -> Result<User, sqlx::Error> {
+        let users = self.users.lock().unwrap();
+        users
+            .iter()
+            .find(|user| user.id == id && (include_deleted || user.deleted_at.is_none()))
+            .cloned()
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn list(
+        &self,
+        include_deleted: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, sqlx::Error> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .filter(|user| include_deleted || user.deleted_at.is_none())
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete(&self, id: i64, at: DateTime<Utc>) -> Result<User, sqlx::Error> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == id && user.deleted_at.is_none())
+            .ok_or(sqlx::Error::RowNotFound)?;
+        user.deleted_at = Some(at);
+        Ok(user.clone())
+    }
+
+    async fn restore(&self, id: i64) -> Result<User, sqlx::Error> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == id && user.deleted_at.is_some())
+            .ok_or(sqlx::Error::RowNotFound)?;
+        user.deleted_at = None;
+        Ok(user.clone())
+    }
+
+    async fn role(&self, id: i64) -> Result<Option<Role>, sqlx::Error> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .any(|user| user.id == id && user.deleted_at.is_none())
+            .then_some(Role::User))
+    }
+}
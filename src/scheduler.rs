@@ -0,0 +1,355 @@
+use std::{str::FromStr, sync::Arc, time::SystemTime};
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc};
+use serde_derive::Deserialize;
+use tokio::{sync::watch, task::JoinSet};
+use tracing::{info, warn};
+
+use crate::{
+    job::Jobs,
+    library::Library,
+    metering::Metering,
+    repository::{idempotency, IdempotencyRepository},
+    session::Sessions,
+    task,
+    upload::Uploads,
+};
+
+// a periodic task from `[[scheduler.tasks]]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Entry {
+    schedule: Schedule,
+    #[serde(flatten)]
+    task: Task,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum Task {
+    // deletes job results written longer ago than that
+    CleanJobOutputs { max_age_hours: u64 },
+    // forgets finished and failed jobs last changed longer ago than that. Jobs of the last day
+    // are kept whatever the age, rate plans count transcodes over them.
+    PruneJobs { max_age_hours: u64 },
+    // drops request counters of users without requests in the metering window
+    PruneUsage,
+    // forgets Idempotency-Keys older than the day they're remembered for
+    PruneIdempotencyKeys,
+    // scans `library.root` for media files that are new, changed or gone
+    ScanLibrary,
+    // deletes uploads left incomplete for longer than that, with what they received
+    PruneUploads { max_age_hours: u64 },
+    // deletes cookie sessions that expired
+    PruneSessions,
+}
+
+// what the tasks work on
+pub struct Context {
+    pub jobs: Arc<Jobs>,
+    pub metering: Arc<Metering>,
+    pub idempotency: Arc<dyn IdempotencyRepository>,
+    pub library: Arc<Library>,
+    pub uploads: Arc<Uploads>,
+    pub sessions: Arc<Sessions>,
+}
+
+impl Task {
+    fn name(&self) -> &'static str {
+        match self {
+            Task::CleanJobOutputs { .. } => "clean_job_outputs",
+            Task::PruneJobs { .. } => "prune_jobs",
+            Task::PruneUsage => "prune_usage",
+            Task::PruneIdempotencyKeys => "prune_idempotency_keys",
+            Task::ScanLibrary => "scan_library",
+            Task::PruneUploads { .. } => "prune_uploads",
+            Task::PruneSessions => "prune_sessions",
+        }
+    }
+
+    // how many things were removed, or updated
+    async fn run(&self, context: &Context) -> Result<usize, String> {
+        match *self {
+            Task::CleanJobOutputs { max_age_hours } => {
+                let dir = context.jobs.output_dir().to_owned();
+                let max_age = std::time::Duration::from_secs(max_age_hours * 3600);
+                tokio::task::spawn_blocking(move || remove_older(&dir, max_age))
+                    .await
+                    .map_err(|err| err.to_string())?
+                    .map_err(|err| err.to_string())
+            }
+            Task::PruneJobs { max_age_hours } => {
+                let max_age = Duration::hours(max_age_hours as i64).max(Duration::days(1));
+                context
+                    .jobs
+                    .prune(Utc::now() - max_age)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            Task::PruneUsage => Ok(context.metering.prune()),
+            Task::PruneIdempotencyKeys => context
+                .idempotency
+                .prune(Utc::now() - idempotency::KEY_TTL)
+                .await
+                .map_err(|err| err.to_string()),
+            Task::ScanLibrary => context
+                .library
+                .scan_root()
+                .await
+                .map(|report| report.updated()),
+            Task::PruneUploads { max_age_hours } => {
+                let max_age = Duration::hours(max_age_hours as i64);
+                context.uploads.prune(Utc::now() - max_age).await
+            }
+            Task::PruneSessions => context
+                .sessions
+                .prune(Utc::now())
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
+// files directly in `dir` last modified more than `max_age` ago, a missing `dir` has none
+fn remove_older(dir: &std::path::Path, max_age: std::time::Duration) -> std::io::Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if metadata.is_file() && age > max_age {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Runs every entry at the times of its schedule until `shutdown` turns true. A task that is
+// running by then gets to finish, the returned handle completes once all of them did.
+pub fn start(
+    entries: Vec<Entry>,
+    context: Context,
+    shutdown: watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<()> {
+    let context = Arc::new(context);
+    let mut tasks = JoinSet::new();
+    for entry in entries {
+        tasks.spawn(run_entry(entry, context.clone(), shutdown.clone()));
+    }
+    task::spawn("scheduler", task::Kind::Work, async move {
+        while tasks.join_next().await.is_some() {}
+    })
+}
+
+async fn run_entry(entry: Entry, context: Arc<Context>, mut shutdown: watch::Receiver<bool>) {
+    let name = entry.task.name();
+    while !*shutdown.borrow() {
+        let Some(next) = entry.schedule.next_after(Utc::now()) else {
+            warn!("scheduled task {} never runs", name);
+            return;
+        };
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            // a dropped sender counts as shutting down too
+            _ = shutdown.changed() => continue,
+        }
+        match entry.task.run(&context).await {
+            Ok(count) => info!("scheduled task {} done, {} affected", name, count),
+            Err(err) => warn!("scheduled task {} failed: {}", name, err),
+        }
+    }
+}
+
+// A cron expression, in UTC: minute, hour, day of the month, month and day of the week (0 or 7
+// is Sunday). Fields take `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n`, and lists of those.
+// When both day fields are restricted either of them matching is enough, as with cron.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    // one bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields in {:?}", expression));
+        };
+        let weekdays = field(weekdays, 0, 7)?;
+        Ok(Schedule {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            // 7 is another Sunday
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            // `*/2` is unrestricted as well, as with cron
+            any_day: days.starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+// the bits of the values `field` allows, between `min` and `max`
+fn field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{:?} is not between {} and {}", value, min, max))
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `a/n` runs from a to the end
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        let step = match step {
+            Some(step) => step
+                .parse::<u32>()
+                .ok()
+                .filter(|step| *step > 0)
+                .ok_or_else(|| format!("bad step in {:?}", part))?,
+            None => 1,
+        };
+        if first > last {
+            return Err(format!("empty range {:?}", part));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Schedule {
+    // the first matching minute after `time`
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut next = time.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        // schedules that never match, like February 30th, would be looked for forever. Anything
+        // else matches within a leap year cycle.
+        let limit = next + Duration::days(5 * 366);
+        while next < limit {
+            if !self.day_matches(next) {
+                next = next
+                    .date_naive()
+                    .succ_opt()?
+                    .and_hms_opt(0, 0, 0)?
+                    .and_utc();
+            } else if self.hours & (1 << next.hour()) == 0 {
+                next = next.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+            } else if self.minutes & (1 << next.minute()) == 0 {
+                next += Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let day = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        };
+        day && self.months & (1 << time.month()) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn next(expression: &str, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        expression.parse::<Schedule>().unwrap().next_after(time)
+    }
+
+    #[test]
+    fn next_times() {
+        let now = at(2024, 2, 15, 10, 7);
+        assert_eq!(next("* * * * *", now), Some(at(2024, 2, 15, 10, 8)));
+        assert_eq!(next("*/15 * * * *", now), Some(at(2024, 2, 15, 10, 15)));
+        assert_eq!(next("30 3 * * *", now), Some(at(2024, 2, 16, 3, 30)));
+        assert_eq!(next("@hourly", now), Some(at(2024, 2, 15, 11, 0)));
+        // the 15th is a Thursday, 7 and 0 are both Sunday
+        assert_eq!(next("0 0 * * 7", now), Some(at(2024, 2, 18, 0, 0)));
+        assert_eq!(next("0 0 * * 1-5", now), Some(at(2024, 2, 16, 0, 0)));
+        // either day field matching is enough
+        assert_eq!(next("0 0 1 * 6", now), Some(at(2024, 2, 17, 0, 0)));
+        assert_eq!(next("0 0 29 2 *", now), Some(at(2024, 2, 29, 0, 0)));
+        assert_eq!(next("0 0 30 2 *", now), None);
+    }
+
+    #[test]
+    fn bad_expressions() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(expression.parse::<Schedule>().is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let entry: Entry = serde_json::from_value(serde_json::json!({
+            "task": "clean_job_outputs",
+            "schedule": "30 3 * * *",
+            "max_age_hours": 72,
+        }))
+        .unwrap();
+        assert_eq!(entry.task, Task::CleanJobOutputs { max_age_hours: 72 });
+        assert_eq!(entry.schedule, "30 3 * * *".parse().unwrap());
+    }
+}
@@ -0,0 +1,130 @@
+// Running the server as a service of the OS: under the Windows Service Control Manager, or as a
+// launchd daemon on macOS. `install` registers `rsapp service run` to start at boot in the
+// current directory, whose config.toml it reads; stopping the service shuts the server down
+// gracefully like Ctrl+C does.
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use serde_json::json;
+
+use crate::{
+    cli::{self, Failure, Report},
+    listen::Fds,
+};
+
+#[cfg(target_os = "macos")]
+mod launchd;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "macos")]
+use launchd as platform;
+#[cfg(windows)]
+use windows as platform;
+
+pub const NAME: &str = "rsapp";
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Action {
+    // registers the service, started at boot and restarted when it fails
+    Install {
+        #[arg(short, long, default_value = "9009")]
+        port: String,
+    },
+    // stops and removes the service
+    Uninstall,
+    // what the service manager starts
+    Run {
+        #[arg(short, long, default_value = "9009")]
+        port: String,
+        // to run in instead of the current directory
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+pub fn install(port: &str) -> Result<Report, Failure> {
+    let failed = |err: String| Failure::new(cli::FAILED, format!("can't install: {}", err));
+    let exe = std::env::current_exe().map_err(|err| failed(err.to_string()))?;
+    let dir = std::env::current_dir().map_err(|err| failed(err.to_string()))?;
+    let arguments = [
+        "service".to_owned(),
+        "run".to_owned(),
+        "--port".to_owned(),
+        port.to_owned(),
+        "--dir".to_owned(),
+        dir.to_string_lossy().into_owned(),
+    ];
+    platform::install(&exe, &dir, &arguments).map_err(failed)?;
+    Ok(Report::new(
+        format!(
+            "installed service {}, serving port {} from {}",
+            NAME,
+            port,
+            dir.display()
+        ),
+        json!({"service": NAME, "port": port, "dir": dir}),
+    ))
+}
+
+pub fn uninstall() -> Result<Report, Failure> {
+    platform::uninstall()
+        .map_err(|err| Failure::new(cli::FAILED, format!("can't uninstall: {}", err)))?;
+    Ok(Report::new(
+        format!("uninstalled service {}", NAME),
+        json!({ "service": NAME }),
+    ))
+}
+
+// serves until stopped; only fails before that, when `dir` can't be changed to
+pub async fn run(port: &str, dir: Option<PathBuf>) -> Result<(), Failure> {
+    if let Some(dir) = dir {
+        std::env::set_current_dir(&dir).map_err(|err| {
+            let message = format!("can't change to {}: {}", dir.display(), err);
+            Failure::new(cli::INVALID_ARGUMENTS, message)
+        })?;
+    }
+    platform::run(port).await;
+    Ok(())
+}
+
+// launchd stops daemons with SIGTERM, which the server handles anyway
+#[cfg(not(windows))]
+async fn run_until_signal(port: &str) {
+    let served = match crate::configure() {
+        Ok(conf) => {
+            crate::serve(
+                conf,
+                port,
+                false,
+                Fds::default(),
+                false,
+                crate::shutdown_signal(),
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+    crate::exit_on_error(served)
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod platform {
+    use std::path::Path;
+
+    const UNSUPPORTED: &str =
+        "services are supported on Windows and macOS, run `rsapp server` from a systemd unit or \
+         the like elsewhere";
+
+    pub fn install(_exe: &Path, _dir: &Path, _arguments: &[String]) -> Result<(), String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        Err(UNSUPPORTED.to_owned())
+    }
+
+    pub async fn run(port: &str) {
+        super::run_until_signal(port).await
+    }
+}
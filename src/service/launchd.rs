@@ -0,0 +1,89 @@
+use std::{path::Path, process::Command};
+
+use super::NAME;
+
+const LABEL: &str = "io.github.donnol.rsapp";
+// seconds launchd waits after SIGTERM before killing the server, for running jobs to finish
+const EXIT_TIMEOUT: u32 = 60;
+
+fn plist_path() -> String {
+    format!("/Library/LaunchDaemons/{}.plist", LABEL)
+}
+
+// the daemon's description: restarted when it exits, logging to `dir`
+fn plist(exe: &Path, dir: &Path, arguments: &[String]) -> String {
+    let arguments: String = std::iter::once(exe.to_string_lossy().into_owned())
+        .chain(arguments.iter().cloned())
+        .map(|argument| format!("        <string>{}</string>\n", escape(&argument)))
+        .collect();
+    let dir = escape(&dir.to_string_lossy());
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>WorkingDirectory</key>
+    <string>{dir}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ExitTimeOut</key>
+    <integer>{timeout}</integer>
+    <key>StandardOutPath</key>
+    <string>{dir}/{name}.log</string>
+    <key>StandardErrorPath</key>
+    <string>{dir}/{name}.log</string>
+</dict>
+</plist>
+"#,
+        label = LABEL,
+        arguments = arguments,
+        dir = dir,
+        timeout = EXIT_TIMEOUT,
+        name = NAME,
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn launchctl(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("launchctl")
+        .args(args)
+        .output()
+        .map_err(|err| format!("can't run launchctl: {}", err))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+    }
+}
+
+pub fn install(exe: &Path, dir: &Path, arguments: &[String]) -> Result<(), String> {
+    let path = plist_path();
+    std::fs::write(&path, plist(exe, dir, arguments))
+        .map_err(|err| format!("can't write {}, run as root: {}", path, err))?;
+    launchctl(&["bootstrap", "system", &path])
+}
+
+pub fn uninstall() -> Result<(), String> {
+    // stops it with SIGTERM first
+    launchctl(&["bootout", &format!("system/{}", LABEL)])?;
+    std::fs::remove_file(plist_path()).map_err(|err| err.to_string())
+}
+
+pub async fn run(port: &str) {
+    super::run_until_signal(port).await
+}
@@ -0,0 +1,191 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use tracing::{error, warn};
+use tracing_subscriber::filter::LevelFilter;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use super::NAME;
+use crate::{listen::Fds, logging};
+
+// how long the service manager is told stopping may take, for running jobs to finish
+const STOP_WAIT: Duration = Duration::from_secs(60);
+
+// the port `run` was given, for `service_main`
+static PORT: OnceLock<String> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+pub fn install(exe: &Path, _dir: &Path, arguments: &[String]) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|err| err.to_string())?;
+    let info = ServiceInfo {
+        name: OsString::from(NAME),
+        display_name: OsString::from(NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: exe.to_owned(),
+        launch_arguments: arguments.iter().map(OsString::from).collect(),
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|err| err.to_string())?;
+    service
+        .set_description("rsapp media server")
+        .map_err(|err| err.to_string())
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|err| err.to_string())?;
+    let service = manager
+        .open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|err| err.to_string())?;
+    let status = service.query_status().map_err(|err| err.to_string())?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().map_err(|err| err.to_string())?;
+    }
+    // removed once the last handle to it is closed
+    service.delete().map_err(|err| err.to_string())
+}
+
+// hands the process to the service manager, which calls `service_main` on a thread of its own
+pub async fn run(port: &str) {
+    let _ = PORT.set(port.to_owned());
+    // services have no console, logs go next to the configuration; from `log_level` on once
+    // the service reads it
+    match File::create(format!("{}.log", NAME)) {
+        Ok(log) => logging::init_to(LevelFilter::INFO, Mutex::new(log), false),
+        Err(_) => logging::init(LevelFilter::INFO),
+    }
+    if let Err(err) = service_dispatcher::start(NAME, ffi_service_main) {
+        error!(
+            "can't run as a service, only the service manager can: {}",
+            err
+        );
+        std::process::exit(1);
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let stop = Mutex::new(Some(stop));
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = stop.lock().unwrap().take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(NAME, handler) {
+        Ok(status) => status,
+        Err(err) => {
+            error!("can't register with the service manager: {}", err);
+            return;
+        }
+    };
+    report(
+        status,
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        Duration::default(),
+    );
+    let port = PORT.get().cloned().unwrap_or_else(|| "9009".to_owned());
+    let shutdown = async move {
+        let _ = stopped.await;
+        report(
+            status,
+            ServiceState::StopPending,
+            ServiceControlAccept::empty(),
+            STOP_WAIT,
+        );
+    };
+    let served = crate::load_conf().and_then(|conf| {
+        // validated with the rest of the configuration
+        let level = logging::parse(&conf.log_level).unwrap_or(LevelFilter::INFO);
+        if let Err(err) = logging::set_level(level) {
+            warn!("keeping the log level at info: {}", err);
+        }
+        let runtime = tokio::runtime::Runtime::new()
+            .map_err(|err| format!("can't start the runtime: {}", err))?;
+        runtime.block_on(crate::serve(
+            conf,
+            &port,
+            false,
+            Fds::default(),
+            false,
+            shutdown,
+        ))
+    });
+    let exit_code = match served {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("{}", err);
+            1
+        }
+    };
+    report_exit(status, exit_code);
+}
+
+// tells the service manager it stopped, with `exit_code` other than 0 when that was on an error
+fn report_exit(status: ServiceStatusHandle, exit_code: u32) {
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::Stopped,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    });
+    if let Err(err) = result {
+        error!("can't tell the service manager it stopped: {}", err);
+    }
+}
+
+fn report(
+    status: ServiceStatusHandle,
+    state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    wait_hint: Duration,
+) {
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+    if let Err(err) = result {
+        error!("can't tell the service manager it's {:?}: {}", state, err);
+    }
+}
@@ -0,0 +1,380 @@
+// Cookie sessions for browsers, such as the bundled web UI, so they don't keep credentials where
+// scripts can read them. `POST /auth/login` checks a username, or email, and password and sets an
+// HttpOnly, SameSite cookie; from then on `CurrentUser` is the user of the session. Sessions are
+// kept in Postgres by tower-sessions, so they survive restarts and are shared by the instances.
+//
+// Browsers send cookies along with requests other sites trigger too, so changes made through a
+// session need the `csrf_token` of the login as `X-CSRF-Token`; reads don't. Clients identified
+// by `X-User-Id` from a trusted proxy, with `auth.trusted_proxy`, are unaffected.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{request::Parts, Method, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tower_sessions::{
+    cookie::{time::Duration, SameSite},
+    Expiry, Session, SessionManagerLayer,
+};
+use tower_sessions_sqlx_store::PostgresStore;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app::AppState,
+    auth::{self, ClientAddress, Role},
+    db_error, internal_error,
+    password::Passwords,
+    repository::UserRepository,
+    validation::Valid,
+};
+
+// of `migrations/20240305000001_sessions`
+const TABLE: &str = "sessions";
+
+// where the signed in user is kept in a session
+const USER: &str = "user";
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// `[sessions]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // off, `POST /auth/login` answers 404 and only `X-User-Id` from a trusted proxy tells users
+    pub enabled: bool,
+    pub cookie: String,
+    // cookies only sent over HTTPS, browsers make an exception for localhost
+    pub secure: bool,
+    // "strict" doesn't send the cookie when following links from other sites either
+    pub same_site: SameSiteSetting,
+    // minutes without requests before a session expires
+    pub idle_timeout: i64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            enabled: true,
+            cookie: "rsapp_session".to_owned(),
+            secure: true,
+            same_site: SameSiteSetting::Lax,
+            idle_timeout: 7 * 24 * 60,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.idle_timeout < 1 {
+            return Err("sessions.idle_timeout must be at least 1 minute".to_owned());
+        }
+        if self.cookie.is_empty()
+            || !self
+                .cookie
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err("sessions.cookie takes letters, digits and _".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSiteSetting {
+    Strict,
+    Lax,
+}
+
+pub struct Sessions {
+    settings: Settings,
+    pool: PgPool,
+}
+
+impl Sessions {
+    pub fn new(settings: Settings, pool: PgPool) -> Self {
+        Sessions { settings, pool }
+    }
+
+    // what keeps the session of requests, None when sessions are off
+    pub fn layer(&self) -> Option<SessionManagerLayer<PostgresStore>> {
+        if !self.settings.enabled {
+            return None;
+        }
+        let store = PostgresStore::new(self.pool.clone())
+            .with_schema_name("public")
+            .and_then(|store| store.with_table_name(TABLE))
+            .expect("the session table has a valid name");
+        let same_site = match self.settings.same_site {
+            SameSiteSetting::Strict => SameSite::Strict,
+            SameSiteSetting::Lax => SameSite::Lax,
+        };
+        Some(
+            SessionManagerLayer::new(store)
+                .with_name(self.settings.cookie.clone())
+                .with_secure(self.settings.secure)
+                .with_http_only(true)
+                .with_same_site(same_site)
+                .with_expiry(Expiry::OnInactivity(Duration::minutes(
+                    self.settings.idle_timeout,
+                ))),
+        )
+    }
+
+    // deletes the sessions expired by `now`, signed out ones are gone already
+    pub async fn prune(&self, now: DateTime<Utc>) -> Result<usize, sqlx::Error> {
+        let done = sqlx::query(&format!("DELETE FROM {} WHERE expiry_date < $1", TABLE))
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(done.rows_affected() as usize)
+    }
+}
+
+// who a session is signed in as
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq)]
+pub struct SessionUser {
+    pub id: i64,
+    pub username: String,
+    // to send as `X-CSRF-Token` with changes
+    pub csrf_token: String,
+    // of the account's role as of the request, see `auth::Admin`
+    #[serde(default)]
+    pub admin: bool,
+}
+
+// the user of the session of the request, if it's signed in. Changes also need the CSRF token
+// of the session, or they're refused with 403. The role is looked up every time, so admins
+// demoted since signing in aren't admins anymore, and sessions of accounts deleted are signed out.
+pub async fn user(parts: &Parts) -> Result<Option<SessionUser>, (StatusCode, String)> {
+    let Some(session) = parts.extensions.get::<Session>() else {
+        return Ok(None);
+    };
+    let Some(mut user) = session
+        .get::<SessionUser>(USER)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok(None);
+    };
+    check_csrf(parts, &user)?;
+    let users = parts.extensions.get::<Arc<dyn UserRepository>>().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "sessions need the users".to_owned(),
+    ))?;
+    let Some(role) = users.role(user.id).await.map_err(db_error)? else {
+        session.flush().await.map_err(internal_error)?;
+        return Ok(None);
+    };
+    user.admin = role == Role::Admin;
+    Ok(Some(user))
+}
+
+fn check_csrf(parts: &Parts, user: &SessionUser) -> Result<(), (StatusCode, String)> {
+    if matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let given = parts
+        .headers
+        .get(CSRF_HEADER)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    if !auth::constant_time_eq(given, user.csrf_token.as_bytes()) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "missing or wrong X-CSRF-Token for the session".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+// signs `session` in as user `id`, under a new session id so one planted before doesn't follow
+pub async fn sign_in(
+    session: &Session,
+    users: &dyn UserRepository,
+    id: i64,
+    username: String,
+) -> Result<SessionUser, (StatusCode, String)> {
+    let role = users.role(id).await.map_err(db_error)?;
+    session.cycle_id().await.map_err(internal_error)?;
+    let user = SessionUser {
+        id,
+        username,
+        csrf_token: auth::random_token(),
+        admin: role == Some(Role::Admin),
+    };
+    session.insert(USER, &user).await.map_err(internal_error)?;
+    info!("user {} signed in", id);
+    Ok(user)
+}
+
+// the session, when sessions are on
+pub fn enabled(session: Option<Session>) -> Result<Session, (StatusCode, String)> {
+    session.ok_or((StatusCode::NOT_FOUND, "sessions are disabled".to_owned()))
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct Login {
+    // the username or email of the account
+    #[validate(length(min = 1, max = 320))]
+    login: String,
+    #[validate(length(min = 1, max = 1024))]
+    password: String,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/login", post(login))
+        .route("/auth/logout", post(logout))
+        .route("/auth/session", get(current_session))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/login",
+    request_body = Login,
+    responses(
+        (status = 200, description = "Signed in, the session cookie is set", body = SessionUser),
+        (status = 401, description = "Wrong login or password"),
+        (status = 404, description = "Sessions are disabled"),
+        (status = 422, description = "Invalid login or password", body = Invalid),
+        (status = 429, description = "Too many failed attempts within the hour"),
+    ),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn login(
+    State(passwords): State<Arc<Passwords>>,
+    State(users): State<Arc<dyn UserRepository>>,
+    client: ClientAddress,
+    session: Option<Session>,
+    Valid(payload): Valid<Login>,
+) -> Result<Json<SessionUser>, (StatusCode, String)> {
+    let session = enabled(session)?;
+    let account = passwords
+        .verify(&client, &payload.login, &payload.password)
+        .await?;
+    let user = sign_in(&session, users.as_ref(), account.id, account.username).await?;
+    Ok(Json(user))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/auth/logout",
+    responses(
+        (status = 204, description = "Signed out, whether the session was signed in or not"),
+        (status = 403, description = "Missing or wrong X-CSRF-Token"),
+        (status = 404, description = "Sessions are disabled"),
+    ),
+    security(("session" = [])),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn logout(
+    session: Option<Session>,
+    request: Request,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let session = enabled(session)?;
+    let (parts, _) = request.into_parts();
+    if let Some(user) = user(&parts).await? {
+        info!("user {} signed out", user.id);
+    }
+    session.flush().await.map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/auth/session",
+    responses(
+        (status = 200, description = "Who the session is signed in as", body = SessionUser),
+        (status = 401, description = "Not signed in"),
+        (status = 404, description = "Sessions are disabled"),
+    ),
+    security(("session" = [])),
+    tag = "users"
+)]
+#[instrument(skip_all)]
+async fn current_session(
+    session: Option<Session>,
+    request: Request,
+) -> Result<Json<SessionUser>, (StatusCode, String)> {
+    enabled(session)?;
+    let (parts, _) = request.into_parts();
+    user(&parts)
+        .await?
+        .map(Json)
+        .ok_or((StatusCode::UNAUTHORIZED, "not signed in".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use tower_sessions::MemoryStore;
+
+    use super::*;
+    use crate::repository::user::MemoryUsers;
+
+    fn parts(method: Method, token: Option<&str>) -> Parts {
+        let mut request = Request::builder().method(method).uri("/api/v1/playlists");
+        if let Some(token) = token {
+            request = request.header(CSRF_HEADER, token);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn changes_need_the_csrf_token() {
+        let user = SessionUser {
+            id: 1,
+            username: "jd".to_owned(),
+            csrf_token: "abc".to_owned(),
+            admin: false,
+        };
+        assert!(check_csrf(&parts(Method::GET, None), &user).is_ok());
+        assert!(check_csrf(&parts(Method::POST, Some("abc")), &user).is_ok());
+        let missing = check_csrf(&parts(Method::POST, None), &user);
+        assert_eq!(missing.unwrap_err().0, StatusCode::FORBIDDEN);
+        let wrong = check_csrf(&parts(Method::DELETE, Some("abd")), &user);
+        assert_eq!(wrong.unwrap_err().0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn signs_out_deleted_users() {
+        let users: Arc<dyn UserRepository> = Arc::new(MemoryUsers::default());
+        let jd = users.create("jd").await.unwrap();
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        sign_in(&session, users.as_ref(), jd.id, jd.username.clone())
+            .await
+            .unwrap();
+        let mut parts = parts(Method::GET, None);
+        parts.extensions.insert(session);
+        parts.extensions.insert(users.clone());
+        let signed_in = user(&parts).await.unwrap();
+        assert_eq!(
+            signed_in.map(|user| (user.id, user.admin)),
+            Some((jd.id, false))
+        );
+
+        users.delete(jd.id, Utc::now()).await.unwrap();
+        assert_eq!(user(&parts).await.unwrap(), None);
+    }
+
+    #[test]
+    fn validates_settings() {
+        assert!(Settings::default().validate().is_ok());
+        let cookie = Settings {
+            cookie: "a session".to_owned(),
+            ..Settings::default()
+        };
+        assert!(cookie.validate().is_err());
+    }
+}
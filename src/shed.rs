@@ -0,0 +1,214 @@
+// Load shedding: the server handles at most `[server.limits]` requests at a time and answers more
+// with `503 Service Unavailable` right away, instead of queueing them until clients time out, which
+// only makes an overloaded server slower. Media routes, which stream, transcode or probe files,
+// have a budget of their own, so a burst of downloads doesn't starve the cheap JSON routes, nor
+// the other way around. A request keeps its place until its response is sent, downloads included.
+//
+// Admin endpoints, readiness checks and the long lived event streams aren't limited.
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_derive::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_stream::StreamExt;
+use tracing::debug;
+
+use crate::admin;
+
+// how long clients are asked to wait before trying again, in seconds
+const RETRY_AFTER: &str = "1";
+
+// routes of the media budget, as registered without the `/api/v<n>` prefix
+const MEDIA: &[&str] = &[
+    "/assets/:id/video",
+    "/assets/:id/thumbnail",
+    "/audio/waveform",
+    "/federation/peers/:peer/assets/:id/media",
+    "/federation/v1/assets/:id/media",
+    "/files/*key",
+    "/files/:id/content",
+    "/jobs/:id/download",
+    "/uploads",
+    "/uploads/:id",
+    "/video/clip",
+    "/video/keyframes",
+    "/video/metadata",
+    "/video/remux",
+];
+
+// routes left out, besides those under /admin
+const UNLIMITED: &[&str] = &["/readyz", "/events", "/ws/jobs/:id"];
+
+// `[server.limits]` in the config, 0 for no limit
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Limits {
+    // requests handled at a time, but for media routes
+    pub requests: usize,
+    // requests to media routes handled at a time
+    pub media_requests: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            requests: 1024,
+            media_requests: 64,
+        }
+    }
+}
+
+pub struct Shedder {
+    requests: Option<Arc<Semaphore>>,
+    media: Option<Arc<Semaphore>>,
+}
+
+impl Shedder {
+    pub fn new(limits: &Limits) -> Self {
+        let semaphore = |limit| (limit > 0).then(|| Arc::new(Semaphore::new(limit)));
+        Shedder {
+            requests: semaphore(limits.requests),
+            media: semaphore(limits.media_requests),
+        }
+    }
+
+    // None without a limit
+    fn semaphore(&self, budget: Budget) -> Option<&Arc<Semaphore>> {
+        match budget {
+            Budget::Requests => self.requests.as_ref(),
+            Budget::Media => self.media.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Budget {
+    Requests,
+    Media,
+}
+
+// the budget of the route registered as `path`, None for unlimited ones
+fn budget(path: &str) -> Option<Budget> {
+    let route = unversioned(path);
+    if admin::is_admin_path(route) || UNLIMITED.contains(&route) {
+        None
+    } else if is_media(route) {
+        Some(Budget::Media)
+    } else {
+        Some(Budget::Requests)
+    }
+}
+
+// whether the route registered as `path` streams, transcodes or probes files
+pub fn is_media(path: &str) -> bool {
+    MEDIA.contains(&unversioned(path))
+}
+
+// `path` without its `/api/v<n>` prefix if it has one
+pub fn unversioned(path: &str) -> &str {
+    let Some(rest) = path.strip_prefix("/api/v") else {
+        return path;
+    };
+    let version = rest.find('/').unwrap_or(rest.len());
+    match rest[..version].parse::<u32>() {
+        Ok(_) => &rest[version..],
+        Err(_) => path,
+    }
+}
+
+// middleware answering 503 when the budget of the route is used up
+pub async fn shed(State(shedder): State<Arc<Shedder>>, request: Request, next: Next) -> Response {
+    // paths no route matches, such as those of the web UI, count as JSON routes
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", MatchedPath::as_str);
+    let Some(semaphore) = budget(path).and_then(|budget| shedder.semaphore(budget)) else {
+        return next.run(request).await;
+    };
+    let Ok(permit) = semaphore.clone().try_acquire_owned() else {
+        debug!(path, "shedding a request, the server is saturated");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER)],
+            "the server is saturated, try again shortly",
+        )
+            .into_response();
+    };
+    let response = next.run(request).await;
+    // bodies in memory are as good as sent, streamed ones take the permit along
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+    response.map(|body| Body::from_stream(with_permit(body, permit)))
+}
+
+// `body` as a stream holding on to `permit` until it's done
+fn with_permit(
+    body: Body,
+    permit: OwnedSemaphorePermit,
+) -> impl tokio_stream::Stream<Item = Result<axum::body::Bytes, axum::Error>> {
+    body.into_data_stream().map(move |chunk| {
+        let _ = &permit;
+        chunk
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn budgets_by_route() {
+        assert_eq!(budget("/api/v1/users/:id"), Some(Budget::Requests));
+        assert_eq!(budget("/api/v2/video/clip"), Some(Budget::Media));
+        assert_eq!(budget("/video/clip"), Some(Budget::Media));
+        assert_eq!(budget("/api/v1/uploads/:id"), Some(Budget::Media));
+        assert_eq!(budget("/api/vx/video/clip"), Some(Budget::Requests));
+        assert_eq!(budget(""), Some(Budget::Requests));
+        assert_eq!(budget("/admin/runtime"), None);
+        assert_eq!(budget("/readyz"), None);
+        let unlimited = Shedder::new(&Limits {
+            requests: 0,
+            media_requests: 1,
+        });
+        assert!(unlimited.semaphore(Budget::Requests).is_none());
+    }
+
+    #[tokio::test]
+    async fn sheds_when_the_budget_is_used_up() {
+        let shedder = Arc::new(Shedder::new(&Limits {
+            requests: 1,
+            media_requests: 1,
+        }));
+        let router = Router::new()
+            .route("/users", get(|| async { "users" }))
+            .route("/video/keyframes", get(|| async { "keyframes" }))
+            .layer(middleware::from_fn_with_state(shedder.clone(), shed));
+        let call = |path: &str| {
+            let request = axum::http::Request::get(path).body(Body::empty()).unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let downloading = shedder.media.clone().unwrap().try_acquire_owned().unwrap();
+        let response = call("/video/keyframes").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER);
+        assert_eq!(call("/users").await.unwrap().status(), StatusCode::OK);
+
+        drop(downloading);
+        assert_eq!(
+            call("/video/keyframes").await.unwrap().status(),
+            StatusCode::OK
+        );
+    }
+}
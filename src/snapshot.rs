@@ -0,0 +1,280 @@
+// Snapshots of expensive admin aggregates, like uptime reports over a year of health checks.
+// The last computed value of each is kept in memory and in Postgres. Within `ttl` it's answered
+// as it is; after that, or when it was loaded after a restart, it's still answered right away,
+// marked stale, while a fresh one is computed in the background. Only an aggregate nobody asked
+// for before is computed while the request waits.
+//
+// Answers say how old they are with `Age` and `X-Computed-At`, and `X-Stale: true` when a
+// fresher one is on its way.
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    repository::{snapshot::Snapshot, SnapshotRepository},
+    task,
+};
+
+const COMPUTED_AT: HeaderName = HeaderName::from_static("x-computed-at");
+const STALE: HeaderName = HeaderName::from_static("x-stale");
+
+// `[snapshots]` in the config
+#[derive(Deserialize, Debug, Clone)]
+pub struct Settings {
+    // seconds a computed aggregate is answered as fresh
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+fn default_ttl() -> u64 {
+    300
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { ttl: default_ttl() }
+    }
+}
+
+struct Kept {
+    snapshot: Snapshot,
+    // by an earlier process, answered as stale until computed again
+    loaded: bool,
+}
+
+pub struct Snapshots {
+    repository: Arc<dyn SnapshotRepository>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    kept: Mutex<HashMap<String, Kept>>,
+    refreshing: Mutex<HashSet<String>>,
+}
+
+// an aggregate as answered, how old it is in the headers
+#[derive(Debug)]
+pub struct Cached<T> {
+    pub value: T,
+    pub computed_at: DateTime<Utc>,
+    // seconds since, as of answering
+    pub age: i64,
+    pub stale: bool,
+}
+
+impl<T: Serialize> IntoResponse for Cached<T> {
+    fn into_response(self) -> Response {
+        let mut response = Json(self.value).into_response();
+        let headers = response.headers_mut();
+        headers.insert("age", HeaderValue::from(self.age));
+        if let Ok(value) = HeaderValue::from_str(&self.computed_at.to_rfc3339()) {
+            headers.insert(COMPUTED_AT, value);
+        }
+        if self.stale {
+            headers.insert(STALE, HeaderValue::from_static("true"));
+        }
+        response
+    }
+}
+
+impl Snapshots {
+    pub fn new(settings: &Settings, repository: Arc<dyn SnapshotRepository>) -> Self {
+        Snapshots {
+            repository,
+            ttl: Duration::seconds(settings.ttl as i64),
+            clock: Arc::new(SystemClock),
+            kept: Mutex::new(HashMap::new()),
+            refreshing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // tells how old snapshots are by `clock`
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // the aggregate `name`, from its last snapshot when there is one, `compute`d otherwise
+    pub async fn get<T, F, Fut>(
+        self: &Arc<Self>,
+        name: &str,
+        compute: F,
+    ) -> Result<Cached<T>, (StatusCode, String)>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, (StatusCode, String)>> + Send + 'static,
+    {
+        if let Some((snapshot, stale)) = self.last(name).await {
+            if let Ok(value) = serde_json::from_value(snapshot.value) {
+                if stale {
+                    self.refresh(name, compute);
+                }
+                return Ok(Cached {
+                    value,
+                    computed_at: snapshot.computed_at,
+                    age: (self.clock.now() - snapshot.computed_at)
+                        .num_seconds()
+                        .max(0),
+                    stale,
+                });
+            }
+            // written by an older version in another shape
+        }
+        let computed_at = self.clock.now();
+        let value = compute().await?;
+        if let Ok(json) = serde_json::to_value(&value) {
+            self.store(name, json, computed_at).await;
+        }
+        Ok(Cached {
+            value,
+            computed_at,
+            age: 0,
+            stale: false,
+        })
+    }
+
+    // the last snapshot of `name` and whether it's stale, from Postgres after a restart
+    async fn last(&self, name: &str) -> Option<(Snapshot, bool)> {
+        let kept = self.kept.lock().unwrap().get(name).map(|kept| {
+            let stale = kept.loaded || self.clock.now() - kept.snapshot.computed_at > self.ttl;
+            (kept.snapshot.clone(), stale)
+        });
+        if kept.is_some() {
+            return kept;
+        }
+        let snapshot = match self.repository.load(name).await {
+            Ok(snapshot) => snapshot?,
+            Err(err) => {
+                warn!("can't load the snapshot of {}: {}", name, err);
+                return None;
+            }
+        };
+        let mut kept = self.kept.lock().unwrap();
+        let kept = kept.entry(name.to_owned()).or_insert(Kept {
+            snapshot,
+            loaded: true,
+        });
+        Some((kept.snapshot.clone(), true))
+    }
+
+    // computes `name` in the background, unless that's already happening
+    fn refresh<T, F, Fut>(self: &Arc<Self>, name: &str, compute: F)
+    where
+        T: Serialize + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, (StatusCode, String)>> + Send + 'static,
+    {
+        if !self.refreshing.lock().unwrap().insert(name.to_owned()) {
+            return;
+        }
+        let snapshots = self.clone();
+        let name = name.to_owned();
+        let task_name = format!("snapshot refresh {}", name);
+        task::spawn(task_name, task::Kind::Work, async move {
+            let computed_at = snapshots.clock.now();
+            match compute().await {
+                Ok(value) => match serde_json::to_value(value) {
+                    Ok(json) => snapshots.store(&name, json, computed_at).await,
+                    Err(err) => warn!("can't keep {}: {}", name, err),
+                },
+                Err((_, err)) => warn!("can't compute {}: {}", name, err),
+            }
+            snapshots.refreshing.lock().unwrap().remove(&name);
+        });
+    }
+
+    async fn store(&self, name: &str, value: Value, computed_at: DateTime<Utc>) {
+        let snapshot = Snapshot { value, computed_at };
+        self.kept.lock().unwrap().insert(
+            name.to_owned(),
+            Kept {
+                snapshot: snapshot.clone(),
+                loaded: false,
+            },
+        );
+        if let Err(err) = self.repository.save(name, &snapshot).await {
+            warn!("can't store the snapshot of {}: {}", name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::FixedClock, repository::snapshot::MemorySnapshots};
+    use std::{
+        future::{ready, Ready},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    // counts the times it's computed, which is what it computes
+    fn counting(
+        computed: &Arc<AtomicU64>,
+    ) -> impl FnOnce() -> Ready<Result<u64, (StatusCode, String)>> + Send + 'static {
+        let computed = computed.clone();
+        move || ready(Ok(computed.fetch_add(1, Ordering::SeqCst) + 1))
+    }
+
+    #[tokio::test]
+    async fn answers_last_known() {
+        let repository: Arc<dyn SnapshotRepository> = Arc::new(MemorySnapshots::default());
+        let computed = Arc::new(AtomicU64::new(0));
+        let snapshots = Arc::new(Snapshots::new(&Settings::default(), repository.clone()));
+
+        // computed while the first request waits, then reused
+        let first = snapshots.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((first.value, first.stale), (1, false));
+        let again = snapshots.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((again.value, again.stale), (1, false));
+
+        // a restarted process answers the stored one, stale, and computes it again
+        let restarted = Arc::new(Snapshots::new(&Settings::default(), repository));
+        let loaded = restarted.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((loaded.value, loaded.stale), (1, true));
+        for _ in 0..100 {
+            if !restarted.refreshing.lock().unwrap().contains("stats") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let fresh = restarted.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((fresh.value, fresh.stale), (2, false));
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+
+        let response = fresh.into_response();
+        assert_eq!(response.headers()["age"], "0");
+        assert!(response.headers().get(STALE).is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_after_ttl() {
+        let clock = Arc::new(FixedClock::at("2024-02-21T16:00:00Z"));
+        let snapshots = Arc::new(
+            Snapshots::new(&Settings { ttl: 60 }, Arc::new(MemorySnapshots::default()))
+                .with_clock(clock.clone()),
+        );
+        let computed = Arc::new(AtomicU64::new(0));
+        snapshots.get("stats", counting(&computed)).await.unwrap();
+
+        clock.advance(Duration::seconds(60));
+        let kept = snapshots.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((kept.value, kept.age, kept.stale), (1, 60, false));
+        clock.advance(Duration::seconds(1));
+        let old = snapshots.get("stats", counting(&computed)).await.unwrap();
+        assert_eq!((old.value, old.age, old.stale), (1, 61, true));
+        assert_eq!(old.into_response().headers()["age"], "61");
+    }
+}
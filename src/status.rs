@@ -0,0 +1,737 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, patch, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{
+    app::AppState,
+    auth::Admin,
+    db::DbExecutor,
+    db_error,
+    snapshot::{Cached, Snapshots},
+    task,
+};
+
+// how often components are checked, and how long a check may take
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// samples kept per component, a month of them
+const HISTORY: usize = 30 * 24 * 60;
+// resolved incidents stay on the page for that long, open ones until they are resolved
+const INCIDENT_DAYS: i64 = 7;
+const MAX_INCIDENTS: i64 = 20;
+// longest window an uptime report may cover
+const MAX_REPORT_DAYS: i64 = 366;
+
+// The health of what the service depends on, sampled once a minute. The public status page
+// works from the samples in memory, which start over with the process, so uptime there is over
+// the samples there are. Every sample is stored in Postgres too, for the availability reports
+// of /admin/uptime; samples taken while the database is down are stored once it's back.
+pub struct Status {
+    db: Arc<DbExecutor>,
+    history: Mutex<BTreeMap<&'static str, VecDeque<bool>>>,
+    // samples not stored yet, at most HISTORY of them
+    pending: Mutex<VecDeque<Sample>>,
+}
+
+struct Sample {
+    component: &'static str,
+    checked_at: DateTime<Utc>,
+    up: bool,
+}
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overall {
+    Operational,
+    // something besides the database is down, or there is an open incident
+    Degraded,
+    // the database is down
+    Outage,
+}
+
+// percentages of samples the component was up in, None without samples
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq)]
+pub struct Uptime {
+    pub last_day: Option<f64>,
+    pub last_week: Option<f64>,
+    pub last_month: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ComponentStatus {
+    pub name: String,
+    pub up: bool,
+    pub uptime: Uptime,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct StatusReport {
+    pub status: Overall,
+    pub components: Vec<ComponentStatus>,
+    // newest first
+    pub incidents: Vec<Incident>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow, ToSchema, Debug, Clone)]
+pub struct Incident {
+    pub id: i64,
+    pub title: String,
+    pub message: String,
+    pub component: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateIncident {
+    title: String,
+    #[serde(default)]
+    message: String,
+    component: Option<String>,
+}
+
+// missing fields stay as they are
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateIncident {
+    title: Option<String>,
+    message: Option<String>,
+    // false reopens a resolved incident
+    resolved: Option<bool>,
+}
+
+// availability over a window, from the stored samples
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct UptimeReport {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub components: Vec<ComponentUptime>,
+    // incidents open at some point of the window, oldest first
+    pub incidents: Vec<Incident>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub struct ComponentUptime {
+    pub name: String,
+    pub samples: usize,
+    // percentage of samples the component was up in
+    pub availability: f64,
+    // mean time to recovery over the outages that ended, in minutes
+    pub mttr_minutes: Option<f64>,
+    pub outages: Vec<Outage>,
+}
+
+// a run of samples the component was down in
+#[derive(Serialize, Deserialize, ToSchema, Debug, PartialEq)]
+pub struct Outage {
+    // the first sample it was down in
+    pub started_at: DateTime<Utc>,
+    // the first sample it was up again in, None while it's still down
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct Window {
+    // like `30d` or `12h`
+    window: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Format {
+    format: Option<String>,
+}
+
+impl Status {
+    pub fn new(db: Arc<DbExecutor>) -> Self {
+        Status {
+            db,
+            history: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    // samples the components in the background for as long as the status is in use
+    pub fn sample(self: &Arc<Self>) {
+        let status = Arc::downgrade(self);
+        task::spawn("status sampling", task::Kind::Background, async move {
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(status) = status.upgrade() else {
+                    return;
+                };
+                let components = status.check().await;
+                status.record(&components);
+                status.store().await;
+            }
+        });
+    }
+
+    async fn check(&self) -> Vec<(&'static str, bool)> {
+        let check = sqlx::query("SELECT 1").execute(self.db.write());
+        let primary = matches!(tokio::time::timeout(CHECK_TIMEOUT, check).await, Ok(Ok(_)));
+        let mut components = vec![("database", primary)];
+        if let Some(up) = self.db.replica_up() {
+            components.push(("replica", up));
+        }
+        components
+    }
+
+    fn record(&self, components: &[(&'static str, bool)]) {
+        let checked_at = Utc::now();
+        let mut history = self.history.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        for (name, up) in components {
+            let samples = history.entry(name).or_default();
+            if samples.len() == HISTORY {
+                samples.pop_front();
+            }
+            samples.push_back(*up);
+            if pending.len() == HISTORY {
+                pending.pop_front();
+            }
+            pending.push_back(Sample {
+                component: name,
+                checked_at,
+                up: *up,
+            });
+        }
+    }
+
+    // writes the pending samples, keeping them for the next try when that fails
+    async fn store(&self) {
+        let samples: Vec<Sample> = self.pending.lock().unwrap().drain(..).collect();
+        if samples.is_empty() {
+            return;
+        }
+        let result = sqlx::query(
+            "INSERT INTO health_checks (component, checked_at, up)
+                SELECT * FROM UNNEST($1::text[], $2::timestamptz[], $3::bool[])
+                ON CONFLICT DO NOTHING",
+        )
+        .bind(samples.iter().map(|s| s.component).collect::<Vec<_>>())
+        .bind(samples.iter().map(|s| s.checked_at).collect::<Vec<_>>())
+        .bind(samples.iter().map(|s| s.up).collect::<Vec<_>>())
+        .execute(self.db.write())
+        .await;
+        if let Err(err) = result {
+            warn!("storing {} health samples failed: {}", samples.len(), err);
+            let mut pending = self.pending.lock().unwrap();
+            let newer: Vec<Sample> = pending.drain(..).collect();
+            pending.extend(samples);
+            pending.extend(newer);
+            while pending.len() > HISTORY {
+                pending.pop_front();
+            }
+        }
+    }
+
+    // the last sample of every component with uptime over its history, checking right away
+    // before the first sample
+    async fn components(&self) -> Vec<ComponentStatus> {
+        let sampled = {
+            let history = self.history.lock().unwrap();
+            history
+                .iter()
+                .filter_map(|(name, samples)| Some((*name, *samples.back()?)))
+                .collect::<Vec<_>>()
+        };
+        let current = if sampled.is_empty() {
+            self.check().await
+        } else {
+            sampled
+        };
+        let history = self.history.lock().unwrap();
+        current
+            .into_iter()
+            .map(|(name, up)| {
+                let samples = history.get(name);
+                let uptime = |minutes| samples.and_then(|samples| uptime(samples, minutes));
+                ComponentStatus {
+                    name: name.to_owned(),
+                    up,
+                    uptime: Uptime {
+                        last_day: uptime(24 * 60),
+                        last_week: uptime(7 * 24 * 60),
+                        last_month: uptime(HISTORY),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+// percentage of the last `count` samples that were up
+fn uptime(samples: &VecDeque<bool>, count: usize) -> Option<f64> {
+    let total = samples.len().min(count);
+    if total == 0 {
+        return None;
+    }
+    let up = samples.iter().rev().take(count).filter(|up| **up).count();
+    Some(up as f64 * 100.0 / total as f64)
+}
+
+fn overall(components: &[ComponentStatus], incidents: &[Incident]) -> Overall {
+    if components.iter().any(|c| c.name == "database" && !c.up) {
+        Overall::Outage
+    } else if components.iter().any(|c| !c.up) || incidents.iter().any(|i| i.resolved_at.is_none())
+    {
+        Overall::Degraded
+    } else {
+        Overall::Operational
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/status", get(status))
+        .route("/admin/incidents", post(create_incident))
+        .route("/admin/incidents/:id", patch(update_incident))
+        .route("/admin/uptime", get(uptime_report))
+}
+
+#[utoipa::path(
+    get,
+    path = "/status",
+    params(("format" = Option<String>, Query, description = "`json` for the JSON variant, which an `Accept: application/json` header asks for too")),
+    responses((status = 200, description = "Component health, uptime and recent incidents", content(
+        ("application/json" = StatusReport),
+        ("text/html" = String),
+    ))),
+    tag = "status"
+)]
+async fn status(
+    State(status): State<Arc<Status>>,
+    State(db): State<Arc<DbExecutor>>,
+    Query(format): Query<Format>,
+    headers: HeaderMap,
+) -> Response {
+    // the page has to work while the database doesn't, it says so then
+    let incidents = sqlx::query_as::<_, Incident>(
+        "SELECT * FROM incidents
+            WHERE resolved_at IS NULL OR resolved_at > now() - make_interval(days => $1)
+            ORDER BY created_at DESC LIMIT $2",
+    )
+    .bind(INCIDENT_DAYS as i32)
+    .bind(MAX_INCIDENTS)
+    .fetch_all(db.read())
+    .await
+    .unwrap_or_else(|err| {
+        warn!("status page can't load incidents: {}", err);
+        Vec::new()
+    });
+    let components = status.components().await;
+    let report = StatusReport {
+        status: overall(&components, &incidents),
+        components,
+        incidents,
+    };
+
+    let json = match format.format.as_deref() {
+        Some(format) => format == "json",
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json")),
+    };
+    if json {
+        Json(report).into_response()
+    } else {
+        Html(render(&report)).into_response()
+    }
+}
+
+fn render(report: &StatusReport) -> String {
+    let summary = match report.status {
+        Overall::Operational => "All systems operational",
+        Overall::Degraded => "Degraded service",
+        Overall::Outage => "Service outage",
+    };
+    let percent = |uptime: Option<f64>| match uptime {
+        Some(uptime) => format!("{:.2}%", uptime),
+        None => "-".to_owned(),
+    };
+
+    let mut page = String::from(
+        "<!doctype html>\n<html>\n<head><meta charset=\"utf-8\"><title>Status</title></head>\n<body>\n",
+    );
+    let _ = writeln!(page, "<h1>{}</h1>", summary);
+    page.push_str("<table>\n<tr><th>Component</th><th>Now</th><th>24 hours</th><th>7 days</th><th>30 days</th></tr>\n");
+    for component in &report.components {
+        let _ = writeln!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&component.name),
+            if component.up { "up" } else { "down" },
+            percent(component.uptime.last_day),
+            percent(component.uptime.last_week),
+            percent(component.uptime.last_month),
+        );
+    }
+    page.push_str("</table>\n<h2>Incidents</h2>\n");
+    if report.incidents.is_empty() {
+        page.push_str("<p>No recent incidents.</p>\n");
+    }
+    for incident in &report.incidents {
+        let state = match incident.resolved_at {
+            Some(resolved) => format!("resolved {}", resolved.format("%Y-%m-%d %H:%M UTC")),
+            None => "ongoing".to_owned(),
+        };
+        let _ = write!(page, "<h3>{}</h3>\n<p>", escape(&incident.title));
+        if let Some(component) = &incident.component {
+            let _ = write!(page, "{}, ", escape(component));
+        }
+        let _ = writeln!(
+            page,
+            "{}, {}</p>",
+            incident.created_at.format("%Y-%m-%d %H:%M UTC"),
+            state
+        );
+        if !incident.message.is_empty() {
+            let _ = writeln!(page, "<p>{}</p>", escape(&incident.message));
+        }
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn validate_title(title: &str) -> Result<(), (StatusCode, String)> {
+    if title.trim().is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "an incident needs a title".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/incidents",
+    request_body = CreateIncident,
+    responses(
+        (status = 201, description = "Incident created, it shows on the status page until resolved", body = Incident),
+        (status = 422, description = "Empty title"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn create_incident(
+    _: Admin,
+    State(pool): State<PgPool>,
+    Json(payload): Json<CreateIncident>,
+) -> Result<(StatusCode, Json<Incident>), (StatusCode, String)> {
+    validate_title(&payload.title)?;
+    let incident = sqlx::query_as::<_, Incident>(
+        "INSERT INTO incidents (title, message, component) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(payload.title.trim())
+    .bind(&payload.message)
+    .bind(&payload.component)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok((StatusCode::CREATED, Json(incident)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/admin/incidents/{id}",
+    params(("id" = i64, Path, description = "Incident id")),
+    request_body = UpdateIncident,
+    responses(
+        (status = 200, description = "Incident updated", body = Incident),
+        (status = 404, description = "No such incident"),
+        (status = 422, description = "Empty title"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn update_incident(
+    _: Admin,
+    State(pool): State<PgPool>,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateIncident>,
+) -> Result<Json<Incident>, (StatusCode, String)> {
+    if let Some(title) = &payload.title {
+        validate_title(title)?;
+    }
+    // resolving keeps the time it was first resolved
+    let incident = sqlx::query_as::<_, Incident>(
+        "UPDATE incidents SET title = COALESCE($2, title), message = COALESCE($3, message),
+            resolved_at = CASE WHEN $4 THEN COALESCE(resolved_at, now())
+                WHEN NOT $4 THEN NULL ELSE resolved_at END,
+            updated_at = now()
+            WHERE id = $1 RETURNING *",
+    )
+    .bind(id)
+    .bind(payload.title.as_deref().map(str::trim))
+    .bind(payload.message)
+    .bind(payload.resolved)
+    .fetch_one(&pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(incident))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/uptime",
+    params(("window" = Option<String>, Query, description = "How far back to report, like `30d` or `12h`, up to a year; 30 days by default")),
+    responses(
+        (status = 200, description = "Availability, outages and time to recovery per component, with the incidents of the window. \
+            `Age` says how old it is, and `X-Stale: true` that a fresher one is being computed", body = UptimeReport),
+        (status = 400, description = "Unreadable window"),
+    ),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn uptime_report(
+    _: Admin,
+    State(db): State<Arc<DbExecutor>>,
+    State(snapshots): State<Arc<Snapshots>>,
+    Query(window): Query<Window>,
+) -> Result<Cached<UptimeReport>, (StatusCode, String)> {
+    let window = parse_window(window.window.as_deref().unwrap_or("30d"))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    let name = format!("uptime:{}", window.num_seconds());
+    snapshots
+        .get(&name, move || compute_uptime(db, window))
+        .await
+}
+
+// over a year of samples at most, so it's answered from snapshots
+async fn compute_uptime(
+    db: Arc<DbExecutor>,
+    window: chrono::Duration,
+) -> Result<UptimeReport, (StatusCode, String)> {
+    let to = Utc::now();
+    let from = to - window;
+
+    let rows = sqlx::query_as::<_, (String, DateTime<Utc>, bool)>(
+        "SELECT component, checked_at, up FROM health_checks
+            WHERE checked_at >= $1 ORDER BY component, checked_at",
+    )
+    .bind(from)
+    .fetch_all(db.read())
+    .await
+    .map_err(db_error)?;
+    let mut samples: BTreeMap<String, Vec<(DateTime<Utc>, bool)>> = BTreeMap::new();
+    for (component, checked_at, up) in rows {
+        samples.entry(component).or_default().push((checked_at, up));
+    }
+
+    let incidents = sqlx::query_as::<_, Incident>(
+        "SELECT * FROM incidents WHERE created_at <= $2 AND (resolved_at IS NULL OR resolved_at >= $1)
+            ORDER BY created_at",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(db.read())
+    .await
+    .map_err(db_error)?;
+
+    Ok(UptimeReport {
+        from,
+        to,
+        components: samples
+            .into_iter()
+            .map(|(name, samples)| component_uptime(name, &samples))
+            .collect(),
+        incidents,
+    })
+}
+
+fn parse_window(window: &str) -> std::result::Result<chrono::Duration, String> {
+    let window = window.trim();
+    let (count, unit) = match window.char_indices().last() {
+        Some((at, unit)) => (&window[..at], unit),
+        None => ("", 'd'),
+    };
+    let count = count.parse::<i64>().ok().filter(|count| *count > 0);
+    let duration = match (count, unit) {
+        (Some(count), 'd') if count <= MAX_REPORT_DAYS => chrono::Duration::days(count),
+        (Some(count), 'h') if count <= MAX_REPORT_DAYS * 24 => chrono::Duration::hours(count),
+        _ => {
+            return Err(format!(
+                "window {:?} should be a number of days or hours up to a year, like 30d or 12h",
+                window
+            ))
+        }
+    };
+    Ok(duration)
+}
+
+// `samples` in the order they were taken
+fn component_uptime(name: String, samples: &[(DateTime<Utc>, bool)]) -> ComponentUptime {
+    let mut outages: Vec<Outage> = Vec::new();
+    for (checked_at, up) in samples {
+        let ongoing = outages
+            .last_mut()
+            .filter(|outage| outage.ended_at.is_none());
+        match (ongoing, up) {
+            (Some(outage), true) => outage.ended_at = Some(*checked_at),
+            (None, false) => outages.push(Outage {
+                started_at: *checked_at,
+                ended_at: None,
+            }),
+            _ => {}
+        }
+    }
+    let recoveries: Vec<f64> = outages
+        .iter()
+        .filter_map(|outage| {
+            Some((outage.ended_at? - outage.started_at).num_seconds() as f64 / 60.0)
+        })
+        .collect();
+    let up = samples.iter().filter(|(_, up)| *up).count();
+
+    ComponentUptime {
+        name,
+        samples: samples.len(),
+        availability: if samples.is_empty() {
+            0.0
+        } else {
+            up as f64 * 100.0 / samples.len() as f64
+        },
+        mttr_minutes: (!recoveries.is_empty())
+            .then(|| recoveries.iter().sum::<f64>() / recoveries.len() as f64),
+        outages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(name: &str, up: bool) -> ComponentStatus {
+        ComponentStatus {
+            name: name.to_owned(),
+            up,
+            uptime: Uptime {
+                last_day: None,
+                last_week: None,
+                last_month: None,
+            },
+        }
+    }
+
+    #[test]
+    fn uptime_over_recent_samples() {
+        let samples: VecDeque<bool> = [false, false, true, true, false, true].into();
+        assert_eq!(uptime(&samples, 4), Some(75.0));
+        assert_eq!(uptime(&samples, 100), Some(50.0));
+        assert_eq!(uptime(&VecDeque::new(), 10), None);
+    }
+
+    #[test]
+    fn reports() {
+        let incident = Incident {
+            id: 1,
+            title: "Slow <uploads>".to_owned(),
+            message: String::new(),
+            component: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            resolved_at: None,
+        };
+        let up = [component("database", true), component("replica", true)];
+        assert_eq!(overall(&up, &[]), Overall::Operational);
+        assert_eq!(
+            overall(&up, std::slice::from_ref(&incident)),
+            Overall::Degraded
+        );
+        let replica_down = [component("database", true), component("replica", false)];
+        assert_eq!(overall(&replica_down, &[]), Overall::Degraded);
+        let database_down = [component("database", false), component("replica", true)];
+        assert_eq!(overall(&database_down, &[]), Overall::Outage);
+
+        let page = render(&StatusReport {
+            status: Overall::Degraded,
+            components: vec![component("database", true)],
+            incidents: vec![incident],
+        });
+        assert!(page.contains("<h1>Degraded service</h1>"));
+        assert!(page.contains("<h3>Slow &lt;uploads&gt;</h3>"));
+        assert!(page.contains("ongoing"));
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(parse_window("30d"), Ok(chrono::Duration::days(30)));
+        assert_eq!(parse_window("12h"), Ok(chrono::Duration::hours(12)));
+        for window in ["", "d", "0d", "-1d", "30", "30m", "367d", "1é"] {
+            assert!(parse_window(window).is_err(), "{}", window);
+        }
+    }
+
+    #[test]
+    fn outages_and_recovery() {
+        let start = Utc::now();
+        let at = |minute| start + chrono::Duration::minutes(minute);
+        let samples = [
+            (at(0), true),
+            (at(1), false),
+            (at(2), false),
+            (at(3), true),
+            (at(4), true),
+            (at(5), false),
+            (at(6), true),
+            (at(7), false),
+        ];
+        let uptime = component_uptime("database".to_owned(), &samples);
+        assert_eq!(uptime.samples, 8);
+        assert_eq!(uptime.availability, 50.0);
+        assert_eq!(
+            uptime.outages,
+            vec![
+                Outage {
+                    started_at: at(1),
+                    ended_at: Some(at(3)),
+                },
+                Outage {
+                    started_at: at(5),
+                    ended_at: Some(at(6)),
+                },
+                Outage {
+                    started_at: at(7),
+                    ended_at: None,
+                },
+            ]
+        );
+        assert_eq!(uptime.mttr_minutes, Some(1.5));
+    }
+}
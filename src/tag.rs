@@ -0,0 +1,294 @@
+// Tags to organize the library with, shared by everyone: any signed-in user may create, rename,
+// delete and put them on files. `GET /api/v1/library?tag=<name>` lists the files with one.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    app::AppState,
+    auth::CurrentUser,
+    db_error,
+    repository::{tag, TagRepository},
+    validation::{self, Valid},
+};
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct Tag {
+    id: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    // how many files of the library have it
+    files: i64,
+}
+
+impl From<tag::Tag> for Tag {
+    fn from(tag: tag::Tag) -> Self {
+        Tag {
+            id: tag.id,
+            name: tag.name,
+            created_at: tag.created_at,
+            files: tag.files,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct TagName {
+    // unique ignoring case
+    #[validate(length(min = 1, max = 64), custom = "validation::label")]
+    name: String,
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/tags", get(list_tags).post(create_tag))
+        .route(
+            "/tags/:id",
+            get(get_tag).patch(rename_tag).delete(delete_tag),
+        )
+        .route("/library/:id/tags", get(file_tags))
+        .route(
+            "/library/:id/tags/:tag_id",
+            put(tag_file).delete(untag_file),
+        )
+}
+
+fn name_taken(name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("tag {:?} exists already", name),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tags",
+    responses((status = 200, description = "Tags ordered by name", body = [Tag])),
+    tag = "library"
+)]
+#[instrument(skip_all)]
+async fn list_tags(
+    State(tags): State<Arc<dyn TagRepository>>,
+) -> Result<Json<Vec<Tag>>, (StatusCode, String)> {
+    let tags = tags.list().await.map_err(db_error)?;
+    Ok(Json(tags.into_iter().map(Tag::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/tags",
+    request_body = TagName,
+    responses(
+        (status = 201, description = "Tag created", body = Tag),
+        (status = 409, description = "A tag has the name already, maybe in another case"),
+        (status = 422, description = "Invalid name", body = Invalid),
+    ),
+    security(("user_id" = [])),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(name = %payload.name))]
+async fn create_tag(
+    State(tags): State<Arc<dyn TagRepository>>,
+    _: CurrentUser,
+    Valid(payload): Valid<TagName>,
+) -> Result<(StatusCode, Json<Tag>), (StatusCode, String)> {
+    match tags.create(&payload.name).await.map_err(db_error)? {
+        Some(tag) => Ok((StatusCode::CREATED, Json(Tag::from(tag)))),
+        None => Err(name_taken(&payload.name)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tags/{id}",
+    params(("id" = i64, Path, description = "Tag id")),
+    responses(
+        (status = 200, description = "The tag", body = Tag),
+        (status = 404, description = "No such tag"),
+    ),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn get_tag(
+    State(tags): State<Arc<dyn TagRepository>>,
+    Path(id): Path<i64>,
+) -> Result<Json<Tag>, (StatusCode, String)> {
+    let tag = tags.find(id).await.map_err(db_error)?;
+    Ok(Json(Tag::from(tag)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/tags/{id}",
+    params(("id" = i64, Path, description = "Tag id")),
+    request_body = TagName,
+    responses(
+        (status = 200, description = "Tag renamed", body = Tag),
+        (status = 404, description = "No such tag"),
+        (status = 409, description = "Another tag has the name, maybe in another case"),
+        (status = 422, description = "Invalid name", body = Invalid),
+    ),
+    security(("user_id" = [])),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(id = id, name = %payload.name))]
+async fn rename_tag(
+    State(tags): State<Arc<dyn TagRepository>>,
+    _: CurrentUser,
+    Path(id): Path<i64>,
+    Valid(payload): Valid<TagName>,
+) -> Result<Json<Tag>, (StatusCode, String)> {
+    match tags.rename(id, &payload.name).await.map_err(db_error)? {
+        Some(tag) => Ok(Json(Tag::from(tag))),
+        None => Err(name_taken(&payload.name)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tags/{id}",
+    params(("id" = i64, Path, description = "Tag id")),
+    responses(
+        (status = 204, description = "Tag deleted, and taken off its files"),
+        (status = 404, description = "No such tag"),
+    ),
+    security(("user_id" = [])),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(id = id))]
+async fn delete_tag(
+    State(tags): State<Arc<dyn TagRepository>>,
+    _: CurrentUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    tags.delete(id).await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/library/{id}/tags",
+    params(("id" = i64, Path, description = "Asset id of the file")),
+    responses(
+        (status = 200, description = "Tags of the file, ordered by name", body = [Tag]),
+        (status = 404, description = "No such file in the library"),
+    ),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(asset_id = asset_id))]
+async fn file_tags(
+    State(tags): State<Arc<dyn TagRepository>>,
+    Path(asset_id): Path<i64>,
+) -> Result<Json<Vec<Tag>>, (StatusCode, String)> {
+    let tags = tags.of_file(asset_id).await.map_err(db_error)?;
+    Ok(Json(tags.into_iter().map(Tag::from).collect()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/library/{id}/tags/{tag_id}",
+    params(
+        ("id" = i64, Path, description = "Asset id of the file"),
+        ("tag_id" = i64, Path, description = "Tag id"),
+    ),
+    responses(
+        (status = 204, description = "The file has the tag, maybe had it before"),
+        (status = 404, description = "No such file in the library, or no such tag"),
+    ),
+    security(("user_id" = [])),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(asset_id = asset_id, tag_id = tag_id))]
+async fn tag_file(
+    State(tags): State<Arc<dyn TagRepository>>,
+    _: CurrentUser,
+    Path((asset_id, tag_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    tags.tag(asset_id, tag_id).await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/library/{id}/tags/{tag_id}",
+    params(
+        ("id" = i64, Path, description = "Asset id of the file"),
+        ("tag_id" = i64, Path, description = "Tag id"),
+    ),
+    responses(
+        (status = 204, description = "Tag taken off the file"),
+        (status = 404, description = "The file doesn't have the tag"),
+    ),
+    security(("user_id" = [])),
+    tag = "library"
+)]
+#[instrument(skip_all, fields(asset_id = asset_id, tag_id = tag_id))]
+async fn untag_file(
+    State(tags): State<Arc<dyn TagRepository>>,
+    _: CurrentUser,
+    Path((asset_id, tag_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    tags.untag(asset_id, tag_id).await.map_err(db_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::tag::MemoryTags;
+
+    fn name(name: &str) -> Valid<TagName> {
+        Valid(TagName {
+            name: name.to_owned(),
+        })
+    }
+
+    #[tokio::test]
+    async fn organizes() {
+        let tags: Arc<dyn TagRepository> = Arc::new(MemoryTags::default());
+        let user = || CurrentUser(1);
+
+        let (status, Json(jazz)) = create_tag(State(tags.clone()), user(), name("Jazz"))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let taken = create_tag(State(tags.clone()), user(), name("jazz")).await;
+        assert_eq!(taken.unwrap_err().0, StatusCode::CONFLICT);
+        let (_, Json(live)) = create_tag(State(tags.clone()), user(), name("live"))
+            .await
+            .unwrap();
+        let renamed = rename_tag(State(tags.clone()), user(), Path(live.id), name("JAZZ"));
+        assert_eq!(renamed.await.unwrap_err().0, StatusCode::CONFLICT);
+
+        for tag_id in [jazz.id, jazz.id, live.id] {
+            let tagged = tag_file(State(tags.clone()), user(), Path((7, tag_id))).await;
+            assert_eq!(tagged, Ok(StatusCode::NO_CONTENT));
+        }
+        let unknown = tag_file(State(tags.clone()), user(), Path((7, 99))).await;
+        assert_eq!(unknown.unwrap_err().0, StatusCode::NOT_FOUND);
+        let Json(listed) = list_tags(State(tags.clone())).await.unwrap();
+        let counted: Vec<(&str, i64)> = listed
+            .iter()
+            .map(|tag| (tag.name.as_str(), tag.files))
+            .collect();
+        assert_eq!(counted, [("Jazz", 1), ("live", 1)]);
+
+        delete_tag(State(tags.clone()), user(), Path(live.id))
+            .await
+            .unwrap();
+        let Json(of_file) = file_tags(State(tags.clone()), Path(7)).await.unwrap();
+        assert_eq!(of_file, [Tag { files: 1, ..jazz }]);
+        let untagged = untag_file(State(tags.clone()), user(), Path((7, live.id))).await;
+        assert_eq!(untagged.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+}
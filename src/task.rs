@@ -0,0 +1,201 @@
+// Tasks spawned in the background, by name. Everything that would call `tokio::spawn` calls
+// `spawn` instead, which keeps the task listed for as long as it runs, for `GET /admin/tasks`.
+// When shutting down, `shutdown` aborts the loops meant to run for as long as the process, waits
+// for the rest to finish for a while and aborts what's left, warning about each.
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{app::AppState, auth::Admin};
+
+// how long tasks get to finish after shutdown before they are aborted
+pub const GRACE: Duration = Duration::from_secs(5);
+const GRACE_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    // ends by itself, like a refresh, or once shutting down, like the job workers
+    Work,
+    // loops for as long as the process runs, like a heartbeat; aborted on shutdown
+    Background,
+}
+
+struct Task {
+    name: String,
+    kind: Kind,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    // None until spawned
+    abort: Option<AbortHandle>,
+}
+
+static RUNNING: Mutex<BTreeMap<u64, Task>> = Mutex::new(BTreeMap::new());
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+// unlists its task when the task ends, is aborted or panics
+struct Listed(u64);
+
+impl Drop for Listed {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+// `tokio::spawn`, listing the task as `name` while it runs
+pub fn spawn<F>(name: impl Into<String>, kind: Kind, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let id = NEXT.fetch_add(1, Ordering::Relaxed);
+    let task = Task {
+        name: name.into(),
+        kind,
+        started_at: Utc::now(),
+        started: Instant::now(),
+        abort: None,
+    };
+    RUNNING.lock().unwrap().insert(id, task);
+    let listed = Listed(id);
+    let handle = tokio::spawn(async move {
+        let _listed = listed;
+        future.await
+    });
+    // it may have ended already
+    if let Some(task) = RUNNING.lock().unwrap().get_mut(&id) {
+        task.abort = Some(handle.abort_handle());
+    }
+    handle
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct RunningTask {
+    id: u64,
+    name: String,
+    kind: Kind,
+    started_at: DateTime<Utc>,
+    // seconds
+    running_for: f64,
+}
+
+// what runs, oldest first
+pub fn running() -> Vec<RunningTask> {
+    RUNNING
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&id, task)| RunningTask {
+            id,
+            name: task.name.clone(),
+            kind: task.kind,
+            started_at: task.started_at,
+            running_for: task.started.elapsed().as_secs_f64(),
+        })
+        .collect()
+}
+
+// aborts the tasks of `kind` still running, their names
+fn abort(kind: Kind) -> Vec<String> {
+    let running = RUNNING.lock().unwrap();
+    running
+        .values()
+        .filter(|task| task.kind == kind)
+        .map(|task| {
+            if let Some(abort) = &task.abort {
+                abort.abort();
+            }
+            task.name.clone()
+        })
+        .collect()
+}
+
+// Aborts background tasks and gives the others up to `grace` to finish before aborting them,
+// once everything was told to stop.
+pub async fn shutdown(grace: Duration) {
+    let stopped = abort(Kind::Background);
+    if !stopped.is_empty() {
+        info!("stopped {}", stopped.join(", "));
+    }
+    let deadline = Instant::now() + grace;
+    let working = |running: &BTreeMap<u64, Task>| running.values().any(|t| t.kind == Kind::Work);
+    while working(&RUNNING.lock().unwrap()) && Instant::now() < deadline {
+        tokio::time::sleep(GRACE_POLL).await;
+    }
+    for name in abort(Kind::Work) {
+        warn!("task {} didn't finish in {:?}, aborted", name, grace);
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/admin/tasks", get(list_tasks))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/tasks",
+    responses((status = 200, description = "Tasks running in the background, oldest first", body = [RunningTask])),
+    security(("admin" = [])),
+    tag = "admin"
+)]
+async fn list_tasks(_: Admin) -> Json<Vec<RunningTask>> {
+    Json(running())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    // other tests may run tasks at the same time
+    fn listed(name: &str) -> Vec<Kind> {
+        running()
+            .into_iter()
+            .filter(|task| task.name == name)
+            .map(|task| task.kind)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_while_running() {
+        let (finish, finished) = oneshot::channel::<()>();
+        let work = spawn("task test work", Kind::Work, async move {
+            let _ = finished.await;
+            7
+        });
+        let background = spawn(
+            "task test loop",
+            Kind::Background,
+            std::future::pending::<()>(),
+        );
+        assert_eq!(listed("task test work"), vec![Kind::Work]);
+        assert_eq!(listed("task test loop"), vec![Kind::Background]);
+
+        finish.send(()).unwrap();
+        assert_eq!(work.await.unwrap(), 7);
+        assert_eq!(listed("task test work"), vec![]);
+
+        background.abort();
+        assert!(background.await.unwrap_err().is_cancelled());
+        assert_eq!(listed("task test loop"), vec![]);
+
+        let panicked = spawn("task test panic", Kind::Work, async {
+            panic!("on purpose")
+        });
+        assert!(panicked.await.unwrap_err().is_panic());
+        assert_eq!(listed("task test panic"), vec![]);
+    }
+}
@@ -0,0 +1,198 @@
+// How long routes have to answer before the client gets `504 Gateway Timeout`, an error shaped
+// like those of `/api/v2`, instead of waiting on a handler that's stuck. `[server.timeouts]` gives
+// most routes a few seconds, the media ones of `shed` minutes, and single routes whatever they
+// need. The time is until the response starts, so downloads and event streams go on past it;
+// uploads, whose handlers answer once the body is in, aren't limited unless they're configured.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde_derive::Deserialize;
+use tracing::warn;
+
+use crate::{api::v2, shed};
+
+// routes reading bodies of any size as clients send them, as registered without the prefix
+const UPLOADS: &[&str] = &["/uploads/:id"];
+
+// `[server.timeouts]` in the config, in seconds, 0 for no limit
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Timeouts {
+    // of the routes without one of their own
+    pub default: u64,
+    // of the media routes without one of their own
+    pub media: u64,
+    // by route as registered without the `/api/v<n>` prefix, like "/assets/:id"
+    pub routes: HashMap<String, u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            default: 5,
+            media: 120,
+            // which answers after 10 seconds on purpose
+            routes: HashMap::from([("/longtime".to_owned(), 15)]),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.routes.keys().find(|route| !route.starts_with('/')) {
+            Some(route) => Err(format!(
+                "server.timeouts.routes: {} isn't a route, they start with /",
+                route
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // of the route registered as `path`, None without a limit
+    fn of(&self, path: &str) -> Option<Duration> {
+        let seconds = match self.routes.get(shed::unversioned(path)) {
+            Some(seconds) => *seconds,
+            None if UPLOADS.contains(&shed::unversioned(path)) => 0,
+            None if shed::is_media(path) => self.media,
+            None => self.default,
+        };
+        (seconds > 0).then(|| Duration::from_secs(seconds))
+    }
+}
+
+// middleware answering 504 for requests whose handler takes longer than their route may
+pub async fn limit(
+    State(timeouts): State<Arc<Timeouts>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // paths no route matches, such as those of the web UI, get the default
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", MatchedPath::as_str)
+        .to_owned();
+    let Some(limit) = timeouts.of(&path) else {
+        return next.run(request).await;
+    };
+    // set by `panics::catch` further out
+    let request_id = request
+        .headers()
+        .get(v2::REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(%path, %request_id, "no answer within {:?}", limit);
+            let message = format!("the server didn't answer within {:?}", limit);
+            v2::error_response(StatusCode::GATEWAY_TIMEOUT, &message, &request_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        middleware,
+        routing::{get, patch},
+        Router,
+    };
+    use serde_json::Value;
+    use tokio_stream::StreamExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn by_route() {
+        let timeouts = Timeouts {
+            routes: HashMap::from([("/users/:id".to_owned(), 0)]),
+            ..Timeouts::default()
+        };
+        assert_eq!(timeouts.of("/assets"), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.of(""), Some(Duration::from_secs(5)));
+        let media = Some(Duration::from_secs(120));
+        assert_eq!(timeouts.of("/api/v1/assets/:id/video"), media);
+        assert_eq!(timeouts.of("/api/v2/users/:id"), None);
+        assert_eq!(timeouts.of("/api/v1/uploads/:id"), None);
+        assert!(timeouts.validate().is_ok());
+        assert!(Timeouts::default().validate().is_ok());
+        let routes = HashMap::from([("users".to_owned(), 1)]);
+        assert!(Timeouts {
+            routes,
+            ..Timeouts::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_504_with_json() {
+        let timeouts = Timeouts {
+            default: 1,
+            ..Timeouts::default()
+        };
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "slow"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(Arc::new(timeouts), limit));
+        let request = axum::http::Request::get("/slow")
+            .header(v2::REQUEST_ID, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "gateway_timeout");
+        assert_eq!(body["request_id"], "abc");
+    }
+
+    #[tokio::test]
+    async fn uploads_take_as_long_as_their_body() {
+        let timeouts = Timeouts {
+            default: 1,
+            media: 1,
+            ..Timeouts::default()
+        };
+        let app = Router::new()
+            .route(
+                "/api/v1/uploads/:id",
+                patch(|body: Body| async move {
+                    let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+                    bytes.len().to_string()
+                }),
+            )
+            .layer(middleware::from_fn_with_state(Arc::new(timeouts), limit));
+        // a second and a half to send, past the limit of every other route
+        let chunks =
+            tokio_stream::iter([&b"slow"[..], &b" body"[..], &b"!"[..]]).then(|chunk| async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                Ok::<_, std::convert::Infallible>(chunk)
+            });
+        let request = axum::http::Request::patch("/api/v1/uploads/1")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], b"10");
+    }
+}
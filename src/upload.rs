@@ -0,0 +1,688 @@
+// Resumable uploads with the tus protocol (https://tus.io/protocols/resumable-upload), core and
+// its creation and termination extensions, which clients such as tus-js-client and Uppy speak:
+//
+//     POST   /api/v1/uploads       Upload-Length: 3000000000, Upload-Metadata: filename <base64>
+//         201 Location: /api/v1/uploads/7
+//     PATCH  /api/v1/uploads/7     Upload-Offset: 0, Content-Type: application/offset+octet-stream
+//         204 Upload-Offset: 1048576, cut short by a flaky connection
+//     HEAD   /api/v1/uploads/7
+//         200 Upload-Offset: 1048576, to resume from there
+//
+// The bytes go to `uploads.dir/partial/<id>` as they arrive, so whatever made it is kept. Once
+// all did the file moves to `uploads.dir/<id>/<filename>` and becomes an asset, whose id the last
+// PATCH and HEADs after it answer with in `X-Asset-Id`. The `prune_uploads` scheduled task
+// deletes uploads left incomplete. One PATCH at a time appends to an upload, whichever server it
+// reaches, holding a lease on it in Postgres while it does.
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Path, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{head, post},
+    Router,
+};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde_derive::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use tracing::{instrument, warn};
+
+use crate::{
+    app::AppState,
+    asset,
+    auth::CurrentUser,
+    db_error, internal_error,
+    repository::{
+        upload::{Upload, UploadLock},
+        UploadRepository,
+    },
+};
+
+pub const TUS_VERSION: &str = "1.0.0";
+const OFFSET_STREAM: &str = "application/offset+octet-stream";
+
+// `[uploads]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // where uploads are written, and kept once complete
+    pub dir: String,
+    // bytes
+    pub max_size: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            dir: "data/uploads".to_owned(),
+            max_size: 50 << 30,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_size == 0 {
+            return Err("uploads.max_size must be at least 1 byte".to_owned());
+        }
+        Ok(())
+    }
+}
+
+pub struct Uploads {
+    settings: Settings,
+    uploads: Arc<dyn UploadRepository>,
+}
+
+// A partial file that's gone, such as after a crash between moving it and registering the asset,
+// leaves nothing to resume: the upload is as good as gone too, for the pruning to clean up.
+fn partial_error(err: io::Error) -> (StatusCode, String) {
+    match err.kind() {
+        io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "no such upload".to_owned()),
+        _ => internal_error(err),
+    }
+}
+
+impl Uploads {
+    pub fn new(settings: Settings, uploads: Arc<dyn UploadRepository>) -> Self {
+        Uploads { settings, uploads }
+    }
+
+    fn partial(&self, id: i64) -> PathBuf {
+        PathBuf::from(&self.settings.dir)
+            .join("partial")
+            .join(id.to_string())
+    }
+
+    // bytes received so far
+    async fn offset(&self, upload: &Upload) -> Result<i64, (StatusCode, String)> {
+        if upload.asset_id.is_some() {
+            return Ok(upload.length);
+        }
+        match tokio::fs::metadata(self.partial(upload.id)).await {
+            Ok(metadata) => Ok(metadata.len() as i64),
+            Err(err) => Err(partial_error(err)),
+        }
+    }
+
+    // only one request at a time may append to an upload, whichever server it reaches
+    async fn lock(&self, id: i64) -> Result<Option<UploadLock>, (StatusCode, String)> {
+        self.uploads.lock(id).await.map_err(db_error)
+    }
+
+    // the user's upload, as if others didn't exist
+    async fn own(&self, user: i64, id: i64) -> Result<Upload, (StatusCode, String)> {
+        match self.uploads.find(id).await {
+            Ok(upload) if upload.user_id == user => Ok(upload),
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                Err((StatusCode::NOT_FOUND, "no such upload".to_owned()))
+            }
+            Err(err) => Err(db_error(err)),
+        }
+    }
+
+    // moves the complete upload to where it's kept and registers it as an asset
+    async fn finish(&self, upload: &Upload) -> Result<i64, (StatusCode, String)> {
+        let dir = PathBuf::from(&self.settings.dir).join(upload.id.to_string());
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(internal_error)?;
+        let dir = tokio::fs::canonicalize(&dir)
+            .await
+            .map_err(internal_error)?;
+        let path = dir.join(&upload.file_name);
+        let partial = self.partial(upload.id);
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(partial_error)?;
+        let path = path.to_string_lossy().into_owned();
+        let title = match &upload.title {
+            Some(title) => title.clone(),
+            None => asset::title_from_path(&path),
+        };
+        match self.uploads.complete(upload.id, &path, &title).await {
+            Ok(asset_id) => Ok(asset_id),
+            Err(err) => {
+                // the last PATCH can be sent again
+                if let Err(err) = tokio::fs::rename(&path, &partial).await {
+                    warn!("can't move upload {} back: {}", upload.id, err);
+                }
+                Err(db_error(err))
+            }
+        }
+    }
+
+    // deletes uploads left incomplete since before `before`, returns how many
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<usize, String> {
+        let stale = self
+            .uploads
+            .stale(before)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut pruned = 0;
+        for upload in stale {
+            let Some(_lock) = self.lock(upload.id).await.map_err(|(_, err)| err)? else {
+                continue;
+            };
+            self.remove(upload.id).await.map_err(|(_, err)| err)?;
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    async fn remove(&self, id: i64) -> Result<(), (StatusCode, String)> {
+        self.uploads.delete(id).await.map_err(db_error)?;
+        match tokio::fs::remove_file(self.partial(id)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(internal_error(err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/uploads", post(create_upload).options(capabilities))
+        .route(
+            "/uploads/:id",
+            head(upload_offset).patch(append).delete(terminate),
+        )
+        .layer(middleware::map_response(resumable))
+}
+
+// every answer says which version of the protocol it speaks
+async fn resumable(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    if response.status() == StatusCode::PRECONDITION_FAILED {
+        let headers = response.headers_mut();
+        headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    }
+    response
+}
+
+// requests but OPTIONS must speak the version of the protocol there is
+fn check_version(headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    match headers.get("tus-resumable") {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err((
+            StatusCode::PRECONDITION_FAILED,
+            format!("Tus-Resumable must be {}", TUS_VERSION),
+        )),
+    }
+}
+
+fn number(headers: &HeaderMap, name: &str) -> Result<i64, (StatusCode, String)> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|number| *number >= 0)
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            format!("{} must be a number of bytes", name),
+        ))
+}
+
+// `key base64,key base64`, values may be left out
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = base64::engine::general_purpose::STANDARD
+            .decode(value.trim())
+            .ok()?;
+        metadata.insert(key.to_owned(), String::from_utf8(value).ok()?);
+    }
+    Some(metadata)
+}
+
+// the last component of what the client calls the file, it doesn't choose where it goes
+fn file_name(name: &str) -> String {
+    let name = std::path::Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().replace('\0', ""))
+        .unwrap_or_default();
+    match name.is_empty() {
+        true => "upload".to_owned(),
+        false => name,
+    }
+}
+
+// the headers telling where an upload is at
+fn progress(upload: &Upload, offset: i64) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers = vec![
+        (HeaderName::from_static("upload-offset"), offset.into()),
+        (
+            HeaderName::from_static("upload-length"),
+            upload.length.into(),
+        ),
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+    ];
+    if let Some(asset_id) = upload.asset_id {
+        headers.push((HeaderName::from_static("x-asset-id"), asset_id.into()));
+    }
+    headers
+}
+
+fn with_headers(status: StatusCode, headers: Vec<(HeaderName, HeaderValue)>) -> Response {
+    let mut response = status.into_response();
+    response.headers_mut().extend(headers);
+    response
+}
+
+#[utoipa::path(
+    options,
+    path = "/api/v1/uploads",
+    responses((status = 204, description = "The protocol versions, extensions and the largest upload there are, in `Tus-*` headers")),
+    tag = "uploads"
+)]
+async fn capabilities(State(uploads): State<Arc<Uploads>>) -> Response {
+    with_headers(
+        StatusCode::NO_CONTENT,
+        vec![
+            (
+                HeaderName::from_static("tus-version"),
+                HeaderValue::from_static(TUS_VERSION),
+            ),
+            (
+                HeaderName::from_static("tus-extension"),
+                HeaderValue::from_static("creation,termination"),
+            ),
+            (
+                HeaderName::from_static("tus-max-size"),
+                uploads.settings.max_size.into(),
+            ),
+        ],
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/uploads",
+    params(
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+        ("Upload-Length" = i64, Header, description = "Bytes to upload"),
+        ("Upload-Metadata" = Option<String>, Header, description = "`filename` and `title` of the asset, base64 encoded: `filename bW92aWUubXA0,title TW92aWU=`"),
+    ),
+    responses(
+        (status = 201, description = "Upload created, PATCH its `Location` with the bytes"),
+        (status = 400, description = "Missing length, or metadata not in base64"),
+        (status = 412, description = "A version of the protocol there isn't"),
+        (status = 413, description = "Larger than `Tus-Max-Size`"),
+    ),
+    security(("user_id" = [])),
+    tag = "uploads"
+)]
+#[instrument(skip_all, fields(user = user))]
+async fn create_upload(
+    State(uploads): State<Arc<Uploads>>,
+    CurrentUser(user): CurrentUser,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(&headers)?;
+    let length = number(&headers, "upload-length")?;
+    if length as u64 > uploads.settings.max_size {
+        let message = format!("uploads go up to {} bytes", uploads.settings.max_size);
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, message));
+    }
+    let metadata = match headers.get("upload-metadata") {
+        Some(value) => value.to_str().ok().and_then(parse_metadata).ok_or((
+            StatusCode::BAD_REQUEST,
+            "Upload-Metadata must be keys with base64 encoded values".to_owned(),
+        ))?,
+        None => HashMap::new(),
+    };
+    let name = file_name(metadata.get("filename").map_or("", String::as_str));
+    let title = metadata
+        .get("title")
+        .filter(|title| !title.trim().is_empty());
+
+    let mut upload = uploads
+        .uploads
+        .create(user, &name, title.map(String::as_str), length)
+        .await
+        .map_err(db_error)?;
+    let partial = uploads.partial(upload.id);
+    if let Some(dir) = partial.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(internal_error)?;
+    }
+    tokio::fs::File::create(&partial)
+        .await
+        .map_err(internal_error)?;
+    // nothing to wait for
+    if length == 0 {
+        upload.asset_id = Some(uploads.finish(&upload).await?);
+    }
+
+    let location = format!("{}/{}", uri.path().trim_end_matches('/'), upload.id);
+    let location = HeaderValue::try_from(location).map_err(internal_error)?;
+    let mut headers = vec![(header::LOCATION, location)];
+    if let Some(asset_id) = upload.asset_id {
+        headers.push((HeaderName::from_static("x-asset-id"), asset_id.into()));
+    }
+    Ok(with_headers(StatusCode::CREATED, headers))
+}
+
+#[utoipa::path(
+    head,
+    path = "/api/v1/uploads/{id}",
+    params(
+        ("id" = i64, Path, description = "Upload id"),
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+    ),
+    responses(
+        (status = 200, description = "How far the upload got in `Upload-Offset`, and its asset in `X-Asset-Id` once complete"),
+        (status = 404, description = "No such upload"),
+        (status = 412, description = "A version of the protocol there isn't"),
+    ),
+    security(("user_id" = [])),
+    tag = "uploads"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn upload_offset(
+    State(uploads): State<Arc<Uploads>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(&headers)?;
+    let upload = uploads.own(user, id).await?;
+    let offset = uploads.offset(&upload).await?;
+    Ok(with_headers(StatusCode::OK, progress(&upload, offset)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/uploads/{id}",
+    params(
+        ("id" = i64, Path, description = "Upload id"),
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+        ("Upload-Offset" = i64, Header, description = "Where the bytes of the body go, the offset the upload is at"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "The bytes are appended, the new offset is in `Upload-Offset`; the asset in `X-Asset-Id` once complete"),
+        (status = 404, description = "No such upload"),
+        (status = 409, description = "The upload isn't at that offset"),
+        (status = 412, description = "A version of the protocol there isn't"),
+        (status = 413, description = "More bytes than `Upload-Length`"),
+        (status = 415, description = "Not `application/offset+octet-stream`"),
+        (status = 423, description = "Another request is appending to it"),
+    ),
+    security(("user_id" = [])),
+    tag = "uploads"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn append(
+    State(uploads): State<Arc<Uploads>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(&headers)?;
+    if headers
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|value| value != OFFSET_STREAM)
+    {
+        let message = format!("Content-Type must be {}", OFFSET_STREAM);
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, message));
+    }
+    let claimed = number(&headers, "upload-offset")?;
+    uploads.own(user, id).await?;
+    let Some(_lock) = uploads.lock(id).await? else {
+        let message = "another request is appending to the upload".to_owned();
+        return Err((StatusCode::LOCKED, message));
+    };
+    // again, the request that held the lock before may have completed it
+    let mut upload = uploads.own(user, id).await?;
+    let mut offset = uploads.offset(&upload).await?;
+    if claimed != offset {
+        let message = format!("the upload is at offset {}", offset);
+        return Err((StatusCode::CONFLICT, message));
+    }
+
+    if upload.asset_id.is_none() {
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(uploads.partial(id))
+            .await
+            .map_err(partial_error)?;
+        let mut chunks = body.into_data_stream();
+        // what arrived is kept even when the connection breaks, the client resumes after it
+        let mut failed = None;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    failed = Some((StatusCode::BAD_REQUEST, err.to_string()));
+                    break;
+                }
+            };
+            let room = (upload.length - offset) as usize;
+            let fits = chunk.len().min(room);
+            file.write_all(&chunk[..fits])
+                .await
+                .map_err(internal_error)?;
+            offset += fits as i64;
+            if fits < chunk.len() {
+                let message = format!("the upload has {} bytes", upload.length);
+                failed = Some((StatusCode::PAYLOAD_TOO_LARGE, message));
+                break;
+            }
+        }
+        file.sync_data().await.map_err(internal_error)?;
+        if let Some(failed) = failed {
+            return Err(failed);
+        }
+        if offset == upload.length {
+            upload.asset_id = Some(uploads.finish(&upload).await?);
+        }
+    }
+    Ok(with_headers(
+        StatusCode::NO_CONTENT,
+        progress(&upload, offset),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/uploads/{id}",
+    params(
+        ("id" = i64, Path, description = "Upload id"),
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+    ),
+    responses(
+        (status = 204, description = "Upload deleted with what it received; a complete one's asset stays"),
+        (status = 404, description = "No such upload"),
+        (status = 412, description = "A version of the protocol there isn't"),
+        (status = 423, description = "A request is appending to it"),
+    ),
+    security(("user_id" = [])),
+    tag = "uploads"
+)]
+#[instrument(skip_all, fields(user = user, id = id))]
+async fn terminate(
+    State(uploads): State<Arc<Uploads>>,
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_version(&headers)?;
+    uploads.own(user, id).await?;
+    let Some(_lock) = uploads.lock(id).await? else {
+        let message = "a request is appending to the upload".to_owned();
+        return Err((StatusCode::LOCKED, message));
+    };
+    uploads.remove(id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::upload::MemoryUploads;
+
+    fn tus(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn resumes() {
+        let dir = std::env::temp_dir().join(format!("rsapp-uploads-{}", std::process::id()));
+        let settings = Settings {
+            dir: dir.to_string_lossy().into_owned(),
+            max_size: 100,
+        };
+        let uploads = Arc::new(Uploads::new(settings, Arc::new(MemoryUploads::default())));
+        let user = || CurrentUser(1);
+        let uri = || OriginalUri("/api/v1/uploads".parse().unwrap());
+
+        let too_large = tus(&[("upload-length", "101")]);
+        let refused = create_upload(State(uploads.clone()), user(), uri(), too_large).await;
+        assert_eq!(refused.unwrap_err().0, StatusCode::PAYLOAD_TOO_LARGE);
+        // "../movie.mp4", the directory goes
+        let headers = tus(&[
+            ("upload-length", "10"),
+            ("upload-metadata", "filename Li4vbW92aWUubXA0,private"),
+        ]);
+        let created = create_upload(State(uploads.clone()), user(), uri(), headers)
+            .await
+            .unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert_eq!(created.headers()["location"], "/api/v1/uploads/1");
+
+        let patch = |offset: &str, bytes: &'static [u8]| {
+            let headers = tus(&[("upload-offset", offset), ("content-type", OFFSET_STREAM)]);
+            append(
+                State(uploads.clone()),
+                user(),
+                Path(1),
+                headers,
+                Body::from(bytes),
+            )
+        };
+        let appended = patch("0", b"012345").await.unwrap();
+        assert_eq!(appended.headers()["upload-offset"], "6");
+        assert!(appended.headers().get("x-asset-id").is_none());
+        assert_eq!(patch("0", b"0").await.unwrap_err().0, StatusCode::CONFLICT);
+        let elsewhere = Path(1);
+        let stranger = upload_offset(State(uploads.clone()), CurrentUser(2), elsewhere, tus(&[]));
+        assert_eq!(stranger.await.unwrap_err().0, StatusCode::NOT_FOUND);
+        let at = upload_offset(State(uploads.clone()), user(), Path(1), tus(&[]))
+            .await
+            .unwrap();
+        assert_eq!(at.headers()["upload-offset"], "6");
+        assert_eq!(at.headers()["upload-length"], "10");
+
+        {
+            let _lock = uploads.lock(1).await.unwrap().unwrap();
+            assert_eq!(patch("6", b"6").await.unwrap_err().0, StatusCode::LOCKED);
+        }
+        let appended = patch("6", b"6789").await.unwrap();
+        assert_eq!(appended.headers()["upload-offset"], "10");
+        assert_eq!(appended.headers()["x-asset-id"], "101");
+        let kept = dir.join("1").join("movie.mp4");
+        assert_eq!(std::fs::read(kept).unwrap(), b"0123456789");
+
+        // a crash moved the partial file but didn't register the asset
+        let headers = tus(&[("upload-length", "10")]);
+        create_upload(State(uploads.clone()), user(), uri(), headers)
+            .await
+            .unwrap();
+        std::fs::remove_file(dir.join("partial").join("2")).unwrap();
+        let gone = upload_offset(State(uploads.clone()), user(), Path(2), tus(&[]));
+        assert_eq!(gone.await.unwrap_err().0, StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // another request completes the upload while this one waits for its turn
+    #[derive(Default)]
+    struct CompletedMeanwhile(MemoryUploads);
+
+    #[axum::async_trait]
+    impl UploadRepository for CompletedMeanwhile {
+        async fn create(
+            &self,
+            user_id: i64,
+            file_name: &str,
+            title: Option<&str>,
+            length: i64,
+        ) -> Result<Upload, sqlx::Error> {
+            self.0.create(user_id, file_name, title, length).await
+        }
+
+        async fn find(&self, id: i64) -> Result<Upload, sqlx::Error> {
+            self.0.find(id).await
+        }
+
+        async fn lock(&self, id: i64) -> Result<Option<UploadLock>, sqlx::Error> {
+            self.0.complete(id, "", "").await?;
+            self.0.lock(id).await
+        }
+
+        async fn complete(&self, id: i64, path: &str, title: &str) -> Result<i64, sqlx::Error> {
+            self.0.complete(id, path, title).await
+        }
+
+        async fn delete(&self, id: i64) -> Result<(), sqlx::Error> {
+            self.0.delete(id).await
+        }
+
+        async fn stale(&self, before: DateTime<Utc>) -> Result<Vec<Upload>, sqlx::Error> {
+            self.0.stale(before).await
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_uploads_again_once_locked() {
+        let settings = Settings {
+            dir: std::env::temp_dir()
+                .join(format!("rsapp-meanwhile-{}", std::process::id()))
+                .to_string_lossy()
+                .into_owned(),
+            max_size: 100,
+        };
+        let repository = Arc::new(CompletedMeanwhile::default());
+        repository.create(1, "movie.mp4", None, 10).await.unwrap();
+        let uploads = Arc::new(Uploads::new(settings, repository));
+        let headers = tus(&[("upload-offset", "6"), ("content-type", OFFSET_STREAM)]);
+        let appended = append(
+            State(uploads),
+            CurrentUser(1),
+            Path(1),
+            headers,
+            Body::from(&b"6789"[..]),
+        )
+        .await;
+        let (status, message) = appended.unwrap_err();
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(message, "the upload is at offset 10");
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let metadata = parse_metadata("filename bW92aWUubXA0, is_public").unwrap();
+        assert_eq!(metadata["filename"], "movie.mp4");
+        assert_eq!(metadata["is_public"], "");
+        assert_eq!(parse_metadata("filename !!"), None);
+        assert_eq!(file_name("/etc/passwd"), "passwd");
+        assert_eq!(file_name(".."), "upload");
+    }
+}
@@ -0,0 +1,193 @@
+// Request bodies checked against the constraints their types declare, e.g.
+//
+//     #[derive(Deserialize, Validate)]
+//     struct CreateUser {
+//         #[validate(length(min = 2, max = 32), custom = "username")]
+//         username: String,
+//     }
+//
+// and taken as `Valid<CreateUser>` instead of `Json<CreateUser>`. Bodies breaking them are
+// answered with `422 Unprocessable Entity` and what's wrong with each field.
+use std::{borrow::Cow, collections::BTreeMap, path::Component};
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        value
+            .validate()
+            .map_err(|errors| Invalid::from(&errors).into_response())?;
+        Ok(Valid(value))
+    }
+}
+
+// what a 422 for a body breaking the constraints says
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub struct Invalid {
+    pub message: String,
+    // what's wrong, by field; nested fields are named like `items[0].title`
+    pub fields: BTreeMap<String, Vec<String>>,
+}
+
+impl From<&ValidationErrors> for Invalid {
+    fn from(errors: &ValidationErrors) -> Self {
+        let mut fields = BTreeMap::new();
+        collect(errors, "", &mut fields);
+        Invalid {
+            message: "the request breaks some constraints".to_owned(),
+            fields,
+        }
+    }
+}
+
+impl IntoResponse for Invalid {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, fields: &mut BTreeMap<String, Vec<String>>) {
+    for (field, kind) in errors.errors() {
+        let name = format!("{}{}", prefix, field);
+        match kind {
+            ValidationErrorsKind::Field(errors) => fields
+                .entry(name)
+                .or_default()
+                .extend(errors.iter().map(describe)),
+            ValidationErrorsKind::Struct(errors) => collect(errors, &format!("{}.", name), fields),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect(errors, &format!("{}[{}].", name, index), fields);
+                }
+            }
+        }
+    }
+}
+
+// the error's message, or one made up from the built-in constraint it comes from
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    let param = |name: &str| error.params.get(name).map(|value| value.to_string());
+    match (error.code.as_ref(), param("min"), param("max")) {
+        ("length", Some(min), Some(max)) => format!("must be {} to {} characters", min, max),
+        ("length", Some(min), None) => format!("must be at least {} characters", min),
+        ("length", None, Some(max)) => format!("must be at most {} characters", max),
+        ("range", Some(min), Some(max)) => format!("must be from {} to {}", min, max),
+        ("range", Some(min), None) => format!("must be at least {}", min),
+        ("range", None, Some(max)) => format!("must be at most {}", max),
+        (code, _, _) => format!("breaks the {} constraint", code),
+    }
+}
+
+fn invalid(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError {
+        code: Cow::Borrowed(code),
+        message: Some(Cow::Borrowed(message)),
+        params: Default::default(),
+    }
+}
+
+// letters, digits, `_`, `-` and `.`, starting with a letter or digit
+pub fn username(name: &str) -> Result<(), ValidationError> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if !name.chars().all(allowed) || !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(invalid(
+            "username",
+            "may have letters, digits, `_`, `-` and `.`, starting with a letter or digit",
+        ));
+    }
+    Ok(())
+}
+
+// a file the server can open: no NUL bytes, no `..` to get out of where it points
+pub fn media_path(path: &str) -> Result<(), ValidationError> {
+    if path.contains('\0') {
+        return Err(invalid("media_path", "may not have NUL bytes"));
+    }
+    if std::path::Path::new(path)
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(invalid("media_path", "may not have `..` components"));
+    }
+    Ok(())
+}
+
+// something to show: not blank, without spaces around it or control characters
+pub fn label(text: &str) -> Result<(), ValidationError> {
+    if text.trim() != text || text.is_empty() {
+        return Err(invalid(
+            "label",
+            "may not be blank or start or end with spaces",
+        ));
+    }
+    if text.chars().any(char::is_control) {
+        return Err(invalid("label", "may not have control characters"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize, Validate)]
+    struct Upload {
+        #[validate(length(min = 2, max = 8), custom = "username")]
+        owner: String,
+        #[validate(custom = "media_path")]
+        path: Option<String>,
+    }
+
+    #[test]
+    fn reports_fields() {
+        let upload = Upload {
+            owner: "jd".to_owned(),
+            path: Some("media/new.mp4".to_owned()),
+        };
+        assert!(upload.validate().is_ok());
+
+        let upload = Upload {
+            owner: "-".to_owned(),
+            path: Some("/srv/media/../../etc/passwd".to_owned()),
+        };
+        let invalid = Invalid::from(&upload.validate().unwrap_err());
+        assert_eq!(
+            invalid.fields["owner"],
+            vec![
+                "must be 2 to 8 characters".to_owned(),
+                "may have letters, digits, `_`, `-` and `.`, starting with a letter or digit"
+                    .to_owned(),
+            ]
+        );
+        assert_eq!(
+            invalid.fields["path"],
+            vec!["may not have `..` components".to_owned()]
+        );
+    }
+}
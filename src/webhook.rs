@@ -0,0 +1,368 @@
+// Webhooks: once a job finished, failed or died, it's POSTed as JSON to the callback URL it was
+// submitted with, or to `webhooks.url` for jobs without one, so that other systems needn't poll
+// `/api/v1/jobs/{id}`:
+//
+//     {"event": "job.finished", "job": {"id": 5, "type": "thumbnail", "state": "finished", ...}}
+//
+// The process that ran the job delivers it, trying again with backoff while the receiver fails or
+// can't be reached. Deliveries still pending when the process stops are lost. Redirects aren't
+// followed.
+//
+// Deliveries carry `X-Request-Id: job-{id}`, the same for each attempt. With `webhooks.secret`
+// set every delivery is signed: `X-Webhook-Timestamp` is the unix time and `X-Webhook-Signature`
+// is "sha256=" and the hex HMAC-SHA256 of "{timestamp}.{body}". Callback
+// URLs are chosen by whoever submits the job, so they're only taken with a secret, and are only
+// delivered to when their host resolves to public addresses, not to the network of the server.
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::header;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::{
+    api::{v1, ToVersion},
+    correlation::Correlation,
+    job::{Job, JobState},
+    task,
+};
+
+const TIMESTAMP: &str = "x-webhook-timestamp";
+const SIGNATURE: &str = "x-webhook-signature";
+// shorter secrets are refused by the config check
+const MIN_SECRET: usize = 16;
+// the longest wait between tries, however many came before
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+type HmacSha256 = Hmac<Sha256>;
+
+// `[webhooks]` in the config
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // where jobs submitted without a callback URL are delivered, nowhere by default
+    pub url: Option<String>,
+    // signs deliveries when set, and needed for jobs to have callback URLs of their own
+    pub secret: Option<String>,
+    // tries per delivery before giving up
+    pub attempts: u32,
+    // seconds before the second try, doubling for each after it
+    pub backoff: u64,
+    // seconds receivers get to answer
+    pub timeout: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            url: None,
+            secret: None,
+            attempts: 5,
+            backoff: 10,
+            timeout: 10,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.url {
+            check_url(url).map_err(|err| format!("webhooks.url: {}", err))?;
+        }
+        if self
+            .secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_SECRET)
+        {
+            return Err(format!(
+                "webhooks.secret needs at least {} characters",
+                MIN_SECRET
+            ));
+        }
+        if self.attempts == 0 {
+            return Err("webhooks.attempts must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// whether `url` can take deliveries, for callback URLs of submitted jobs too
+pub fn check_url(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("{} urls can't take webhooks", scheme)),
+    }
+}
+
+// whether `ip` is on the internet rather than in a private, loopback, link-local or reserved
+// network, such as the cloud metadata service at 169.254.169.254
+fn public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local
+                || (first & 0xfe00) == 0xfc00
+                // link-local
+                || (first & 0xffc0) == 0xfe80
+                // documentation
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+// deliveries go where they're sent, not where receivers redirect them
+fn client() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().redirect(reqwest::redirect::Policy::none())
+}
+
+#[derive(Serialize, Debug)]
+struct Payload {
+    // `job.finished`, `job.failed` or `job.dead`
+    event: String,
+    job: v1::Job,
+}
+
+pub struct Webhooks {
+    settings: Settings,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(settings: Settings) -> Self {
+        Webhooks {
+            settings,
+            client: client().build().expect("the webhook client builds"),
+        }
+    }
+
+    // whether a job may be submitted with `url` as its callback URL
+    pub fn check_callback(&self, url: &str) -> Result<(), String> {
+        if self.settings.secret.is_none() {
+            return Err(
+                "callback URLs need webhooks.secret, so receivers can tell deliveries are ours"
+                    .to_owned(),
+            );
+        }
+        check_url(url)?;
+        let url = reqwest::Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+        let host = url.host_str().ok_or("callback URLs need a host")?;
+        // names are checked once resolved, on delivery
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) if !public(ip) => Err(format!("{} isn't a public address", ip)),
+            _ => Ok(()),
+        }
+    }
+
+    // delivers the done `job` in the background, to its callback URL or `webhooks.url`
+    pub fn notify(self: &Arc<Self>, job: Job) {
+        // callback URLs are the submitter's, `webhooks.url` the operator's
+        let (url, callback) = match (&job.callback_url, &self.settings.url) {
+            (Some(url), _) => (url.clone(), true),
+            (None, Some(url)) => (url.clone(), false),
+            (None, None) => return,
+        };
+        let id = job.id;
+        let Some(body) = payload(job) else {
+            return;
+        };
+        let webhooks = self.clone();
+        let name = format!("webhook of job {}", id);
+        task::spawn(name, task::Kind::Work, async move {
+            webhooks.deliver(id, &url, callback, body).await;
+        });
+    }
+
+    async fn deliver(&self, id: i64, url: &str, callback: bool, body: Vec<u8>) {
+        let mut backoff = Duration::from_secs(self.settings.backoff);
+        // the same for each attempt, so receivers can tell one delivery from another
+        let correlation = Correlation::new(format!("job-{}", id));
+        for attempt in 1..=self.settings.attempts {
+            let err = match self.post(url, callback, &body, &correlation).await {
+                Ok(()) => {
+                    info!("delivered job {} to {}", id, url);
+                    return;
+                }
+                Err(err) => err,
+            };
+            if attempt == self.settings.attempts {
+                warn!(
+                    "delivering job {} to {} failed {} times, giving up: {}",
+                    id, url, attempt, err
+                );
+                return;
+            }
+            warn!(
+                "delivering job {} to {} failed, trying again in {:?}: {}",
+                id, url, backoff, err
+            );
+            sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn post(
+        &self,
+        url: &str,
+        callback: bool,
+        body: &[u8],
+        correlation: &Correlation,
+    ) -> Result<(), String> {
+        let client = match callback {
+            true => pinned(url).await?,
+            false => self.client.clone(),
+        };
+        let mut request = correlation
+            .apply(client.post(url))
+            .timeout(Duration::from_secs(self.settings.timeout))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(secret) = &self.settings.secret {
+            let timestamp = Utc::now().timestamp();
+            request = request
+                .header(TIMESTAMP, timestamp.to_string())
+                .header(SIGNATURE, signature(secret, timestamp, body));
+        }
+        let response = request.send().await.map_err(|err| err.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("the receiver answered {}", status)),
+        }
+    }
+}
+
+// A client for `url` that connects to where its host resolves now, if that's public. Resolving once
+// for both, a name answering with a public address for the check and a private one for the
+// connection doesn't get around it.
+async fn pinned(url: &str) -> Result<reqwest::Client, String> {
+    let url = reqwest::Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+    let host = url.host_str().ok_or("no host")?.to_owned();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|err| format!("can't resolve {}: {}", host, err))?
+        .collect();
+    if let Some(private) = addresses.iter().find(|address| !public(address.ip())) {
+        return Err(format!(
+            "{} resolves to {}, which isn't public",
+            host,
+            private.ip()
+        ));
+    }
+    let address = addresses
+        .first()
+        .ok_or_else(|| format!("{} resolves to nothing", host))?;
+    client()
+        .resolve(&host, *address)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+// the JSON delivered for `job`, None unless it's done
+fn payload(job: Job) -> Option<Vec<u8>> {
+    let event = match job.state {
+        JobState::Finished => "job.finished",
+        JobState::Failed => "job.failed",
+        JobState::Dead => "job.dead",
+        JobState::Queued | JobState::Running => return None,
+    };
+    let payload = Payload {
+        event: event.to_owned(),
+        job: job.to_version(),
+    };
+    serde_json::to_vec(&payload).ok()
+}
+
+fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(check_url("https://hooks.example.com/rsapp"), Ok(()));
+        assert!(check_url("ftp://example.com").is_err());
+        assert!(check_url("not a url").is_err());
+        let settings = Settings {
+            secret: Some("short".to_owned()),
+            ..Settings::default()
+        };
+        assert!(settings.validate().is_err());
+        assert_eq!(Settings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn callbacks_need_a_secret_and_a_public_host() {
+        let unsigned = Webhooks::new(Settings::default());
+        assert!(unsigned
+            .check_callback("https://hooks.example.com/rsapp")
+            .unwrap_err()
+            .contains("webhooks.secret"));
+        let webhooks = Webhooks::new(Settings {
+            secret: Some("0123456789abcdef".to_owned()),
+            ..Settings::default()
+        });
+        assert_eq!(
+            webhooks.check_callback("https://hooks.example.com/rsapp"),
+            Ok(())
+        );
+        assert_eq!(webhooks.check_callback("http://93.184.216.34/hook"), Ok(()));
+        for private in [
+            "http://127.0.0.1:9009/admin",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.1.2.3/",
+            "http://[::1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[fd00::1]/",
+        ] {
+            assert!(webhooks.check_callback(private).is_err(), "{}", private);
+        }
+        assert!(!public("100.64.0.1".parse().unwrap()));
+        assert!(public("2606:4700::1111".parse().unwrap()));
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        let signed = signature("0123456789abcdef", 1708531200, br#"{"event":"job.failed"}"#);
+        assert!(signed.starts_with("sha256="));
+        assert_eq!(signed.len(), "sha256=".len() + 64);
+        assert_ne!(
+            signed,
+            signature("0123456789abcdef", 1708531201, br#"{"event":"job.failed"}"#)
+        );
+    }
+}